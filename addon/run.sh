@@ -32,10 +32,117 @@ if bashio::config.has_value mqtt_password ; then
   export GOVEE_MQTT_PASSWORD="$(bashio::config mqtt_password)"
 fi
 
+if bashio::config.has_value mqtt_topic_prefix ; then
+  export GOVEE_MQTT_TOPIC_PREFIX="$(bashio::config mqtt_topic_prefix)"
+fi
+
+if bashio::config.has_value mqtt_discovery_qos ; then
+  export GOVEE_MQTT_DISCOVERY_QOS="$(bashio::config mqtt_discovery_qos)"
+fi
+
+if bashio::config.has_value mqtt_discovery_retain ; then
+  export GOVEE_MQTT_DISCOVERY_RETAIN="$(bashio::config mqtt_discovery_retain)"
+fi
+
+if bashio::config.has_value mqtt_state_qos ; then
+  export GOVEE_MQTT_STATE_QOS="$(bashio::config mqtt_state_qos)"
+fi
+
+if bashio::config.has_value mqtt_state_retain ; then
+  export GOVEE_MQTT_STATE_RETAIN="$(bashio::config mqtt_state_retain)"
+fi
+
+if bashio::config.has_value mqtt_command_qos ; then
+  export GOVEE_MQTT_COMMAND_QOS="$(bashio::config mqtt_command_qos)"
+fi
+
+if bashio::config.has_value availability_grace_period ; then
+  export GOVEE_AVAILABILITY_GRACE_PERIOD="$(bashio::config availability_grace_period)"
+fi
+if bashio::config.has_value device_removal_cycles ; then
+  export GOVEE_DEVICE_REMOVAL_CYCLES="$(bashio::config device_removal_cycles)"
+fi
+
+if bashio::config.has_value hass_republish_interval ; then
+  export GOVEE_HASS_REPUBLISH_INTERVAL="$(bashio::config hass_republish_interval)"
+fi
+
+if bashio::config.has_value hass_discovery_stagger ; then
+  export GOVEE_HASS_DISCOVERY_STAGGER="$(bashio::config hass_discovery_stagger)"
+fi
+
+if bashio::config.has_value sensor_calibration ; then
+  export GOVEE_SENSOR_CALIBRATION="$(bashio::config sensor_calibration)"
+fi
+
+if bashio::config.has_value entity_names ; then
+  export GOVEE_ENTITY_NAMES="$(bashio::config entity_names)"
+fi
+
+if bashio::config.has_value entity_icons ; then
+  export GOVEE_ENTITY_ICONS="$(bashio::config entity_icons)"
+fi
+
+if bashio::config.has_value include_entities ; then
+  export GOVEE_INCLUDE_ENTITIES="$(bashio::config include_entities)"
+fi
+
+if bashio::config.has_value exclude_entities ; then
+  export GOVEE_EXCLUDE_ENTITIES="$(bashio::config exclude_entities)"
+fi
+
+if bashio::config.has_value include_devices ; then
+  export GOVEE_INCLUDE_DEVICES="$(bashio::config include_devices)"
+fi
+
+if bashio::config.has_value exclude_devices ; then
+  export GOVEE_EXCLUDE_DEVICES="$(bashio::config exclude_devices)"
+fi
+
 if bashio::config.has_value debug_level ; then
   export RUST_LOG="$(bashio::config debug_level)"
 fi
 
+if bashio::config.has_value log_devices ; then
+  export GOVEE_LOG_DEVICES="$(bashio::config log_devices)"
+fi
+
+if bashio::config.has_value log_format ; then
+  export GOVEE_LOG_FORMAT="$(bashio::config log_format)"
+fi
+
+if bashio::config.true debug_sensors ; then
+  export GOVEE_DEBUG_SENSORS=true
+fi
+
+if bashio::config.true use_sku_names ; then
+  export GOVEE_USE_SKU_NAMES=true
+fi
+
+if bashio::config.true disable_transitions ; then
+  export GOVEE_DISABLE_TRANSITIONS=true
+fi
+
+if bashio::config.true gamut_clamping ; then
+  export GOVEE_GAMUT_CLAMPING=true
+fi
+
+if bashio::config.true scene_categories ; then
+  export GOVEE_SCENE_CATEGORIES=true
+fi
+
+if bashio::config.has_value color_mode_priority ; then
+  export GOVEE_COLOR_MODE_PRIORITY="$(bashio::config color_mode_priority)"
+fi
+
+if bashio::config.has_value group_command_window ; then
+  export GOVEE_GROUP_COMMAND_WINDOW="$(bashio::config group_command_window)"
+fi
+
+if bashio::config.has_value device_timeout ; then
+  export GOVEE_DEVICE_TIMEOUT="$(bashio::config device_timeout)"
+fi
+
 if bashio::config.has_value govee_email ; then
   export GOVEE_EMAIL="$(bashio::config govee_email)"
 fi
@@ -48,6 +155,14 @@ if bashio::config.has_value govee_api_key ; then
   export GOVEE_API_KEY="$(bashio::config govee_api_key)"
 fi
 
+if bashio::config.has_value platform_cache_ttl ; then
+  export GOVEE_PLATFORM_CACHE_TTL="$(bashio::config platform_cache_ttl)"
+fi
+
+if bashio::config.has_value extra_accounts ; then
+  export GOVEE_EXTRA_ACCOUNTS="$(bashio::config extra_accounts | jq -s -c .)"
+fi
+
 if bashio::config.has_value no_multicast ; then
   export GOVEE_LAN_NO_MULTICAST="$(bashio::config no_multicast)"
 fi
@@ -64,11 +179,35 @@ if bashio::config.has_value scan ; then
   export GOVEE_LAN_SCAN="$(bashio::config scan)"
 fi
 
+if bashio::config.has_value lan_only ; then
+  export GOVEE_LAN_ONLY="$(bashio::config lan_only)"
+fi
+
+if bashio::config.true persist_state ; then
+  export GOVEE_STATE_FILE=/data/govee2mqtt-state.json
+fi
+
+if bashio::config.true enable_metrics ; then
+  export GOVEE_ENABLE_METRICS=true
+fi
+
+if bashio::config.has_value http_token ; then
+  export GOVEE_HTTP_TOKEN="$(bashio::config http_token)"
+fi
+
+if bashio::config.true dry_run ; then
+  export GOVEE_DRY_RUN=true
+fi
+
+if bashio::config.has_value quirks_file ; then
+  export GOVEE_QUIRKS_FILE="$(bashio::config quirks_file)"
+fi
+
 if bashio::config.has_value temperature_scale ; then
   export GOVEE_TEMPERATURE_SCALE="$(bashio::config temperature_scale)"
 fi
 
-env | grep GOVEE_ | sed -r 's/_(EMAIL|KEY|PASSWORD)=.*/_\1=REDACTED/'
+env | grep GOVEE_ | sed -r 's/_(EMAIL|KEY|PASSWORD|ACCOUNTS|TOKEN)=.*/_\1=REDACTED/'
 set -x
 
 cd /app
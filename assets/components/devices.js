@@ -3,6 +3,17 @@ import { styleMap} from "lit/directives/style-map.js";
 import { Task } from '@lit/task';
 import { timeAgo } from './timeago.js';
 
+// When the HTTP API requires a token, the web UI is opened with
+// ?token=... and passes it along with each request
+const token = new URLSearchParams(window.location.search).get('token');
+
+function apiFetch(url, options = {}) {
+  if (token) {
+    options.headers = { ...options.headers, Authorization: `Bearer ${token}` };
+  }
+  return fetch(url, options);
+}
+
 export class DeviceList extends LitElement {
   timer;
   deviceList;
@@ -20,7 +31,7 @@ export class DeviceList extends LitElement {
 
   _deviceListTask = new Task(this, {
     task: async ([], {signal}) => {
-      const response = await fetch('/api/devices', {signal});
+      const response = await apiFetch('/api/devices', {signal});
       if (!response.ok) {
         throw new Error(response.status);
       }
@@ -77,14 +88,14 @@ export class DeviceList extends LitElement {
   _set_power_on(e) {
     const device_id = e.target.dataset.id;
     const power = e.target.checked ? 'on' : 'off';
-    fetch(`/api/device/${device_id}/power/${power}`);
+    apiFetch(`/api/device/${device_id}/power/${power}`);
   }
 
   _set_color(e) {
     const device_id = e.target.dataset.id;
     const color = encodeURIComponent(e.target.value);
     console.log(`color will change to ${color}`);
-    fetch(`/api/device/${device_id}/color/${color}`);
+    apiFetch(`/api/device/${device_id}/color/${color}`);
   }
 
   _render_item = (item) => {
@@ -0,0 +1,170 @@
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::platform_api::{DeviceParameters, HttpDeviceInfo};
+use serde::Serialize;
+
+/// Show the devices in the account and their capabilities, as
+/// reported by the Platform API. This is useful to include when
+/// reporting an issue with a device.
+#[derive(clap::Parser, Debug)]
+pub struct DumpDevicesCommand {
+    /// Print the raw device list as JSON, rather than a summary
+    #[arg(long)]
+    json: bool,
+
+    /// Only show devices whose SKU or id matches
+    #[arg(long)]
+    device: Option<String>,
+}
+
+/// Show at most this many options for an enum;
+/// some devices have hundreds of scenes
+const MAX_OPTIONS: usize = 12;
+
+impl DumpDevicesCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let client = args.api_args.api_client()?;
+
+        let mut devices: Vec<HttpDeviceInfo> = client
+            .get_devices()
+            .await?
+            .into_iter()
+            .filter(|info| match &self.device {
+                Some(entry) => crate::service::device_filter::entry_matches_sku_or_id(
+                    entry,
+                    &info.sku,
+                    &info.device,
+                ),
+                None => true,
+            })
+            .collect();
+        devices.sort_by(|a, b| (&a.sku, &a.device).cmp(&(&b.sku, &b.device)));
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&devices)?);
+            return Ok(());
+        }
+
+        for info in &devices {
+            println!(
+                "{sku} {id} {name} ({kind})",
+                sku = info.sku,
+                id = info.device,
+                name = info.device_name,
+                kind = enum_name(&info.device_type, "devices.types."),
+            );
+            for cap in &info.capabilities {
+                println!(
+                    "  {instance}: {kind}{params}",
+                    instance = cap.instance,
+                    kind = enum_name(&cap.kind, "devices.capabilities."),
+                    params = cap
+                        .parameters
+                        .as_ref()
+                        .map(|p| format!(" {}", describe_parameters(p)))
+                        .unwrap_or_default()
+                );
+            }
+            print_work_modes(info);
+            println!();
+        }
+
+        Ok(())
+    }
+}
+
+fn print_work_modes(info: &HttpDeviceInfo) {
+    let Some(cap) = info.capability_by_instance("workMode") else {
+        return;
+    };
+    let work_modes = match ParsedWorkMode::with_capability(cap) {
+        Ok(mut work_modes) => {
+            work_modes.adjust_for_device(&info.sku);
+            work_modes
+        }
+        Err(err) => {
+            println!("  work modes: unable to parse: {err:#}");
+            return;
+        }
+    };
+
+    println!("  work modes:");
+    for mode in work_modes.modes.values() {
+        let values = match mode.contiguous_value_range() {
+            Some(range) => format!("{}..={}", range.start, range.end - 1),
+            None => mode
+                .values
+                .iter()
+                .map(|v| v.computed_label.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        println!(
+            "    {label} ({value}): {values}",
+            label = mode.label(),
+            value = mode.value
+        );
+    }
+}
+
+/// Returns the name of a Platform API enum, such as a device
+/// type, without the common prefix
+fn enum_name<T: Serialize>(value: &T, prefix: &str) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s.strip_prefix(prefix).unwrap_or(&s).to_string(),
+        _ => "?".to_string(),
+    }
+}
+
+fn describe_parameters(params: &DeviceParameters) -> String {
+    match params {
+        DeviceParameters::Integer { unit, range } => format!(
+            "{}..={}{}",
+            range.min,
+            range.max,
+            unit.as_ref()
+                .map(|unit| format!(" {unit}"))
+                .unwrap_or_default()
+        ),
+        DeviceParameters::Enum { options } => {
+            let mut names: Vec<String> = options
+                .iter()
+                .take(MAX_OPTIONS)
+                .map(|opt| match opt.value.as_i64() {
+                    Some(value) => format!("{}={value}", opt.name),
+                    None => opt.name.to_string(),
+                })
+                .collect();
+            if options.len() > MAX_OPTIONS {
+                names.push(format!("and {} more", options.len() - MAX_OPTIONS));
+            }
+            format!("[{}]", names.join(", "))
+        }
+        DeviceParameters::Struct { fields } => {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|field| {
+                    format!(
+                        "{}: {}",
+                        field.field_name,
+                        describe_parameters(&field.field_type)
+                    )
+                })
+                .collect();
+            format!("{{{}}}", fields.join("; "))
+        }
+        DeviceParameters::Array {
+            size,
+            element_range,
+            ..
+        } => {
+            let mut result = "array".to_string();
+            if let Some(size) = size {
+                result.push_str(&format!(" of {}..={}", size.min, size.max));
+            }
+            if let Some(range) = element_range {
+                result.push_str(&format!(" in {}..={}", range.min, range.max));
+            }
+            result
+        }
+    }
+}
@@ -1,7 +1,9 @@
+pub mod dump_devices;
 pub mod http_control;
 pub mod lan_control;
 pub mod lan_disco;
 pub mod list;
 pub mod list_http;
+pub mod self_test;
 pub mod serve;
 pub mod undoc;
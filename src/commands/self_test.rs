@@ -0,0 +1,235 @@
+use crate::hass_mqtt::work_mode::{current_work_mode, ParsedWorkMode};
+use crate::lan_api::DeviceColor;
+use crate::platform_api::{GoveeApiClient, HttpDeviceInfo};
+use crate::service::device::Device as ServiceDevice;
+use std::time::Duration;
+
+/// Exercises each of the capabilities of a device in turn, logging
+/// the outcome of each step, to help verify that a device works
+/// end-to-end. The device will visibly change its state while the
+/// test is running, and is returned to its prior state afterwards.
+#[derive(clap::Parser, Debug)]
+pub struct SelfTestCommand {
+    /// The id of the device to test
+    #[arg(long)]
+    pub id: String,
+
+    /// How long to wait after each step, in seconds
+    #[arg(long, default_value_t = 3)]
+    pub delay: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    Power(bool),
+    Brightness(u8),
+    Color(DeviceColor),
+    Temperature(u32),
+    WorkMode { name: String, mode: i64, value: i64 },
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Power(on) => write!(fmt, "power {}", if *on { "on" } else { "off" }),
+            Self::Brightness(percent) => write!(fmt, "brightness {percent}%"),
+            Self::Color(DeviceColor { r, g, b }) => write!(fmt, "color #{r:02x}{g:02x}{b:02x}"),
+            Self::Temperature(kelvin) => write!(fmt, "color temperature {kelvin}K"),
+            Self::WorkMode { name, value, .. } => write!(fmt, "work mode {name} ({value})"),
+        }
+    }
+}
+
+/// Returns the steps that exercise the capabilities of the device
+fn plan_steps(device: &ServiceDevice) -> Vec<Step> {
+    let Some(info) = &device.http_device_info else {
+        return vec![];
+    };
+    let mut steps = vec![];
+
+    if info.capability_by_instance("powerSwitch").is_some() {
+        steps.push(Step::Power(false));
+        steps.push(Step::Power(true));
+    }
+    if info.supports_brightness() {
+        steps.push(Step::Brightness(100));
+        steps.push(Step::Brightness(25));
+        steps.push(Step::Brightness(100));
+    }
+    if info.supports_rgb() {
+        for (r, g, b) in [(255, 0, 0), (0, 255, 0), (0, 0, 255)] {
+            steps.push(Step::Color(DeviceColor { r, g, b }));
+        }
+    }
+    if let Some((min, max)) = info.get_color_temperature_range() {
+        steps.push(Step::Temperature(min));
+        steps.push(Step::Temperature(max));
+    }
+    if let Ok(work_modes) = ParsedWorkMode::with_device(device) {
+        for mode in work_modes.modes.values() {
+            if let Some(number) = mode.value.as_i64() {
+                steps.push(Step::WorkMode {
+                    name: mode.name.to_string(),
+                    mode: number,
+                    value: mode.default_value(),
+                });
+            }
+        }
+    }
+
+    steps
+}
+
+/// Returns the steps that return the device to the state that it
+/// reported before the test. The power is restored last, so that
+/// a device that was off doesn't turn back on.
+fn restore_steps(device: &ServiceDevice) -> Vec<Step> {
+    let (Some(info), Some(state)) = (&device.http_device_info, device.device_state()) else {
+        return vec![];
+    };
+    let mut steps = vec![];
+
+    if let (Ok(work_modes), Some((mode, value))) = (
+        ParsedWorkMode::with_device(device),
+        current_work_mode(device),
+    ) {
+        if let Some(work_mode) = work_modes.mode_for_value(&mode.into()) {
+            steps.push(Step::WorkMode {
+                name: work_mode.name.to_string(),
+                mode,
+                value: value.unwrap_or_else(|| work_mode.default_value()),
+            });
+        }
+    }
+    if state.kelvin > 0 && info.get_color_temperature_range().is_some() {
+        steps.push(Step::Temperature(state.kelvin));
+    } else if info.supports_rgb() {
+        steps.push(Step::Color(state.color));
+    }
+    if info.supports_brightness() {
+        steps.push(Step::Brightness(state.brightness));
+    }
+    if info.capability_by_instance("powerSwitch").is_some() {
+        steps.push(Step::Power(state.on));
+    }
+
+    steps
+}
+
+async fn run_step(
+    client: &GoveeApiClient,
+    info: &HttpDeviceInfo,
+    step: &Step,
+) -> anyhow::Result<()> {
+    match step {
+        Step::Power(on) => client.set_power_state(info, *on).await?,
+        Step::Brightness(percent) => client.set_brightness(info, *percent).await?,
+        Step::Color(DeviceColor { r, g, b }) => client.set_color_rgb(info, *r, *g, *b).await?,
+        Step::Temperature(kelvin) => client.set_color_temperature(info, *kelvin).await?,
+        Step::WorkMode { mode, value, .. } => client.set_work_mode(info, *mode, *value).await?,
+    };
+    Ok(())
+}
+
+impl SelfTestCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let client = args.api_args.api_client()?;
+        let info = client.get_device_by_id(&self.id).await?;
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info.clone());
+
+        let restore = match client.get_device_state(&info).await {
+            Ok(state) => {
+                let mut before = device.clone();
+                before.set_http_device_state(state);
+                restore_steps(&before)
+            }
+            Err(err) => {
+                log::warn!(
+                    "Unable to query the state of {device}, so it won't be restored: {err:#}"
+                );
+                vec![]
+            }
+        };
+
+        let steps = plan_steps(&device);
+        if steps.is_empty() {
+            anyhow::bail!("{device} has no capabilities that can be tested");
+        }
+        log::info!("Testing {} capabilities of {device}", steps.len());
+
+        let delay = Duration::from_secs(self.delay);
+        let mut failures = 0;
+        for step in &steps {
+            match run_step(&client, &info, step).await {
+                Ok(()) => log::info!("{step}: ok"),
+                Err(err) => {
+                    log::error!("{step}: failed: {err:#}");
+                    failures += 1;
+                }
+            }
+            tokio::time::sleep(delay).await;
+        }
+
+        // Restore the prior state regardless of the outcome of the test
+        if !restore.is_empty() {
+            log::info!("Restoring the prior state of {device}");
+        }
+        for step in &restore {
+            if let Err(err) = run_step(&client, &info, step).await {
+                log::error!("Restoring {step}: failed: {err:#}");
+            }
+        }
+
+        if failures > 0 {
+            anyhow::bail!("{failures} of {} steps failed for {device}", steps.len());
+        }
+        log::info!("All {} steps passed for {device}", steps.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn steps() {
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("../../test-data/replay/H7131.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(fixture["device"].clone()).unwrap();
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+
+        let steps = plan_steps(&device);
+        assert_eq!(steps[0], Step::Power(false));
+        assert_eq!(steps[1], Step::Power(true));
+        assert!(steps.contains(&Step::Brightness(25)));
+        assert!(steps.iter().any(|s| matches!(s, Step::WorkMode { .. })));
+        assert!(!steps.iter().any(|s| matches!(s, Step::Temperature(_))));
+
+        assert!(plan_steps(&ServiceDevice::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A")).is_empty());
+    }
+
+    #[test]
+    fn restore() {
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("../../test-data/replay/H7131.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(fixture["device"].clone()).unwrap();
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+        assert!(restore_steps(&device).is_empty());
+
+        device.set_http_device_state(serde_json::from_value(fixture["state"].clone()).unwrap());
+        let steps = restore_steps(&device);
+        assert!(matches!(
+            steps.first(),
+            Some(Step::WorkMode {
+                mode: 1,
+                value: 2,
+                ..
+            })
+        ));
+        assert_eq!(steps.last(), Some(&Step::Power(true)));
+    }
+}
@@ -1,24 +1,146 @@
-use crate::lan_api::Client as LanClient;
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::sensor::{BridgeStatusSensor, PlatformQueueDepthSensor};
+use crate::lan_api::{truthy, Client as LanClient};
+use crate::opt_env_var;
+use crate::platform_api::GoveeApiClient;
+use crate::service::account::parse_extra_accounts;
 use crate::service::device::Device;
-use crate::service::hass::spawn_hass_integration;
+use crate::service::dry_run::set_dry_run;
+use crate::service::hass::{apply_reloadable_settings, spawn_hass_integration};
 use crate::service::http::run_http_server;
 use crate::service::iot::start_iot_client;
-use crate::service::state::StateHandle;
+use crate::service::log_context;
+use crate::service::quirks::load_custom_quirks;
+use crate::service::state::{ReloadSources, StateHandle};
+use crate::undoc_api::GoveeUndocumentedApi;
 use crate::version_info::govee_version;
 use anyhow::Context;
 use chrono::Utc;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::time::{sleep, Duration};
 
-pub const POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+pub static POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+
+/// LAN devices can be queried without using any of our cloud
+/// API request quota, so we can afford to poll them more often
+pub static LAN_POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(60));
+
+/// For this long after a device has been controlled, we poll it
+/// at CONTROL_POLL_INTERVAL rather than its usual interval, so that
+/// any state changes that are a side effect of the control are
+/// picked up promptly
+pub static CONTROL_POLL_WINDOW: Lazy<chrono::Duration> =
+    Lazy::new(|| chrono::Duration::seconds(120));
+pub static CONTROL_POLL_INTERVAL: Lazy<chrono::Duration> =
+    Lazy::new(|| chrono::Duration::seconds(15));
+
+/// Bounds on how frequently the periodic poller wakes up to
+/// see which devices are due for a poll
+const MIN_POLL_TICK: Duration = Duration::from_secs(5);
+const MAX_POLL_TICK: Duration = Duration::from_secs(60);
+
+/// How often we check whether the Platform API queue depth
+/// has changed and needs to be reported to hass
+const QUEUE_DEPTH_REPORT_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(clap::Parser, Debug)]
 pub struct ServeCommand {
     /// The port on which the HTTP API will listen
     #[arg(long, default_value_t = 8056)]
     http_port: u16,
+
+    /// Save the last known state of each device to this file,
+    /// so that it can be reported immediately after a restart
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Expose operational metrics in the Prometheus text format
+    /// at /metrics on the HTTP API port
+    #[arg(long)]
+    enable_metrics: bool,
+
+    /// Require this token to use the HTTP API, either as a bearer
+    /// token in the Authorization header, or as the token query
+    /// parameter. If not passed here, it will be read from the
+    /// GOVEE_HTTP_TOKEN environment variable, which is preferred
+    /// as the command line is visible to other users via `ps`.
+    #[arg(long)]
+    http_token: Option<String>,
+
+    /// Log the commands that would be sent to devices, rather than
+    /// sending them. Devices are still discovered and their state is
+    /// still reported, so that automations can be tested safely.
+    /// You may also set GOVEE_DRY_RUN=true via the environment.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// A JSON list of additional Govee accounts whose devices should
+    /// be merged with those of the primary account, for example:
+    /// `[{"label": "shop", "api_key": "...", "email": "...", "password": "..."}]`.
+    /// If not passed here, it will be read from the
+    /// GOVEE_EXTRA_ACCOUNTS environment variable, which is preferred
+    /// as the command line is visible to other users via `ps`.
+    #[arg(long)]
+    extra_accounts: Option<String>,
+
+    /// Load additional quirks, or overrides for the built-in quirks,
+    /// from this JSON file. Invalid entries are logged and ignored.
+    /// If not passed here, it will be read from the GOVEE_QUIRKS_FILE
+    /// environment variable.
+    #[arg(long)]
+    quirks_file: Option<PathBuf>,
+}
+
+/// Use the cloud APIs of an account to determine its devices and
+/// their names, and connect to its IoT service.
+/// account is None for the primary account.
+async fn discover_account_devices(
+    args: &crate::Args,
+    state: &StateHandle,
+    account: Option<&str>,
+    platform: Option<GoveeApiClient>,
+    undoc: Option<GoveeUndocumentedApi>,
+) -> anyhow::Result<()> {
+    let for_account = account
+        .map(|label| format!(" for account {label}"))
+        .unwrap_or_default();
+
+    if let Some(client) = platform {
+        log::info!("Querying platform API for device list{for_account}");
+        for info in client.get_devices().await? {
+            let mut device = state.device_mut(&info.sku, &info.device).await;
+            if device.claim_account(account) {
+                device.set_http_device_info(info);
+            }
+        }
+
+        state.set_platform_client(account, client).await;
+    }
+    if let Some(client) = undoc {
+        log::info!("Querying undocumented API for device + room list{for_account}");
+        let acct = client.login_account_cached().await?;
+        let info = client.get_device_list(&acct.token).await?;
+        let mut group_by_id = HashMap::new();
+        for group in info.groups {
+            group_by_id.insert(group.group_id, group.group_name);
+        }
+        for entry in info.devices {
+            let mut device = state.device_mut(&entry.sku, &entry.device).await;
+            if !device.claim_account(account) {
+                continue;
+            }
+            let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
+            device.set_undoc_device_info(entry, room_name);
+        }
+
+        start_iot_client(args, state.clone(), &client, account, Some(acct)).await?;
+
+        state.set_undoc_client(account, client).await;
+    }
+    Ok(())
 }
 
 async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Result<()> {
@@ -52,13 +174,28 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
 
     let needs_platform = device.needs_platform_poll();
 
-    // Don't interrogate via HTTP if we can use the LAN.
-    // If we have LAN and the device is stale, it is likely
-    // offline and there is little sense in burning up request
-    // quota to the platform API for it
-    if device.lan_device.is_some() && !needs_platform {
-        log::trace!("LAN-available device {device} needs a status update; it's likely offline.");
-        return Ok(());
+    if device.lan_device.is_some() {
+        match state.poll_lan_status(device).await {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                log::debug!("LAN status query for {device} failed: {err:#}");
+                // Try again at the normal interval, rather than holding
+                // up the polling of the other devices on every tick
+                state
+                    .device_mut(&device.sku, &device.id)
+                    .await
+                    .set_last_polled();
+            }
+        }
+
+        // The device didn't respond via the LAN; fall back to the
+        // cloud, but only at the normal cadence, so that an unresponsive
+        // device doesn't burn through our request quota
+        if let Some(device_state) = &device_state {
+            if now - device_state.updated < *POLL_INTERVAL {
+                return Ok(());
+            }
+        }
     }
 
     if !needs_platform {
@@ -75,13 +212,59 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
 async fn periodic_state_poll(state: StateHandle) -> anyhow::Result<()> {
     sleep(Duration::from_secs(20)).await;
     loop {
+        let mut tick = MAX_POLL_TICK;
         for d in state.devices().await {
-            if let Err(err) = poll_single_device(&state, &d).await {
+            if !state.is_device_included(&d).await {
+                continue;
+            }
+            if let Err(err) = log_context::with_device(&d, poll_single_device(&state, &d)).await {
                 log::error!("while polling {d}: {err:#}");
             }
+            // A device that stops responding won't produce a
+            // state change, so we need to check for that here
+            if let Err(err) = state.publish_device_availability(&d.id, false).await {
+                log::error!("while updating availability of {d}: {err:#}");
+            }
+            if let Ok(interval) = d.preferred_poll_interval().to_std() {
+                tick = tick.min(interval);
+            }
+        }
+
+        if let Some(client) = state.get_hass_client().await {
+            if let Err(err) = BridgeStatusSensor::new(&state).notify_state(&client).await {
+                log::error!("while reporting bridge status: {err:#}");
+            }
         }
 
-        sleep(Duration::from_secs(60)).await;
+        sleep(tick.max(MIN_POLL_TICK)).await;
+    }
+}
+
+/// Keeps the Platform API queue depth diagnostic up to date
+async fn periodic_queue_depth_report(state: StateHandle) {
+    let mut last_depth = None;
+    loop {
+        sleep(QUEUE_DEPTH_REPORT_INTERVAL).await;
+        let depth = state.platform_queue_depth().await;
+        if last_depth == Some(depth) {
+            continue;
+        }
+        let Some(client) = state.get_hass_client().await else {
+            continue;
+        };
+        if !state.has_platform_client().await {
+            continue;
+        }
+        match PlatformQueueDepthSensor::new(&state)
+            .notify_state(&client)
+            .await
+        {
+            Ok(()) => last_depth.replace(depth),
+            Err(err) => {
+                log::error!("while reporting Platform API queue depth: {err:#}");
+                None
+            }
+        };
     }
 }
 
@@ -90,40 +273,71 @@ impl ServeCommand {
         log::info!("Starting service. version {}", govee_version());
         let state = Arc::new(crate::service::state::State::new());
 
-        // First, use the HTTP APIs to determine the list of devices and
-        // their names.
+        let quirks_file = match &self.quirks_file {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_QUIRKS_FILE")?,
+        };
+        if let Some(path) = &quirks_file {
+            load_custom_quirks(path);
+        }
 
-        if let Ok(client) = args.api_args.api_client() {
-            log::info!("Querying platform API for device list");
-            for info in client.get_devices().await? {
-                let mut device = state.device_mut(&info.sku, &info.device).await;
-                device.set_http_device_info(info);
-            }
+        let dry_run = self.dry_run
+            || opt_env_var::<String>("GOVEE_DRY_RUN")?
+                .map(|v| truthy(&v))
+                .transpose()?
+                .unwrap_or(false);
+        if dry_run {
+            log::warn!("Dry-run mode: commands will be logged rather than sent to devices");
+        }
+        set_dry_run(dry_run);
 
-            state.set_platform_client(client).await;
+        let lan_only = args.lan_disco_args.lan_only()?;
+        if lan_only {
+            log::info!("LAN-only mode: Govee cloud APIs will not be used");
         }
-        if let Ok(client) = args.undoc_args.api_client() {
-            log::info!("Querying undocumented API for device + room list");
-            let acct = client.login_account_cached().await?;
-            let info = client.get_device_list(&acct.token).await?;
-            let mut group_by_id = HashMap::new();
-            for group in info.groups {
-                group_by_id.insert(group.group_id, group.group_name);
-            }
-            for entry in info.devices {
-                let mut device = state.device_mut(&entry.sku, &entry.device).await;
-                let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
-                device.set_undoc_device_info(entry, room_name);
-            }
 
-            start_iot_client(args, state.clone(), Some(acct)).await?;
+        // First, use the HTTP APIs to determine the list of devices and
+        // their names.
 
-            state.set_undoc_client(client).await;
+        let platform = args.api_args.api_client().ok().filter(|_| !lan_only);
+        let undoc = args.undoc_args.api_client().ok().filter(|_| !lan_only);
+        discover_account_devices(args, &state, None, platform, undoc).await?;
+
+        let extra_accounts = match &self.extra_accounts {
+            Some(json) => Some(json.clone()),
+            None => opt_env_var("GOVEE_EXTRA_ACCOUNTS")?,
+        };
+        if let Some(json) = extra_accounts.filter(|_| !lan_only) {
+            for acct in parse_extra_accounts(&json).context("parsing extra accounts")? {
+                let platform = match &acct.api_key {
+                    Some(key) => Some(
+                        args.api_args
+                            .api_client_for_key(key.to_string())?
+                            .with_account(&acct.label),
+                    ),
+                    None => None,
+                };
+                let undoc = match (&acct.email, &acct.password) {
+                    (Some(email), Some(password)) => {
+                        Some(GoveeUndocumentedApi::new(email, password).with_account(&acct.label))
+                    }
+                    _ => None,
+                };
+                discover_account_devices(args, &state, Some(&acct.label), platform, undoc)
+                    .await
+                    .with_context(|| format!("discovering devices for account {}", acct.label))?;
+            }
         }
 
         // Now start discovery
 
         let options = args.lan_disco_args.to_disco_options()?;
+        if lan_only && options.is_empty() {
+            anyhow::bail!(
+                "LAN-only mode was requested, but all LAN discovery \
+                 options are disabled, so no devices can be found"
+            );
+        }
         if !options.is_empty() {
             log::info!("Starting LAN discovery");
             let state = state.clone();
@@ -132,8 +346,18 @@ impl ServeCommand {
             state.set_lan_client(client.clone()).await;
 
             tokio::spawn(async move {
+                // Devices that we have refreshed the platform device list
+                // for. LAN-only devices never show up in that list, so we
+                // only try once for each of them.
+                let mut refreshed_for = HashSet::new();
                 while let Some(lan_device) = scan.recv().await {
                     log::trace!("LAN disco: {lan_device:?}");
+                    let unknown_to_platform = state
+                        .device_by_id(&lan_device.device)
+                        .await
+                        .map(|device| device.http_device_info.is_none())
+                        .unwrap_or(true)
+                        && refreshed_for.insert(lan_device.device.clone());
                     state
                         .device_mut(&lan_device.sku, &lan_device.device)
                         .await
@@ -142,6 +366,12 @@ impl ServeCommand {
                     let state = state.clone();
                     let client = client.clone();
                     tokio::spawn(async move {
+                        if unknown_to_platform {
+                            // Our cached device list may predate this device
+                            if let Err(err) = state.refresh_platform_device_list().await {
+                                log::error!("refresh_platform_device_list: {err:#}");
+                            }
+                        }
                         if let Ok(status) = client.query_status(&lan_device).await {
                             state
                                 .device_mut(&lan_device.sku, &lan_device.device)
@@ -213,7 +443,7 @@ impl ServeCommand {
                 }
             } else if device.http_device_info.is_none() {
                 log::warn!("  Unknown device type. Cannot map to Home Assistant.");
-                if state.get_platform_client().await.is_none() {
+                if !state.has_platform_client().await && !lan_only {
                     log::warn!(
                         "  Recommendation: configure your Govee API Key so that \
                                   metadata can be fetched from Govee"
@@ -224,6 +454,14 @@ impl ServeCommand {
             log::info!("");
         }
 
+        let state_file = match &self.state_file {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_STATE_FILE")?,
+        };
+        if let Some(path) = state_file {
+            state.load_persisted_state(path).await;
+        }
+
         // Start periodic status polling
         {
             let state = state.clone();
@@ -233,11 +471,44 @@ impl ServeCommand {
                 }
             });
         }
+        tokio::spawn(periodic_queue_depth_report(state.clone()));
+
+        state
+            .set_reload_sources(ReloadSources {
+                hass_args: args.hass_args.clone(),
+                quirks_file,
+            })
+            .await;
 
         // start advertising on local mqtt
-        spawn_hass_integration(state.clone(), &args.hass_args).await?;
+        if args.hass_args.opt_mqtt_host()?.is_some() {
+            spawn_hass_integration(state.clone(), &args.hass_args).await?;
+        } else {
+            log::warn!(
+                "No MQTT broker is configured, so devices can only \
+                 be controlled via the HTTP API"
+            );
+            apply_reloadable_settings(&state, &args.hass_args).await?;
+        }
+
+        let enable_metrics = self.enable_metrics
+            || opt_env_var::<String>("GOVEE_ENABLE_METRICS")?
+                .map(|v| truthy(&v))
+                .transpose()?
+                .unwrap_or(false);
+
+        let http_token = match &self.http_token {
+            Some(token) => Some(token.to_string()),
+            None => opt_env_var("GOVEE_HTTP_TOKEN")?,
+        };
+        if http_token
+            .as_deref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            anyhow::bail!("The HTTP token must not be empty");
+        }
 
-        run_http_server(state.clone(), self.http_port)
+        run_http_server(state.clone(), self.http_port, enable_metrics, http_token)
             .await
             .with_context(|| format!("Starting HTTP service on port {}", self.http_port))
     }
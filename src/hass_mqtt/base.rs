@@ -1,14 +1,56 @@
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::topic_safe_id;
+use crate::service::discovery_gc::SERVICE_IDENTIFIER;
+use crate::service::hass::{availability_topic, device_availability_topic, topic_safe_id};
 use crate::version_info::govee_version;
 use serde::Serialize;
 
 const MODEL: &str = "gv2mqtt";
 const URL: &str = "https://github.com/wez/govee2mqtt";
 
+#[derive(Serialize, Clone, Debug)]
+pub struct AvailabilityTopic {
+    pub topic: String,
+}
+
+/// Entities are available only when govee2mqtt itself is running.
+/// Entities that belong to a device additionally require that
+/// device to be online.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Availability {
+    pub availability: Vec<AvailabilityTopic>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_mode: Option<&'static str>,
+}
+
+impl Availability {
+    pub fn bridge() -> Self {
+        Self {
+            availability: vec![AvailabilityTopic {
+                topic: availability_topic(),
+            }],
+            availability_mode: None,
+        }
+    }
+
+    pub fn for_device(device: &ServiceDevice) -> Self {
+        Self {
+            availability: vec![
+                AvailabilityTopic {
+                    topic: availability_topic(),
+                },
+                AvailabilityTopic {
+                    topic: device_availability_topic(device),
+                },
+            ],
+            availability_mode: Some("all"),
+        }
+    }
+}
+
 #[derive(Serialize, Clone, Debug, Default)]
 pub struct EntityConfig {
-    pub availability_topic: String,
+    #[serde(flatten)]
+    pub availability: Availability,
     pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_class: Option<&'static str>,
@@ -55,17 +97,27 @@ pub struct Device {
     pub connections: Vec<(String, String)>,
 }
 
+/// The identifier of the hass device that represents device
+pub fn device_identifier(device: &ServiceDevice) -> String {
+    format!("gv2mqtt-{}", topic_safe_id(device))
+}
+
 impl Device {
     pub fn for_device(device: &ServiceDevice) -> Self {
         Self {
-            name: device.name(),
-            manufacturer: "Govee".to_string(),
+            name: device.hass_name(),
+            manufacturer: match &device.account {
+                Some(label) => format!("Govee ({label} account)"),
+                None => "Govee".to_string(),
+            },
             model: device.sku.to_string(),
-            sw_version: None,
+            sw_version: device
+                .firmware_version()
+                .and_then(|v| v.summary().map(|s| s.to_string())),
             suggested_area: device.room_name().map(|s| s.to_string()),
             via_device: Some("gv2mqtt".to_string()),
             identifiers: vec![
-                format!("gv2mqtt-{}", topic_safe_id(device)),
+                device_identifier(device),
                 /*
                 device.computed_name(),
                 device.id.to_string(),
@@ -83,7 +135,7 @@ impl Device {
             sw_version: Some(govee_version().to_string()),
             suggested_area: None,
             via_device: None,
-            identifiers: vec!["gv2mqtt".to_string()],
+            identifiers: vec![SERVICE_IDENTIFIER.to_string()],
             connections: vec![],
         }
     }
@@ -0,0 +1,401 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::DeviceCapability;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+/// <https://www.home-assistant.io/integrations/binary_sensor.mqtt/>
+/// We use the default ON/OFF payloads
+#[derive(Serialize, Clone, Debug)]
+pub struct BinarySensorConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+}
+
+impl BinarySensorConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("binary_sensor", state, client, &self.base, self).await
+    }
+
+    pub async fn notify_state(&self, client: &HassClient, on: bool) -> anyhow::Result<()> {
+        client
+            .publish(&self.state_topic, if on { "ON" } else { "OFF" })
+            .await
+    }
+}
+
+/// Interpret the value of an event capability that reports a
+/// condition, such as a full ice basket or a water leak. The events
+/// carry a non-zero value while the condition is present; option
+/// names such as "normal" are used to clear it.
+pub fn event_flag(cap: &DeviceCapability, value: &JsonValue) -> Option<bool> {
+    let options = cap
+        .event_state
+        .as_ref()
+        .and_then(|s| s.get("options"))
+        .and_then(|o| o.as_array());
+    if let Some(options) = options {
+        for opt in options {
+            if opt.get("value") == Some(value) {
+                if let Some(name) = opt.get("name").and_then(|n| n.as_str()) {
+                    if ["normal", "none", "ok", "dry"]
+                        .iter()
+                        .any(|n| name.eq_ignore_ascii_case(n))
+                    {
+                        return Some(false);
+                    }
+                }
+            }
+        }
+    }
+
+    value.as_bool().or_else(|| value.as_i64().map(|v| v != 0))
+}
+
+/// Capability instances used by water leak sensors, such as
+/// the H5054, to report that they have detected water
+pub const LEAK_INSTANCES: &[&str] = &["waterLeakEvent", "leakEvent", "waterLeak", "leak"];
+
+/// A water leak sensor. Leaks are reported as soon as they are seen,
+/// without any debouncing, as they are usually urgent.
+pub struct LeakSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl LeakSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let unique_id = format!("binary-sensor-{id}-leak", id = topic_safe_id(device));
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Leak".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("moisture"),
+                    icon: None,
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", topic_prefix()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LeakSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(cap) = device.get_capability_by_instance(&self.instance_name) else {
+            return Ok(());
+        };
+        match device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|s| s.state.pointer("/value"))
+            .and_then(|value| event_flag(cap, value))
+        {
+            Some(leak) => self.sensor.notify_state(client, leak).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Presence sensors can briefly lose track of someone who is
+/// sitting still; we don't report absence until it has persisted
+/// for this long, to avoid flapping
+const PRESENCE_CLEAR_DELAY: chrono::Duration = chrono::Duration::seconds(60);
+
+/// Occupancy, as reported by the presence event of Govee's
+/// radar based presence sensors
+pub struct PresenceSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+pub const PRESENCE_INSTANCE: &str = "bodyAppearedEvent";
+
+/// Interpret the value of the presence event, using the
+/// option names from the capability where possible
+fn presence_from_value(cap: &DeviceCapability, value: &JsonValue) -> Option<bool> {
+    let options = cap
+        .event_state
+        .as_ref()
+        .and_then(|s| s.get("options"))
+        .and_then(|o| o.as_array());
+    if let Some(options) = options {
+        for opt in options {
+            if opt.get("value") == Some(value) {
+                if let Some(name) = opt.get("name").and_then(|n| n.as_str()) {
+                    if name.eq_ignore_ascii_case("presence") {
+                        return Some(true);
+                    }
+                    if name.eq_ignore_ascii_case("absence") {
+                        return Some(false);
+                    }
+                }
+            }
+        }
+    }
+
+    // Fallback to the values used by the H5127
+    match value.as_i64()? {
+        1 => Some(true),
+        2 => Some(false),
+        _ => None,
+    }
+}
+
+impl PresenceSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Occupancy".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("occupancy"),
+                    icon: None,
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", topic_prefix()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PresenceSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(cap) = device.get_capability_by_instance(&self.instance_name) else {
+            return Ok(());
+        };
+        let Some(present) = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|s| s.state.pointer("/value"))
+            .and_then(|value| presence_from_value(cap, value))
+        else {
+            return Ok(());
+        };
+
+        let (report, recheck) = self
+            .state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .debounce_binary_state(&self.instance_name, present, PRESENCE_CLEAR_DELAY);
+
+        if let Some(delay) = recheck {
+            // Check again once the delay has elapsed
+            let state = self.state.clone();
+            let device_id = self.device_id.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay.to_std().unwrap_or_default()).await;
+                state.notify_of_state_change(&device_id).await
+            });
+        }
+
+        self.sensor.notify_state(client, report).await
+    }
+}
+
+/// Whether the device is connected to the Govee cloud.
+/// This is per-device, unlike the availability topic, which
+/// reflects whether govee2mqtt itself is running.
+pub struct ConnectivitySensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl ConnectivitySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("binary-sensor-{id}-online", id = topic_safe_id(device));
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::bridge(),
+                    name: Some("Connected to Govee Cloud".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("connectivity"),
+                    icon: None,
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", topic_prefix()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ConnectivitySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.is_online() {
+            Some(online) => self.sensor.notify_state(client, online).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reports that a purifier filter needs replacing, either as
+/// reported by the device or inferred from the remaining filter life
+pub struct FilterReplacementSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl FilterReplacementSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-filter-replacement",
+            id = topic_safe_id(device)
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Replace Filter".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("problem"),
+                    icon: Some("mdi:air-filter".to_string()),
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", topic_prefix()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FilterReplacementSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.filter_needs_replacement() {
+            Some(replace) => self.sensor.notify_state(client, replace).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::DeviceCapabilityKind;
+
+    #[test]
+    fn presence_values() {
+        let cap = DeviceCapability {
+            kind: DeviceCapabilityKind::Event,
+            instance: PRESENCE_INSTANCE.to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: Some(serde_json::json!({
+                "options": [
+                    {"name": "Absence", "value": 3},
+                    {"name": "Presence", "value": 4},
+                ]
+            })),
+        };
+        assert_eq!(presence_from_value(&cap, &serde_json::json!(4)), Some(true));
+        assert_eq!(
+            presence_from_value(&cap, &serde_json::json!(3)),
+            Some(false)
+        );
+
+        let cap = DeviceCapability {
+            event_state: None,
+            ..cap
+        };
+        assert_eq!(presence_from_value(&cap, &serde_json::json!(1)), Some(true));
+        assert_eq!(
+            presence_from_value(&cap, &serde_json::json!(2)),
+            Some(false)
+        );
+        assert_eq!(presence_from_value(&cap, &serde_json::json!(7)), None);
+    }
+
+    #[test]
+    fn leak_values() {
+        let cap = DeviceCapability {
+            kind: DeviceCapabilityKind::Event,
+            instance: "waterLeakEvent".to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: Some(serde_json::json!({
+                "options": [
+                    {"name": "leak", "value": 1},
+                    {"name": "dry", "value": 2},
+                ]
+            })),
+        };
+        assert_eq!(event_flag(&cap, &serde_json::json!(1)), Some(true));
+        assert_eq!(event_flag(&cap, &serde_json::json!(2)), Some(false));
+        assert!(LEAK_INSTANCES.contains(&cap.instance.as_str()));
+    }
+}
@@ -1,9 +1,9 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, topic_safe_id, topic_safe_string, HassClient,
+    camel_case_to_space_separated, topic_prefix, topic_safe_id, topic_safe_string, HassClient,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
@@ -26,11 +26,12 @@ impl ButtonConfig {
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
+            "{}/switch/{id}/command/{inst}",
+            topic_prefix(),
             id = topic_safe_id(device),
             inst = instance.instance
         );
-        let availability_topic = availability_topic();
+        let availability = Availability::for_device(device);
         let unique_id = format!(
             "gv2mqtt-{id}-{inst}",
             id = topic_safe_id(device),
@@ -39,7 +40,7 @@ impl ButtonConfig {
 
         Ok(Self {
             base: EntityConfig {
-                availability_topic,
+                availability,
                 name: Some(camel_case_to_space_separated(&instance.instance)),
                 device_class: None,
                 origin: Origin::default(),
@@ -58,7 +59,7 @@ impl ButtonConfig {
         let unique_id = format!("global-{}", topic_safe_string(&name));
         Self {
             base: EntityConfig {
-                availability_topic: availability_topic(),
+                availability: Availability::bridge(),
                 name: Some(name.to_string()),
                 entity_category: None,
                 origin: Origin::default(),
@@ -85,13 +86,14 @@ impl ButtonConfig {
             mode = topic_safe_string(mode_name),
         );
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
+            "{}/number/{id}/command/{mode}/{mode_num}",
+            topic_prefix(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name),
         );
         Self {
             base: EntityConfig {
-                availability_topic: availability_topic(),
+                availability: Availability::for_device(device),
                 name: Some(name.to_string()),
                 entity_category: None,
                 origin: Origin::default(),
@@ -105,18 +107,42 @@ impl ButtonConfig {
         }
     }
 
+    pub fn refresh_device(device: &ServiceDevice) -> Self {
+        let unique_id = format!("gv2mqtt-{id}-refresh", id = topic_safe_id(device));
+        let command_topic = format!(
+            "{}/{id}/refresh",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        Self {
+            base: EntityConfig {
+                availability: Availability::bridge(),
+                name: Some("Refresh State".to_string()),
+                entity_category: None,
+                origin: Origin::default(),
+                device: Device::for_device(device),
+                unique_id: unique_id.clone(),
+                device_class: None,
+                icon: Some("mdi:refresh".to_string()),
+            },
+            command_topic,
+            payload_press: None,
+        }
+    }
+
     pub fn request_platform_data_for_device(device: &ServiceDevice) -> Self {
         let unique_id = format!(
             "gv2mqtt-{id}-request-platform-data",
             id = topic_safe_id(device)
         );
         let command_topic = format!(
-            "gv2mqtt/{id}/request-platform-data",
+            "{}/{id}/request-platform-data",
+            topic_prefix(),
             id = topic_safe_id(device)
         );
         Self {
             base: EntityConfig {
-                availability_topic: availability_topic(),
+                availability: Availability::bridge(),
                 name: Some("Request Platform API State".to_string()),
                 entity_category: Some("diagnostic".to_string()),
                 origin: Origin::default(),
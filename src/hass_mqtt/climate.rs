@@ -1,9 +1,13 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
-use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::number::NumberConfig;
-use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::hass_mqtt::sensor::format_temperature_reading;
+use crate::hass_mqtt::work_mode::{current_work_mode, ParsedWorkMode, WorkMode};
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceParameters, DeviceType};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdParameter,
+};
 use crate::service::state::StateHandle;
 use crate::temperature::{
     TemperatureScale, TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE,
@@ -11,12 +15,10 @@ use crate::temperature::{
 use anyhow::anyhow;
 use axum::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::str::FromStr;
 
-// TODO: register an actual climate entity.
-// I don't have one of these devices, so it is currently guesswork!
-
 pub struct TargetTemperatureEntity {
     number: NumberConfig,
     device_id: String,
@@ -92,19 +94,21 @@ impl TargetTemperatureEntity {
 
         let name = "Target Temperature".to_string();
         let command_topic = format!(
-            "gv2mqtt/{id}/set-temperature/{inst}/{units}",
+            "{}/{id}/set-temperature/{inst}/{units}",
+            topic_prefix(),
             id = topic_safe_id(device),
             inst = topic_safe_string(&instance.instance)
         );
         let state_topic = format!(
-            "gv2mqtt/{id}/advise-set-temperature",
+            "{}/{id}/advise-set-temperature",
+            topic_prefix(),
             id = topic_safe_id(device),
         );
 
         Ok(Self {
             number: NumberConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::for_device(device),
                     name: Some(name),
                     entity_category: None,
                     origin: Origin::default(),
@@ -134,37 +138,17 @@ impl EntityInstance for TargetTemperatureEntity {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
-
-        let quirk = device.resolve_quirk();
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         log::debug!("notify_state for {device} {}", self.instance_name);
 
-        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
-            log::debug!("have: {cap:?}");
-
-            let units = cap
-                .state
-                .pointer("/value/unit")
-                .and_then(|unit| {
-                    unit.as_str()
-                        .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
-                })
-                .or_else(|| quirk.and_then(|q| q.platform_temperature_sensor_units))
-                .unwrap_or(TemperatureUnits::Celsius);
-
-            log::debug!("units are reported as {units:?}");
-
-            let value = match cap
-                .state
-                .pointer("/value/targetTemperature")
-                .and_then(|v| v.as_f64())
-                .map(|v| TemperatureValue::new(v, units))
-            {
+        if device
+            .get_state_capability_by_instance(&self.instance_name)
+            .is_some()
+        {
+            let value = match reported_target_temperature(&device, &self.instance_name) {
                 Some(v) => {
                     let pref_units = self.state.get_temperature_scale().await;
                     log::debug!("reported temp is {v}, pref_units: {pref_units}");
@@ -183,6 +167,362 @@ impl EntityInstance for TargetTemperatureEntity {
     }
 }
 
+/// Returns the target temperature reported by the device
+/// in the state of the capability named instance_name
+fn reported_target_temperature(
+    device: &ServiceDevice,
+    instance_name: &str,
+) -> Option<TemperatureValue> {
+    let cap = device.get_state_capability_by_instance(instance_name)?;
+    log::debug!("have: {cap:?}");
+
+    let units = cap
+        .state
+        .pointer("/value/unit")
+        .and_then(|unit| {
+            unit.as_str()
+                .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
+        })
+        .or_else(|| {
+            device
+                .resolve_quirk()
+                .and_then(|q| q.platform_temperature_sensor_units)
+        })
+        .unwrap_or(TemperatureUnits::Celsius);
+
+    log::debug!("units are reported as {units:?}");
+
+    cap.state
+        .pointer("/value/targetTemperature")
+        .and_then(|v| v.as_f64())
+        .map(|v| TemperatureValue::new(v, units))
+}
+
+const OSCILLATION_INSTANCE: &str = "oscillationToggle";
+
+/// Returns the hvac mode that hass should show for a heater work mode
+fn hvac_mode_for_work_mode(name: &str) -> Option<&'static str> {
+    match name {
+        "gearMode" | "Manual" | "Heat" => Some("heat"),
+        "Fan" => Some("fan_only"),
+        "Auto" => Some("auto"),
+        _ => None,
+    }
+}
+
+fn work_mode_for_hvac_mode<'a>(work_modes: &'a ParsedWorkMode, mode: &str) -> Option<&'a WorkMode> {
+    work_modes
+        .modes
+        .values()
+        .find(|m| hvac_mode_for_work_mode(&m.name) == Some(mode))
+}
+
+/// Returns the names of the fixed heat levels of a heater,
+/// which we present to hass as presets
+fn heat_level_names(work_modes: &ParsedWorkMode) -> Vec<String> {
+    work_mode_for_hvac_mode(work_modes, "heat")
+        .map(|m| m.values.iter().filter_map(|v| v.name.clone()).collect())
+        .unwrap_or_default()
+}
+
+/// <https://www.home-assistant.io/integrations/climate.mqtt>
+#[derive(Serialize, Clone, Debug)]
+pub struct ClimateConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    /// HASS will publish ON/OFF here to turn the device on or off
+    pub power_command_topic: String,
+
+    /// HASS will publish here to change the hvac mode
+    pub mode_command_topic: String,
+    /// we will publish the current hvac mode here
+    pub mode_state_topic: String,
+    pub modes: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preset_modes: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_temp: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_temp: Option<f32>,
+    pub temp_step: f32,
+    pub temperature_unit: &'static str,
+
+    /// we will publish the current temperature reading here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_temperature_topic: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swing_mode_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swing_mode_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub swing_modes: Vec<String>,
+
+    pub optimistic: bool,
+}
+
+/// A climate entity for a space heater, which combines its power,
+/// work modes, target temperature and oscillation into a thermostat
+pub struct Climate {
+    climate: ClimateConfig,
+    device_id: String,
+    state: StateHandle,
+    target_instance: Option<String>,
+}
+
+impl Climate {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
+        let id = topic_safe_id(device);
+        let scale = state.get_temperature_scale().await;
+        let topic = |name: &str| format!("{}/climate/{id}/{name}", topic_prefix());
+
+        // Power is routed to the general power switch handler
+        let power_command_topic = format!("{}/switch/{id}/command/powerSwitch", topic_prefix());
+
+        let work_modes = ParsedWorkMode::with_device(device).ok();
+        let mut modes = vec!["off".to_string()];
+        let mut preset_modes = vec![];
+        if let Some(work_modes) = &work_modes {
+            for mode in work_modes.modes.values() {
+                if let Some(hvac_mode) = hvac_mode_for_work_mode(&mode.name) {
+                    if !modes.iter().any(|m| m == hvac_mode) {
+                        modes.push(hvac_mode.to_string());
+                    }
+                }
+            }
+            preset_modes = heat_level_names(work_modes);
+        }
+        if modes.len() == 1 {
+            modes.push("heat".to_string());
+        }
+
+        let target = device.http_device_info.as_ref().and_then(|info| {
+            info.capabilities
+                .iter()
+                .find(|cap| cap.kind == DeviceCapabilityKind::TemperatureSetting)
+        });
+        let constraints = target
+            .and_then(|cap| parse_temperature_constraints(cap).ok())
+            .map(|c| c.as_unit(scale.into()));
+
+        let has_current_temperature = device
+            .get_capability_by_instance("sensorTemperature")
+            .is_some();
+        let has_oscillation = device
+            .get_capability_by_instance(OSCILLATION_INSTANCE)
+            .is_some();
+
+        Ok(Self {
+            climate: ClimateConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: None,
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-climate"),
+                    device_class: None,
+                    icon: None,
+                },
+                power_command_topic,
+                mode_command_topic: topic("set-mode"),
+                mode_state_topic: topic("notify-mode"),
+                modes,
+                preset_mode_command_topic: (!preset_modes.is_empty()).then(|| topic("set-preset")),
+                preset_mode_state_topic: (!preset_modes.is_empty()).then(|| topic("notify-preset")),
+                preset_modes,
+                temperature_command_topic: target.map(|cap| {
+                    format!(
+                        "{}/{id}/set-temperature/{inst}/{scale}",
+                        topic_prefix(),
+                        inst = topic_safe_string(&cap.instance)
+                    )
+                }),
+                temperature_state_topic: target.map(|_| topic("notify-target")),
+                min_temp: constraints.as_ref().map(|c| c.min.value().floor() as f32),
+                max_temp: constraints.as_ref().map(|c| c.max.value().ceil() as f32),
+                temp_step: 1.0,
+                temperature_unit: match scale {
+                    TemperatureScale::Celsius => "C",
+                    TemperatureScale::Farenheit => "F",
+                },
+                current_temperature_topic: has_current_temperature.then(|| topic("notify-current")),
+                swing_mode_command_topic: has_oscillation.then(|| {
+                    format!(
+                        "{}/switch/{id}/command/{OSCILLATION_INSTANCE}",
+                        topic_prefix()
+                    )
+                }),
+                swing_mode_state_topic: has_oscillation.then(|| topic("notify-swing")),
+                swing_modes: if has_oscillation {
+                    vec!["on".to_string(), "off".to_string()]
+                } else {
+                    vec![]
+                },
+                optimistic: false,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            target_instance: target.map(|cap| cap.instance.to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for Climate {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("climate", state, client, &self.climate.base, &self.climate).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        let work_modes = ParsedWorkMode::with_device(&device).ok();
+        let current = current_work_mode(&device).and_then(|(mode, param)| {
+            let work_mode = work_modes.as_ref()?.mode_for_value(&json!(mode))?;
+            Some((work_mode, param))
+        });
+
+        let mode = match &current {
+            _ if !is_on => "off",
+            Some((work_mode, _)) => hvac_mode_for_work_mode(&work_mode.name).unwrap_or("heat"),
+            None => "heat",
+        };
+        client.publish(&self.climate.mode_state_topic, mode).await?;
+
+        if let Some(topic) = &self.climate.preset_mode_state_topic {
+            let preset = match &current {
+                Some((work_mode, Some(param))) if mode == "heat" => work_mode
+                    .values
+                    .iter()
+                    .find(|v| v.value.as_i64() == Some(*param))
+                    .and_then(|v| v.name.as_deref()),
+                _ => None,
+            };
+            client.publish(topic, preset.unwrap_or("None")).await?;
+        }
+
+        let scale = self.state.get_temperature_scale().await;
+        if let (Some(topic), Some(instance)) =
+            (&self.climate.temperature_state_topic, &self.target_instance)
+        {
+            if let Some(target) = reported_target_temperature(&device, instance) {
+                let value = target.as_unit(scale.into()).value();
+                client.publish(topic, format!("{value:.2}")).await?;
+            }
+        }
+
+        if let Some(topic) = &self.climate.current_temperature_topic {
+            let units = device
+                .resolve_quirk()
+                .and_then(|q| q.platform_temperature_sensor_units)
+                .unwrap_or(TemperatureUnits::Celsius);
+            if let Some(v) = device
+                .get_state_capability_by_instance("sensorTemperature")
+                .and_then(|cap| cap.state.pointer("/value"))
+                .and_then(|v| v.as_f64())
+            {
+                let value =
+                    format_temperature_reading(v, units, scale, &device.sensor_calibration());
+                client.publish(topic, value).await?;
+            }
+        }
+
+        if let Some(topic) = &self.climate.swing_mode_state_topic {
+            if let Some(oscillating) = device.toggle_state(OSCILLATION_INSTANCE) {
+                client
+                    .publish(topic, if oscillating { "on" } else { "off" })
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Set a work mode of a heater, turning it on first if needed
+async fn heater_set_work_mode(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    mode_num: i64,
+    value: i64,
+) -> anyhow::Result<()> {
+    if !device.device_state().map(|s| s.on).unwrap_or(false) {
+        state.device_power_on(device, true).await?;
+    }
+    state
+        .humidifier_set_parameter(device, mode_num, value)
+        .await
+}
+
+pub async fn mqtt_climate_set_mode(
+    Payload(mode): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_climate_set_mode: {id}: {mode}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    if mode == "off" {
+        return state.device_power_on(&device, false).await;
+    }
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let work_mode = work_mode_for_hvac_mode(&work_modes, &mode)
+        .ok_or_else(|| anyhow!("{device} has no work mode for {mode}"))?;
+    let mode_num = work_mode
+        .value
+        .as_i64()
+        .ok_or_else(|| anyhow!("expected workMode to be a number"))?;
+
+    // Keep the current heat level if we're already in this mode
+    let value = match current_work_mode(&device) {
+        Some((current, Some(param))) if current == mode_num => param,
+        _ => work_mode.default_value(),
+    };
+
+    heater_set_work_mode(&state, &device, mode_num, value).await
+}
+
+pub async fn mqtt_climate_set_preset(
+    Payload(preset): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_climate_set_preset: {id}: {preset}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let work_mode = work_mode_for_hvac_mode(&work_modes, "heat")
+        .ok_or_else(|| anyhow!("{device} has no heat mode"))?;
+    let mode_num = work_mode
+        .value
+        .as_i64()
+        .ok_or_else(|| anyhow!("expected workMode to be a number"))?;
+    let level = work_mode
+        .values
+        .iter()
+        .find(|v| v.name.as_deref() == Some(preset.as_str()))
+        .and_then(|v| v.value.as_i64())
+        .ok_or_else(|| anyhow!("{device} has no heat level {preset}"))?;
+
+    heater_set_work_mode(&state, &device, mode_num, level).await
+}
+
 #[derive(Deserialize)]
 pub struct IdInstAndUnits {
     id: String,
@@ -211,3 +551,195 @@ pub async fn mqtt_set_temperature(
 
     Ok(())
 }
+
+/// How the target of a temperature regulating work mode is set
+#[derive(Clone, Debug, PartialEq)]
+enum RegulatedTarget {
+    /// The target is the parameter of the work mode,
+    /// in degrees Celsius
+    ModeValue,
+    /// The target is held by a temperature_setting capability,
+    /// and the work mode has no parameter of its own
+    Capability(String),
+}
+
+/// Decide how the target of the temperature regulating work mode
+/// of a device is set, returning None if it has no such mode
+fn regulated_target(
+    device: &ServiceDevice,
+    work_modes: &ParsedWorkMode,
+) -> Option<(i64, i64, RegulatedTarget, TemperatureConstraints)> {
+    let temperature_cap = device.http_device_info.as_ref().and_then(|info| {
+        info.capabilities
+            .iter()
+            .find(|cap| cap.kind == DeviceCapabilityKind::TemperatureSetting)
+    });
+    // Fans and purifiers also have an Auto mode, but it has nothing
+    // to do with temperature unless the device has a target for it
+    if temperature_cap.is_none() && device.device_type() != DeviceType::Heater {
+        return None;
+    }
+
+    let mode = work_modes.temperature_mode()?;
+    let mode_num = mode.value.as_i64()?;
+
+    if let Some(range) = mode.contiguous_value_range() {
+        let constraints = TemperatureConstraints {
+            min: TemperatureValue::new(range.start as f64, TemperatureUnits::Celsius),
+            max: TemperatureValue::new((range.end - 1) as f64, TemperatureUnits::Celsius),
+        };
+        return Some((
+            mode_num,
+            mode.default_value(),
+            RegulatedTarget::ModeValue,
+            constraints,
+        ));
+    }
+
+    let cap = temperature_cap?;
+    let constraints = parse_temperature_constraints(cap).ok()?;
+    Some((
+        mode_num,
+        mode.default_value(),
+        RegulatedTarget::Capability(cap.instance.to_string()),
+        constraints,
+    ))
+}
+
+/// Exposes the target of the work mode in which a heater or fan
+/// regulates itself to maintain a temperature, as distinct from its
+/// manual heat level or speed. Setting it switches the device into
+/// that mode.
+pub struct RegulatedTemperatureNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    mode_num: i64,
+    target: RegulatedTarget,
+}
+
+impl RegulatedTemperatureNumber {
+    pub async fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        work_modes: &ParsedWorkMode,
+    ) -> Option<Self> {
+        let (mode_num, _, target, constraints) = regulated_target(device, work_modes)?;
+        let units = state.get_temperature_scale().await;
+        let constraints = constraints.as_unit(units.into());
+
+        let id = topic_safe_id(device);
+        let command_topic = format!("{}/{id}/set-regulated-temperature/{units}", topic_prefix());
+        let state_topic = format!("{}/{id}/notify-regulated-temperature", topic_prefix());
+        let unique_id = format!("gv2mqtt-{id}-regulated-temperature");
+
+        Some(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Thermostat Target".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    device_class: Some(DEVICE_CLASS_TEMPERATURE),
+                    icon: Some("mdi:thermostat-auto".to_string()),
+                },
+                state_topic: Some(state_topic),
+                command_topic,
+                min: Some(constraints.min.value().floor() as f32),
+                max: Some(constraints.max.value().ceil() as f32),
+                step: 1.0,
+                unit_of_measurement: Some(units.unit_of_measurement()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            mode_num,
+            target,
+        })
+    }
+
+    /// Returns true if this takes the place of the generic
+    /// number entity for the parameter of work mode mode_num
+    pub fn replaces_work_mode_number(&self, mode_num: i64) -> bool {
+        self.mode_num == mode_num && self.target == RegulatedTarget::ModeValue
+    }
+}
+
+#[async_trait]
+impl EntityInstance for RegulatedTemperatureNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let target = match &self.target {
+            RegulatedTarget::ModeValue => {
+                // The parameter of the current mode is only a
+                // temperature while the device is in our mode
+                let param = match current_work_mode(&device) {
+                    Some((mode, param)) if mode == self.mode_num => param,
+                    _ => device
+                        .humidifier_param_by_mode
+                        .get(&(self.mode_num as u8))
+                        .map(|p| *p as i64),
+                };
+                param.map(|p| TemperatureValue::new(p as f64, TemperatureUnits::Celsius))
+            }
+            RegulatedTarget::Capability(instance) => reported_target_temperature(&device, instance),
+        };
+
+        match target {
+            Some(target) => {
+                let units = self.state.get_temperature_scale().await;
+                let value = target.as_unit(units.into()).value();
+                self.number
+                    .notify_state(client, &format!("{value:.2}"))
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct IdAndUnits {
+    id: String,
+    units: String,
+}
+
+pub async fn mqtt_set_regulated_temperature(
+    Payload(value): Payload<String>,
+    Params(IdAndUnits { id, units }): Params<IdAndUnits>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("Command: set-regulated-temperature for {id}: {value}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let scale: TemperatureScale = units.parse()?;
+    let target = TemperatureValue::parse_with_optional_scale(&value, Some(scale))?;
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let (mode_num, default_value, regulated, _) = regulated_target(&device, &work_modes)
+        .ok_or_else(|| anyhow!("{device} has no temperature regulating mode"))?;
+
+    match regulated {
+        RegulatedTarget::ModeValue => {
+            let celsius = target.as_unit(TemperatureUnits::Celsius).value().round() as i64;
+            heater_set_work_mode(&state, &device, mode_num, celsius).await
+        }
+        RegulatedTarget::Capability(instance) => {
+            state
+                .device_set_target_temperature(&device, &instance, target)
+                .await?;
+            if current_work_mode(&device).map(|(mode, _)| mode) != Some(mode_num) {
+                heater_set_work_mode(&state, &device, mode_num, default_value).await?;
+            }
+            Ok(())
+        }
+    }
+}
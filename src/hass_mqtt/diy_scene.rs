@@ -0,0 +1,112 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::select::SelectConfig;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+
+/// A select entity listing the user-defined DIY scenes
+/// that were created for the device in the Govee app
+pub struct DiySceneSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DiySceneSelect {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Option<Self>> {
+        let mut scenes = state.device_list_diy_scenes(device).await?;
+        if scenes.is_empty() {
+            return Ok(None);
+        }
+        // The empty option represents "not running a DIY scene"
+        scenes.insert(0, "".to_string());
+
+        let command_topic = format!(
+            "{}/{id}/set-diy-scene",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-diy-scene",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let availability = Availability::for_device(device);
+        let unique_id = format!("gv2mqtt-{id}-diy-scene", id = topic_safe_id(device));
+
+        Ok(Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability,
+                    name: Some("DIY Scene".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:palette".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: scenes,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DiySceneSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        // Report the empty option unless the device is on and
+        // the active scene is one of our DIY scenes
+        let active = device
+            .device_state()
+            .filter(|s| s.light_on.unwrap_or(s.on))
+            .and_then(|s| s.scene)
+            .and_then(|scene| {
+                self.select
+                    .options
+                    .iter()
+                    .find(|opt| opt.eq_ignore_ascii_case(&scene))
+                    .cloned()
+            })
+            .unwrap_or_default();
+
+        client.publish(&self.select.state_topic, active).await
+    }
+}
+
+pub async fn mqtt_set_diy_scene(
+    Payload(scene): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if scene.is_empty() {
+        // There is no way to stop a DIY scene other than
+        // to select something else
+        return Ok(());
+    }
+
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_diy_scene(&device, &scene)
+        .await
+        .context("mqtt_set_diy_scene: state.device_set_diy_scene")?;
+
+    Ok(())
+}
@@ -0,0 +1,458 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{EntityInstance, EntityList};
+use crate::hass_mqtt::number::NumberConfig;
+use crate::hass_mqtt::switch::SwitchConfig;
+use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+/// Capability instances used for the do not disturb, or quiet
+/// hours, feature of a device. Those that are simple toggles are
+/// presented by CapabilitySwitch; those with a schedule are
+/// handled here.
+pub const DO_NOT_DISTURB_INSTANCES: &[&str] = &[
+    "dndToggle",
+    "doNotDisturb",
+    "doNotDisturbToggle",
+    "dnd",
+    "quietHours",
+    "quietMode",
+    "quietModeToggle",
+];
+
+/// The names of the struct field that enables the schedule
+const ENABLE_FIELDS: &[&str] = &["onOff", "open", "enable", "enabled"];
+
+/// A time of day field of a do not disturb schedule,
+/// in whatever unit the device uses
+#[derive(Debug, Clone, PartialEq)]
+struct TimeField {
+    name: String,
+    min: u32,
+    max: u32,
+}
+
+impl TimeField {
+    /// Times that don't go beyond 24 are in hours,
+    /// otherwise they are minutes after midnight
+    fn unit(&self) -> &'static str {
+        if self.max <= 24 {
+            "h"
+        } else {
+            "min"
+        }
+    }
+}
+
+/// A do not disturb capability that takes a struct, with a field
+/// that enables it and optionally the times at which it starts
+/// and ends
+#[derive(Debug, Clone, PartialEq)]
+struct DoNotDisturbSchedule {
+    instance: String,
+    enable: String,
+    start: Option<TimeField>,
+    end: Option<TimeField>,
+}
+
+impl DoNotDisturbSchedule {
+    fn from_capability(cap: &DeviceCapability) -> Option<Self> {
+        if !DO_NOT_DISTURB_INSTANCES.contains(&cap.instance.as_str()) {
+            return None;
+        }
+        let Some(DeviceParameters::Struct { fields }) = &cap.parameters else {
+            return None;
+        };
+
+        let enable = fields
+            .iter()
+            .find(|f| ENABLE_FIELDS.contains(&f.field_name.as_str()))?
+            .field_name
+            .to_string();
+
+        let time_field = |which: &str| {
+            fields.iter().find_map(|f| match &f.field_type {
+                DeviceParameters::Integer { range, .. }
+                    if f.field_name.to_ascii_lowercase().contains(which) =>
+                {
+                    Some(TimeField {
+                        name: f.field_name.to_string(),
+                        min: range.min,
+                        max: range.max,
+                    })
+                }
+                _ => None,
+            })
+        };
+
+        Some(Self {
+            instance: cap.instance.to_string(),
+            enable,
+            start: time_field("start"),
+            end: time_field("end"),
+        })
+    }
+
+    fn for_device(device: &ServiceDevice) -> Option<Self> {
+        device
+            .http_device_info
+            .as_ref()?
+            .capabilities
+            .iter()
+            .find_map(Self::from_capability)
+    }
+
+    fn time_field(&self, name: &str) -> Option<&TimeField> {
+        [&self.start, &self.end]
+            .into_iter()
+            .flatten()
+            .find(|f| f.name == name)
+    }
+
+    /// Returns the complete value of the schedule, as reported by
+    /// the device, or else as we last set it. Fields that we don't
+    /// know are filled in from their defaults.
+    fn current_value(&self, device: &ServiceDevice, cap: &DeviceCapability) -> JsonValue {
+        let known = device
+            .get_state_capability_by_instance(&self.instance)
+            .and_then(|state| state.state.pointer("/value"))
+            .filter(|value| value.is_object())
+            .or(device.do_not_disturb.as_ref());
+
+        let mut value = serde_json::Map::new();
+        if let Some(DeviceParameters::Struct { fields }) = &cap.parameters {
+            for field in fields {
+                let v = known
+                    .and_then(|known| known.get(&field.field_name))
+                    .or(field.default_value.as_ref())
+                    .cloned()
+                    .unwrap_or_else(|| match &field.field_type {
+                        DeviceParameters::Integer { range, .. } => range.min.into(),
+                        _ => 0.into(),
+                    });
+                value.insert(field.field_name.to_string(), v);
+            }
+        }
+        JsonValue::Object(value)
+    }
+
+    fn is_enabled(value: &JsonValue, enable: &str) -> Option<bool> {
+        let v = value.get(enable)?;
+        v.as_bool().or_else(|| v.as_i64().map(|n| n != 0))
+    }
+}
+
+fn switch_state_topic(device: &ServiceDevice) -> String {
+    format!(
+        "{}/{id}/notify-do-not-disturb",
+        topic_prefix(),
+        id = topic_safe_id(device)
+    )
+}
+
+fn time_state_topic(device: &ServiceDevice, field: &str) -> String {
+    format!(
+        "{}/{id}/notify-do-not-disturb/{field}",
+        topic_prefix(),
+        id = topic_safe_id(device)
+    )
+}
+
+/// A switch that enables the do not disturb schedule of a device
+pub struct DoNotDisturbSwitch {
+    switch: SwitchConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DoNotDisturbSwitch {
+    fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("gv2mqtt-{id}-do-not-disturb", id = topic_safe_id(device));
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Do Not Disturb".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:minus-circle-outline".to_string()),
+                },
+                command_topic: format!(
+                    "{}/{id}/set-do-not-disturb",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                ),
+                state_topic: switch_state_topic(device),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DoNotDisturbSwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+        let Some(schedule) = DoNotDisturbSchedule::for_device(&device) else {
+            return Ok(());
+        };
+        let Some(cap) = device.get_capability_by_instance(&schedule.instance) else {
+            return Ok(());
+        };
+
+        let value = schedule.current_value(&device, cap);
+        match DoNotDisturbSchedule::is_enabled(&value, &schedule.enable) {
+            Some(on) => {
+                client
+                    .publish(&self.switch.state_topic, if on { "ON" } else { "OFF" })
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// A number for the time at which the do not disturb
+/// schedule of a device starts or ends
+pub struct DoNotDisturbTimeNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    field: String,
+}
+
+impl DoNotDisturbTimeNumber {
+    fn new(device: &ServiceDevice, state: &StateHandle, field: &TimeField, label: &str) -> Self {
+        let unique_id = format!(
+            "gv2mqtt-{id}-do-not-disturb-{field}",
+            id = topic_safe_id(device),
+            field = field.name
+        );
+        Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(format!("Do Not Disturb {label}")),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:clock-outline".to_string()),
+                },
+                command_topic: format!(
+                    "{}/{id}/set-do-not-disturb/{field}",
+                    topic_prefix(),
+                    id = topic_safe_id(device),
+                    field = field.name
+                ),
+                state_topic: Some(time_state_topic(device, &field.name)),
+                min: Some(field.min as f32),
+                max: Some(field.max as f32),
+                step: 1.,
+                unit_of_measurement: Some(field.unit()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            field: field.name.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DoNotDisturbTimeNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+        let Some(schedule) = DoNotDisturbSchedule::for_device(&device) else {
+            return Ok(());
+        };
+        let Some(cap) = device.get_capability_by_instance(&schedule.instance) else {
+            return Ok(());
+        };
+
+        match schedule
+            .current_value(&device, cap)
+            .get(&self.field)
+            .and_then(|v| v.as_i64())
+        {
+            Some(n) => self.number.notify_state(client, &n.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Adds the entities for a do not disturb schedule, if the device has one
+pub fn entities_for_do_not_disturb(
+    device: &ServiceDevice,
+    state: &StateHandle,
+    entities: &mut EntityList,
+) {
+    let Some(schedule) = DoNotDisturbSchedule::for_device(device) else {
+        return;
+    };
+    entities.add(DoNotDisturbSwitch::new(device, state));
+    if let Some(start) = &schedule.start {
+        entities.add(DoNotDisturbTimeNumber::new(device, state, start, "Start"));
+    }
+    if let Some(end) = &schedule.end {
+        entities.add(DoNotDisturbTimeNumber::new(device, state, end, "End"));
+    }
+}
+
+/// Returns true if cap is a do not disturb schedule, which
+/// is handled by entities_for_do_not_disturb
+pub fn is_do_not_disturb_schedule(cap: &DeviceCapability) -> bool {
+    DoNotDisturbSchedule::from_capability(cap).is_some()
+}
+
+/// Set field of the schedule of device to value, keeping the other fields
+async fn update_schedule(
+    state: &StateHandle,
+    id: &str,
+    field: impl FnOnce(&DoNotDisturbSchedule) -> anyhow::Result<(String, JsonValue)>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(id).await?;
+    let schedule = DoNotDisturbSchedule::for_device(&device)
+        .ok_or_else(|| anyhow!("{device} doesn't have a do not disturb schedule"))?;
+    let cap = device
+        .get_capability_by_instance(&schedule.instance)
+        .ok_or_else(|| anyhow!("{device} has no {} capability", schedule.instance))?;
+
+    let (name, v) = field(&schedule)?;
+    let mut value = schedule.current_value(&device, cap);
+    value[name] = v;
+
+    state.device_control(&device, cap, value.clone()).await?;
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .do_not_disturb
+        .replace(value);
+    state.notify_of_state_change(&device.id).await?;
+    Ok(())
+}
+
+pub async fn mqtt_set_do_not_disturb(
+    Payload(command): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("Do not disturb for {id}: {command}");
+    let on = match command.as_str() {
+        "ON" | "on" => 1,
+        "OFF" | "off" => 0,
+        _ => anyhow::bail!("invalid {command} for {id}"),
+    };
+    update_schedule(&state, &id, |schedule| {
+        Ok((schedule.enable.to_string(), on.into()))
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct IdAndField {
+    id: String,
+    field: String,
+}
+
+pub async fn mqtt_set_do_not_disturb_time(
+    Payload(value): Payload<String>,
+    Params(IdAndField { id, field }): Params<IdAndField>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("Do not disturb {field} for {id}: {value}");
+    let value: f64 = value.parse()?;
+    update_schedule(&state, &id, |schedule| {
+        let time = schedule
+            .time_field(&field)
+            .ok_or_else(|| anyhow!("{field} is not a time of the do not disturb schedule"))?;
+        let value = (value.round().max(0.) as u32).clamp(time.min, time.max);
+        Ok((field.to_string(), value.into()))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+
+    #[test]
+    fn schedule_capability() {
+        let cap: DeviceCapability = from_json(
+            r#"{
+                "type": "devices.capabilities.dynamic_setting",
+                "instance": "doNotDisturb",
+                "parameters": {"dataType": "STRUCT", "fields": [
+                    {"fieldName": "onOff", "dataType": "ENUM", "options": [
+                        {"name": "on", "value": 1}, {"name": "off", "value": 0}
+                    ], "required": true},
+                    {"fieldName": "startTime", "dataType": "INTEGER",
+                     "range": {"min": 0, "max": 1439, "precision": 1}, "required": true},
+                    {"fieldName": "endTime", "dataType": "INTEGER",
+                     "range": {"min": 0, "max": 1439, "precision": 1},
+                     "defaultValue": 420, "required": true}
+                ]}
+            }"#,
+        )
+        .unwrap();
+
+        let schedule = DoNotDisturbSchedule::from_capability(&cap).unwrap();
+        assert_eq!(schedule.enable, "onOff");
+        assert_eq!(schedule.start.as_ref().unwrap().name, "startTime");
+        assert_eq!(schedule.end.as_ref().unwrap().unit(), "min");
+
+        let mut device = ServiceDevice::new("H7126", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(
+            schedule.current_value(&device, &cap),
+            serde_json::json!({"onOff": 0, "startTime": 0, "endTime": 420})
+        );
+
+        device
+            .do_not_disturb
+            .replace(serde_json::json!({"onOff": 1, "startTime": 1320}));
+        let value = schedule.current_value(&device, &cap);
+        assert_eq!(
+            value,
+            serde_json::json!({"onOff": 1, "startTime": 1320, "endTime": 420})
+        );
+        assert_eq!(
+            DoNotDisturbSchedule::is_enabled(&value, &schedule.enable),
+            Some(true)
+        );
+
+        let toggle: DeviceCapability = from_json(
+            r#"{
+                "type": "devices.capabilities.toggle",
+                "instance": "dndToggle",
+                "parameters": {"dataType": "ENUM", "options": [
+                    {"name": "on", "value": 1}, {"name": "off", "value": 0}
+                ]}
+            }"#,
+        )
+        .unwrap();
+        assert!(!is_do_not_disturb_schedule(&toggle));
+    }
+}
@@ -1,33 +1,81 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::binary_sensor::{
+    ConnectivitySensor, FilterReplacementSensor, LeakSensor, PresenceSensor, LEAK_INSTANCES,
+    PRESENCE_INSTANCE,
+};
 use crate::hass_mqtt::button::ButtonConfig;
-use crate::hass_mqtt::climate::TargetTemperatureEntity;
+use crate::hass_mqtt::climate::{Climate, RegulatedTemperatureNumber, TargetTemperatureEntity};
+use crate::hass_mqtt::diy_scene::DiySceneSelect;
+use crate::hass_mqtt::do_not_disturb::{entities_for_do_not_disturb, is_do_not_disturb_schedule};
+use crate::hass_mqtt::fan::{Fan, FanSpeedSelect};
 use crate::hass_mqtt::humidifier::Humidifier;
+use crate::hass_mqtt::ice_maker::entities_for_ice_maker;
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::light::DeviceLight;
-use crate::hass_mqtt::number::WorkModeNumber;
+use crate::hass_mqtt::light::{DeviceLight, NightLight, NIGHTLIGHT_BRIGHTNESS_INSTANCE};
+use crate::hass_mqtt::light_scene::LightSceneSelect;
+use crate::hass_mqtt::light_segments::enumerate_segment_lights;
+use crate::hass_mqtt::music_mode::{MusicModeSelect, MusicSensitivityNumber};
+use crate::hass_mqtt::number::{
+    is_sleep_timer, SleepTimerNumber, TargetHumidityNumber, WorkModeNumber,
+};
+use crate::hass_mqtt::power_on_behavior::{PowerOnBehaviorSelect, POWER_ON_BEHAVIOR_INSTANCES};
 use crate::hass_mqtt::scene::SceneConfig;
 use crate::hass_mqtt::select::{SceneModeSelect, WorkModeSelect};
-use crate::hass_mqtt::sensor::{CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic};
+use crate::hass_mqtt::sensor::{
+    BatterySensor, BridgeStatusSensor, CapabilitySensor, DeviceStatusDiagnostic, FilterLifeSensor,
+    FirmwareVersionSensor, GlobalFixedDiagnostic, IpAddressSensor, LastSeenSensor,
+    PlatformQueueDepthSensor, UsageStatisticSensor, WifiSignalSensor, WorkModeDiagnostic,
+};
+use crate::hass_mqtt::snapshot::SnapshotSelect;
 use crate::hass_mqtt::switch::CapabilitySwitch;
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceType};
-use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, oneclick_topic, purge_cache_topic};
+use crate::service::device::{
+    is_usage_statistic_instance, Device as ServiceDevice, FILTER_EXPIRED_INSTANCES,
+    FILTER_LIFE_INSTANCES, USAGE_STATISTICS,
+};
+use crate::service::hass::{oneclick_topic, purge_cache_topic, reload_topic};
 use crate::service::state::StateHandle;
 use crate::version_info::govee_version;
 use anyhow::Context;
 
 use uuid::Uuid;
 
-pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<EntityList> {
+/// Enumerates all of the entities that we register with hass,
+/// grouped so that the global entities come first, followed by
+/// a group for each included device
+pub async fn enumerate_entity_groups(state: &StateHandle) -> anyhow::Result<Vec<EntityList>> {
     let mut entities = EntityList::new();
 
     enumerate_global_entities(state, &mut entities).await?;
     enumerate_scenes(state, &mut entities).await?;
+    let mut groups = vec![entities];
 
     let devices = state.devices().await;
 
     for d in &devices {
+        if !state.is_device_included(d).await {
+            continue;
+        }
+        let mut entities = EntityList::new();
+        enumerate_entities_for_device(d, state, &mut entities)
+            .await
+            .with_context(|| format!("Config::for_device({d})"))?;
+        groups.push(entities);
+    }
+
+    Ok(groups)
+}
+
+/// Enumerates the entities that would belong to the devices
+/// that are excluded from hass, so that they can be removed
+pub async fn enumerate_excluded_entities(state: &StateHandle) -> anyhow::Result<EntityList> {
+    let mut entities = EntityList::new();
+
+    for d in &state.devices().await {
+        if state.is_device_included(d).await {
+            continue;
+        }
         enumerate_entities_for_device(d, state, &mut entities)
             .await
             .with_context(|| format!("Config::for_device({d})"))?;
@@ -37,11 +85,16 @@ pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<Entity
 }
 
 async fn enumerate_global_entities(
-    _state: &StateHandle,
+    state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
     entities.add(GlobalFixedDiagnostic::new("Version", govee_version()));
+    entities.add(BridgeStatusSensor::new(state));
+    if state.has_platform_client().await {
+        entities.add(PlatformQueueDepthSensor::new(state));
+    }
     entities.add(ButtonConfig::new("Purge Caches", purge_cache_topic()));
+    entities.add(ButtonConfig::new("Reload Configuration", reload_topic()));
     Ok(())
 }
 
@@ -56,7 +109,7 @@ async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> any
                     );
                     entities.add(SceneConfig {
                         base: EntityConfig {
-                            availability_topic: availability_topic(),
+                            availability: Availability::bridge(),
                             name: Some(oc.name.to_string()),
                             entity_category: None,
                             origin: Origin::default(),
@@ -90,10 +143,30 @@ async fn entities_for_work_mode<'a>(
 
     let quirk = d.resolve_quirk();
 
+    // The manual mode of a humidifier sets the mist intensity, which
+    // is presented separately from the target humidity of Auto mode
+    let mist_level_mode = if d.device_type() == DeviceType::Humidifier {
+        work_modes.mist_level_mode().map(|mode| mode.name.clone())
+    } else {
+        None
+    };
+
+    let regulated_temperature = if matches!(d.device_type(), DeviceType::Heater | DeviceType::Fan) {
+        RegulatedTemperatureNumber::new(d, state, &work_modes).await
+    } else {
+        None
+    };
+
     for work_mode in work_modes.modes.values() {
         let Some(mode_num) = work_mode.value.as_i64() else {
             continue;
         };
+        if regulated_temperature
+            .as_ref()
+            .is_some_and(|n| n.replaces_work_mode_number(mode_num))
+        {
+            continue;
+        }
 
         let range = work_mode.contiguous_value_range();
 
@@ -125,6 +198,18 @@ async fn entities_for_work_mode<'a>(
                     }
                 }
             }
+        } else if mist_level_mode.as_ref() == Some(&work_mode.name) {
+            entities.add(
+                WorkModeNumber::new(
+                    d,
+                    state,
+                    "Mist Level".to_string(),
+                    &work_mode.name,
+                    work_mode.value.clone(),
+                    range,
+                )
+                .with_icon("mdi:weather-fog"),
+            );
         } else {
             let label = work_mode.label().to_string();
 
@@ -139,6 +224,9 @@ async fn entities_for_work_mode<'a>(
         }
     }
 
+    if let Some(number) = regulated_temperature {
+        entities.add(number);
+    }
     entities.add(WorkModeSelect::new(d, &work_modes, state));
 
     Ok(())
@@ -154,11 +242,50 @@ pub async fn enumerate_entities_for_device<'a>(
     }
 
     entities.add(DeviceStatusDiagnostic::new(d, state));
-    entities.add(ButtonConfig::request_platform_data_for_device(d));
+    entities.add(LastSeenSensor::new(d, state));
+    if d.is_ble_only_device() != Some(true) {
+        entities.add(ButtonConfig::refresh_device(d));
+    }
+    if state.get_platform_client_for(d).await.is_some() {
+        entities.add(ButtonConfig::request_platform_data_for_device(d));
+    }
 
-    if d.supports_rgb() || d.get_color_temperature_range().is_some() || d.supports_brightness() {
+    // The battery capability may not have reported a value yet,
+    // in which case the sensor is unknown until it does
+    if d.battery_percent().is_some() || d.get_capability_by_instance("battery").is_some() {
+        entities.add(BatterySensor::new(d, state));
+    }
+
+    if d.is_online().is_some() || d.get_capability_by_instance("online").is_some() {
+        entities.add(ConnectivitySensor::new(d, state));
+    }
+    if d.wifi_rssi().is_some() {
+        entities.add(WifiSignalSensor::new(d, state));
+    }
+    if d.firmware_version().is_some() {
+        entities.add(FirmwareVersionSensor::new(d, state));
+    }
+    if d.ip_addr().is_some() {
+        entities.add(IpAddressSensor::new(d, state));
+    }
+    if d.has_filter_life() {
+        entities.add(FilterLifeSensor::new(d, state));
+    }
+    if d.has_filter_life() || d.has_filter_expired() {
+        entities.add(FilterReplacementSensor::new(d, state));
+    }
+    for stat in USAGE_STATISTICS {
+        if d.usage_statistic_instance(stat).is_some() {
+            entities.add(UsageStatisticSensor::new(d, state, *stat));
+        }
+    }
+
+    if d.has_light() {
         entities.add(DeviceLight::for_device(&d, state, None).await?);
     }
+    if d.has_secondary_nightlight() {
+        entities.add(NightLight::new(d, state));
+    }
 
     if matches!(
         d.device_type(),
@@ -167,18 +294,74 @@ pub async fn enumerate_entities_for_device<'a>(
         entities.add(Humidifier::new(&d, state).await?);
     }
 
-    if d.device_type() != DeviceType::Light {
-        if let Some(scenes) = SceneModeSelect::new(d, state).await? {
+    if d.device_type() == DeviceType::Heater {
+        entities.add(Climate::new(d, state).await?);
+    }
+
+    if d.device_type() == DeviceType::Fan {
+        entities.add(Fan::new(d, state).await?);
+    }
+
+    if let Some(speed) = FanSpeedSelect::new(d, state) {
+        entities.add(speed);
+    }
+
+    if d.device_type() == DeviceType::Light {
+        if let Some(scenes) = LightSceneSelect::new(d, state).await? {
             entities.add(scenes);
         }
+    } else if let Some(scenes) = SceneModeSelect::new(d, state).await? {
+        entities.add(scenes);
+    }
+
+    if let Some(diy) = DiySceneSelect::new(d, state).await? {
+        entities.add(diy);
+    }
+
+    if let Some(snapshots) = SnapshotSelect::new(d, state).await {
+        entities.add(snapshots);
+    }
+
+    if state.get_platform_client_for(d).await.is_some() {
+        if let Some(music) = MusicModeSelect::new(d, state) {
+            entities.add(music);
+        }
+        if let Some(sensitivity) = MusicSensitivityNumber::new(d, state) {
+            entities.add(sensitivity);
+        }
+        if let Some(power_on) = PowerOnBehaviorSelect::new(d, state) {
+            entities.add(power_on);
+        }
+        entities_for_do_not_disturb(d, state, entities);
     }
 
     if let Some(info) = &d.http_device_info {
         for cap in &info.capabilities {
             match &cap.kind {
                 DeviceCapabilityKind::Toggle | DeviceCapabilityKind::OnOff => {
+                    if d.has_secondary_nightlight()
+                        && d.nightlight_toggle_instance() == Some(cap.instance.as_str())
+                    {
+                        // Handled by NightLight above
+                        continue;
+                    }
                     entities.add(CapabilitySwitch::new(&d, state, cap).await?);
                 }
+                DeviceCapabilityKind::Event if cap.instance == PRESENCE_INSTANCE => {
+                    entities.add(PresenceSensor::new(d, state, cap));
+                }
+                _ if LEAK_INSTANCES.contains(&cap.instance.as_str()) => {
+                    entities.add(LeakSensor::new(d, state, cap));
+                }
+                // Handled by entities_for_do_not_disturb above
+                _ if is_do_not_disturb_schedule(cap) => {}
+                // Handled by PowerOnBehaviorSelect above
+                _ if POWER_ON_BEHAVIOR_INSTANCES.contains(&cap.instance.as_str()) => {}
+                // Handled by FilterLifeSensor and FilterReplacementSensor above
+                _ if FILTER_LIFE_INSTANCES.contains(&cap.instance.as_str())
+                    || FILTER_EXPIRED_INSTANCES.contains(&cap.instance.as_str()) => {}
+                // Handled by UsageStatisticSensor above
+                _ if is_usage_statistic_instance(&cap.instance) => {}
                 DeviceCapabilityKind::ColorSetting
                 | DeviceCapabilityKind::SegmentColorSetting
                 | DeviceCapabilityKind::MusicSetting
@@ -186,16 +369,43 @@ pub async fn enumerate_entities_for_device<'a>(
                 | DeviceCapabilityKind::Mode
                 | DeviceCapabilityKind::DynamicScene => {}
 
+                DeviceCapabilityKind::Range | DeviceCapabilityKind::Timer
+                    if is_sleep_timer(cap) =>
+                {
+                    if let Some(timer) = SleepTimerNumber::new(d, state, cap) {
+                        entities.add(timer);
+                    }
+                }
                 DeviceCapabilityKind::Range if cap.instance == "brightness" => {}
-                DeviceCapabilityKind::Range if cap.instance == "humidity" => {}
+                DeviceCapabilityKind::Range if cap.instance == NIGHTLIGHT_BRIGHTNESS_INSTANCE => {}
+                DeviceCapabilityKind::Range if cap.instance == "humidity" => {
+                    entities.add(TargetHumidityNumber::new(d, state, cap)?);
+                }
+                DeviceCapabilityKind::WorkMode if d.device_type() == DeviceType::IceMaker => {
+                    entities_for_ice_maker(d, state, cap, entities)?;
+                    if state.debug_sensors().await {
+                        entities.add(WorkModeDiagnostic::new(d, state));
+                    }
+                }
                 DeviceCapabilityKind::WorkMode => {
                     entities_for_work_mode(d, state, cap, entities).await?;
+                    if state.debug_sensors().await {
+                        entities.add(WorkModeDiagnostic::new(d, state));
+                    }
                 }
 
+                // Handled by ConnectivitySensor above
+                DeviceCapabilityKind::Online => {}
+
+                // Handled by BatterySensor above
+                DeviceCapabilityKind::Property if cap.instance == "battery" => {}
                 DeviceCapabilityKind::Property => {
                     entities.add(CapabilitySensor::new(&d, state, cap).await?);
                 }
 
+                // Handled by Climate above
+                DeviceCapabilityKind::TemperatureSetting
+                    if d.device_type() == DeviceType::Heater => {}
                 DeviceCapabilityKind::TemperatureSetting => {
                     entities.add(TargetTemperatureEntity::new(&d, state, cap).await?);
                 }
@@ -209,11 +419,7 @@ pub async fn enumerate_entities_for_device<'a>(
             }
         }
 
-        if let Some(segments) = info.supports_segmented_rgb() {
-            for n in segments {
-                entities.add(DeviceLight::for_device(&d, state, Some(n)).await?);
-            }
-        }
+        enumerate_segment_lights(d, state, entities).await?;
     }
     Ok(())
 }
@@ -0,0 +1,1191 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::select::SelectConfig;
+use crate::hass_mqtt::work_mode::{current_work_mode, ParsedWorkMode, WorkMode};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::ops::Range;
+
+/// Work modes that represent the manual speed control of a fan,
+/// in order of preference
+const SPEED_MODE_NAMES: &[&str] = &["FanSpeed", "gearMode", "Manual"];
+
+/// The toggle capability used to control oscillation
+pub const OSCILLATION_INSTANCE: &str = "oscillationToggle";
+
+/// <https://www.home-assistant.io/integrations/fan.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct FanConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    /// Controls the power state; routed to the powerSwitch handler
+    pub command_topic: String,
+    pub state_topic: String,
+
+    /// HASS will publish a percentage (0-100) here; never a raw
+    /// device step. We rescale it to the device's native range
+    /// in mqtt_fan_set_speed. A value of 0 means "turn off".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_command_topic: Option<String>,
+    /// We publish the current speed here, expressed as a percentage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_state_topic: Option<String>,
+    /// Always 1 together with speed_range_max=100, so that the
+    /// percentage topics carry a percentage rather than a step
+    pub speed_range_min: u8,
+    pub speed_range_max: u8,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preset_modes: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oscillation_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oscillation_state_topic: Option<String>,
+    /// We use ON/OFF rather than the HASS default of
+    /// oscillate_on/oscillate_off
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_oscillation_on: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_oscillation_off: Option<&'static str>,
+
+    pub optimistic: bool,
+}
+
+/// Describes the native speed range of a device and takes care of
+/// translating between it and the percentages used by HASS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FanSpeedRange {
+    /// The lowest speed step that the device accepts
+    pub min: i64,
+    /// The highest speed step that the device accepts (inclusive)
+    pub max: i64,
+}
+
+impl FanSpeedRange {
+    /// Create from an exclusive range, such as that produced by
+    /// WorkMode::contiguous_value_range
+    pub fn from_range(range: Range<i64>) -> Option<Self> {
+        if range.is_empty() {
+            return None;
+        }
+        Some(Self {
+            min: range.start,
+            max: range.end - 1,
+        })
+    }
+
+    /// Some devices report their speed as a percentage rather
+    /// than a small number of discrete steps. In that case
+    /// the value is passed through as-is, clamped to the range.
+    pub fn is_percent(&self) -> bool {
+        self.max == 100 && self.min <= 1
+    }
+
+    /// The number of distinct speed steps, excluding off.
+    /// A device with min=0 treats 0 as its lowest running speed,
+    /// so it is counted as a step.
+    pub fn num_steps(&self) -> i64 {
+        self.max - self.min + 1
+    }
+
+    /// Map a HASS percentage to a device speed step.
+    /// Returns None when the percentage means "off".
+    /// The rounding matches that used by HASS so that a speed
+    /// reported back via step_to_percent maps to the same step.
+    pub fn percent_to_step(&self, percent: u8) -> Option<i64> {
+        let percent = (percent as i64).min(100);
+        if percent == 0 {
+            return None;
+        }
+        if self.is_percent() {
+            return Some(percent.max(self.min).min(self.max));
+        }
+        let steps = self.num_steps();
+        let offset = (percent * steps + 99) / 100;
+        Some((self.min + offset - 1).max(self.min).min(self.max))
+    }
+
+    /// The labels used for each speed step by FanSpeedSelect
+    pub fn step_labels(&self) -> Vec<String> {
+        (1..=self.num_steps())
+            .map(|n| format!("Speed {n}"))
+            .collect()
+    }
+
+    /// Map a label produced by step_labels back to a speed step
+    pub fn label_to_step(&self, label: &str) -> Option<i64> {
+        let n: i64 = label.strip_prefix("Speed ")?.trim().parse().ok()?;
+        (1..=self.num_steps())
+            .contains(&n)
+            .then_some(self.min + n - 1)
+    }
+
+    pub fn step_to_label(&self, step: i64) -> Option<String> {
+        (self.min..=self.max)
+            .contains(&step)
+            .then(|| format!("Speed {}", step - self.min + 1))
+    }
+
+    /// Map a device speed step to a HASS percentage
+    pub fn step_to_percent(&self, step: i64) -> u8 {
+        let step = step.max(self.min).min(self.max);
+        if self.is_percent() {
+            return step as u8;
+        }
+        let steps = self.num_steps();
+        (((step - self.min + 1) * 100) / steps) as u8
+    }
+}
+
+/// Locate the work mode that is used to control the speed
+/// of the fan, along with its speed range
+pub fn find_speed_mode(work_modes: &ParsedWorkMode) -> Option<(&WorkMode, FanSpeedRange)> {
+    for name in SPEED_MODE_NAMES {
+        if let Some(mode) = work_modes.mode_by_name(name) {
+            if let Some(range) = mode_speed_range(mode) {
+                return Some((mode, range));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the speed range of a work mode whose parameter is a speed.
+/// Some fans have presets, such as Sleep, that run at an adjustable speed.
+fn mode_speed_range(mode: &WorkMode) -> Option<FanSpeedRange> {
+    mode.contiguous_value_range()
+        .and_then(FanSpeedRange::from_range)
+}
+
+/// A preset offered by the fan entity. Most presets are work modes
+/// in their own right, but some fans have a single work mode whose
+/// named values are the presets, such as Normal, Sleep and Nature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FanPreset {
+    pub name: String,
+    pub mode: i64,
+    /// The value that selects the preset within its work mode,
+    /// or None if the preset is the work mode as a whole
+    pub param: Option<i64>,
+}
+
+/// Returns the presets of the fan, sorted by name. The speed mode
+/// is excluded, as it is represented by the speed percentage.
+pub fn fan_presets(work_modes: &ParsedWorkMode) -> Vec<FanPreset> {
+    let speed_mode_name = find_speed_mode(work_modes).map(|(mode, _)| mode.name.clone());
+    let mut presets: Vec<FanPreset> = vec![];
+
+    for mode in work_modes.modes.values() {
+        if Some(&mode.name) == speed_mode_name.as_ref() {
+            continue;
+        }
+        let Some(mode_num) = mode.value.as_i64() else {
+            continue;
+        };
+
+        let named_values: Vec<FanPreset> = mode
+            .values
+            .iter()
+            .filter_map(|v| {
+                Some(FanPreset {
+                    name: v.name.clone()?,
+                    mode: mode_num,
+                    param: Some(v.value.as_i64()?),
+                })
+            })
+            .collect();
+
+        if named_values.is_empty() {
+            presets.push(FanPreset {
+                name: mode.name.clone(),
+                mode: mode_num,
+                param: None,
+            });
+        } else {
+            presets.extend(named_values);
+        }
+    }
+
+    // Work modes take precedence over values with the same name
+    presets.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then(a.param.is_some().cmp(&b.param.is_some()))
+    });
+    presets.dedup_by(|a, b| a.name == b.name);
+    presets
+}
+
+/// Returns the name of the preset that matches the current work mode
+fn current_preset(presets: &[FanPreset], mode_num: i64, param: Option<i64>) -> Option<&str> {
+    presets
+        .iter()
+        .find(|p| p.mode == mode_num && p.param.is_some() && p.param == param)
+        .or_else(|| {
+            presets
+                .iter()
+                .find(|p| p.mode == mode_num && p.param.is_none())
+        })
+        .map(|p| p.name.as_str())
+}
+
+/// A workMode/modeValue pair to send to a fan
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FanModeCommand {
+    pub mode: i64,
+    pub param: i64,
+}
+
+/// Decide how to apply a non-zero speed percentage.
+/// If the active preset has a speed of its own, the speed is changed
+/// within that preset. Otherwise the fan has to be switched to its
+/// manual speed mode, as the preset has no way to express a speed.
+pub fn speed_command(
+    work_modes: &ParsedWorkMode,
+    current: Option<(i64, Option<i64>)>,
+    percent: u8,
+) -> anyhow::Result<FanModeCommand> {
+    if let Some((mode_num, _)) = current {
+        if let Some(mode) = work_modes.mode_for_value(&json!(mode_num)) {
+            if let Some(range) = mode_speed_range(mode) {
+                if let Some(param) = range.percent_to_step(percent) {
+                    return Ok(FanModeCommand {
+                        mode: mode_num,
+                        param,
+                    });
+                }
+            }
+        }
+    }
+
+    let (speed_mode, range) =
+        find_speed_mode(work_modes).ok_or_else(|| anyhow!("no speed work mode"))?;
+    Ok(FanModeCommand {
+        mode: speed_mode
+            .value
+            .as_i64()
+            .ok_or_else(|| anyhow!("expected workMode to be a number"))?,
+        param: range
+            .percent_to_step(percent)
+            .ok_or_else(|| anyhow!("{percent}% is not a running speed"))?,
+    })
+}
+
+/// Decide how to apply a preset. A preset that has a speed of its
+/// own runs at the most recently known speed, rather than at its
+/// default, so that choosing it doesn't reset the speed.
+pub fn preset_command(
+    work_modes: &ParsedWorkMode,
+    preset: &str,
+    speed_percent: Option<u8>,
+) -> anyhow::Result<FanModeCommand> {
+    if let Some(FanPreset {
+        mode,
+        param: Some(param),
+        ..
+    }) = fan_presets(work_modes)
+        .into_iter()
+        .find(|p| p.name == preset)
+    {
+        return Ok(FanModeCommand { mode, param });
+    }
+
+    let mode = work_modes
+        .mode_by_name(preset)
+        .ok_or_else(|| anyhow!("preset {preset} not found"))?;
+    let mode_num = mode
+        .value
+        .as_i64()
+        .ok_or_else(|| anyhow!("expected workMode to be a number"))?;
+
+    let param = match (mode_speed_range(mode), speed_percent) {
+        (Some(range), Some(percent)) => range
+            .percent_to_step(percent)
+            .unwrap_or_else(|| mode.default_value()),
+        _ => mode.default_value(),
+    };
+
+    Ok(FanModeCommand {
+        mode: mode_num,
+        param,
+    })
+}
+
+/// Sets the speed, preset and oscillation of a fan in a single
+/// message, such as `{"percentage": 50, "preset_mode": "Sleep",
+/// "oscillation": "ON"}`. Each field is optional.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FanCombinedCommand {
+    #[serde(default)]
+    pub percentage: Option<f64>,
+    #[serde(default)]
+    pub preset_mode: Option<String>,
+    #[serde(default)]
+    pub oscillation: Option<JsonValue>,
+}
+
+impl FanCombinedCommand {
+    /// Returns the speed as a percentage, which must be in 0-100
+    pub fn percent(&self) -> anyhow::Result<Option<u8>> {
+        match self.percentage {
+            Some(p) if (0. ..=100.).contains(&p) => Ok(Some(p.round() as u8)),
+            Some(p) => anyhow::bail!("percentage {p} is not in the range 0-100"),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the desired oscillation, which may be either
+    /// a boolean or ON/OFF
+    pub fn oscillation(&self) -> anyhow::Result<Option<bool>> {
+        match &self.oscillation {
+            None => Ok(None),
+            Some(JsonValue::Bool(on)) => Ok(Some(*on)),
+            Some(JsonValue::String(s)) => Ok(Some(parse_on_off(s)?)),
+            Some(other) => anyhow::bail!("invalid oscillation {other}"),
+        }
+    }
+}
+
+pub fn parse_on_off(command: &str) -> anyhow::Result<bool> {
+    match command {
+        "ON" | "on" => Ok(true),
+        "OFF" | "off" => Ok(false),
+        _ => anyhow::bail!("expected ON or OFF but got {command}"),
+    }
+}
+
+/// Decide which work mode to apply for a combined command.
+/// Selecting a preset and then setting the speed would switch a
+/// preset that has no speed of its own back to the manual speed
+/// mode, so the speed is folded into the preset instead, and only
+/// applies if the preset has a speed. Without a preset, the speed
+/// is applied in the same way as via the percentage topic.
+pub fn combined_mode_command(
+    work_modes: &ParsedWorkMode,
+    current: Option<(i64, Option<i64>)>,
+    current_speed: Option<u8>,
+    preset: Option<&str>,
+    percent: Option<u8>,
+) -> anyhow::Result<Option<FanModeCommand>> {
+    match (preset, percent) {
+        (Some(_), Some(0)) => anyhow::bail!("cannot select a preset while turning the fan off"),
+        (Some(preset), percent) => {
+            preset_command(work_modes, preset, percent.or(current_speed)).map(Some)
+        }
+        (None, Some(percent)) if percent > 0 => {
+            speed_command(work_modes, current, percent).map(Some)
+        }
+        (None, _) => Ok(None),
+    }
+}
+
+/// Returns the current speed of the fan as a percentage, if the active
+/// mode reports one, or else the most recently known speed, or else
+/// the initial speed from the quirk for the device
+fn current_speed_percent(device: &ServiceDevice, work_modes: &ParsedWorkMode) -> Option<u8> {
+    let initial_speed = device.resolve_quirk().and_then(|q| q.fan_initial_speed);
+    current_speed_percent_or(device, work_modes, initial_speed)
+}
+
+/// current_speed_percent, with initial_speed in place of the quirk
+fn current_speed_percent_or(
+    device: &ServiceDevice,
+    work_modes: &ParsedWorkMode,
+    initial_speed: Option<u8>,
+) -> Option<u8> {
+    current_work_mode(device)
+        .and_then(|(mode_num, param)| {
+            let mode = work_modes.mode_for_value(&json!(mode_num))?;
+            Some(mode_speed_range(mode)?.step_to_percent(param?))
+        })
+        .or(device.fan_speed_percent)
+        .or(initial_speed)
+}
+
+pub struct Fan {
+    fan: FanConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl Fan {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
+        let use_iot =
+            device.iot_api_supported() && state.get_iot_client_for(device).await.is_some();
+        Ok(Self::with_optimistic(
+            device,
+            state,
+            device.optimistic(use_iot),
+        ))
+    }
+
+    fn with_optimistic(device: &ServiceDevice, state: &StateHandle, optimistic: bool) -> Self {
+        let command_topic = format!(
+            "{}/switch/{id}/command/powerSwitch",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/fan/{id}/state",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+
+        let work_modes = ParsedWorkMode::with_device(device).ok();
+        let speed = work_modes.as_ref().and_then(find_speed_mode);
+
+        let (percentage_command_topic, percentage_state_topic) = if speed.is_some() {
+            (
+                Some(format!(
+                    "{}/fan/{id}/set-speed",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                )),
+                Some(format!(
+                    "{}/fan/{id}/notify-speed",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                )),
+            )
+        } else {
+            (None, None)
+        };
+
+        let preset_modes: Vec<String> = match &work_modes {
+            Some(wm) => fan_presets(wm).into_iter().map(|p| p.name).collect(),
+            None => vec![],
+        };
+
+        let (preset_mode_command_topic, preset_mode_state_topic) = if preset_modes.is_empty() {
+            (None, None)
+        } else {
+            (
+                Some(format!(
+                    "{}/fan/{id}/set-preset",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                )),
+                Some(format!(
+                    "{}/fan/{id}/notify-preset",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                )),
+            )
+        };
+
+        let supports_oscillation = device
+            .get_capability_by_instance(OSCILLATION_INSTANCE)
+            .is_some();
+        let (oscillation_command_topic, oscillation_state_topic) = if supports_oscillation {
+            (
+                Some(format!(
+                    "{}/fan/{id}/set-oscillation",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                )),
+                Some(format!(
+                    "{}/fan/{id}/notify-oscillation",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                )),
+            )
+        } else {
+            (None, None)
+        };
+
+        let unique_id = format!("gv2mqtt-{id}-fan", id = topic_safe_id(device));
+
+        Self {
+            fan: FanConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: None,
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic,
+                state_topic,
+                percentage_command_topic,
+                percentage_state_topic,
+                speed_range_min: 1,
+                speed_range_max: 100,
+                preset_mode_command_topic,
+                preset_mode_state_topic,
+                preset_modes,
+                oscillation_command_topic,
+                oscillation_state_topic,
+                payload_oscillation_on: supports_oscillation.then_some("ON"),
+                payload_oscillation_off: supports_oscillation.then_some("OFF"),
+                optimistic,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for Fan {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("fan", state, client, &self.fan.base, &self.fan).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        client
+            .publish(&self.fan.state_topic, if is_on { "ON" } else { "OFF" })
+            .await?;
+
+        if let Some(topic) = &self.fan.oscillation_state_topic {
+            if let Some(oscillating) = device.toggle_state(OSCILLATION_INSTANCE) {
+                client
+                    .publish(topic, if oscillating { "ON" } else { "OFF" })
+                    .await?;
+            }
+        }
+
+        let Some((mode_num, param)) = current_work_mode(&device) else {
+            return Ok(());
+        };
+        let work_modes = ParsedWorkMode::with_device(&device)?;
+        let Some(mode) = work_modes.mode_for_value(&json!(mode_num)) else {
+            return Ok(());
+        };
+        let speed_mode = find_speed_mode(&work_modes);
+        let in_speed_mode = speed_mode
+            .as_ref()
+            .map(|(speed_mode, _)| speed_mode.name == mode.name)
+            .unwrap_or(false);
+
+        if let Some(topic) = &self.fan.preset_mode_state_topic {
+            // HASS resets the preset when it receives "None"
+            let presets = fan_presets(&work_modes);
+            let preset = if in_speed_mode {
+                "None"
+            } else {
+                current_preset(&presets, mode_num, param).unwrap_or(&mode.name)
+            };
+            client.publish(topic, preset).await?;
+        }
+
+        // The speed is reported independently of the preset, so that
+        // choosing a preset doesn't appear to change the speed
+        if let Some(topic) = &self.fan.percentage_state_topic {
+            let percent = if is_on {
+                current_speed_percent(&device, &work_modes)
+            } else {
+                Some(0)
+            };
+            if let Some(percent) = percent {
+                client.publish(topic, percent.to_string()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A select entity that lists the discrete speed steps of
+/// the device, for those that want to choose an exact speed
+/// rather than have it computed from a percentage.
+/// Enabled via Quirk::with_fan_speed_select.
+pub struct FanSpeedSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    speed_range: FanSpeedRange,
+}
+
+impl FanSpeedSelect {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        if !device.resolve_quirk()?.fan_speed_select {
+            return None;
+        }
+        let work_modes = ParsedWorkMode::with_device(device).ok()?;
+        let (_, speed_range) = find_speed_mode(&work_modes)?;
+        if speed_range.is_percent() {
+            // Too many options to be useful
+            return None;
+        }
+
+        let command_topic = format!(
+            "{}/fan/{id}/set-speed-step",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/fan/{id}/notify-speed-step",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-speed-step", id = topic_safe_id(device));
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Speed".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:speedometer".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: speed_range.step_labels(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            speed_range,
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FanSpeedSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some((mode_num, Some(step))) = current_work_mode(&device) else {
+            return Ok(());
+        };
+        let work_modes = ParsedWorkMode::with_device(&device)?;
+        let in_speed_mode = find_speed_mode(&work_modes)
+            .and_then(|(mode, _)| mode.value.as_i64())
+            .map(|speed_mode_num| speed_mode_num == mode_num)
+            .unwrap_or(false);
+
+        if in_speed_mode {
+            if let Some(label) = self.speed_range.step_to_label(step) {
+                client.publish(&self.select.state_topic, label).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_fan_set_speed_step(
+    Payload(label): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_speed_step: {id}: {label}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let (speed_mode, range) =
+        find_speed_mode(&work_modes).ok_or_else(|| anyhow!("{device} has no speed work mode"))?;
+    let mode_num = speed_mode
+        .value
+        .as_i64()
+        .ok_or_else(|| anyhow!("expected workMode to be a number"))?;
+    let step = range
+        .label_to_step(&label)
+        .ok_or_else(|| anyhow!("invalid speed {label} for {device}"))?;
+
+    // Setting the parameter of the speed mode also switches
+    // the device into that mode
+    state
+        .humidifier_set_parameter(&device, mode_num, step)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn mqtt_fan_set_speed(
+    Payload(percent): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_speed: {id}: {percent}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    // HASS may send eg: "50" or "50.0"
+    let percent: f64 = percent.trim().parse()?;
+    let percent = percent.round().clamp(0., 100.) as u8;
+
+    fan_set_speed(&state, &device, percent).await
+}
+
+/// Set the speed of the fan to a percentage, where 0 turns it off
+pub async fn fan_set_speed(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    percent: u8,
+) -> anyhow::Result<()> {
+    if percent == 0 {
+        return state.device_power_on(device, false).await;
+    }
+
+    let work_modes = ParsedWorkMode::with_device(device)?;
+    let command = speed_command(&work_modes, current_work_mode(device), percent.min(100))
+        .with_context(|| format!("setting the speed of {device}"))?;
+    log::info!("{device}: {percent}% is {command:?}");
+    state
+        .humidifier_set_parameter(device, command.mode, command.param)
+        .await?;
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .set_fan_speed_percent(percent);
+
+    Ok(())
+}
+
+pub async fn mqtt_fan_set_preset(
+    Payload(preset): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_preset: {id}: {preset}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let speed_percent = current_speed_percent(&device, &work_modes);
+    let command = preset_command(&work_modes, &preset, speed_percent)
+        .with_context(|| format!("setting the preset of {device}"))?;
+    state
+        .humidifier_set_parameter(&device, command.mode, command.param)
+        .await?;
+    if let Some(percent) = speed_percent {
+        // Remember the speed so that it continues to be reported
+        // while the preset is active
+        state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .set_fan_speed_percent(percent);
+    }
+
+    Ok(())
+}
+
+pub async fn mqtt_fan_set_oscillation(
+    Payload(command): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_oscillation: {id}: {command}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let on = parse_on_off(&command).with_context(|| format!("invalid {command} for {id}"))?;
+    fan_set_oscillation(&state, &device, on).await?;
+    state.notify_of_state_change(&device.id).await?;
+
+    Ok(())
+}
+
+async fn fan_set_oscillation(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    on: bool,
+) -> anyhow::Result<()> {
+    let client = state
+        .get_platform_client_for(device)
+        .await
+        .ok_or_else(|| anyhow!("Platform API is required to set oscillation for {device}"))?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow!("HTTP device info is missing for {device}"))?;
+    client
+        .set_toggle_state(info, OSCILLATION_INSTANCE, on)
+        .await?;
+
+    // Govee don't reliably report the toggle state, so remember
+    // what we set it to
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .set_last_toggle_state(OSCILLATION_INSTANCE, on);
+    Ok(())
+}
+
+pub async fn mqtt_fan_set_combined(
+    Payload(command): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_combined: {id}: {command}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    // Validate everything before changing anything, so that
+    // a bad field doesn't leave the fan half configured
+    let command: FanCombinedCommand =
+        serde_json::from_str(&command).context("parsing fan command")?;
+    let percent = command.percent()?;
+    let oscillation = command.oscillation()?;
+    if oscillation.is_some()
+        && device
+            .get_capability_by_instance(OSCILLATION_INSTANCE)
+            .is_none()
+    {
+        anyhow::bail!("{device} does not support oscillation");
+    }
+
+    if percent == Some(0) && command.preset_mode.is_none() {
+        // Turning the fan off makes the other fields moot
+        fan_set_speed(&state, &device, 0).await?;
+    } else {
+        let work_modes = ParsedWorkMode::with_device(&device)?;
+        let current_speed = current_speed_percent(&device, &work_modes);
+        let mode_command = combined_mode_command(
+            &work_modes,
+            current_work_mode(&device),
+            current_speed,
+            command.preset_mode.as_deref(),
+            percent,
+        )
+        .with_context(|| format!("setting the state of {device}"))?;
+
+        // The work mode goes first, as changing the mode
+        // of some fans also resets their oscillation
+        if let Some(mode_command) = mode_command {
+            log::info!("{device}: {command:?} is {mode_command:?}");
+            state
+                .humidifier_set_parameter(&device, mode_command.mode, mode_command.param)
+                .await?;
+            if let Some(percent) = percent.or(current_speed) {
+                state
+                    .device_mut(&device.sku, &device.id)
+                    .await
+                    .set_fan_speed_percent(percent);
+            }
+        }
+        if let Some(on) = oscillation {
+            fan_set_oscillation(&state, &device, on).await?;
+        }
+    }
+    state.notify_of_state_change(&device.id).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn published_config() {
+        use crate::platform_api::HttpDeviceInfo;
+        let resp: serde_json::Value =
+            serde_json::from_str(include_str!("../../test-data/list_devices_issue4.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(resp["data"][0].clone()).unwrap();
+        assert_eq!(info.sku, "H7111");
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+
+        let state = std::sync::Arc::new(crate::service::state::State::new());
+        let fan = Fan::with_optimistic(&device, &state, true);
+        let config = serde_json::to_value(&fan.fan).unwrap();
+
+        let id = topic_safe_id(&device);
+        assert_eq!(
+            config["percentage_command_topic"],
+            format!("gv2mqtt/fan/{id}/set-speed")
+        );
+        assert_eq!(
+            config["percentage_state_topic"],
+            format!("gv2mqtt/fan/{id}/notify-speed")
+        );
+        assert_eq!(config["speed_range_min"], 1);
+        assert_eq!(config["speed_range_max"], 100);
+        assert_eq!(
+            config["oscillation_command_topic"],
+            format!("gv2mqtt/fan/{id}/set-oscillation")
+        );
+    }
+
+    /// A fan with a manual speed mode, a Sleep preset that has a
+    /// speed of its own, and a Nature preset that doesn't
+    fn fan_work_modes() -> ParsedWorkMode {
+        let mut work_modes = ParsedWorkMode::default();
+        work_modes.add("FanSpeed".to_string(), json!(1));
+        work_modes.add("Sleep".to_string(), json!(5));
+        work_modes.add("Nature".to_string(), json!(6));
+        work_modes.get_mut("FanSpeed").unwrap().value_range = Some(1..9);
+        work_modes.get_mut("Sleep").unwrap().value_range = Some(1..4);
+        work_modes.get_mut("Nature").unwrap().default_value = Some(json!(0));
+        work_modes
+    }
+
+    #[test]
+    fn speed_and_preset_interaction() {
+        let work_modes = fan_work_modes();
+        let cmd = |mode, param| FanModeCommand { mode, param };
+
+        // Changing the speed in manual mode stays in manual mode
+        assert_eq!(
+            speed_command(&work_modes, Some((1, Some(2))), 50).unwrap(),
+            cmd(1, 4)
+        );
+        // A preset with a speed keeps the preset and changes its speed
+        assert_eq!(
+            speed_command(&work_modes, Some((5, Some(1))), 100).unwrap(),
+            cmd(5, 3)
+        );
+        // A preset without a speed has to be left for manual mode
+        assert_eq!(
+            speed_command(&work_modes, Some((6, Some(0))), 50).unwrap(),
+            cmd(1, 4)
+        );
+        assert_eq!(speed_command(&work_modes, None, 100).unwrap(), cmd(1, 8));
+
+        // Choosing a preset with a speed carries the speed over
+        assert_eq!(
+            preset_command(&work_modes, "Sleep", Some(66)).unwrap(),
+            cmd(5, 2)
+        );
+        assert_eq!(
+            preset_command(&work_modes, "Sleep", None).unwrap(),
+            cmd(5, 1)
+        );
+        // while a preset without a speed uses its default
+        assert_eq!(
+            preset_command(&work_modes, "Nature", Some(67)).unwrap(),
+            cmd(6, 0)
+        );
+        assert!(preset_command(&work_modes, "Turbo", None).is_err());
+    }
+
+    #[test]
+    fn combined_command() {
+        let work_modes = fan_work_modes();
+        let cmd = |mode, param| Some(FanModeCommand { mode, param });
+
+        let command: FanCombinedCommand = serde_json::from_str(
+            r#"{"percentage": 66.0, "preset_mode": "Sleep", "oscillation": "ON"}"#,
+        )
+        .unwrap();
+        assert_eq!(command.percent().unwrap(), Some(66));
+        assert_eq!(command.oscillation().unwrap(), Some(true));
+        // The speed is applied within the preset, rather than
+        // switching back to the manual speed mode
+        assert_eq!(
+            combined_mode_command(
+                &work_modes,
+                Some((1, Some(2))),
+                Some(25),
+                command.preset_mode.as_deref(),
+                command.percent().unwrap(),
+            )
+            .unwrap(),
+            cmd(5, 2)
+        );
+        // A preset without a speed ignores the speed
+        assert_eq!(
+            combined_mode_command(&work_modes, None, None, Some("Nature"), Some(50)).unwrap(),
+            cmd(6, 0)
+        );
+        assert_eq!(
+            combined_mode_command(&work_modes, Some((1, Some(2))), None, None, Some(50)).unwrap(),
+            cmd(1, 4)
+        );
+        assert_eq!(
+            combined_mode_command(&work_modes, None, None, None, None).unwrap(),
+            None
+        );
+        assert!(combined_mode_command(&work_modes, None, None, Some("Sleep"), Some(0)).is_err());
+        assert!(combined_mode_command(&work_modes, None, None, Some("Turbo"), None).is_err());
+
+        let command: FanCombinedCommand =
+            serde_json::from_str(r#"{"percentage": 120, "oscillation": false}"#).unwrap();
+        assert!(command.percent().is_err());
+        assert_eq!(command.oscillation().unwrap(), Some(false));
+        assert!(serde_json::from_str::<FanCombinedCommand>(r#"{"speed": 50}"#).is_err());
+    }
+
+    #[test]
+    fn oscillation_payloads() {
+        // These are the payload_oscillation_on/off that we register
+        assert!(parse_on_off("ON").unwrap());
+        assert!(!parse_on_off("OFF").unwrap());
+        assert!(parse_on_off("oscillate_on").is_err());
+    }
+
+    #[test]
+    fn presets_from_mode_values() {
+        use crate::hass_mqtt::work_mode::WorkModeValue;
+
+        let mut work_modes = fan_work_modes();
+        work_modes.add("gearMode".to_string(), json!(2));
+        for (value, name) in [(1, "Normal"), (2, "Sleep"), (3, "Breeze")] {
+            work_modes
+                .get_mut("gearMode")
+                .unwrap()
+                .values
+                .push(WorkModeValue {
+                    value: json!(value),
+                    name: Some(name.to_string()),
+                    computed_label: String::new(),
+                });
+        }
+        // A defaultValue that isn't one of the values is ignored
+        work_modes.get_mut("gearMode").unwrap().default_value = Some(json!(0));
+        assert_eq!(
+            work_modes.mode_by_name("gearMode").unwrap().default_value(),
+            1
+        );
+
+        let presets = fan_presets(&work_modes);
+        k9::assert_equal!(
+            presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            vec!["Breeze", "Nature", "Normal", "Sleep"]
+        );
+        // The Sleep work mode takes precedence over the value
+        assert_eq!(
+            preset_command(&work_modes, "Sleep", None).unwrap(),
+            FanModeCommand { mode: 5, param: 1 }
+        );
+        assert_eq!(
+            preset_command(&work_modes, "Breeze", Some(50)).unwrap(),
+            FanModeCommand { mode: 2, param: 3 }
+        );
+
+        assert_eq!(current_preset(&presets, 2, Some(1)), Some("Normal"));
+        assert_eq!(current_preset(&presets, 5, Some(2)), Some("Sleep"));
+        assert_eq!(current_preset(&presets, 2, Some(9)), None);
+    }
+
+    #[test]
+    fn speed_reported_during_preset() {
+        let mut device = ServiceDevice::new("H7111", "AA:BB:CC:DD:EE:FF:00:11");
+        let work_modes = fan_work_modes();
+        let set_mode = |device: &mut ServiceDevice, mode: u8, param: u8| {
+            device.humidifier_work_mode.replace(mode);
+            device.humidifier_param_by_mode.insert(mode, param);
+        };
+
+        set_mode(&mut device, 1, 4);
+        assert_eq!(current_speed_percent(&device, &work_modes), Some(50));
+
+        // Nature doesn't report a speed, so the last known one is used
+        set_mode(&mut device, 6, 0);
+        assert_eq!(current_speed_percent(&device, &work_modes), None);
+        device.set_fan_speed_percent(50);
+        assert_eq!(current_speed_percent(&device, &work_modes), Some(50));
+
+        set_mode(&mut device, 5, 3);
+        assert_eq!(current_speed_percent(&device, &work_modes), Some(100));
+    }
+
+    #[test]
+    fn initial_speed_fallback() {
+        let mut device = ServiceDevice::new("H7111", "AA:BB:CC:DD:EE:FF:00:11");
+        let work_modes = fan_work_modes();
+
+        // Without a quirk, nothing is known until the fan reports a speed
+        assert!(device.resolve_quirk().is_none());
+        assert_eq!(current_speed_percent(&device, &work_modes), None);
+        assert_eq!(current_speed_percent_or(&device, &work_modes, None), None);
+
+        // The quirk provides the speed until the real one is known
+        assert_eq!(
+            current_speed_percent_or(&device, &work_modes, Some(40)),
+            Some(40)
+        );
+        device.set_fan_speed_percent(75);
+        assert_eq!(
+            current_speed_percent_or(&device, &work_modes, Some(40)),
+            Some(75)
+        );
+        device.humidifier_work_mode.replace(1);
+        device.humidifier_param_by_mode.insert(1, 4);
+        assert_eq!(
+            current_speed_percent_or(&device, &work_modes, Some(40)),
+            Some(50)
+        );
+    }
+
+    #[test]
+    fn speed_steps() {
+        let range = FanSpeedRange::from_range(1..9).unwrap();
+        assert!(!range.is_percent());
+        assert_eq!(range.num_steps(), 8);
+
+        assert_eq!(range.percent_to_step(0), None);
+        assert_eq!(range.percent_to_step(1), Some(1));
+        assert_eq!(range.percent_to_step(12), Some(1));
+        assert_eq!(range.percent_to_step(13), Some(2));
+        assert_eq!(range.percent_to_step(50), Some(4));
+        assert_eq!(range.percent_to_step(100), Some(8));
+
+        assert_eq!(range.step_to_percent(1), 12);
+        assert_eq!(range.step_to_percent(4), 50);
+        assert_eq!(range.step_to_percent(8), 100);
+
+        // Round trip is stable
+        for step in 1..=8 {
+            assert_eq!(
+                range.percent_to_step(range.step_to_percent(step)),
+                Some(step)
+            );
+        }
+    }
+
+    #[test]
+    fn speed_zero_based() {
+        let range = FanSpeedRange::from_range(0..3).unwrap();
+        assert_eq!(range.percent_to_step(0), None);
+        assert_eq!(range.percent_to_step(1), Some(0));
+        assert_eq!(range.percent_to_step(34), Some(1));
+        assert_eq!(range.percent_to_step(100), Some(2));
+        assert_eq!(range.step_to_percent(0), 33);
+        assert_eq!(range.step_to_percent(2), 100);
+    }
+
+    #[test]
+    fn speed_labels() {
+        let range = FanSpeedRange::from_range(0..3).unwrap();
+        k9::assert_equal!(range.step_labels(), vec!["Speed 1", "Speed 2", "Speed 3"]);
+        assert_eq!(range.label_to_step("Speed 1"), Some(0));
+        assert_eq!(range.label_to_step("Speed 3"), Some(2));
+        assert_eq!(range.label_to_step("Speed 4"), None);
+        assert_eq!(range.label_to_step("Speed 0"), None);
+        assert_eq!(range.label_to_step("Turbo"), None);
+        assert_eq!(range.step_to_label(2).as_deref(), Some("Speed 3"));
+        assert_eq!(range.step_to_label(3), None);
+    }
+
+    #[test]
+    fn speed_percent() {
+        let range = FanSpeedRange::from_range(1..101).unwrap();
+        assert!(range.is_percent());
+        assert_eq!(range.percent_to_step(0), None);
+        assert_eq!(range.percent_to_step(1), Some(1));
+        assert_eq!(range.percent_to_step(42), Some(42));
+        assert_eq!(range.step_to_percent(42), 42);
+
+        let range = FanSpeedRange::from_range(10..101).unwrap();
+        assert!(!range.is_percent());
+    }
+}
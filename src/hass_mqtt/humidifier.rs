@@ -1,10 +1,11 @@
 use crate::ble::TargetHumidity;
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::hass_mqtt::work_mode::{current_work_mode, ParsedWorkMode};
 use crate::platform_api::{DeviceParameters, DeviceType, IntegerRange};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::quirks::HumidityUnits;
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -25,6 +26,9 @@ pub struct HumidifierConfig {
     pub target_humidity_command_topic: String,
     /// HASS will subscribe here to receive the humidity target percentage
     pub target_humidity_state_topic: String,
+    /// we will publish the current humidity reading here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_humidity_topic: Option<String>,
 
     /// HASS will publish here to change the current mode
     pub mode_command_topic: String,
@@ -55,8 +59,9 @@ pub struct Humidifier {
 impl Humidifier {
     pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
         let _quirk = device.resolve_quirk();
-        let use_iot = device.iot_api_supported() && state.get_iot_client().await.is_some();
-        let optimistic = !use_iot;
+        let use_iot =
+            device.iot_api_supported() && state.get_iot_client_for(device).await.is_some();
+        let optimistic = device.optimistic(use_iot);
 
         let device_class = if device.device_type() == DeviceType::Humidifier {
             Some("humidifier")
@@ -67,26 +72,46 @@ impl Humidifier {
         // command_topic controls the power state; just route it to
         // the general power switch handler
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/powerSwitch",
+            "{}/switch/{id}/command/powerSwitch",
+            topic_prefix(),
             id = topic_safe_id(device)
         );
 
         let target_humidity_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-target",
+            "{}/humidifier/{id}/set-target",
+            topic_prefix(),
             id = topic_safe_id(device)
         );
         let target_humidity_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-target",
+            "{}/humidifier/{id}/notify-target",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/humidifier/{id}/state",
+            topic_prefix(),
             id = topic_safe_id(device)
         );
-        let state_topic = format!("gv2mqtt/humidifier/{id}/state", id = topic_safe_id(device));
+        let current_humidity_topic = device
+            .http_device_info
+            .as_ref()
+            .and_then(|info| info.capability_by_instance("sensorHumidity"))
+            .map(|_| {
+                format!(
+                    "{}/humidifier/{id}/notify-current",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                )
+            });
 
         let mode_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-mode",
+            "{}/humidifier/{id}/set-mode",
+            topic_prefix(),
             id = topic_safe_id(device)
         );
         let mode_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-mode",
+            "{}/humidifier/{id}/notify-mode",
+            topic_prefix(),
             id = topic_safe_id(device)
         );
 
@@ -121,7 +146,7 @@ impl Humidifier {
         Ok(Self {
             humidifier: HumidifierConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::for_device(device),
                     name: if matches!(
                         device.device_type(),
                         DeviceType::Humidifier | DeviceType::Dehumidifier
@@ -140,6 +165,7 @@ impl Humidifier {
                 command_topic,
                 target_humidity_command_topic,
                 target_humidity_state_topic,
+                current_humidity_topic,
 
                 min_humidity,
                 max_humidity,
@@ -170,11 +196,9 @@ impl EntityInstance for Humidifier {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         match device.device_state() {
             Some(device_state) => {
@@ -216,6 +240,22 @@ impl EntityInstance for Humidifier {
                 .await?;
         }
 
+        if let Some(topic) = &self.humidifier.current_humidity_topic {
+            let units = device
+                .resolve_quirk()
+                .and_then(|q| q.platform_humidity_sensor_units)
+                .unwrap_or(HumidityUnits::RelativePercent);
+            if let Some(humidity) = device
+                .get_state_capability_by_instance("sensorHumidity")
+                .and_then(|cap| cap.state.pointer("/value/currentHumidity"))
+                .and_then(|v| v.as_f64())
+                .map(|v| units.from_reading_to_relative_percent(v))
+                .map(|v| device.sensor_calibration().apply_to_humidity(v))
+            {
+                client.publish(topic, format!("{humidity:.2}")).await?;
+            }
+        }
+
         if let Some(mode_value) = device.humidifier_work_mode {
             if let Ok(work_mode) = ParsedWorkMode::with_device(&device) {
                 let mode_value_json = json!(mode_value);
@@ -249,10 +289,19 @@ pub async fn mqtt_device_set_work_mode(
 ) -> anyhow::Result<()> {
     log::info!("mqtt_humidifier_set_mode: {id}: {mode}");
     let device = state.resolve_device_for_control(&id).await?;
+    set_work_mode(&state, &device, &mode).await
+}
 
-    let work_modes = ParsedWorkMode::with_device(&device)?;
+/// Switch the device into the named work mode, using the
+/// default parameter of that mode
+pub async fn set_work_mode(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    mode: &str,
+) -> anyhow::Result<()> {
+    let work_modes = ParsedWorkMode::with_device(device)?;
     let work_mode = work_modes
-        .mode_by_name(&mode)
+        .mode_by_name(mode)
         .ok_or_else(|| anyhow!("mode {mode} not found"))?;
     let mode_num = work_mode
         .value
@@ -262,7 +311,7 @@ pub async fn mqtt_device_set_work_mode(
     let value = work_mode.default_value();
 
     state
-        .humidifier_set_parameter(&device, mode_num, value)
+        .humidifier_set_parameter(device, mode_num, value)
         .await?;
 
     Ok(())
@@ -276,13 +325,47 @@ pub async fn mqtt_humidifier_set_target(
     log::info!("mqtt_humidifier_set_target: {id}: {percent}");
 
     let device = state.resolve_device_for_control(&id).await?;
+    set_target_humidity(&state, &device, percent).await
+}
 
-    let use_iot = device.pollable_via_iot() && state.get_iot_client().await.is_some();
+/// Set the target humidity for the device.
+/// If the device is currently off or in another mode, and it
+/// has an Auto mode, it will be switched into that mode so that
+/// the target has some effect.
+pub async fn set_target_humidity(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    percent: i64,
+) -> anyhow::Result<()> {
+    let use_iot = device.pollable_via_iot() && state.get_iot_client_for(device).await.is_some();
 
     if !use_iot {
         if let Some(info) = &device.http_device_info {
             if let Some(cap) = info.capability_by_instance("humidity") {
-                state.device_control(&device, cap, percent).await?;
+                let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+                let work_modes = ParsedWorkMode::with_device(device).ok();
+                if let Some(auto_mode) = work_modes.as_ref().and_then(|wm| wm.mode_by_name("Auto"))
+                {
+                    if let Some(mode_num) = auto_mode.value.as_i64() {
+                        // The target only applies in Auto mode; in Manual
+                        // mode the device runs at a fixed mist level
+                        let in_auto_mode = current_work_mode(device)
+                            .map(|(mode, _)| mode == mode_num)
+                            .unwrap_or(false);
+                        if !is_on || !in_auto_mode {
+                            log::info!("{device} is not running in Auto mode, switching to it");
+                            state
+                                .humidifier_set_parameter(
+                                    device,
+                                    mode_num,
+                                    auto_mode.default_value(),
+                                )
+                                .await?;
+                        }
+                    }
+                }
+
+                state.device_control(device, cap, percent).await?;
 
                 // We're running in optimistic mode; stash
                 // the last set value so that we can report it
@@ -306,7 +389,7 @@ pub async fn mqtt_humidifier_set_target(
         }
     }
 
-    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let work_modes = ParsedWorkMode::with_device(device)?;
     let work_mode = work_modes
         .mode_by_name("Auto")
         .ok_or_else(|| anyhow!("mode Auto not found"))?;
@@ -318,7 +401,7 @@ pub async fn mqtt_humidifier_set_target(
     let value = TargetHumidity::from_percent(percent as u8);
 
     state
-        .humidifier_set_parameter(&device, mode_num, value.into_inner().into())
+        .humidifier_set_parameter(device, mode_num, value.into_inner().into())
         .await?;
 
     Ok(())
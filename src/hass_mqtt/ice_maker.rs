@@ -0,0 +1,182 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::binary_sensor::{event_flag, BinarySensorConfig};
+use crate::hass_mqtt::instance::{EntityInstance, EntityList};
+use crate::hass_mqtt::select::WorkModeSelect;
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::platform_api::DeviceCapability;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+
+/// Capability instances used by ice makers to report that
+/// the ice basket is full
+pub const ICE_FULL_INSTANCES: &[&str] = &["iceFullEvent", "iceFull", "fullIceEvent"];
+
+/// Capability instances used by ice makers to report that
+/// the water reservoir needs refilling
+pub const LACK_WATER_INSTANCES: &[&str] = &["lackWaterEvent", "lackWater"];
+
+/// Returns the state of the first of the instances that the
+/// device reports, if any
+fn status_flag(device: &ServiceDevice, instances: &[&str]) -> Option<bool> {
+    instances.iter().find_map(|instance| {
+        let cap = device.get_capability_by_instance(instance)?;
+        let value = device
+            .get_state_capability_by_instance(instance)?
+            .state
+            .pointer("/value")?;
+        event_flag(cap, value)
+    })
+}
+
+fn has_any_instance(device: &ServiceDevice, instances: &[&str]) -> bool {
+    instances
+        .iter()
+        .any(|instance| device.get_capability_by_instance(instance).is_some())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IceMakerStatus {
+    Making,
+    BasketFull,
+    AddWater,
+}
+
+/// A binary sensor for one aspect of the status of an ice maker
+pub struct IceMakerStatusSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    status: IceMakerStatus,
+}
+
+impl IceMakerStatusSensor {
+    fn new(device: &ServiceDevice, state: &StateHandle, status: IceMakerStatus) -> Self {
+        let (suffix, name, device_class, icon) = match status {
+            IceMakerStatus::Making => ("making-ice", "Making Ice", Some("running"), None),
+            IceMakerStatus::BasketFull => ("ice-full", "Ice Basket Full", None, Some("mdi:cube")),
+            IceMakerStatus::AddWater => (
+                "add-water",
+                "Add Water",
+                Some("problem"),
+                Some("mdi:water-alert"),
+            ),
+        };
+        let icon = icon.map(|icon| icon.to_string());
+        let unique_id = format!("binary-sensor-{id}-{suffix}", id = topic_safe_id(device));
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(name.to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class,
+                    icon,
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", topic_prefix()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            status,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for IceMakerStatusSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let full = status_flag(&device, ICE_FULL_INSTANCES);
+        let lack_water = status_flag(&device, LACK_WATER_INSTANCES);
+
+        let value = match self.status {
+            IceMakerStatus::BasketFull => full,
+            IceMakerStatus::AddWater => lack_water,
+            // The ice maker pauses while the basket is full
+            // or it is out of water, even though it is on
+            IceMakerStatus::Making => device
+                .device_state()
+                .map(|s| s.on && full != Some(true) && lack_water != Some(true)),
+        };
+
+        match value {
+            Some(on) => self.sensor.notify_state(client, on).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Adds the entities for an ice maker. The work modes of an ice
+/// maker select the size of the ice cubes, so they are presented
+/// as a single select entity. Its power switch, which starts and
+/// stops ice production, is handled with the other toggles.
+pub fn entities_for_ice_maker(
+    d: &ServiceDevice,
+    state: &StateHandle,
+    cap: &DeviceCapability,
+    entities: &mut EntityList,
+) -> anyhow::Result<()> {
+    let mut work_modes = ParsedWorkMode::with_capability(cap)?;
+    work_modes.adjust_for_device(&d.sku);
+    entities.add(
+        WorkModeSelect::new(d, &work_modes, state)
+            .with_name("Cube Size")
+            .with_icon("mdi:cube-outline"),
+    );
+
+    entities.add(IceMakerStatusSensor::new(d, state, IceMakerStatus::Making));
+    if has_any_instance(d, ICE_FULL_INSTANCES) {
+        entities.add(IceMakerStatusSensor::new(
+            d,
+            state,
+            IceMakerStatus::BasketFull,
+        ));
+    }
+    if has_any_instance(d, LACK_WATER_INSTANCES) {
+        entities.add(IceMakerStatusSensor::new(
+            d,
+            state,
+            IceMakerStatus::AddWater,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::DeviceCapabilityKind;
+
+    #[test]
+    fn status_values() {
+        let cap = DeviceCapability {
+            kind: DeviceCapabilityKind::Event,
+            instance: "lackWaterEvent".to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: Some(serde_json::json!({
+                "options": [
+                    {"name": "lack", "value": 1},
+                    {"name": "normal", "value": 2},
+                ]
+            })),
+        };
+        assert_eq!(event_flag(&cap, &serde_json::json!(1)), Some(true));
+        assert_eq!(event_flag(&cap, &serde_json::json!(2)), Some(false));
+        assert_eq!(event_flag(&cap, &serde_json::json!(0)), Some(false));
+        assert_eq!(event_flag(&cap, &serde_json::json!(true)), Some(true));
+        assert_eq!(event_flag(&cap, &serde_json::json!("")), None);
+    }
+}
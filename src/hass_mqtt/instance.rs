@@ -1,4 +1,6 @@
 use crate::hass_mqtt::base::EntityConfig;
+use crate::service::entity_filter::is_entity_included;
+use crate::service::entity_overrides::apply_entity_overrides;
 use crate::service::hass::HassClient;
 use crate::service::state::StateHandle;
 use anyhow::Context;
@@ -19,14 +21,23 @@ pub async fn publish_entity_config<T: Serialize>(
     base: &EntityConfig,
     config: &T,
 ) -> anyhow::Result<()> {
-    // TODO: remember all published topics for future GC
-
     let disco = state.get_hass_disco_prefix().await;
     let topic = format!(
         "{disco}/{integration}/{unique_id}/config",
         unique_id = base.unique_id
     );
 
+    if client.is_removing_entities() || !is_entity_included(integration, base) {
+        // An empty config causes hass to remove the entity
+        return client.publish(topic, "").await;
+    }
+
+    if let Some(identifier) = base.device.identifiers.first() {
+        client.note_config_topic(&topic, identifier);
+    }
+    if let Some(config) = apply_entity_overrides(base, config)? {
+        return client.publish_obj(topic, config).await;
+    }
     client.publish_obj(topic, config).await
 }
 
@@ -48,6 +59,11 @@ impl EntityList {
         self.entities.len()
     }
 
+    #[cfg(test)]
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn EntityInstance + Send + Sync + 'static>> {
+        self.entities.iter()
+    }
+
     pub async fn publish_config(
         &self,
         state: &StateHandle,
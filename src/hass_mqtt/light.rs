@@ -1,16 +1,24 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::DeviceParameters;
 use crate::platform_api::DeviceType;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, kelvin_to_mired, light_segment_state_topic, light_state_topic,
-    topic_safe_id, HassClient,
+    kelvin_to_mired, light_segment_state_topic, light_state_topic, nightlight_state_topic,
+    topic_prefix, topic_safe_id, HassClient, HassLightCommand, IdParameter,
+    APPROXIMATE_COLOR_TEMP_RANGE,
 };
+use crate::service::scene_categories::find_scene_option;
 use crate::service::state::StateHandle;
+use anyhow::anyhow;
 use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
 use serde_json::json;
 
+/// The optional capability used to set the brightness of a nightlight
+pub const NIGHTLIGHT_BRIGHTNESS_INSTANCE: &str = "nightlightBrightness";
+
 /// <https://www.home-assistant.io/integrations/light.mqtt/#json-schema>
 #[derive(Serialize, Clone, Debug)]
 pub struct LightConfig {
@@ -29,6 +37,8 @@ pub struct LightConfig {
     /// Flag that defines if the light supports brightness.
     pub brightness: bool,
     /// Defines the maximum brightness value (i.e., 100%) of the MQTT device.
+    /// Always 100, as we convert to and from the native brightness scale
+    /// of the device, which may be 0-254, when talking to it.
     pub brightness_scale: u32,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -59,6 +69,7 @@ pub struct DeviceLight {
     light: LightConfig,
     device_id: String,
     state: StateHandle,
+    segment: Option<u32>,
 }
 
 #[async_trait]
@@ -68,44 +79,58 @@ impl EntityInstance for DeviceLight {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        if let Some(segment) = self.segment {
+            return self.notify_segment_state(segment, client).await;
+        }
         if self.light.optimistic {
             return Ok(());
         }
 
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         match device.device_state() {
             Some(device_state) => {
                 log::trace!("LightConfig::notify_state: state is {device_state:?}");
 
                 let is_on = device_state.light_on.unwrap_or(false);
+                let effect = device_state
+                    .scene
+                    .as_deref()
+                    .and_then(|scene| self.active_effect(scene));
 
                 let light_state = if is_on {
-                    if device_state.kelvin == 0 {
-                        json!({
-                            "state": "ON",
-                            "color_mode": "rgb",
-                            "color": {
+                    let color_mode =
+                        active_color_mode(&self.light.supported_color_modes, device_state.kelvin);
+                    let mut light_state = json!({
+                        "state": "ON",
+                        "color_mode": color_mode,
+                        "effect": effect,
+                    });
+                    if self.light.brightness {
+                        light_state["brightness"] = device_state.brightness.into();
+                    }
+                    match color_mode {
+                        "rgb" => {
+                            light_state["color"] = json!({
                                 "r": device_state.color.r,
                                 "g": device_state.color.g,
                                 "b": device_state.color.b,
-                            },
-                            "brightness": device_state.brightness,
-                            "effect": device_state.scene,
-                        })
-                    } else {
-                        json!({
-                            "state": "ON",
-                            "color_mode": "color_temp",
-                            "brightness": device_state.brightness,
-                            "color_temp": kelvin_to_mired(device_state.kelvin),
-                            "effect": device_state.scene,
-                        })
+                            });
+                        }
+                        "color_temp" => {
+                            if let Some(mired) = reported_mired(
+                                device_state.kelvin,
+                                self.light.min_mireds,
+                                self.light.max_mireds,
+                            ) {
+                                light_state["color_temp"] = mired.into();
+                            }
+                        }
+                        _ => {}
                     }
+                    light_state
                 } else {
                     json!({"state":"OFF"})
                 };
@@ -126,7 +151,88 @@ impl EntityInstance for DeviceLight {
     }
 }
 
+/// Convert the color temperature reported by the device to the
+/// mireds that hass expects. Devices may report a temperature that
+/// is slightly outside of the range that they advertise, which hass
+/// would fail to show on the slider, so it is kept within that range.
+/// Returns None if the device didn't report a color temperature.
+fn reported_mired(kelvin: u32, min_mireds: Option<u32>, max_mireds: Option<u32>) -> Option<u32> {
+    if kelvin == 0 {
+        return None;
+    }
+    let mut mired = kelvin_to_mired(kelvin);
+    if let Some(min) = min_mireds {
+        mired = mired.max(min);
+    }
+    if let Some(max) = max_mireds {
+        mired = mired.min(max);
+    }
+    Some(mired)
+}
+
+/// Returns the color mode to report to hass, based on the modes
+/// that the light supports and what the device last reported.
+/// Govee reports a kelvin value of 0 when the light is in color mode.
+/// Hass requires that the reported mode is one of the supported modes.
+fn active_color_mode(supported_color_modes: &[String], kelvin: u32) -> &'static str {
+    let supports = |mode: &str| supported_color_modes.iter().any(|m| m == mode);
+    if kelvin != 0 && supports("color_temp") {
+        "color_temp"
+    } else if supports("rgb") {
+        "rgb"
+    } else if supports("color_temp") {
+        "color_temp"
+    } else if supports("brightness") {
+        "brightness"
+    } else {
+        "onoff"
+    }
+}
+
 impl DeviceLight {
+    /// Map the active scene name to the corresponding
+    /// entry from our effect list
+    fn active_effect(&self, scene: &str) -> Option<String> {
+        let diy = format!("DIY: {scene}");
+        find_scene_option(&self.light.effect_list, scene)
+            .or_else(|| {
+                self.light
+                    .effect_list
+                    .iter()
+                    .find(|effect| effect.eq_ignore_ascii_case(&diy))
+            })
+            .cloned()
+    }
+
+    /// Report the color of the segment, which some devices report,
+    /// along with the brightness that was most recently set for the
+    /// segment, as Govee doesn't report that
+    async fn notify_segment_state(&self, segment: u32, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+        let color = device.segment_color(segment);
+        let brightness = device
+            .segment_brightness
+            .get(&segment)
+            .filter(|_| self.light.brightness);
+        if color.is_none() && brightness.is_none() {
+            return Ok(());
+        }
+
+        let mut light_state = json!({"state": "ON"});
+        if let Some(color) = color {
+            light_state["color_mode"] = "rgb".into();
+            light_state["color"] = json!({"r": color.r, "g": color.g, "b": color.b});
+        }
+        if let Some(brightness) = brightness {
+            light_state["brightness"] = (*brightness).into();
+        }
+        client
+            .publish_obj(&self.light.state_topic, &light_state)
+            .await
+    }
+
     pub async fn for_device(
         device: &ServiceDevice,
         state: &StateHandle,
@@ -136,9 +242,14 @@ impl DeviceLight {
         let device_type = device.device_type();
 
         let command_topic = match segment {
-            None => format!("gv2mqtt/light/{id}/command", id = topic_safe_id(device)),
+            None => format!(
+                "{}/light/{id}/command",
+                topic_prefix(),
+                id = topic_safe_id(device)
+            ),
             Some(seg) => format!(
-                "gv2mqtt/light/{id}/command/{seg}",
+                "{}/light/{id}/command/{seg}",
+                topic_prefix(),
                 id = topic_safe_id(device)
             ),
         };
@@ -153,7 +264,7 @@ impl DeviceLight {
             Some(seg) => light_segment_state_topic(device, seg),
             None => light_state_topic(device),
         };
-        let availability_topic = availability_topic();
+        let availability = Availability::for_device(device);
         let unique_id = format!(
             "gv2mqtt-{id}{seg}",
             id = topic_safe_id(device),
@@ -163,10 +274,10 @@ impl DeviceLight {
         let effect_list = if segment.is_some() {
             vec![]
         } else {
-            match state.device_list_scenes(device).await {
-                Ok(scenes) => scenes,
+            match state.device_list_effects(device).await {
+                Ok(effects) => effects,
                 Err(err) => {
-                    log::error!("Unable to list scenes for {device}: {err:#}");
+                    log::error!("Unable to list effects for {device}: {err:#}");
                     vec![]
                 }
             }
@@ -182,7 +293,11 @@ impl DeviceLight {
 
         let (min_mireds, max_mireds) = if segment.is_some() {
             (None, None)
-        } else if let Some((min, max)) = device.get_color_temperature_range() {
+        } else if let Some((min, max)) = device.get_color_temperature_range().or_else(|| {
+            device
+                .supports_rgb()
+                .then_some(APPROXIMATE_COLOR_TEMP_RANGE)
+        }) {
             supported_color_modes.push("color_temp".to_string());
             color_mode = true;
             // Note that min and max are swapped by the translation
@@ -192,16 +307,34 @@ impl DeviceLight {
             (None, None)
         };
 
-        let brightness = segment.is_some()
-            || quirk
-                .as_ref()
-                .map(|q| q.supports_brightness)
-                .unwrap_or(false)
-            || device
+        let brightness = match segment {
+            Some(_) => device
                 .http_device_info
                 .as_ref()
-                .map(|info| info.supports_brightness())
-                .unwrap_or(false);
+                .and_then(|info| info.supports_segmented_brightness())
+                .is_some(),
+            None => {
+                quirk
+                    .as_ref()
+                    .map(|q| q.supports_brightness)
+                    .unwrap_or(false)
+                    || device
+                        .http_device_info
+                        .as_ref()
+                        .map(|info| info.supports_brightness())
+                        .unwrap_or(false)
+            }
+        };
+
+        // Hass requires that at least one mode is supported
+        if supported_color_modes.is_empty() {
+            supported_color_modes.push(if brightness {
+                "brightness".to_string()
+            } else {
+                "onoff".to_string()
+            });
+            color_mode = true;
+        }
 
         let name = match segment {
             Some(n) => Some(format!("Segment {:03}", n + 1)),
@@ -212,7 +345,7 @@ impl DeviceLight {
         Ok(Self {
             light: LightConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability,
                     name,
                     device_class: None,
                     origin: Origin::default(),
@@ -233,11 +366,183 @@ impl DeviceLight {
                 payload_available: "online".to_string(),
                 max_mireds,
                 min_mireds,
-                optimistic: segment.is_some(),
+                // Most devices don't report the colors of their
+                // segments, in which case hass has to assume them
+                optimistic: segment.is_some() && device.reported_segment_colors().is_none(),
                 icon,
             },
             device_id: device.id.to_string(),
             state: state.clone(),
+            segment,
         })
     }
 }
+
+/// A secondary light entity for the nightlight or indicator
+/// light of a device, which is controlled independently of
+/// the device itself.
+pub struct NightLight {
+    light: LightConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl NightLight {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let brightness = device
+            .get_capability_by_instance(NIGHTLIGHT_BRIGHTNESS_INSTANCE)
+            .is_some();
+
+        Self {
+            light: LightConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Night Light".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-nightlight", id = topic_safe_id(device)),
+                    entity_category: None,
+                    icon: None,
+                },
+                schema: "json".to_string(),
+                command_topic: format!(
+                    "{}/light/{id}/nightlight/command",
+                    topic_prefix(),
+                    id = topic_safe_id(device)
+                ),
+                state_topic: nightlight_state_topic(device),
+                supported_color_modes: vec![if brightness {
+                    "brightness".to_string()
+                } else {
+                    "onoff".to_string()
+                }],
+                color_mode: true,
+                brightness,
+                brightness_scale: 100,
+                effect: false,
+                effect_list: vec![],
+                payload_available: "online".to_string(),
+                max_mireds: None,
+                min_mireds: None,
+                optimistic: false,
+                icon: Some("mdi:lightbulb-night".to_string()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for NightLight {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.light.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(on) = device
+            .nightlight_toggle_instance()
+            .and_then(|instance| device.toggle_state(instance))
+        else {
+            return Ok(());
+        };
+
+        let mut light_state = json!({"state": if on { "ON" } else { "OFF" }});
+        if self.light.brightness {
+            if let Some(brightness) = device
+                .get_state_capability_by_instance(NIGHTLIGHT_BRIGHTNESS_INSTANCE)
+                .and_then(|cap| cap.state.pointer("/value"))
+                .and_then(|v| v.as_i64())
+            {
+                light_state["brightness"] = brightness.into();
+            }
+        }
+
+        client
+            .publish_obj(&self.light.state_topic, &light_state)
+            .await
+    }
+}
+
+/// HASS is sending a command to the nightlight of a device
+pub async fn mqtt_nightlight_command(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let command: HassLightCommand = serde_json::from_str(&payload)?;
+    let device = state.resolve_device_for_control(&id).await?;
+    log::info!("Nightlight command for {device}: {payload}");
+
+    let instance = device
+        .nightlight_toggle_instance()
+        .ok_or_else(|| anyhow!("{device} has no nightlight"))?;
+    let client = state
+        .get_platform_client_for(&device)
+        .await
+        .ok_or_else(|| anyhow!("set nightlight for {device}: Platform API is not available"))?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow!("HTTP device info is missing"))?;
+
+    let on = command.state == "ON";
+    client.set_toggle_state(info, instance, on).await?;
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .set_last_toggle_state(instance, on);
+
+    if let (true, Some(brightness), Some(cap)) = (
+        on,
+        command.brightness,
+        device.get_capability_by_instance(NIGHTLIGHT_BRIGHTNESS_INSTANCE),
+    ) {
+        let brightness = match &cap.parameters {
+            Some(DeviceParameters::Integer { range, .. }) => {
+                (brightness as u32).clamp(range.min, range.max)
+            }
+            _ => brightness as u32,
+        };
+        state.device_control(&device, cap, brightness).await?;
+    }
+
+    state.notify_of_state_change(&device.id).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn color_mode() {
+        let modes =
+            |modes: &[&str]| -> Vec<String> { modes.iter().map(|m| m.to_string()).collect() };
+
+        let full = modes(&["rgb", "color_temp"]);
+        assert_eq!(active_color_mode(&full, 0), "rgb");
+        assert_eq!(active_color_mode(&full, 4000), "color_temp");
+
+        // Never report a mode that we didn't advertise
+        assert_eq!(active_color_mode(&modes(&["rgb"]), 4000), "rgb");
+        assert_eq!(active_color_mode(&modes(&["color_temp"]), 0), "color_temp");
+        assert_eq!(active_color_mode(&modes(&["brightness"]), 0), "brightness");
+        assert_eq!(active_color_mode(&[], 0), "onoff");
+    }
+
+    #[test]
+    fn color_temperature() {
+        // 2700K-6500K
+        let (min, max) = (Some(153), Some(370));
+        assert_eq!(reported_mired(4000, min, max), Some(250));
+        assert_eq!(reported_mired(2000, min, max), Some(370));
+        assert_eq!(reported_mired(9000, min, max), Some(153));
+        assert_eq!(reported_mired(2000, None, None), Some(500));
+        assert_eq!(reported_mired(0, min, max), None);
+    }
+}
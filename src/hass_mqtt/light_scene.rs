@@ -0,0 +1,136 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::select::SelectConfig;
+use crate::service::device::{Device as ServiceDevice, UNKNOWN_SCENE};
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+
+/// A select entity listing the scenes from the lightScene
+/// capability of the device
+pub struct LightSceneSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl LightSceneSelect {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Option<Self>> {
+        let mut scenes = state.device_list_light_scenes(device).await?;
+        if scenes.is_empty() {
+            return Ok(None);
+        }
+        // The empty option represents "not running a scene", while
+        // UNKNOWN_SCENE represents a scene that the device reports, but
+        // which isn't in our list, such as one set via the Govee App
+        scenes.insert(0, "".to_string());
+        scenes.push(UNKNOWN_SCENE.to_string());
+
+        let command_topic = format!(
+            "{}/{id}/set-light-scene",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-light-scene",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let availability = Availability::for_device(device);
+        let unique_id = format!("gv2mqtt-{id}-light-scene", id = topic_safe_id(device));
+
+        Ok(Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability,
+                    name: Some("Scene".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:palette-swatch".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: scenes,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LightSceneSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        // Report the empty option unless the device is on and
+        // the active scene is one of our scenes
+        let active = device
+            .device_state()
+            .filter(|s| s.light_on.unwrap_or(s.on))
+            .and_then(|s| s.scene)
+            .and_then(|scene| {
+                self.select
+                    .options
+                    .iter()
+                    .find(|opt| opt.eq_ignore_ascii_case(&scene))
+                    .cloned()
+            })
+            .unwrap_or_default();
+
+        client.publish(&self.select.state_topic, active).await
+    }
+}
+
+pub async fn mqtt_set_light_scene(
+    Payload(scene): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if scene.is_empty() || scene == UNKNOWN_SCENE {
+        // There is no way to stop a scene other than
+        // to select something else
+        return Ok(());
+    }
+
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_light_scene(&device, &scene)
+        .await
+        .context("mqtt_set_light_scene: state.device_set_light_scene")?;
+
+    Ok(())
+}
+
+/// Activates a scene by its numeric id, for use from scripts
+pub async fn mqtt_set_scene_id(
+    Payload(scene_id): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let scene_id: u64 = scene_id
+        .trim()
+        .parse()
+        .with_context(|| format!("mqtt_set_scene_id: '{scene_id}' is not a numeric scene id"))?;
+
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_scene_by_id(&device, scene_id)
+        .await
+        .context("mqtt_set_scene_id: state.device_set_scene_by_id")?;
+
+    Ok(())
+}
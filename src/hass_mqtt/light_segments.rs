@@ -0,0 +1,496 @@
+use crate::hass_mqtt::instance::EntityList;
+use crate::hass_mqtt::light::DeviceLight;
+use crate::lan_api::DeviceColor;
+use crate::platform_api::from_json;
+use crate::service::device::{Device as ServiceDevice, GRADIENT_INSTANCE};
+use crate::service::hass::{HassLightCommand, IdParameter, LIGHT_COMMAND_DEBOUNCE};
+use crate::service::state::StateHandle;
+use anyhow::anyhow;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Deserialize;
+use std::ops::Range;
+
+/// Returns the range of segment indices that can be addressed
+/// via the segmentedColorRgb capability.
+/// A quirk may override the segment count for devices where
+/// the platform API metadata is wrong.
+pub fn segment_range(device: &ServiceDevice) -> Option<Range<u32>> {
+    let range = device.http_device_info.as_ref()?.supports_segmented_rgb()?;
+    match device.resolve_quirk().and_then(|q| q.segment_count) {
+        Some(count) => Some(range.start..range.start + count),
+        None => Some(range),
+    }
+}
+
+/// Adds a light entity for each segment of the device
+pub async fn enumerate_segment_lights(
+    device: &ServiceDevice,
+    state: &StateHandle,
+    entities: &mut EntityList,
+) -> anyhow::Result<()> {
+    if let Some(segments) = segment_range(device) {
+        for n in segments {
+            entities.add(DeviceLight::for_device(device, state, Some(n)).await?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct IdAndSeg {
+    id: String,
+    segment: String,
+}
+
+/// HASS is sending a command to an individual segment light
+pub async fn mqtt_light_segment_command(
+    Payload(payload): Payload<String>,
+    Params(IdAndSeg { id, segment }): Params<IdAndSeg>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let segment: u32 = segment.parse()?;
+    let command: HassLightCommand = from_json(&payload)?;
+
+    let device_id = state.resolve_device_read_only(&id).await?.id;
+    if !state
+        .debounce_command(
+            &device_id,
+            &command.debounce_key(&format!("segment/{segment}")),
+            LIGHT_COMMAND_DEBOUNCE,
+        )
+        .await
+    {
+        return Ok(());
+    }
+
+    let device = state.resolve_device_for_control(&id).await?;
+    log::info!("Command for {device} segment {segment}: {payload}");
+
+    if let Some(client) = state.get_platform_client_for(&device).await {
+        let info = device
+            .http_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("HTTP device info is missing"))?;
+
+        log::info!("Using Platform API to control {device} segment");
+
+        if let Some(brightness) = command.brightness {
+            client
+                .set_segment_brightness(info, &[segment], brightness)
+                .await?;
+            state
+                .device_mut(&device.sku, &device.id)
+                .await
+                .set_segment_brightness(&[segment], brightness);
+        } else if command.state == "OFF" {
+            // Do nothing here. We used to set brightness to zero,
+            // but it is problematic:
+            // * Some devices don't have a 0
+            // * Setting it to 0 will power up the rest of the device,
+            //   so if HASS is turning off all lights in an area, the
+            //   effect is that they will turn off and then immediate
+            //   on again when there are segments involved
+            // client.set_segment_brightness(&info, segment, 0).await?;
+        }
+        if let Some(color) = &command.color {
+            client
+                .set_segment_rgb(info, &[segment], color.r, color.g, color.b)
+                .await?;
+            state
+                .device_mut(&device.sku, &device.id)
+                .await
+                .set_segment_color(&[segment], *color);
+        }
+    } else {
+        anyhow::bail!("set segments for {device}: Platform API is not available");
+    }
+
+    state.notify_of_state_change(&device.id).await
+}
+
+/// The color of a segment, as accepted by the segment-color topic.
+/// Can be specified as `{"r":255,"g":0,"b":0}`, `[255,0,0]`
+/// or a packed `0xRRGGBB` integer.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SegmentRgb {
+    Packed(u32),
+    Triple([u8; 3]),
+    Color(DeviceColor),
+}
+
+impl SegmentRgb {
+    pub fn to_color(self) -> DeviceColor {
+        match self {
+            Self::Packed(value) => DeviceColor {
+                r: ((value >> 16) & 0xff) as u8,
+                g: ((value >> 8) & 0xff) as u8,
+                b: (value & 0xff) as u8,
+            },
+            Self::Triple([r, g, b]) => DeviceColor { r, g, b },
+            Self::Color(color) => color,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SegmentColor {
+    pub segment: u32,
+    pub rgb: SegmentRgb,
+}
+
+/// Group the requested segment colors so that segments sharing
+/// the same color can be set in a single platform API request.
+/// If a segment is listed more than once, the last entry wins.
+fn group_segment_colors(colors: &[SegmentColor]) -> Vec<(DeviceColor, Vec<u32>)> {
+    let mut groups: Vec<(DeviceColor, Vec<u32>)> = vec![];
+    for (idx, entry) in colors.iter().enumerate() {
+        if colors[idx + 1..].iter().any(|e| e.segment == entry.segment) {
+            continue;
+        }
+        let color = entry.rgb.to_color();
+        match groups.iter_mut().find(|(c, _)| *c == color) {
+            Some((_, segments)) => segments.push(entry.segment),
+            None => groups.push((color, vec![entry.segment])),
+        }
+    }
+    groups
+}
+
+/// HASS (or a script) is setting the colors of several segments
+/// at once. The payload is a JSON array of `{"segment": N, "rgb": COLOR}`
+pub async fn mqtt_light_segment_colors(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+    let colors: Vec<SegmentColor> = from_json(&payload)?;
+    log::info!("Segment colors for {device}: {payload}");
+
+    let range =
+        segment_range(&device).ok_or_else(|| anyhow!("{device} doesn't support segments"))?;
+    if let Some(bad) = colors.iter().find(|c| !range.contains(&c.segment)) {
+        anyhow::bail!(
+            "segment {} is outside of the range {range:?} for {device}",
+            bad.segment
+        );
+    }
+
+    let client = state
+        .get_platform_client_for(&device)
+        .await
+        .ok_or_else(|| anyhow!("set segments for {device}: Platform API is not available"))?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow!("HTTP device info is missing"))?;
+
+    for (color, segments) in group_segment_colors(&colors) {
+        client
+            .set_segment_rgb(info, &segments, color.r, color.g, color.b)
+            .await?;
+        state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .set_segment_color(&segments, color);
+    }
+
+    Ok(())
+}
+
+/// One or more segment indices
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SegmentList {
+    One(u32),
+    Many(Vec<u32>),
+}
+
+impl SegmentList {
+    fn as_slice(&self) -> &[u32] {
+        match self {
+            Self::One(segment) => std::slice::from_ref(segment),
+            Self::Many(segments) => segments,
+        }
+    }
+}
+
+/// A change to one or more segments, as accepted by the
+/// segment-command topic, such as `{"segment": 3, "brightness": 50}`
+/// or `{"segment": [0, 1], "rgb": [255, 0, 0], "brightness": 20}`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentCommand {
+    pub segment: SegmentList,
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    #[serde(default)]
+    pub rgb: Option<SegmentRgb>,
+}
+
+/// The payload of the segment-command topic; either a single
+/// command or a list of them, which are applied in order
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum SegmentCommands {
+    One(SegmentCommand),
+    Many(Vec<SegmentCommand>),
+}
+
+impl SegmentCommands {
+    fn into_vec(self) -> Vec<SegmentCommand> {
+        match self {
+            Self::One(command) => vec![command],
+            Self::Many(commands) => commands,
+        }
+    }
+}
+
+/// HASS (or a script) is changing the brightness or color
+/// of individual segments. Only the settings that the device has
+/// segment capabilities for are accepted.
+pub async fn mqtt_light_segment_settings(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+    let commands = from_json::<SegmentCommands, _>(&payload)?.into_vec();
+    log::info!("Segment command for {device}: {payload}");
+
+    let range =
+        segment_range(&device).ok_or_else(|| anyhow!("{device} doesn't support segments"))?;
+    let client = state
+        .get_platform_client_for(&device)
+        .await
+        .ok_or_else(|| anyhow!("set segments for {device}: Platform API is not available"))?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow!("HTTP device info is missing"))?;
+
+    // Validate everything before we change anything
+    for command in &commands {
+        let segments = command.segment.as_slice();
+        if let Some(bad) = segments.iter().find(|s| !range.contains(s)) {
+            anyhow::bail!("segment {bad} is outside of the range {range:?} for {device}");
+        }
+        if command.brightness.is_some() && info.supports_segmented_brightness().is_none() {
+            anyhow::bail!("{device} doesn't support segment brightness");
+        }
+    }
+
+    for command in &commands {
+        let segments = command.segment.as_slice();
+        if let Some(rgb) = command.rgb {
+            let color = rgb.to_color();
+            client
+                .set_segment_rgb(info, segments, color.r, color.g, color.b)
+                .await?;
+            state
+                .device_mut(&device.sku, &device.id)
+                .await
+                .set_segment_color(segments, color);
+        }
+        if let Some(brightness) = command.brightness {
+            client
+                .set_segment_brightness(info, segments, brightness)
+                .await?;
+            state
+                .device_mut(&device.sku, &device.id)
+                .await
+                .set_segment_brightness(segments, brightness);
+        }
+    }
+
+    state.notify_of_state_change(&device.id).await
+}
+
+/// Compute the color of each segment so that the colors blend
+/// smoothly between the stops, which are spread evenly across
+/// the length of the strip
+fn gradient_segment_colors(stops: &[DeviceColor], range: Range<u32>) -> Vec<SegmentColor> {
+    let count = range.len();
+    range
+        .enumerate()
+        .map(|(idx, segment)| {
+            let color = match stops.len() {
+                0 => DeviceColor::default(),
+                1 => stops[0],
+                _ => {
+                    let position = if count > 1 {
+                        idx as f64 * (stops.len() - 1) as f64 / (count - 1) as f64
+                    } else {
+                        0.
+                    };
+                    let first = (position.floor() as usize).min(stops.len() - 2);
+                    let t = position - first as f64;
+                    let (a, b) = (stops[first], stops[first + 1]);
+                    let lerp = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+                    DeviceColor {
+                        r: lerp(a.r, b.r),
+                        g: lerp(a.g, b.g),
+                        b: lerp(a.b, b.b),
+                    }
+                }
+            };
+            SegmentColor {
+                segment,
+                rgb: SegmentRgb::Color(color),
+            }
+        })
+        .collect()
+}
+
+/// HASS (or a script) is setting a gradient across the strip.
+/// The payload is a JSON array of colors, using any of the forms
+/// accepted by the segment-color topic.
+pub async fn mqtt_light_gradient(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+    let stops: Vec<SegmentRgb> = from_json(&payload)?;
+    log::info!("Gradient for {device}: {payload}");
+    if stops.is_empty() {
+        anyhow::bail!("gradient for {device} must have at least one color");
+    }
+    let stops: Vec<DeviceColor> = stops.into_iter().map(SegmentRgb::to_color).collect();
+
+    let range =
+        segment_range(&device).ok_or_else(|| anyhow!("{device} doesn't support segments"))?;
+
+    let client = state
+        .get_platform_client_for(&device)
+        .await
+        .ok_or_else(|| anyhow!("set gradient for {device}: Platform API is not available"))?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow!("HTTP device info is missing"))?;
+
+    // Have the device blend between the segments, if it can
+    if info.capability_by_instance(GRADIENT_INSTANCE).is_some() {
+        client
+            .set_toggle_state(info, GRADIENT_INSTANCE, true)
+            .await?;
+        state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .set_last_toggle_state(GRADIENT_INSTANCE, true);
+    }
+
+    let colors = gradient_segment_colors(&stops, range);
+    for (color, segments) in group_segment_colors(&colors) {
+        client
+            .set_segment_rgb(info, &segments, color.r, color.g, color.b)
+            .await?;
+        state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .set_segment_color(&segments, color);
+    }
+
+    state.notify_of_state_change(&device.id).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn segment_color_payload() {
+        let colors: Vec<SegmentColor> = from_json(
+            r#"[
+                {"segment": 0, "rgb": {"r": 255, "g": 0, "b": 0}},
+                {"segment": 1, "rgb": [0, 255, 0]},
+                {"segment": 2, "rgb": 255},
+                {"segment": 3, "rgb": 16711680},
+                {"segment": 1, "rgb": [255, 0, 0]}
+            ]"#,
+        )
+        .unwrap();
+
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        let blue = DeviceColor { r: 0, g: 0, b: 255 };
+
+        assert_eq!(
+            group_segment_colors(&colors),
+            vec![(red, vec![0, 3, 1]), (blue, vec![2])]
+        );
+    }
+
+    #[test]
+    fn segment_command_payload() {
+        let commands = from_json::<SegmentCommands, _>(r#"{"segment": 3, "brightness": 50}"#)
+            .unwrap()
+            .into_vec();
+        assert_eq!(
+            commands,
+            vec![SegmentCommand {
+                segment: SegmentList::One(3),
+                brightness: Some(50),
+                rgb: None,
+            }]
+        );
+        assert_eq!(commands[0].segment.as_slice(), &[3]);
+
+        let commands = from_json::<SegmentCommands, _>(
+            r#"[
+                {"segment": [0, 1], "rgb": [255, 0, 0]},
+                {"segment": 2, "brightness": 100}
+            ]"#,
+        )
+        .unwrap()
+        .into_vec();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].segment.as_slice(), &[0, 1]);
+        assert_eq!(commands[1].brightness, Some(100));
+
+        assert!(from_json::<SegmentCommands, _>(r#"{"segment": 1, "speed": 4}"#).is_err());
+        // Govee only offers scenes for the device as a whole
+        assert!(from_json::<SegmentCommands, _>(r#"{"segment": 1, "effect": "Sunrise"}"#).is_err());
+    }
+
+    #[test]
+    fn gradient() {
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        let blue = DeviceColor { r: 0, g: 0, b: 255 };
+        let purple = DeviceColor {
+            r: 128,
+            g: 0,
+            b: 128,
+        };
+
+        let colors: Vec<DeviceColor> = gradient_segment_colors(&[red, blue], 0..5)
+            .into_iter()
+            .map(|c| c.rgb.to_color())
+            .collect();
+        assert_eq!(colors[0], red);
+        assert_eq!(colors[2], purple);
+        assert_eq!(colors[4], blue);
+
+        let colors: Vec<DeviceColor> = gradient_segment_colors(&[red, blue, red], 0..5)
+            .into_iter()
+            .map(|c| c.rgb.to_color())
+            .collect();
+        assert_eq!(colors, vec![red, purple, blue, purple, red]);
+
+        let colors = gradient_segment_colors(&[red], 2..4);
+        assert_eq!(
+            colors,
+            vec![
+                SegmentColor {
+                    segment: 2,
+                    rgb: SegmentRgb::Color(red)
+                },
+                SegmentColor {
+                    segment: 3,
+                    rgb: SegmentRgb::Color(red)
+                }
+            ]
+        );
+    }
+}
@@ -1,14 +1,26 @@
 pub mod base;
+pub mod binary_sensor;
 pub mod button;
 pub mod climate;
 pub mod cover;
+pub mod diy_scene;
+pub mod do_not_disturb;
 pub mod enumerator;
+pub mod fan;
 pub mod humidifier;
+pub mod ice_maker;
 pub mod instance;
 pub mod light;
+pub mod light_scene;
+pub mod light_segments;
+pub mod music_mode;
 pub mod number;
+pub mod power_on_behavior;
+#[cfg(test)]
+pub mod replay;
 pub mod scene;
 pub mod select;
 pub mod sensor;
+pub mod snapshot;
 pub mod switch;
 pub mod work_mode;
@@ -0,0 +1,200 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::number::NumberConfig;
+use crate::hass_mqtt::select::SelectConfig;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+
+/// A select entity for choosing the music reactive mode
+/// from the musicMode capability of the device
+pub struct MusicModeSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MusicModeSelect {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let mut modes = device.http_device_info.as_ref()?.music_mode_names();
+        if modes.is_empty() {
+            return None;
+        }
+        // The empty option represents "not in music mode"
+        modes.insert(0, "".to_string());
+
+        let command_topic = format!(
+            "{}/{id}/set-music-mode",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-music-mode",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-music-mode", id = topic_safe_id(device));
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Music Mode".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:music".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: modes,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MusicModeSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let is_on = device
+            .device_state()
+            .map(|s| s.light_on.unwrap_or(s.on))
+            .unwrap_or(false);
+
+        // Activating a scene or changing the color replaces
+        // the active music mode, so this is cleared as a side
+        // effect of those changes
+        let active = device
+            .active_music_mode()
+            .filter(|_| is_on)
+            .filter(|mode| self.select.options.contains(mode))
+            .unwrap_or_default();
+
+        client.publish(&self.select.state_topic, active).await
+    }
+}
+
+/// A number entity for the sensitivity that is applied
+/// when activating a music mode
+pub struct MusicSensitivityNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MusicSensitivityNumber {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let range = device
+            .http_device_info
+            .as_ref()?
+            .music_sensitivity_range()?;
+
+        let command_topic = format!(
+            "{}/{id}/set-music-sensitivity",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/number/{id}/state/music-sensitivity",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-music-sensitivity", id = topic_safe_id(device));
+
+        Some(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Music Sensitivity".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:microphone".to_string()),
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(range.min as f32),
+                max: Some(range.max as f32),
+                step: range.precision.max(1) as f32,
+                unit_of_measurement: Some("%"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MusicSensitivityNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let sensitivity = device.music_sensitivity.unwrap_or(100);
+        self.number
+            .notify_state(client, &sensitivity.to_string())
+            .await
+    }
+}
+
+pub async fn mqtt_set_music_mode(
+    Payload(mode): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if mode.is_empty() {
+        // There is no way to leave music mode other than
+        // to select something else
+        return Ok(());
+    }
+
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_music_mode(&device, &mode)
+        .await
+        .context("mqtt_set_music_mode: state.device_set_music_mode")?;
+
+    Ok(())
+}
+
+pub async fn mqtt_set_music_sensitivity(
+    Payload(sensitivity): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let sensitivity: f64 = sensitivity.parse()?;
+    let sensitivity = sensitivity.clamp(0., 100.) as u8;
+
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_music_sensitivity(&device, sensitivity)
+        .await
+        .context("mqtt_set_music_sensitivity: state.device_set_music_sensitivity")?;
+    state.notify_of_state_change(&device.id).await?;
+
+    Ok(())
+}
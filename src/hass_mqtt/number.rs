@@ -1,7 +1,9 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::humidifier::DEVICE_CLASS_HUMIDITY;
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceParameters, IntegerRange};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdAndInst};
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -62,7 +64,8 @@ impl WorkModeNumber {
         range: Option<Range<i64>>,
     ) -> Self {
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
+            "{}/number/{id}/command/{mode}/{mode_num}",
+            topic_prefix(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name),
             mode_num = work_mode
@@ -71,12 +74,13 @@ impl WorkModeNumber {
                 .unwrap_or_else(|| "work-mode-was-not-int".to_string()),
         );
         let state_topic = format!(
-            "gv2mqtt/number/{id}/state/{mode}",
+            "{}/number/{id}/state/{mode}",
+            topic_prefix(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name)
         );
 
-        let availability_topic = availability_topic();
+        let availability = Availability::for_device(device);
         let unique_id = format!(
             "gv2mqtt-{id}-{mode}-number",
             id = topic_safe_id(device),
@@ -86,7 +90,7 @@ impl WorkModeNumber {
         Self {
             number: NumberConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability,
                     name: Some(label),
                     device_class: None,
                     origin: Origin::default(),
@@ -111,6 +115,11 @@ impl WorkModeNumber {
             work_mode,
         }
     }
+
+    pub fn with_icon(mut self, icon: &str) -> Self {
+        self.number.base.icon.replace(icon.to_string());
+        self
+    }
 }
 
 #[async_trait]
@@ -126,11 +135,9 @@ impl EntityInstance for WorkModeNumber {
             .as_ref()
             .ok_or_else(|| anyhow!("state_topic is None!?"))?;
 
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         if let Some(cap) = device.get_state_capability_by_instance("workMode") {
             if let Some(work_mode) = cap.state.pointer("/value/workMode") {
@@ -167,6 +174,95 @@ impl EntityInstance for WorkModeNumber {
     }
 }
 
+/// Exposes the target humidity of a humidifier as a slider.
+/// Commands are routed to the same handler as the humidifier
+/// entity uses for its target humidity.
+pub struct TargetHumidityNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl TargetHumidityNumber {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> anyhow::Result<Self> {
+        let (min, max, step) = match &instance.parameters {
+            Some(DeviceParameters::Integer {
+                range:
+                    IntegerRange {
+                        min,
+                        max,
+                        precision,
+                    },
+                ..
+            }) => (*min as f32, *max as f32, (*precision).max(1) as f32),
+            _ => anyhow::bail!("Unexpected parameter type for {instance:?}"),
+        };
+
+        let command_topic = format!(
+            "{}/humidifier/{id}/set-target",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/number/{id}/state/target-humidity",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-target-humidity", id = topic_safe_id(device));
+
+        Ok(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Target Humidity".to_string()),
+                    device_class: Some(DEVICE_CLASS_HUMIDITY),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(min),
+                max: Some(max),
+                step,
+                unit_of_measurement: Some("%"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for TargetHumidityNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let target = device
+            .get_state_capability_by_instance("humidity")
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|v| v.as_i64())
+            .or(device.target_humidity_percent.map(|n| n as i64));
+
+        match target {
+            Some(n) => self.number.notify_state(client, &n.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct IdAndModeName {
     id: String,
@@ -193,3 +289,210 @@ pub async fn mqtt_number_command(
 
     Ok(())
 }
+
+/// Returns true if the capability is a sleep/auto-off timer
+pub fn is_sleep_timer(cap: &DeviceCapability) -> bool {
+    match &cap.kind {
+        DeviceCapabilityKind::Timer => true,
+        DeviceCapabilityKind::Range => cap.instance.ends_with("Timer"),
+        _ => false,
+    }
+}
+
+fn sleep_timer_state_topic(device: &ServiceDevice, instance: &str) -> String {
+    format!(
+        "{}/number/{id}/state/{instance}",
+        topic_prefix(),
+        id = topic_safe_id(device),
+        instance = topic_safe_string(instance)
+    )
+}
+
+/// A number entity for the sleep timer of a device; the value is
+/// the number of minutes until the device turns off, or 0 if the
+/// timer is disabled.
+pub struct SleepTimerNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance: String,
+}
+
+impl SleepTimerNumber {
+    /// Returns None if the capability doesn't have the
+    /// integer range that we know how to work with
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        cap: &DeviceCapability,
+    ) -> Option<Self> {
+        let (max, step) = match &cap.parameters {
+            Some(DeviceParameters::Integer {
+                range: IntegerRange { max, precision, .. },
+                ..
+            }) => (*max as f32, (*precision).max(1) as f32),
+            _ => {
+                log::warn!("Unexpected parameter type for timer {cap:?} of {device}");
+                return None;
+            }
+        };
+
+        let command_topic = format!(
+            "{}/number/{id}/sleep-timer/{instance}",
+            topic_prefix(),
+            id = topic_safe_id(device),
+            instance = topic_safe_string(&cap.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{instance}",
+            id = topic_safe_id(device),
+            instance = topic_safe_string(&cap.instance)
+        );
+
+        Some(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Sleep Timer".to_string()),
+                    device_class: Some("duration"),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:timer-outline".to_string()),
+                },
+                command_topic,
+                state_topic: Some(sleep_timer_state_topic(device, &cap.instance)),
+                // 0 disables the timer
+                min: Some(0.),
+                max: Some(max),
+                step,
+                unit_of_measurement: Some("min"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance: cap.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for SleepTimerNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        // Report the remaining time, if the device provides it
+        let remaining = device
+            .get_state_capability_by_instance(&self.instance)
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|v| v.as_i64());
+
+        match remaining {
+            Some(n) => self.number.notify_state(client, &n.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn mqtt_set_sleep_timer(
+    Payload(minutes): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{instance} for {id}: {minutes}");
+    let minutes: f64 = minutes.parse()?;
+    let device = state.resolve_device_for_control(&id).await?;
+
+    // The topic carries the topic safe form of the instance name
+    let cap = device
+        .http_device_info
+        .as_ref()
+        .and_then(|info| {
+            info.capabilities
+                .iter()
+                .find(|cap| topic_safe_string(&cap.instance) == instance)
+        })
+        .ok_or_else(|| anyhow!("{device} has no {instance} capability"))?;
+
+    let minutes = minutes.max(0.) as u32;
+    // 0 disables the timer, even if the advertised range starts at 1
+    let minutes = match &cap.parameters {
+        Some(DeviceParameters::Integer { range, .. }) if minutes > 0 => {
+            minutes.clamp(range.min, range.max)
+        }
+        _ => minutes,
+    };
+
+    state.device_control(&device, cap, minutes).await?;
+
+    // Reflect the new value right away; the platform API
+    // may not report it until the next time we poll
+    if let Some(client) = state.get_hass_client().await {
+        client
+            .publish(
+                sleep_timer_state_topic(&device, &cap.instance),
+                minutes.to_string(),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+
+    #[test]
+    fn sleep_timer_capability() {
+        let timer: DeviceCapability = from_json(
+            r#"{
+                "type": "devices.capabilities.timer",
+                "instance": "autoOffTimer",
+                "parameters": {"dataType": "INTEGER", "range": {"min": 1, "max": 720, "precision": 1}}
+            }"#,
+        )
+        .unwrap();
+        assert!(is_sleep_timer(&timer));
+
+        let sleep_range: DeviceCapability = from_json(
+            r#"{
+                "type": "devices.capabilities.range",
+                "instance": "sleepTimer",
+                "parameters": {"dataType": "INTEGER", "range": {"min": 0, "max": 480, "precision": 30}}
+            }"#,
+        )
+        .unwrap();
+        assert!(is_sleep_timer(&sleep_range));
+
+        let brightness: DeviceCapability = from_json(
+            r#"{
+                "type": "devices.capabilities.range",
+                "instance": "brightness",
+                "parameters": {"dataType": "INTEGER", "range": {"min": 1, "max": 100, "precision": 1}}
+            }"#,
+        )
+        .unwrap();
+        assert!(!is_sleep_timer(&brightness));
+
+        // The instance name is in the same form in both topics
+        let device = ServiceDevice::new("H7131", "AA:BB:CC:DD:EE:FF:42:2A");
+        let state = std::sync::Arc::new(crate::service::state::State::new());
+        let number = SleepTimerNumber::new(&device, &state, &timer).unwrap();
+        assert_eq!(
+            number.number.command_topic,
+            "gv2mqtt/number/AABBCCDDEEFF422A/sleep-timer/autoofftimer"
+        );
+        assert_eq!(
+            number.number.state_topic.as_deref(),
+            Some("gv2mqtt/number/AABBCCDDEEFF422A/state/autoofftimer")
+        );
+    }
+}
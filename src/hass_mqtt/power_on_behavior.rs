@@ -0,0 +1,211 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::select::SelectConfig;
+use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde_json::Value as JsonValue;
+
+/// Capability instances used for what the device does
+/// when power is restored after an outage
+pub const POWER_ON_BEHAVIOR_INSTANCES: &[&str] =
+    &["powerOnBehavior", "powerOnState", "powerRestoreState"];
+
+/// Returns the power-on behavior capability of the device,
+/// provided that it has enumerated options that we can offer
+pub fn power_on_behavior_capability(device: &ServiceDevice) -> Option<&DeviceCapability> {
+    POWER_ON_BEHAVIOR_INSTANCES
+        .iter()
+        .find_map(|instance| device.get_capability_by_instance(instance))
+        .filter(|cap| !power_on_options(cap).is_empty())
+}
+
+/// Map the option name used by Govee to the label we show in hass
+fn option_label(name: &str) -> String {
+    let lower = name.to_ascii_lowercase();
+    if lower.contains("last") || lower.contains("memory") || lower.contains("restore") {
+        "Last State".to_string()
+    } else if lower == "on" {
+        "On".to_string()
+    } else if lower == "off" {
+        "Off".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Returns the (label, value) pairs for the options of the capability
+fn power_on_options(cap: &DeviceCapability) -> Vec<(String, JsonValue)> {
+    match &cap.parameters {
+        Some(DeviceParameters::Enum { options }) => options
+            .iter()
+            .map(|opt| (option_label(&opt.name), opt.value.clone()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// A select entity for the behavior of the device when
+/// power is restored: returning to its last state, or
+/// always turning on or off
+pub struct PowerOnBehaviorSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl PowerOnBehaviorSelect {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let cap = power_on_behavior_capability(device)?;
+        let options = power_on_options(cap)
+            .into_iter()
+            .map(|(label, _)| label)
+            .collect();
+
+        let command_topic = format!(
+            "{}/{id}/set-power-on-behavior",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-power-on-behavior",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-power-on-behavior", id = topic_safe_id(device));
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Power On Behavior".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:power-settings".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PowerOnBehaviorSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(cap) = power_on_behavior_capability(&device) else {
+            return Ok(());
+        };
+
+        // Prefer the state reported by the platform API, falling
+        // back to the setting that we most recently applied
+        let reported = device
+            .get_state_capability_by_instance(&cap.instance)
+            .and_then(|state| state.state.pointer("/value"))
+            .and_then(|value| {
+                power_on_options(cap)
+                    .into_iter()
+                    .find(|(_, v)| v == value)
+                    .map(|(label, _)| label)
+            });
+
+        match reported.or_else(|| device.power_on_behavior.clone()) {
+            Some(label) => client.publish(&self.select.state_topic, label).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn mqtt_set_power_on_behavior(
+    Payload(label): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let cap = power_on_behavior_capability(&device)
+        .ok_or_else(|| anyhow!("{device} doesn't support setting the power-on behavior"))?;
+    let value = power_on_options(cap)
+        .into_iter()
+        .find(|(l, _)| *l == label)
+        .map(|(_, value)| value)
+        .ok_or_else(|| anyhow!("{label} is not a valid power-on behavior for {device}"))?;
+
+    let client = state
+        .get_platform_client_for(&device)
+        .await
+        .ok_or_else(|| {
+            anyhow!("set power-on behavior for {device}: Platform API is not available")
+        })?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow!("HTTP device info is missing"))?;
+
+    client
+        .control_device(info, cap, value)
+        .await
+        .context("mqtt_set_power_on_behavior: control_device")?;
+
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .power_on_behavior
+        .replace(label);
+    state.notify_of_state_change(&device.id).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{DeviceCapabilityKind, EnumOption};
+
+    #[test]
+    fn options() {
+        let option = |name: &str, value: i64| EnumOption {
+            name: name.to_string(),
+            value: value.into(),
+            extras: Default::default(),
+        };
+        let cap = DeviceCapability {
+            kind: DeviceCapabilityKind::Mode,
+            instance: "powerOnBehavior".to_string(),
+            parameters: Some(DeviceParameters::Enum {
+                options: vec![option("lastState", 0), option("on", 1), option("off", 2)],
+            }),
+            alarm_type: None,
+            event_state: None,
+        };
+
+        assert_eq!(
+            power_on_options(&cap),
+            vec![
+                ("Last State".to_string(), JsonValue::from(0)),
+                ("On".to_string(), JsonValue::from(1)),
+                ("Off".to_string(), JsonValue::from(2)),
+            ]
+        );
+        assert_eq!(option_label("memory"), "Last State");
+        assert_eq!(option_label("Custom"), "Custom");
+    }
+}
@@ -0,0 +1,400 @@
+//! A harness for testing the entities that we build for a device,
+//! by replaying payloads that were recorded from the Govee APIs
+//! and capturing what would be published to hass.
+//!
+//! A fixture is a JSON file in test-data/replay with the following
+//! fields; only `device` is required:
+//!
+//! * `device` - the entry for the device from the Platform API
+//!   device list, which describes its capabilities
+//! * `state` - the `payload` of a Platform API device state response
+//! * `iot` - the `data` of a `devStatus` message received via the
+//!   IoT or LAN APIs
+//!
+//! To add a regression test for a SKU, record those payloads (the
+//! IoT messages are shown in the trace logs), redact the device id,
+//! save them as a fixture and write a test that asserts on the
+//! published topics.
+use crate::hass_mqtt::enumerator::enumerate_entities_for_device;
+use crate::hass_mqtt::instance::EntityList;
+use crate::lan_api::DeviceStatus as LanDeviceStatus;
+use crate::platform_api::{HttpDeviceInfo, HttpDeviceState};
+use crate::service::hass::{CapturedPublishes, HassClient};
+use crate::service::state::{State, StateHandle};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    device: HttpDeviceInfo,
+    state: Option<HttpDeviceState>,
+    iot: Option<LanDeviceStatus>,
+}
+
+pub struct Replay {
+    pub state: StateHandle,
+    pub device_id: String,
+    client: HassClient,
+    captured: CapturedPublishes,
+}
+
+impl Replay {
+    /// Load a fixture, such as `include_str!("../../test-data/replay/H7111.json")`
+    pub async fn new(fixture: &str) -> anyhow::Result<Self> {
+        let fixture: Fixture = serde_json::from_str(fixture)?;
+        let state = Arc::new(State::new());
+        state
+            .set_hass_disco_prefix("homeassistant".to_string())
+            .await;
+
+        let device_id = fixture.device.device.to_string();
+        {
+            let mut device = state.device_mut(&fixture.device.sku, &device_id).await;
+            device.set_http_device_info(fixture.device);
+            if let Some(http_state) = fixture.state {
+                device.set_http_device_state(http_state);
+            }
+            if let Some(status) = fixture.iot {
+                device.set_iot_device_status(status);
+            }
+        }
+
+        let (client, captured) = HassClient::capture();
+        Ok(Self {
+            state,
+            device_id,
+            client,
+            captured,
+        })
+    }
+
+    async fn entities(&self) -> anyhow::Result<EntityList> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+        let mut entities = EntityList::new();
+        enumerate_entities_for_device(&device, &self.state, &mut entities).await?;
+        Ok(entities)
+    }
+
+    /// Register the entities for the device and report their state,
+    /// as we would on startup, returning the (topic, payload) pairs
+    /// that were published
+    pub async fn publish(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let entities = self.entities().await?;
+        for e in entities.iter() {
+            e.publish_config(&self.state, &self.client).await?;
+        }
+        entities.notify_state(&self.client).await?;
+        Ok(std::mem::take(&mut *self.captured.lock().unwrap()))
+    }
+
+    /// Make the capturing client the hass client of the state, so
+    /// that command handlers report the state changes that they make
+    pub async fn connect_hass(&self) {
+        self.state.set_hass_client(self.client.clone()).await;
+    }
+
+    /// Returns what has been published since the last call
+    pub fn take_published(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut *self.captured.lock().unwrap())
+    }
+
+    /// Apply a further IoT status message to the device, and return
+    /// the state updates that result from it
+    pub async fn replay_iot(&self, status: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let status: LanDeviceStatus = serde_json::from_str(status)?;
+        let sku = self.state.device_by_id(&self.device_id).await.unwrap().sku;
+        self.state
+            .device_mut(&sku, &self.device_id)
+            .await
+            .set_iot_device_status(status);
+
+        self.captured.lock().unwrap().clear();
+        self.entities().await?.notify_state(&self.client).await?;
+        Ok(std::mem::take(&mut *self.captured.lock().unwrap()))
+    }
+}
+
+/// Returns the most recent payload published to topic
+pub fn payload_for<'a>(published: &'a [(String, String)], topic: &str) -> Option<&'a str> {
+    published
+        .iter()
+        .rev()
+        .find(|(t, _)| t == topic)
+        .map(|(_, payload)| payload.as_str())
+}
+
+/// Returns the discovery config published for the entity with unique_id
+pub fn config_for(published: &[(String, String)], unique_id: &str) -> Option<JsonValue> {
+    published
+        .iter()
+        .find(|(t, _)| t.ends_with(&format!("/{unique_id}/config")))
+        .and_then(|(_, payload)| serde_json::from_str(payload).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hass_mqtt::fan::{parse_on_off, OSCILLATION_INSTANCE};
+    use crate::lan_api::LanDevice;
+    use crate::service::quirks::{parse_custom_quirks, set_custom_quirks};
+
+    #[tokio::test]
+    async fn replay_h7111_fan() {
+        let replay = Replay::new(include_str!("../../test-data/replay/H7111.json"))
+            .await
+            .unwrap();
+        let published = replay.publish().await.unwrap();
+
+        let fan = config_for(&published, "gv2mqtt-AABBCCDDEEFF0011-fan").unwrap();
+        assert_eq!(fan["name"], JsonValue::Null);
+        assert_eq!(fan["device"]["name"], "Smart Floor Fan");
+        assert_eq!(
+            fan["preset_modes"],
+            serde_json::json!(["Auto", "Custom", "Nature", "Sleep", "Storm"])
+        );
+
+        let topic = |suffix: &str| format!("gv2mqtt/fan/AABBCCDDEEFF0011/{suffix}");
+        assert_eq!(payload_for(&published, &topic("state")), Some("ON"));
+        assert_eq!(payload_for(&published, &topic("notify-speed")), Some("50"));
+        assert_eq!(
+            payload_for(&published, &topic("notify-preset")),
+            Some("None")
+        );
+        assert_eq!(
+            payload_for(&published, &topic("notify-oscillation")),
+            Some("ON")
+        );
+    }
+
+    #[tokio::test]
+    async fn fan_auto_mode_is_not_a_temperature() {
+        // Give the Auto mode of the fan a parameter range, as some
+        // purifiers do for their automatic level
+        let mut fixture: JsonValue =
+            serde_json::from_str(include_str!("../../test-data/replay/H7111.json")).unwrap();
+        let auto = fixture
+            .pointer_mut("/device/capabilities/2/parameters/fields/1/options/2")
+            .unwrap();
+        assert_eq!(auto["name"], "Auto");
+        *auto = serde_json::json!({
+            "name": "Auto",
+            "options": [{"value": 1}, {"value": 2}, {"value": 3}],
+        });
+        let replay = Replay::new(&fixture.to_string()).await.unwrap();
+        let published = replay.publish().await.unwrap();
+
+        assert!(config_for(&published, "gv2mqtt-AABBCCDDEEFF0011-regulated-temperature").is_none());
+        let fan = config_for(&published, "gv2mqtt-AABBCCDDEEFF0011-fan").unwrap();
+        assert_eq!(
+            fan["preset_modes"],
+            serde_json::json!(["Auto", "Custom", "Nature", "Sleep", "Storm"])
+        );
+    }
+
+    #[tokio::test]
+    async fn battery_before_first_report() {
+        let mut fixture: JsonValue =
+            serde_json::from_str(include_str!("../../test-data/replay/H7111.json")).unwrap();
+        fixture["device"]["capabilities"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "type": "devices.capabilities.property",
+                "instance": "battery",
+            }));
+        let replay = Replay::new(&fixture.to_string()).await.unwrap();
+        let published = replay.publish().await.unwrap();
+
+        // The sensor is offered even though no value has been reported
+        let battery = config_for(&published, "sensor-AABBCCDDEEFF0011-battery").unwrap();
+        assert_eq!(battery["device_class"], "battery");
+        assert!(payload_for(&published, battery["state_topic"].as_str().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn fan_oscillation_round_trip() {
+        let replay = Replay::new(include_str!("../../test-data/replay/H7111.json"))
+            .await
+            .unwrap();
+        let topic = "gv2mqtt/fan/AABBCCDDEEFF0011/notify-oscillation";
+        assert_eq!(
+            payload_for(&replay.publish().await.unwrap(), topic),
+            Some("ON")
+        );
+        replay.connect_hass().await;
+
+        // Do what mqtt_fan_set_oscillation does once Govee has accepted
+        // the command; sending it would need the Platform API. The
+        // commanded state is reported until the next poll, even
+        // though the polled state says otherwise.
+        for command in ["OFF", "ON", "OFF"] {
+            let on = parse_on_off(command).unwrap();
+            replay
+                .state
+                .device_mut("H7111", &replay.device_id)
+                .await
+                .set_last_toggle_state(OSCILLATION_INSTANCE, on);
+            replay
+                .state
+                .notify_of_state_change(&replay.device_id)
+                .await
+                .unwrap();
+            assert_eq!(payload_for(&replay.take_published(), topic), Some(command));
+        }
+
+        // Until the next poll reports the actual state
+        let fixture: JsonValue =
+            serde_json::from_str(include_str!("../../test-data/replay/H7111.json")).unwrap();
+        replay
+            .state
+            .device_mut("H7111", &replay.device_id)
+            .await
+            .set_http_device_state(serde_json::from_value(fixture["state"].clone()).unwrap());
+        replay
+            .state
+            .notify_of_state_change(&replay.device_id)
+            .await
+            .unwrap();
+        assert_eq!(payload_for(&replay.take_published(), topic), Some("ON"));
+    }
+
+    #[tokio::test]
+    async fn replay_h6601_light() {
+        let replay = Replay::new(include_str!("../../test-data/replay/H6601.json"))
+            .await
+            .unwrap();
+        let published = replay.publish().await.unwrap();
+
+        let light = config_for(&published, "gv2mqtt-9DFA85EBD3008BFF").unwrap();
+        assert_eq!(light["device"]["model"], "H6601");
+
+        let state_topic = light["state_topic"].as_str().unwrap();
+        let light_state: JsonValue =
+            serde_json::from_str(payload_for(&published, state_topic).unwrap()).unwrap();
+        assert_eq!(light_state["state"], "ON");
+        assert_eq!(
+            light_state["color"],
+            serde_json::json!({"r":255,"g":128,"b":0})
+        );
+
+        let updates = replay
+            .replay_iot(r#"{"onOff":0,"brightness":42,"color":{"r":255,"g":128,"b":0},"colorTemInKelvin":0}"#)
+            .await
+            .unwrap();
+        let light_state: JsonValue =
+            serde_json::from_str(payload_for(&updates, state_topic).unwrap()).unwrap();
+        assert_eq!(light_state["state"], "OFF");
+    }
+
+    #[tokio::test]
+    async fn replay_h7021_white_light() {
+        // The built-in quirk assumes RGB; a custom quirk is needed
+        // for the warm white variant
+        let (quirks, errors) = parse_custom_quirks(r#"[{"sku": "H7021", "supports_rgb": false}]"#);
+        assert!(errors.is_empty());
+        set_custom_quirks(quirks);
+
+        let replay = Replay::new(include_str!("../../test-data/replay/H7021.json"))
+            .await
+            .unwrap();
+        // The LAN API also knows about it, which must not cause
+        // us to assume that it is an RGB light
+        replay
+            .state
+            .device_mut("H7021", &replay.device_id)
+            .await
+            .set_lan_device(LanDevice {
+                ip: "10.0.0.2".parse().unwrap(),
+                device: replay.device_id.clone(),
+                sku: "H7021".to_string(),
+                ble_version_hard: String::new(),
+                ble_version_soft: String::new(),
+                wifi_version_hard: String::new(),
+                wifi_version_soft: String::new(),
+            });
+        let published = replay.publish().await.unwrap();
+
+        let light = config_for(&published, "gv2mqtt-AABBCCDDEEFF7021").unwrap();
+        assert_eq!(
+            light["supported_color_modes"],
+            serde_json::json!(["brightness"])
+        );
+        assert_eq!(light["brightness"], true);
+        assert!(light.get("min_mireds").is_none());
+
+        let state_topic = light["state_topic"].as_str().unwrap();
+        let light_state: JsonValue =
+            serde_json::from_str(payload_for(&published, state_topic).unwrap()).unwrap();
+        assert_eq!(light_state["color_mode"], "brightness");
+        assert_eq!(light_state["brightness"], 60);
+        assert!(light_state.get("color").is_none());
+    }
+
+    #[tokio::test]
+    async fn replay_h7131_heater() {
+        let replay = Replay::new(include_str!("../../test-data/replay/H7131.json"))
+            .await
+            .unwrap();
+        let published = replay.publish().await.unwrap();
+
+        let climate = config_for(&published, "gv2mqtt-AABBCCDDEEFF0011-climate").unwrap();
+        assert_eq!(
+            climate["modes"],
+            serde_json::json!(["off", "auto", "fan_only", "heat"])
+        );
+        assert_eq!(
+            climate["preset_modes"],
+            serde_json::json!(["Low", "Medium", "High"])
+        );
+        assert_eq!(climate["min_temp"], 5.0);
+        assert_eq!(climate["max_temp"], 30.0);
+        assert_eq!(climate["temperature_unit"], "C");
+        assert_eq!(
+            climate["power_command_topic"],
+            "gv2mqtt/switch/AABBCCDDEEFF0011/command/powerSwitch"
+        );
+
+        // The target temperature is part of the climate entity
+        assert!(config_for(&published, "AABBCCDDEEFF0011-targetTemperature").is_none());
+
+        // The Auto mode regulates to the target temperature,
+        // which can be set without leaving the climate entity
+        let regulated =
+            config_for(&published, "gv2mqtt-AABBCCDDEEFF0011-regulated-temperature").unwrap();
+        assert_eq!(
+            regulated["command_topic"],
+            "gv2mqtt/AABBCCDDEEFF0011/set-regulated-temperature/°C"
+        );
+        assert_eq!(regulated["min"], 5.0);
+        assert_eq!(regulated["max"], 30.0);
+        assert_eq!(
+            payload_for(
+                &published,
+                "gv2mqtt/AABBCCDDEEFF0011/notify-regulated-temperature"
+            ),
+            Some("22.00")
+        );
+
+        let topic = |suffix: &str| format!("gv2mqtt/climate/AABBCCDDEEFF0011/{suffix}");
+        assert_eq!(payload_for(&published, &topic("notify-mode")), Some("heat"));
+        assert_eq!(
+            payload_for(&published, &topic("notify-preset")),
+            Some("Medium")
+        );
+        assert_eq!(
+            payload_for(&published, &topic("notify-target")),
+            Some("22.00")
+        );
+        assert_eq!(
+            payload_for(&published, &topic("notify-current")),
+            Some("20.00")
+        );
+        assert_eq!(payload_for(&published, &topic("notify-swing")), Some("off"));
+    }
+}
@@ -1,8 +1,9 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::scene_categories::find_scene_option;
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use axum::async_trait;
@@ -34,15 +35,23 @@ pub struct WorkModeSelect {
 
 impl WorkModeSelect {
     pub fn new(device: &ServiceDevice, work_modes: &ParsedWorkMode, state: &StateHandle) -> Self {
-        let command_topic = format!("gv2mqtt/{id}/set-work-mode", id = topic_safe_id(device),);
-        let state_topic = format!("gv2mqtt/{id}/notify-work-mode", id = topic_safe_id(device));
-        let availability_topic = availability_topic();
+        let command_topic = format!(
+            "{}/{id}/set-work-mode",
+            topic_prefix(),
+            id = topic_safe_id(device),
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-work-mode",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let availability = Availability::for_device(device);
         let unique_id = format!("gv2mqtt-{id}-workMode", id = topic_safe_id(device),);
 
         Self {
             select: SelectConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability,
                     name: Some("Mode".to_string()),
                     device_class: None,
                     origin: Origin::default(),
@@ -59,6 +68,16 @@ impl WorkModeSelect {
             state: state.clone(),
         }
     }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.select.base.name.replace(name.to_string());
+        self
+    }
+
+    pub fn with_icon(mut self, icon: &str) -> Self {
+        self.select.base.icon.replace(icon.to_string());
+        self
+    }
 }
 
 #[async_trait]
@@ -68,11 +87,9 @@ impl EntityInstance for WorkModeSelect {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         if let Some(mode_value) = device.humidifier_work_mode {
             if let Ok(work_mode) = ParsedWorkMode::with_device(&device) {
@@ -113,15 +130,23 @@ impl SceneModeSelect {
             return Ok(None);
         }
 
-        let command_topic = format!("gv2mqtt/{id}/set-mode-scene", id = topic_safe_id(device));
-        let state_topic = format!("gv2mqtt/{id}/notify-mode-scene", id = topic_safe_id(device));
-        let availability_topic = availability_topic();
+        let command_topic = format!(
+            "{}/{id}/set-mode-scene",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-mode-scene",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let availability = Availability::for_device(device);
         let unique_id = format!("gv2mqtt-{id}-mode-scene", id = topic_safe_id(device));
 
         Ok(Some(Self {
             select: SelectConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability,
                     name: Some("Mode/Scene".to_string()),
                     device_class: None,
                     origin: Origin::default(),
@@ -147,19 +172,16 @@ impl EntityInstance for SceneModeSelect {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         if let Some(device_state) = device.device_state() {
-            client
-                .publish(
-                    &self.select.state_topic,
-                    device_state.scene.as_deref().unwrap_or(""),
-                )
-                .await?;
+            let scene = device_state.scene.as_deref().unwrap_or("");
+            let option = find_scene_option(&self.select.options, scene)
+                .map(|s| s.as_str())
+                .unwrap_or(scene);
+            client.publish(&self.select.state_topic, option).await?;
         }
 
         Ok(())
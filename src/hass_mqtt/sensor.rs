@@ -1,17 +1,24 @@
 use crate::commands::serve::POLL_INTERVAL;
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::humidifier::DEVICE_CLASS_HUMIDITY;
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::DeviceCapability;
-use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
-use crate::service::quirks::HumidityUnits;
+use crate::service::device::{Device as ServiceDevice, UsageStatistic};
+use crate::service::hass::{topic_prefix, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::metrics::METRICS;
+use crate::service::quirks::{HumidityUnits, SensorCalibration, SensorFilter, SensorFilterState};
 use crate::service::state::StateHandle;
-use crate::temperature::{TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE};
+use crate::temperature::{
+    TemperatureScale, TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE,
+};
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
 use serde::Serialize;
 use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SensorConfig {
@@ -25,6 +32,8 @@ pub struct SensorConfig {
     pub unit_of_measurement: Option<&'static str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub json_attributes_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_display_precision: Option<u8>,
 }
 
 #[allow(unused)]
@@ -73,7 +82,7 @@ impl GlobalFixedDiagnostic {
         Self {
             sensor: SensorConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::bridge(),
                     name: Some(name),
                     entity_category: Some("diagnostic".to_string()),
                     origin: Origin::default(),
@@ -82,16 +91,185 @@ impl GlobalFixedDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
                 state_class: None,
                 unit_of_measurement: None,
                 json_attributes_topic: None,
+                suggested_display_precision: None,
             },
             value: value.into(),
         }
     }
 }
 
+/// The number of Platform API requests that are waiting to be
+/// sent, summed across all accounts
+pub struct PlatformQueueDepthSensor {
+    sensor: SensorConfig,
+    state: StateHandle,
+}
+
+impl PlatformQueueDepthSensor {
+    pub fn new(state: &StateHandle) -> Self {
+        let unique_id = "global-platform-api-queue-depth";
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::bridge(),
+                    name: Some("Platform API Queue Depth".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.to_string(),
+                    device_class: None,
+                    icon: Some("mdi:tray-full".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: None,
+                json_attributes_topic: None,
+                suggested_display_precision: None,
+            },
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PlatformQueueDepthSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let depth = self.state.platform_queue_depth().await;
+        self.sensor.notify_state(client, &depth.to_string()).await
+    }
+}
+
+/// Summarizes the health of the bridge, so that users can alert
+/// when it loses connectivity to the Govee cloud. The details are
+/// reported via the attributes of the sensor.
+pub struct BridgeStatusSensor {
+    sensor: SensorConfig,
+    state: StateHandle,
+}
+
+impl BridgeStatusSensor {
+    pub fn new(state: &StateHandle) -> Self {
+        let unique_id = "global-bridge-status";
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::bridge(),
+                    name: Some("Bridge Status".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.to_string(),
+                    device_class: None,
+                    icon: Some("mdi:cloud-check".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    topic_prefix()
+                )),
+                suggested_display_precision: None,
+            },
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for BridgeStatusSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let mut online = 0;
+        let mut offline = 0;
+        for d in self.state.devices().await {
+            if !self.state.is_device_included(&d).await {
+                continue;
+            }
+            match d.published_availability() {
+                Some(true) => online += 1,
+                Some(false) => offline += 1,
+                None => {}
+            }
+        }
+
+        // We only expect an IoT connection when we have an account
+        let iot_connected = if self.state.get_undoc_client().await.is_some() {
+            Some(self.state.get_iot_client().await.is_some())
+        } else {
+            None
+        };
+        let uses_cloud = iot_connected.is_some() || self.state.has_platform_client().await;
+
+        let (summary, attributes) = describe_bridge_status(
+            online,
+            offline,
+            iot_connected,
+            uses_cloud,
+            METRICS.last_cloud_success(),
+            METRICS.last_cloud_failure(),
+        );
+
+        self.sensor.notify_state(client, summary).await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, attributes).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Produces the state and attributes for BridgeStatusSensor.
+/// iot_connected is None when no IoT connection is expected.
+fn describe_bridge_status(
+    online: usize,
+    offline: usize,
+    iot_connected: Option<bool>,
+    uses_cloud: bool,
+    last_cloud_success: Option<DateTime<Utc>>,
+    last_cloud_failure: Option<DateTime<Utc>>,
+) -> (&'static str, serde_json::Value) {
+    // Only an actual failure makes the cloud unreachable; LAN-only
+    // devices may never make a request to it at all
+    let cloud_reachable = match (last_cloud_failure, last_cloud_success) {
+        (Some(failure), Some(success)) => success > failure,
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+
+    let summary = if !uses_cloud {
+        "local only"
+    } else if !cloud_reachable {
+        "cloud unreachable"
+    } else if iot_connected == Some(false) {
+        "iot disconnected"
+    } else {
+        "connected"
+    };
+
+    (
+        summary,
+        json!({
+            "online_devices": online,
+            "offline_devices": offline,
+            "iot_connected": iot_connected,
+            "last_api_success": last_cloud_success.map(|t| t.to_rfc3339()),
+        }),
+    )
+}
+
 #[derive(Clone)]
 pub struct CapabilitySensor {
     sensor: SensorConfig,
@@ -100,6 +278,12 @@ pub struct CapabilitySensor {
     instance_name: String,
 }
 
+/// The filter history for each (device id, instance) pair.
+/// Entities are re-created each time that we report state,
+/// so this has to be kept separately from them.
+static FILTER_STATES: Lazy<Mutex<HashMap<(String, String), SensorFilterState>>> =
+    Lazy::new(Default::default);
+
 impl CapabilitySensor {
     pub async fn new(
         device: &ServiceDevice,
@@ -115,18 +299,32 @@ impl CapabilitySensor {
         let unit_of_measurement = match instance.instance.as_str() {
             "sensorTemperature" => Some(state.get_temperature_scale().await.unit_of_measurement()),
             "sensorHumidity" => Some("%"),
+            i if PM25_INSTANCES.contains(&i) => Some("µg/m³"),
             _ => None,
         };
 
         let device_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(DEVICE_CLASS_TEMPERATURE),
             "sensorHumidity" => Some(DEVICE_CLASS_HUMIDITY),
+            i if PM25_INSTANCES.contains(&i) => Some("pm25"),
+            i if AQI_INSTANCES.contains(&i) => Some("aqi"),
             _ => None,
         };
 
+        // Some sensors report tenths of a degree, but converting
+        // between scales yields more digits than are meaningful
+        let suggested_display_precision = match instance.instance.as_str() {
+            "sensorTemperature" | "sensorHumidity" => Some(1),
+            _ => None,
+        };
+
+        let is_air_quality = PM25_INSTANCES.contains(&instance.instance.as_str())
+            || AQI_INSTANCES.contains(&instance.instance.as_str());
+
         let state_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(StateClass::Measurement),
             "sensorHumidity" => Some(StateClass::Measurement),
+            _ if is_air_quality => Some(StateClass::Measurement),
             _ => None,
         };
 
@@ -134,31 +332,62 @@ impl CapabilitySensor {
             "sensorTemperature" => "Temperature".to_string(),
             "sensorHumidity" => "Humidity".to_string(),
             "online" => "Connected to Govee Cloud".to_string(),
+            i if PM25_INSTANCES.contains(&i) => "PM2.5".to_string(),
+            i if AQI_INSTANCES.contains(&i) => "Air Quality Index".to_string(),
             _ => instance.instance.to_string(),
         };
 
+        // Air quality is the primary function of the devices
+        // that report it, so don't hide it away as a diagnostic
+        let entity_category = if is_air_quality {
+            None
+        } else {
+            Some("diagnostic".to_string())
+        };
+
         Ok(Self {
             sensor: SensorConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::for_device(device),
                     name: Some(name),
-                    entity_category: Some("diagnostic".to_string()),
+                    entity_category,
                     origin: Origin::default(),
                     device: Device::for_device(device),
                     unique_id: unique_id.clone(),
                     device_class,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
                 state_class: state_class,
                 unit_of_measurement,
                 json_attributes_topic: None,
+                suggested_display_precision,
             },
             device_id: device.id.to_string(),
             state: state.clone(),
             instance_name: instance.instance.to_string(),
         })
     }
+
+    /// Apply the filter from the quirk for the device, if any,
+    /// to a reading that was polled at read_at
+    fn filter_reading(
+        &self,
+        filter: Option<SensorFilter>,
+        value: f64,
+        read_at: Option<DateTime<Utc>>,
+    ) -> f64 {
+        match filter {
+            Some(filter) => {
+                let mut states = FILTER_STATES.lock().unwrap();
+                let state = states
+                    .entry((self.device_id.clone(), self.instance_name.clone()))
+                    .or_default();
+                filter.apply(state, value, read_at.unwrap_or_else(Utc::now))
+            }
+            None => value,
+        }
+    }
 }
 
 #[async_trait]
@@ -168,11 +397,9 @@ impl EntityInstance for CapabilitySensor {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         let quirk = device.resolve_quirk();
 
@@ -180,26 +407,28 @@ impl EntityInstance for CapabilitySensor {
             let value = match self.instance_name.as_str() {
                 "sensorTemperature" => {
                     let units = quirk
+                        .as_ref()
                         .and_then(|q| q.platform_temperature_sensor_units)
                         .unwrap_or(TemperatureUnits::Celsius);
 
-                    match cap
-                        .state
-                        .pointer("/value")
-                        .and_then(|v| v.as_f64())
-                        .map(|v| TemperatureValue::new(v, units))
-                    {
+                    let scale = self.state.get_temperature_scale().await;
+                    match cap.state.pointer("/value").and_then(|v| v.as_f64()) {
                         Some(v) => {
-                            let value = v
-                                .as_unit(self.state.get_temperature_scale().await.into())
-                                .value();
-                            format!("{value:.2}")
+                            let v =
+                                temperature_reading(v, units, scale, &device.sensor_calibration());
+                            let v = self.filter_reading(
+                                quirk.as_ref().and_then(|q| q.temperature_filter),
+                                v,
+                                device.last_http_device_state_update,
+                            );
+                            format!("{v:.2}")
                         }
                         None => "".to_string(),
                     }
                 }
                 "sensorHumidity" => {
                     let units = quirk
+                        .as_ref()
                         .and_then(|q| q.platform_humidity_sensor_units)
                         .unwrap_or(HumidityUnits::RelativePercent);
                     match cap
@@ -207,8 +436,22 @@ impl EntityInstance for CapabilitySensor {
                         .pointer("/value/currentHumidity")
                         .and_then(|v| v.as_f64())
                         .map(|v| units.from_reading_to_relative_percent(v))
+                        .map(|v| device.sensor_calibration().apply_to_humidity(v))
                     {
-                        Some(v) => format!("{v:.2}"),
+                        Some(v) => {
+                            let v = self.filter_reading(
+                                quirk.as_ref().and_then(|q| q.humidity_filter),
+                                v,
+                                device.last_http_device_state_update,
+                            );
+                            format!("{v:.2}")
+                        }
+                        None => "".to_string(),
+                    }
+                }
+                i if PM25_INSTANCES.contains(&i) || AQI_INSTANCES.contains(&i) => {
+                    match air_quality_reading(&cap.state) {
+                        Some(v) => format!("{v:.0}"),
                         None => "".to_string(),
                     }
                 }
@@ -225,6 +468,163 @@ impl EntityInstance for CapabilitySensor {
     }
 }
 
+/// A diagnostic sensor that reports the raw workMode and modeValue
+/// of a device, to help with troubleshooting quirks and new devices.
+/// Only exposed when debug sensors are enabled.
+pub struct WorkModeDiagnostic {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl WorkModeDiagnostic {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-raw-work-mode", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Raw Work Mode".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:bug".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    topic_prefix()
+                )),
+                suggested_display_precision: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for WorkModeDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        // Prefer the mode reported via the IoT API, as that is
+        // what the other work mode entities do
+        let (work_mode, mode_value) = match device.humidifier_work_mode {
+            Some(mode) => (
+                json!(mode),
+                device.humidifier_param_by_mode.get(&mode).map(|v| json!(v)),
+            ),
+            None => {
+                let Some(cap) = device.get_state_capability_by_instance("workMode") else {
+                    return Ok(());
+                };
+                let Some(work_mode) = cap.state.pointer("/value/workMode") else {
+                    return Ok(());
+                };
+                (
+                    work_mode.clone(),
+                    cap.state.pointer("/value/modeValue").cloned(),
+                )
+            }
+        };
+
+        let work_modes = ParsedWorkMode::with_device(&device).ok();
+        let (summary, attributes) =
+            describe_raw_work_mode(work_modes.as_ref(), &work_mode, mode_value.as_ref());
+
+        self.sensor.notify_state(client, &summary).await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, attributes).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Produces the state and attributes for WorkModeDiagnostic,
+/// mapping the raw values to names where we know them
+fn describe_raw_work_mode(
+    work_modes: Option<&ParsedWorkMode>,
+    work_mode: &serde_json::Value,
+    mode_value: Option<&serde_json::Value>,
+) -> (String, serde_json::Value) {
+    let summary = match mode_value {
+        Some(mode_value) => format!("{work_mode}/{mode_value}"),
+        None => work_mode.to_string(),
+    };
+
+    let mode = work_modes.and_then(|modes| modes.mode_for_value(work_mode));
+    let value_label = mode.zip(mode_value).and_then(|(mode, mode_value)| {
+        mode.values
+            .iter()
+            .find(|v| v.value == *mode_value)
+            .map(|v| v.computed_label.to_string())
+    });
+
+    (
+        summary,
+        json!({
+            "work_mode": work_mode,
+            "mode_value": mode_value,
+            "mode_name": mode.map(|m| m.name.to_string()),
+            "mode_label": mode.map(|m| m.label().to_string()),
+            "value_label": value_label,
+        }),
+    )
+}
+
+/// Capability instances that report PM2.5 concentration in µg/m³
+const PM25_INSTANCES: &[&str] = &["airQuality", "pm25"];
+/// Capability instances that report an air quality index
+const AQI_INSTANCES: &[&str] = &["aqi", "airQualityIndex"];
+
+/// Extract an air quality reading from the state of a property
+/// capability. The value is usually a plain number, but may be
+/// wrapped in an object, as it is for sensorHumidity.
+fn air_quality_reading(state: &serde_json::Value) -> Option<f64> {
+    let value = state.pointer("/value")?;
+    value
+        .as_f64()
+        .or_else(|| value.as_object()?.values().find_map(|v| v.as_f64()))
+}
+
+/// Convert a temperature reading from the units used by the device
+/// to the scale that the user prefers to see in hass, and then
+/// apply the calibration offset
+pub fn temperature_reading(
+    value: f64,
+    units: TemperatureUnits,
+    scale: TemperatureScale,
+    calibration: &SensorCalibration,
+) -> f64 {
+    let value = TemperatureValue::new(value, units)
+        .as_unit(scale.into())
+        .value();
+    calibration.apply_to_temperature(value)
+}
+
+/// As temperature_reading, formatted for publishing to hass
+pub fn format_temperature_reading(
+    value: f64,
+    units: TemperatureUnits,
+    scale: TemperatureScale,
+    calibration: &SensorCalibration,
+) -> String {
+    let value = temperature_reading(value, units, scale, calibration);
+    format!("{value:.2}")
+}
+
 pub struct DeviceStatusDiagnostic {
     sensor: SensorConfig,
     device_id: String,
@@ -238,7 +638,7 @@ impl DeviceStatusDiagnostic {
         Self {
             sensor: SensorConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::bridge(),
                     name: Some("Status".to_string()),
                     entity_category: Some("diagnostic".to_string()),
                     origin: Origin::default(),
@@ -247,10 +647,14 @@ impl DeviceStatusDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
                 state_class: None,
-                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    topic_prefix()
+                )),
                 unit_of_measurement: None,
+                suggested_display_precision: None,
             },
             device_id: device.id.to_string(),
             state: state.clone(),
@@ -265,11 +669,9 @@ impl EntityInstance for DeviceStatusDiagnostic {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         let iot_state = device.compute_iot_device_state();
         let lan_state = device.compute_lan_device_state();
@@ -280,7 +682,8 @@ impl EntityInstance for DeviceStatusDiagnostic {
 
         let now = Utc::now();
 
-        let threshold = *POLL_INTERVAL + chrono::Duration::seconds(30);
+        let threshold =
+            device.preferred_poll_interval().max(*POLL_INTERVAL) + chrono::Duration::seconds(30);
 
         let summary = match &device_state {
             Some(state) => {
@@ -309,3 +712,523 @@ impl EntityInstance for DeviceStatusDiagnostic {
         Ok(())
     }
 }
+
+pub struct BatterySensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl BatterySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-battery", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Battery".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("battery"),
+                    icon: None,
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("%"),
+                json_attributes_topic: None,
+                suggested_display_precision: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for BatterySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.battery_percent() {
+            Some(percent) => self.sensor.notify_state(client, &percent.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Remaining filter life of an air purifier
+pub struct FilterLifeSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl FilterLifeSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-filter-life", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Filter Life".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:air-filter".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("%"),
+                json_attributes_topic: None,
+                suggested_display_precision: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FilterLifeSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.filter_life_percent() {
+            Some(percent) => self.sensor.notify_state(client, &percent.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// A cumulative usage counter, such as the total runtime, which
+/// hass can use to graph usage over time
+pub struct UsageStatisticSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    stat: UsageStatistic,
+}
+
+impl UsageStatisticSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, stat: UsageStatistic) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-{stat}",
+            id = topic_safe_id(device),
+            stat = stat.id
+        );
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(stat.name.to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: if stat.is_runtime {
+                        Some("duration")
+                    } else {
+                        None
+                    },
+                    icon: Some(stat.icon.to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: Some(StateClass::TotalIncreasing),
+                unit_of_measurement: if stat.is_runtime { Some("h") } else { None },
+                json_attributes_topic: None,
+                suggested_display_precision: if stat.is_runtime { Some(1) } else { None },
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            stat,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for UsageStatisticSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.usage_statistic(&self.stat) {
+            Some(value) => self.sensor.notify_state(client, &value.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// The time at which state was last received for the device.
+/// This remains available while the device is unreachable, so
+/// that automations can tell how long it has been missing.
+pub struct LastSeenSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl LastSeenSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-last-seen", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::bridge(),
+                    name: Some("Last Seen".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("timestamp"),
+                    icon: None,
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: None,
+                suggested_display_precision: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LastSeenSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.last_seen() {
+            Some(when) => self.sensor.notify_state(client, &when.to_rfc3339()).await,
+            // hass treats "None" as unknown, which is accurate
+            // until we've heard from the device
+            None => self.sensor.notify_state(client, "None").await,
+        }
+    }
+}
+
+pub struct WifiSignalSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl WifiSignalSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-wifi-rssi", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("WiFi Signal".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("signal_strength"),
+                    icon: None,
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("dBm"),
+                json_attributes_topic: None,
+                suggested_display_precision: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for WifiSignalSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.wifi_rssi() {
+            Some(rssi) => self.sensor.notify_state(client, &rssi.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct FirmwareVersionSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl FirmwareVersionSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-firmware-version", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::bridge(),
+                    name: Some("Firmware Version".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:chip".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    topic_prefix()
+                )),
+                suggested_display_precision: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FirmwareVersionSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(version) = device.firmware_version() else {
+            return Ok(());
+        };
+
+        if let Some(summary) = version.summary() {
+            self.sensor.notify_state(client, summary).await?;
+        }
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, &version).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The address at which we reach a device via the LAN API,
+/// to help with matching devices to DHCP leases
+pub struct IpAddressSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl IpAddressSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-ip-address", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::bridge(),
+                    name: Some("IP Address".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:ip-network".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", topic_prefix()),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: None,
+                suggested_display_precision: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for IpAddressSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.ip_addr() {
+            Some(ip) => self.sensor.notify_state(client, &ip.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bridge_status() {
+        let now = Utc::now();
+        let last = now - chrono::Duration::minutes(1);
+        let recent = Some(last);
+        let earlier = Some(now - chrono::Duration::hours(2));
+
+        let (summary, attributes) = describe_bridge_status(3, 1, Some(true), true, recent, None);
+        assert_eq!(summary, "connected");
+        assert_eq!(attributes["online_devices"], 3);
+        assert_eq!(attributes["offline_devices"], 1);
+        assert_eq!(attributes["iot_connected"], true);
+        assert_eq!(attributes["last_api_success"], last.to_rfc3339());
+
+        assert_eq!(
+            describe_bridge_status(3, 0, Some(false), true, recent, None).0,
+            "iot disconnected"
+        );
+        assert_eq!(
+            describe_bridge_status(3, 0, Some(true), true, earlier, recent).0,
+            "cloud unreachable"
+        );
+        assert_eq!(
+            describe_bridge_status(0, 0, Some(true), true, None, recent).0,
+            "cloud unreachable"
+        );
+        // Recovered since the failure
+        assert_eq!(
+            describe_bridge_status(3, 0, Some(true), true, recent, earlier).0,
+            "connected"
+        );
+        // Devices that are only used via the LAN API never make a
+        // cloud request, which isn't a reason to raise the alarm
+        assert_eq!(
+            describe_bridge_status(2, 0, Some(true), true, None, None).0,
+            "connected"
+        );
+        assert_eq!(
+            describe_bridge_status(2, 0, None, false, None, None).0,
+            "local only"
+        );
+    }
+
+    #[test]
+    fn raw_work_mode() {
+        let mut work_modes = ParsedWorkMode::default();
+        work_modes.add("gearMode".to_string(), json!(1));
+        work_modes.add("Auto".to_string(), json!(3));
+        work_modes.adjust_for_device("H7131");
+
+        let (summary, attributes) =
+            describe_raw_work_mode(Some(&work_modes), &json!(1), Some(&json!(2)));
+        k9::assert_equal!(summary, "1/2");
+        k9::assert_equal!(attributes["mode_name"], json!("gearMode"));
+        k9::assert_equal!(attributes["mode_label"], json!("Heat"));
+
+        // Values that we can't map are still reported
+        let (summary, attributes) = describe_raw_work_mode(Some(&work_modes), &json!(9), None);
+        k9::assert_equal!(summary, "9");
+        k9::assert_equal!(attributes["mode_name"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn air_quality_readings() {
+        assert_eq!(air_quality_reading(&json!({"value": 12})), Some(12.));
+        assert_eq!(
+            air_quality_reading(&json!({"value": {"currentPm25": 35}})),
+            Some(35.)
+        );
+        assert_eq!(air_quality_reading(&json!({"value": ""})), None);
+        assert_eq!(air_quality_reading(&json!({})), None);
+    }
+
+    #[test]
+    fn temperature_readings() {
+        let uncalibrated = SensorCalibration::default();
+        // The H5179 reports tenths of a degree farenheit
+        assert_eq!(
+            format_temperature_reading(
+                72.5,
+                TemperatureUnits::Farenheit,
+                TemperatureScale::Celsius,
+                &uncalibrated
+            ),
+            "22.50"
+        );
+        assert_eq!(
+            format_temperature_reading(
+                72.5,
+                TemperatureUnits::Farenheit,
+                TemperatureScale::Farenheit,
+                &uncalibrated
+            ),
+            "72.50"
+        );
+        assert_eq!(
+            format_temperature_reading(
+                2250.,
+                TemperatureUnits::CelsiusTimes100,
+                TemperatureScale::Farenheit,
+                &uncalibrated
+            ),
+            "72.50"
+        );
+
+        // The offset applies in the configured scale
+        let calibration = SensorCalibration {
+            temperature_offset: -1.5,
+            humidity_offset: 0.,
+        };
+        assert_eq!(
+            format_temperature_reading(
+                72.5,
+                TemperatureUnits::Farenheit,
+                TemperatureScale::Celsius,
+                &calibration
+            ),
+            "21.00"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::select::SelectConfig;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+
+/// A select entity listing the snapshots that were saved
+/// for the device in the Govee app, so that they can be recalled
+pub struct SnapshotSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl SnapshotSelect {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let mut snapshots = state.device_list_snapshots(device).await;
+        if snapshots.is_empty() {
+            return None;
+        }
+        // The empty option represents "not recalled a snapshot",
+        // as a snapshot no longer applies once something else changes
+        snapshots.insert(0, "".to_string());
+
+        let command_topic = format!(
+            "{}/{id}/set-snapshot",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-snapshot",
+            topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-snapshot", id = topic_safe_id(device));
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Snapshot".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:camera-burst".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: snapshots,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for SnapshotSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
+
+        // Govee doesn't report the snapshot, so we can only show
+        // the one that we most recently recalled
+        let active = device
+            .last_snapshot()
+            .filter(|_| device.device_state().map(|s| s.on).unwrap_or(true))
+            .and_then(|snapshot| {
+                self.select
+                    .options
+                    .iter()
+                    .find(|opt| opt.eq_ignore_ascii_case(snapshot))
+                    .cloned()
+            })
+            .unwrap_or_default();
+
+        client.publish(&self.select.state_topic, active).await
+    }
+}
+
+pub async fn mqtt_set_snapshot(
+    Payload(snapshot): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if snapshot.is_empty() {
+        // There is nothing to recall
+        return Ok(());
+    }
+
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_snapshot(&device, &snapshot)
+        .await
+        .context("mqtt_set_snapshot: state.device_set_snapshot")?;
+
+    Ok(())
+}
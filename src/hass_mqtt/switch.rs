@@ -1,9 +1,9 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, switch_instance_state_topic, topic_safe_id,
+    camel_case_to_space_separated, switch_instance_state_topic, topic_prefix, topic_safe_id,
     HassClient,
 };
 use crate::service::state::StateHandle;
@@ -11,6 +11,128 @@ use async_trait::async_trait;
 use serde::Serialize;
 use serde_json::json;
 
+/// How we present a toggle capability that we know about
+struct KnownToggle {
+    instance: &'static str,
+    name: &'static str,
+    icon: &'static str,
+    /// Whether the toggle controls the primary function of the
+    /// device, rather than one of its settings
+    primary: bool,
+}
+
+const KNOWN_TOGGLES: &[KnownToggle] = &[
+    KnownToggle {
+        instance: "oscillationToggle",
+        name: "Oscillation",
+        icon: "mdi:arrow-oscillating",
+        primary: true,
+    },
+    KnownToggle {
+        instance: "nightlightToggle",
+        name: "Night Light",
+        icon: "mdi:lightbulb-night",
+        primary: true,
+    },
+    KnownToggle {
+        instance: "warmMistToggle",
+        name: "Warm Mist",
+        icon: "mdi:weather-fog",
+        primary: true,
+    },
+    KnownToggle {
+        instance: "airDeflectorToggle",
+        name: "Air Deflector",
+        icon: "mdi:weather-windy",
+        primary: true,
+    },
+    KnownToggle {
+        instance: "dreamViewToggle",
+        name: "DreamView",
+        icon: "mdi:television-ambient-light",
+        primary: true,
+    },
+    KnownToggle {
+        instance: "gradientToggle",
+        name: "Gradient",
+        icon: "mdi:gradient-horizontal",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "childLock",
+        name: "Child Lock",
+        icon: "mdi:lock",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "childLockToggle",
+        name: "Child Lock",
+        icon: "mdi:lock",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "displayToggle",
+        name: "Display",
+        icon: "mdi:monitor",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "thermostatToggle",
+        name: "Thermostat",
+        icon: "mdi:thermostat",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "buzzerToggle",
+        name: "Sound",
+        icon: "mdi:volume-high",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "dndToggle",
+        name: "Do Not Disturb",
+        icon: "mdi:minus-circle-outline",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "doNotDisturbToggle",
+        name: "Do Not Disturb",
+        icon: "mdi:minus-circle-outline",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "quietModeToggle",
+        name: "Quiet Mode",
+        icon: "mdi:volume-off",
+        primary: false,
+    },
+    KnownToggle {
+        instance: "indicatorLightToggle",
+        name: "Indicator Light",
+        icon: "mdi:led-on",
+        primary: false,
+    },
+];
+
+/// Returns the name, icon and entity category for the switch that
+/// represents a toggle capability. Toggles that we don't know about
+/// are assumed to change a setting of the device, and are named
+/// after their instance.
+fn toggle_presentation(instance: &str) -> (String, Option<String>, Option<String>) {
+    if instance == "powerSwitch" {
+        return (camel_case_to_space_separated(instance), None, None);
+    }
+    let config = Some("config".to_string());
+    match KNOWN_TOGGLES.iter().find(|t| t.instance == instance) {
+        Some(toggle) => (
+            toggle.name.to_string(),
+            Some(toggle.icon.to_string()),
+            if toggle.primary { None } else { config },
+        ),
+        None => (camel_case_to_space_separated(instance), None, config),
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct SwitchConfig {
     #[serde(flatten)]
@@ -25,28 +147,31 @@ impl SwitchConfig {
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
+            "{}/switch/{id}/command/{inst}",
+            topic_prefix(),
             id = topic_safe_id(device),
             inst = instance.instance
         );
         let state_topic = switch_instance_state_topic(device, &instance.instance);
-        let availability_topic = availability_topic();
+        let availability = Availability::for_device(device);
         let unique_id = format!(
             "gv2mqtt-{id}-{inst}",
             id = topic_safe_id(device),
             inst = instance.instance
         );
 
+        let (name, icon, entity_category) = toggle_presentation(&instance.instance);
+
         Ok(Self {
             base: EntityConfig {
-                availability_topic,
-                name: Some(camel_case_to_space_separated(&instance.instance)),
+                availability,
+                name: Some(name),
                 device_class: None,
                 origin: Origin::default(),
                 device: Device::for_device(device),
                 unique_id,
-                entity_category: None,
-                icon: None,
+                entity_category,
+                icon,
             },
             command_topic,
             state_topic,
@@ -88,11 +213,9 @@ impl EntityInstance for CapabilitySwitch {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_by_id(&self.device_id).await else {
+            return Ok(());
+        };
 
         if self.instance_name == "powerSwitch" {
             if let Some(state) = device.device_state() {
@@ -110,30 +233,27 @@ impl EntityInstance for CapabilitySwitch {
         // additional states. When they do, we'll need to start reporting
         // it here, but we'll also need to start polling it from the
         // platform API in order for it to even be available here.
-        // Until then, the switch will show in the hass UI with an
-        // unknown state but provide you with separate on and off push
-        // buttons so that you can at least send the commands to the device.
+        // Until then, we report the state that we last set, and
+        // the switch will show in the hass UI with an unknown state
+        // if we haven't set it.
         // <https://developer.govee.com/discuss/6596e84c901fb900312d5968>
 
+        if let Some(on) = device.toggle_state(&self.instance_name) {
+            return client
+                .publish(&self.switch.state_topic, if on { "ON" } else { "OFF" })
+                .await;
+        }
+
         if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
-            match cap.state.pointer("/value").and_then(|v| v.as_i64()) {
-                Some(n) => {
-                    return client
-                        .publish(&self.switch.state_topic, if n != 0 { "ON" } else { "OFF" })
-                        .await;
-                }
-                None => {
-                    if cap.state.pointer("/value") == Some(&json!("")) {
-                        log::trace!(
-                            "CapabilitySwitch::notify_state ignore useless \
-                                            empty string state for {cap:?}"
-                        );
-                    } else {
-                        log::warn!("CapabilitySwitch::notify_state: Do something with {cap:#?}");
-                    }
-                    return Ok(());
-                }
+            if cap.state.pointer("/value") == Some(&json!("")) {
+                log::trace!(
+                    "CapabilitySwitch::notify_state ignore useless \
+                                    empty string state for {cap:?}"
+                );
+            } else {
+                log::warn!("CapabilitySwitch::notify_state: Do something with {cap:#?}");
             }
+            return Ok(());
         }
         log::trace!(
             "CapabilitySwitch::notify_state: didn't find state for {device} {instance}",
@@ -142,3 +262,37 @@ impl EntityInstance for CapabilitySwitch {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn toggle_names() {
+        let config = Some("config".to_string());
+        assert_eq!(
+            toggle_presentation("childLock"),
+            (
+                "Child Lock".to_string(),
+                Some("mdi:lock".to_string()),
+                config.clone()
+            )
+        );
+        assert_eq!(
+            toggle_presentation("oscillationToggle"),
+            (
+                "Oscillation".to_string(),
+                Some("mdi:arrow-oscillating".to_string()),
+                None
+            )
+        );
+        assert_eq!(
+            toggle_presentation("sceneStageToggle"),
+            ("Scene Stage Toggle".to_string(), None, config)
+        );
+        assert_eq!(
+            toggle_presentation("powerSwitch"),
+            ("Power Switch".to_string(), None, None)
+        );
+    }
+}
@@ -6,6 +6,32 @@ use serde_json::Value as JsonValue;
 use std::collections::BTreeMap;
 use std::ops::Range;
 
+/// The names that humidifiers use for their manual mode
+const MIST_LEVEL_MODE_NAMES: &[&str] = &["Manual", "gearMode"];
+
+/// The names that heaters and fans use for the mode in which they
+/// regulate themselves to maintain a target temperature
+const TEMPERATURE_MODE_NAMES: &[&str] = &["Auto", "Temperature", "Constant Temperature"];
+
+/// Returns the current work mode number and its parameter, if known
+pub fn current_work_mode(device: &ServiceDevice) -> Option<(i64, Option<i64>)> {
+    if let Some(mode) = device.humidifier_work_mode {
+        let param = device
+            .humidifier_param_by_mode
+            .get(&mode)
+            .map(|p| *p as i64);
+        return Some((mode as i64, param));
+    }
+
+    let cap = device.get_state_capability_by_instance("workMode")?;
+    let mode = cap.state.pointer("/value/workMode")?.as_i64()?;
+    let param = cap
+        .state
+        .pointer("/value/modeValue")
+        .and_then(|v| v.as_i64());
+    Some((mode, param))
+}
+
 #[derive(Default, Debug)]
 pub struct ParsedWorkMode {
     pub modes: BTreeMap<String, WorkMode>,
@@ -110,6 +136,23 @@ impl ParsedWorkMode {
         self.modes.get(name)
     }
 
+    /// Returns the mode of a humidifier in which the parameter is the
+    /// intensity of the mist, rather than a target humidity
+    pub fn mist_level_mode(&self) -> Option<&WorkMode> {
+        MIST_LEVEL_MODE_NAMES
+            .iter()
+            .filter_map(|name| self.mode_by_name(name))
+            .find(|mode| mode.contiguous_value_range().is_some())
+    }
+
+    /// Returns the mode of a heater or fan in which it regulates
+    /// itself to maintain a target temperature
+    pub fn temperature_mode(&self) -> Option<&WorkMode> {
+        TEMPERATURE_MODE_NAMES
+            .iter()
+            .find_map(|name| self.mode_by_name(name))
+    }
+
     #[allow(unused)]
     pub fn mode_by_label(&self, name: &str) -> Option<&WorkMode> {
         for mode in self.modes.values() {
@@ -233,15 +276,28 @@ impl WorkMode {
         }
     }
 
+    /// Returns the parameter to use when switching to this mode.
+    /// Some devices report a defaultValue that isn't one of the
+    /// values accepted by the mode, so that is only used if valid.
     pub fn default_value(&self) -> i64 {
         self.default_value
             .as_ref()
             .and_then(|v| v.as_i64())
+            .filter(|v| self.accepts_value(*v))
             .or_else(|| self.values.get(0).and_then(|wmv| wmv.value.as_i64()))
             .or_else(|| self.value_range.as_ref().map(|r| r.start))
             .unwrap_or(0)
     }
 
+    /// Returns whether value is a valid parameter for this mode.
+    /// Modes that don't describe their values accept anything.
+    pub fn accepts_value(&self, value: i64) -> bool {
+        if let Some(range) = self.contiguous_value_range() {
+            return range.contains(&value);
+        }
+        self.values.is_empty() || self.values.iter().any(|v| v.value.as_i64() == Some(value))
+    }
+
     pub fn contiguous_value_range(&self) -> Option<Range<i64>> {
         if let Some(range) = &self.value_range {
             return Some(range.clone());
@@ -379,6 +435,11 @@ ParsedWorkMode {
         // we should get a contiguous range that we can show as a slider
         let auto_mode = wm.mode_by_name("Auto").unwrap();
         assert!(auto_mode.contiguous_value_range().is_some());
+
+        // Manual mode sets the mist level, from 1 through 9
+        let mist = wm.mist_level_mode().unwrap();
+        assert_eq!(mist.name, "Manual");
+        assert_eq!(mist.contiguous_value_range(), Some(1..10));
         k9::snapshot!(
             wm,
             r#"
@@ -1,6 +1,7 @@
 use crate::ble::{Base64HexBytes, SetSceneCode};
 use crate::opt_env_var;
 use crate::platform_api::from_json;
+use crate::service::dry_run;
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
 use if_addrs::IfAddr;
@@ -54,6 +55,12 @@ pub struct LanDiscoArguments {
     /// You may also set GOVEE_LAN_DISCO_TIMEOUT via the environment.
     #[arg(long, default_value_t = 3, global = true)]
     disco_timeout: u64,
+
+    /// Only use the LAN API; don't use the Govee cloud APIs
+    /// even if credentials are configured.
+    /// You may also set GOVEE_LAN_ONLY=true via the environment.
+    #[arg(long, global = true)]
+    lan_only: bool,
 }
 
 pub fn truthy(s: &str) -> anyhow::Result<bool> {
@@ -108,6 +115,14 @@ impl LanDiscoArguments {
         Ok(options)
     }
 
+    pub fn lan_only(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_ONLY")? {
+            truthy(&v)
+        } else {
+            Ok(self.lan_only)
+        }
+    }
+
     pub fn disco_timeout(&self) -> anyhow::Result<u64> {
         if let Some(v) = opt_env_var("GOVEE_LAN_DISCO_TIMEOUT")? {
             Ok(v)
@@ -194,8 +209,12 @@ pub struct LanDevice {
 impl LanDevice {
     pub async fn send_request(&self, msg: Request) -> anyhow::Result<()> {
         log::trace!("LanDevice::send_request to {:?} {msg:?}", self.ip);
-        let client = udp_socket_for_target(self.ip).await?;
+        let is_control = !matches!(msg, Request::Scan { .. } | Request::DevStatus {});
         let data = serde_json::to_string(&RequestMessage { msg })?;
+        if is_control && dry_run::intercept(&self.device, "LAN API", &data) {
+            return Ok(());
+        }
+        let client = udp_socket_for_target(self.ip).await?;
         client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
 
         Ok(())
@@ -560,8 +579,17 @@ impl Client {
     }
 
     pub async fn query_status(&self, device: &LanDevice) -> anyhow::Result<DeviceStatus> {
+        self.query_status_with_timeout(device, Duration::from_secs(10))
+            .await
+    }
+
+    pub async fn query_status_with_timeout(
+        &self,
+        device: &LanDevice,
+        timeout: Duration,
+    ) -> anyhow::Result<DeviceStatus> {
         let mut rx = self.add_listener(device.ip).await?;
-        let deadline = Instant::now() + Duration::from_secs(10);
+        let deadline = Instant::now() + timeout;
         while Instant::now() <= deadline {
             log::trace!("query status of {}", device.ip);
             device.send_request(Request::DevStatus {}).await?;
@@ -36,11 +36,14 @@ pub struct Args {
 
 #[derive(clap::Parser, Debug)]
 pub enum SubCommand {
+    DumpDevices(commands::dump_devices::DumpDevicesCommand),
     LanControl(commands::lan_control::LanControlCommand),
     LanDisco(commands::lan_disco::LanDiscoCommand),
     ListHttp(commands::list_http::ListHttpCommand),
     List(commands::list::ListCommand),
     HttpControl(commands::http_control::HttpControlCommand),
+    /// Exercise each capability of a device to verify that it works
+    SelfTest(commands::self_test::SelfTestCommand),
     Serve(commands::serve::ServeCommand),
     Undoc(commands::undoc::UndocCommand),
 }
@@ -48,11 +51,13 @@ pub enum SubCommand {
 impl Args {
     pub async fn run(&self) -> anyhow::Result<()> {
         match &self.cmd {
+            SubCommand::DumpDevices(cmd) => cmd.run(self).await,
             SubCommand::LanControl(cmd) => cmd.run(self).await,
             SubCommand::LanDisco(cmd) => cmd.run(self).await,
             SubCommand::ListHttp(cmd) => cmd.run(self).await,
             SubCommand::HttpControl(cmd) => cmd.run(self).await,
             SubCommand::List(cmd) => cmd.run(self).await,
+            SubCommand::SelfTest(cmd) => cmd.run(self).await,
             SubCommand::Serve(cmd) => cmd.run(self).await,
             SubCommand::Undoc(cmd) => cmd.run(self).await,
         }
@@ -63,7 +68,7 @@ pub fn opt_env_var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
 where
     <T as FromStr>::Err: std::fmt::Display,
 {
-    match std::env::var(name) {
+    match service::env_file::var(name) {
         Ok(p) => {
             Ok(Some(p.parse().map_err(|err| {
                 anyhow::anyhow!("parsing ${name}: {err:#}")
@@ -85,6 +90,9 @@ fn setup_logger() {
 
     let tz = resolve_timezone();
     let utc_suffix = if tz == chrono_tz::UTC { "Z" } else { "" };
+    let json_format = std::env::var("GOVEE_LOG_FORMAT")
+        .map(|format| format.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
 
     env_logger::builder()
         // A bit of boilerplate here to get timestamps printed in local time.
@@ -93,6 +101,23 @@ fn setup_logger() {
             use chrono::Utc;
             use std::io::Write;
 
+            let device = service::log_context::current_device();
+            if !service::log_context::device_is_logged(device.as_ref()) {
+                return Ok(());
+            }
+
+            if json_format {
+                let line = serde_json::json!({
+                    "time": Utc::now().to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "module": record.module_path(),
+                    "sku": device.as_ref().map(|d| &d.sku),
+                    "device": device.as_ref().map(|d| &d.id),
+                    "message": record.args().to_string(),
+                });
+                return writeln!(buf, "{line}");
+            }
+
             let level_style = buf.default_level_style(record.level());
             write!(
                 buf,
@@ -103,6 +128,9 @@ fn setup_logger() {
             if let Some(path) = record.module_path() {
                 write!(buf, " {}", path)?;
             }
+            if let Some(device) = &device {
+                write!(buf, " {}/{}", device.sku, device.id)?;
+            }
             writeln!(buf, "] {}", record.args())
         })
         .filter_level(log::LevelFilter::Info)
@@ -113,7 +141,7 @@ fn setup_logger() {
 #[tokio::main(worker_threads = 2)]
 async fn main() -> anyhow::Result<()> {
     color_backtrace::install();
-    if let Ok(path) = dotenvy::dotenv() {
+    if let Some(path) = service::env_file::load_at_startup() {
         eprintln!("Loading environment overrides from {path:?}");
     }
 
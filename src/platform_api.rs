@@ -1,6 +1,10 @@
 use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
 use crate::hass_mqtt::climate::parse_temperature_constraints;
 use crate::opt_env_var;
+use crate::service::account::account_cache_key;
+use crate::service::dry_run;
+use crate::service::metrics::METRICS;
+use crate::service::request_queue::{RequestPriority, RequestQueue};
 use crate::service::state::sort_and_dedup_scenes;
 use crate::temperature::{TemperatureUnits, TemperatureValue};
 use crate::undoc_api::GoveeUndocumentedApi;
@@ -9,6 +13,7 @@ use reqwest::Method;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value as JsonValue};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 
@@ -33,6 +38,15 @@ pub struct GoveeApiArguments {
     /// the GOVEE_API_KEY environment variable.
     #[arg(long, global = true)]
     pub api_key: Option<String>,
+
+    /// How long, in seconds, to cache the device list, capability
+    /// metadata and scene lists retrieved from the Platform API before
+    /// fetching them again. The cache is stored on disk, so a longer
+    /// value reduces startup time and API usage. If not passed here,
+    /// it will be read from the GOVEE_PLATFORM_CACHE_TTL environment
+    /// variable.
+    #[arg(long, global = true)]
+    pub platform_cache_ttl: Option<u64>,
 }
 
 impl GoveeApiArguments {
@@ -52,28 +66,89 @@ impl GoveeApiArguments {
         })
     }
 
+    pub fn platform_cache_ttl(&self) -> anyhow::Result<Option<Duration>> {
+        let secs = match self.platform_cache_ttl {
+            Some(secs) => Some(secs),
+            None => opt_env_var("GOVEE_PLATFORM_CACHE_TTL")?,
+        };
+        Ok(secs.map(Duration::from_secs))
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeApiClient> {
-        let key = self.api_key()?;
-        Ok(GoveeApiClient::new(key))
+        self.api_client_for_key(self.api_key()?)
+    }
+
+    /// Create a client for the given key, with the cache
+    /// options from these arguments
+    pub fn api_client_for_key(&self, key: String) -> anyhow::Result<GoveeApiClient> {
+        let mut client = GoveeApiClient::new(key);
+        if let Some(ttl) = self.platform_cache_ttl()? {
+            client = client.with_cache_ttl(ttl);
+        }
+        Ok(client)
     }
 }
 
+/// The cache key for the device list, which also holds
+/// the capability metadata for each device
+const DEVICE_LIST_CACHE_KEY: &str = "device-list";
+
 #[derive(Clone)]
 pub struct GoveeApiClient {
     key: String,
+    /// Overrides the default soft TTL for cached metadata
+    cache_ttl: Option<Duration>,
+    /// The label of the account, if it isn't the primary account
+    account: Option<String>,
+    /// Paces our requests; shared by all clones of the client
+    queue: Arc<RequestQueue>,
 }
 
 impl GoveeApiClient {
     pub fn new<K: Into<String>>(key: K) -> Self {
-        Self { key: key.into() }
+        Self {
+            key: key.into(),
+            cache_ttl: None,
+            account: None,
+            queue: Arc::new(RequestQueue::default()),
+        }
+    }
+
+    /// Returns the number of requests waiting to be sent
+    pub fn queue_depth(&self) -> usize {
+        self.queue.depth()
+    }
+
+    pub fn with_account(mut self, label: &str) -> Self {
+        self.account.replace(label.to_string());
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl.replace(ttl);
+        self
+    }
+
+    fn device_list_cache_key(&self) -> String {
+        account_cache_key(self.account.as_deref(), DEVICE_LIST_CACHE_KEY)
+    }
+
+    fn metadata_ttl(&self, default_ttl: Duration) -> Duration {
+        self.cache_ttl.unwrap_or(default_ttl)
+    }
+
+    /// Discard the cached device list, so that the next call
+    /// to get_devices will fetch it from Govee
+    pub fn invalidate_device_list(&self) -> anyhow::Result<()> {
+        crate::cache::invalidate_key("http-api", &self.device_list_cache_key())
     }
 
     pub async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
-                key: "device-list",
-                soft_ttl: Duration::from_secs(900),
+                key: &self.device_list_cache_key(),
+                soft_ttl: self.metadata_ttl(Duration::from_secs(900)),
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(60),
                 allow_stale: true,
@@ -118,8 +193,31 @@ impl GoveeApiClient {
             },
         };
 
+        if dry_run::intercept(
+            &device.device,
+            "Platform API",
+            serde_json::to_string(&request.payload.capability)?,
+        ) {
+            let capability = request.payload.capability;
+            return Ok(ControlDeviceResponseCapability {
+                kind: capability.kind,
+                instance: capability.instance,
+                value: capability.value,
+                state: json!({"status": "success"}),
+            });
+        }
+
+        // A command that is still waiting when a newer one arrives for
+        // the same capability of the device is no longer wanted
+        let key = format!("{}/{}", device.device, capability.instance);
         let resp: ControlDeviceResponse = self
-            .request_with_json_response(Method::POST, url, &request)
+            .request_with_json_response(
+                RequestPriority::Command,
+                Some(&key),
+                Method::POST,
+                url,
+                &request,
+            )
             .await?;
 
         log::info!("control_device result: {resp:?}");
@@ -141,7 +239,13 @@ impl GoveeApiClient {
         };
 
         let resp: GetDeviceStateResponse = self
-            .request_with_json_response(Method::POST, url, &request)
+            .request_with_json_response(
+                RequestPriority::Background,
+                None,
+                Method::POST,
+                url,
+                &request,
+            )
             .await?;
 
         Ok(resp.payload)
@@ -160,7 +264,7 @@ impl GoveeApiClient {
             CacheGetOptions {
                 topic: "http-api",
                 key: &key,
-                soft_ttl: Duration::from_secs(300),
+                soft_ttl: self.metadata_ttl(Duration::from_secs(300)),
                 hard_ttl: ONE_WEEK,
                 negative_ttl: FIVE_MINUTES,
                 allow_stale: true,
@@ -176,7 +280,13 @@ impl GoveeApiClient {
                 };
 
                 let resp: GetDeviceScenesResponse = self
-                    .request_with_json_response(Method::POST, url, &request)
+                    .request_with_json_response(
+                        RequestPriority::Background,
+                        None,
+                        Method::POST,
+                        url,
+                        &request,
+                    )
                     .await?;
 
                 Ok(CacheComputeResult::Value(resp.payload.capabilities))
@@ -193,12 +303,14 @@ impl GoveeApiClient {
             return Ok(vec![]);
         }
 
-        let key = format!("scene-list-{}-{}", device.sku, device.device);
+        // The scene list is the same for all devices of the same
+        // SKU, and it can be quite large, so cache it per SKU
+        let key = format!("scene-list-{}", device.sku);
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
                 key: &key,
-                soft_ttl: Duration::from_secs(300),
+                soft_ttl: self.metadata_ttl(Duration::from_secs(300)),
                 hard_ttl: ONE_WEEK,
                 negative_ttl: FIVE_MINUTES,
                 allow_stale: true,
@@ -214,7 +326,13 @@ impl GoveeApiClient {
                 };
 
                 let resp: GetDeviceScenesResponse = self
-                    .request_with_json_response(Method::POST, url, &request)
+                    .request_with_json_response(
+                        RequestPriority::Background,
+                        None,
+                        Method::POST,
+                        url,
+                        &request,
+                    )
                     .await?;
 
                 Ok(CacheComputeResult::Value(resp.payload.capabilities))
@@ -253,6 +371,9 @@ impl GoveeApiClient {
                         | DeviceCapabilityKind::DynamicSetting
                         | DeviceCapabilityKind::Mode
                 );
+                // Snapshots are also offered via their own select,
+                // but remain here so that existing automations that
+                // activate them as scenes keep working
                 if !is_scene {
                     continue;
                 }
@@ -299,21 +420,8 @@ impl GoveeApiClient {
         }
 
         // Add in music modes
-        if let Some(cap) = device.capability_by_instance("musicMode") {
-            if let Some(DeviceParameters::Struct { fields }) = &cap.parameters {
-                for f in fields {
-                    if f.field_name == "musicMode" {
-                        match &f.field_type {
-                            DeviceParameters::Enum { options } => {
-                                for opt in options {
-                                    result.push(format!("Music: {}", opt.name));
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            }
+        for name in device.music_mode_names() {
+            result.push(format!("Music: {name}"));
         }
 
         if !result.is_empty() {
@@ -334,17 +442,8 @@ impl GoveeApiClient {
         }
 
         if let Some(music_mode) = scene.strip_prefix("Music: ") {
-            if let Some(cap) = device.capability_by_instance("musicMode") {
-                if let Some(field) = cap.struct_field_by_name("musicMode") {
-                    if let Some(value) = field.field_type.enum_parameter_by_name(music_mode) {
-                        let value = serde_json::json!({
-                            "musicMode": value,
-                            "sensitivity": 100,
-                            "autoColor": 1,
-                        });
-                        return self.control_device(&device, &cap, value).await;
-                    }
-                }
+            if device.music_mode_names().iter().any(|m| m == music_mode) {
+                return self.set_music_mode(device, music_mode, 100).await;
             }
         }
 
@@ -364,6 +463,154 @@ impl GoveeApiClient {
         anyhow::bail!("Scene '{scene}' is not available for this device");
     }
 
+    /// Activate the scene whose numeric id is scene_id, returning its name
+    pub async fn set_scene_by_id(
+        &self,
+        device: &HttpDeviceInfo,
+        scene_id: u64,
+    ) -> anyhow::Result<String> {
+        let caps = self.get_scene_caps(device).await?;
+        let (cap, opt) = find_scene_by_id(&caps, scene_id).ok_or_else(|| {
+            anyhow::anyhow!("Scene id {scene_id} is not available for this device")
+        })?;
+        self.control_device(device, cap, opt.value.clone()).await?;
+        Ok(opt.name.to_string())
+    }
+
+    pub async fn set_music_mode(
+        &self,
+        device: &HttpDeviceInfo,
+        music_mode: &str,
+        sensitivity: u8,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance("musicMode")
+            .ok_or_else(|| anyhow::anyhow!("device has no musicMode"))?;
+        let value = cap
+            .struct_field_by_name("musicMode")
+            .and_then(|field| field.field_type.enum_parameter_by_name(music_mode))
+            .ok_or_else(|| anyhow::anyhow!("Music mode '{music_mode}' is not available"))?;
+        let value = serde_json::json!({
+            "musicMode": value,
+            "sensitivity": sensitivity,
+            "autoColor": 1,
+        });
+        self.control_device(device, cap, value).await
+    }
+
+    /// Returns the lightScene capabilities for the device, which
+    /// may come from both the device metadata and the scene list
+    async fn get_light_scene_caps(
+        &self,
+        device: &HttpDeviceInfo,
+    ) -> anyhow::Result<Vec<DeviceCapability>> {
+        let scene_caps = self.get_device_scenes(device).await?;
+        Ok(device
+            .capabilities
+            .iter()
+            .chain(scene_caps.iter())
+            .filter(|cap| cap.instance == "lightScene")
+            .cloned()
+            .collect())
+    }
+
+    /// Returns the names of the scenes in the lightScene capability
+    pub async fn list_light_scene_names(
+        &self,
+        device: &HttpDeviceInfo,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut result = vec![];
+        for cap in self.get_light_scene_caps(device).await? {
+            if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+                for opt in options {
+                    result.push(opt.name.to_string());
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub async fn set_light_scene_by_name(
+        &self,
+        device: &HttpDeviceInfo,
+        scene: &str,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        for cap in self.get_light_scene_caps(device).await? {
+            if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+                for opt in options {
+                    if scene.eq_ignore_ascii_case(&opt.name) {
+                        return self.control_device(device, &cap, opt.value.clone()).await;
+                    }
+                }
+            }
+        }
+        anyhow::bail!("Scene '{scene}' is not available for this device");
+    }
+
+    /// Returns the name of the light scene that corresponds to a
+    /// value reported by HttpDeviceState::light_scene_value
+    pub async fn light_scene_name_for_value(
+        &self,
+        device: &HttpDeviceInfo,
+        value: &JsonValue,
+    ) -> anyhow::Result<Option<String>> {
+        let caps = self.get_light_scene_caps(device).await?;
+        Ok(find_scene_by_value(&caps, value).map(|opt| opt.name.to_string()))
+    }
+
+    /// Returns the names of the user-defined DIY scenes for the device
+    pub async fn list_diy_scene_names(
+        &self,
+        device: &HttpDeviceInfo,
+    ) -> anyhow::Result<Vec<String>> {
+        let caps = self.get_device_diy_scenes(device).await?;
+        let mut result = vec![];
+        for cap in &caps {
+            if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+                for opt in options {
+                    result.push(opt.name.to_string());
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub async fn set_diy_scene_by_name(
+        &self,
+        device: &HttpDeviceInfo,
+        scene: &str,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let caps = self.get_device_diy_scenes(device).await?;
+        for cap in &caps {
+            if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+                for opt in options {
+                    if scene.eq_ignore_ascii_case(&opt.name) {
+                        return self.control_device(device, cap, opt.value.clone()).await;
+                    }
+                }
+            }
+        }
+        anyhow::bail!("DIY scene '{scene}' is not available for this device");
+    }
+
+    pub async fn set_snapshot_by_name(
+        &self,
+        device: &HttpDeviceInfo,
+        snapshot: &str,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance(SNAPSHOT_INSTANCE)
+            .ok_or_else(|| anyhow::anyhow!("device has no snapshots"))?;
+        if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+            for opt in options {
+                if snapshot.eq_ignore_ascii_case(&opt.name) {
+                    return self.control_device(device, cap, opt.value.clone()).await;
+                }
+            }
+        }
+        anyhow::bail!("Snapshot '{snapshot}' is not available for this device");
+    }
+
     pub async fn set_target_temperature(
         &self,
         device: &HttpDeviceInfo,
@@ -447,10 +694,7 @@ impl GoveeApiClient {
             .capability_by_instance("brightness")
             .ok_or_else(|| anyhow::anyhow!("device has no brightness"))?;
         let value = match &cap.parameters {
-            Some(DeviceParameters::Integer {
-                range: IntegerRange { min, max, .. },
-                ..
-            }) => (percent as u32).max(*min).min(*max),
+            Some(DeviceParameters::Integer { range, .. }) => range.percent_to_native(percent),
             _ => anyhow::bail!("unexpected parameter type for brightness"),
         };
         self.control_device(&device, &cap, value).await
@@ -488,10 +732,11 @@ impl GoveeApiClient {
         self.control_device(&device, &cap, value).await
     }
 
+    /// Set the color of one or more segments in a single request
     pub async fn set_segment_rgb(
         &self,
         device: &HttpDeviceInfo,
-        segment: u32,
+        segments: &[u32],
         r: u8,
         g: u8,
         b: u8,
@@ -504,17 +749,18 @@ impl GoveeApiClient {
             &device,
             &cap,
             json!({
-                "segment": vec![segment],
+                "segment": segments,
                 "rgb": value,
             }),
         )
         .await
     }
 
+    /// Set the brightness of one or more segments in a single request
     pub async fn set_segment_brightness(
         &self,
         device: &HttpDeviceInfo,
-        segment: u32,
+        segments: &[u32],
         percent: u8,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
@@ -525,13 +771,18 @@ impl GoveeApiClient {
             .supports_segmented_brightness()
             .ok_or_else(|| anyhow::anyhow!("device doesnt support segmented brightness"))?;
 
-        let value = (percent as u32).max(min).min(max);
+        let value = IntegerRange {
+            min,
+            max,
+            precision: 1,
+        }
+        .percent_to_native(percent);
 
         self.control_device(
             &device,
             &cap,
             json!({
-                "segment": vec![segment],
+                "segment": segments,
                 "brightness": value,
             }),
         )
@@ -653,6 +904,24 @@ impl HttpDeviceState {
             .iter()
             .find(|c| c.instance.eq_ignore_ascii_case(instance))
     }
+
+    /// Returns the value of the lightScene capability, if the device
+    /// reported that a scene is active. Devices report an empty value
+    /// when no scene is active, or when they don't track it.
+    pub fn light_scene_value(&self) -> Option<&JsonValue> {
+        let value = self
+            .capability_by_instance("lightScene")?
+            .state
+            .get("value")?;
+        let is_empty = match value {
+            JsonValue::Null => true,
+            JsonValue::String(s) => s.is_empty(),
+            JsonValue::Number(n) => n.as_u64() == Some(0),
+            JsonValue::Object(o) => o.is_empty(),
+            _ => false,
+        };
+        (!is_empty).then_some(value)
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -682,6 +951,15 @@ pub struct HttpDeviceInfo {
     pub device_name: String,
     #[serde(default, rename = "type")]
     pub device_type: DeviceType,
+    /// The room that the device is assigned to in the Govee App,
+    /// which is only returned for some accounts
+    #[serde(
+        default,
+        rename = "roomName",
+        alias = "room",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub room_name: Option<String>,
     pub capabilities: Vec<DeviceCapability>,
 }
 
@@ -706,6 +984,58 @@ impl HttpDeviceInfo {
             .any(|cap| cap.kind == DeviceCapabilityKind::DynamicScene)
     }
 
+    /// Returns the names of the modes from the musicMode capability
+    pub fn music_mode_names(&self) -> Vec<String> {
+        match self
+            .capability_by_instance("musicMode")
+            .and_then(|cap| cap.struct_field_by_name("musicMode"))
+            .map(|field| &field.field_type)
+        {
+            Some(DeviceParameters::Enum { options }) => {
+                options.iter().map(|opt| opt.name.to_string()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Returns the names of the snapshots that were saved
+    /// for the device in the Govee app
+    pub fn snapshot_names(&self) -> Vec<String> {
+        match self
+            .capability_by_instance(SNAPSHOT_INSTANCE)
+            .and_then(|cap| cap.parameters.as_ref())
+        {
+            Some(DeviceParameters::Enum { options }) => {
+                options.iter().map(|opt| opt.name.to_string()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// If supported, returns the range of the brightness capability
+    pub fn brightness_range(&self) -> Option<&IntegerRange> {
+        match self
+            .capability_by_instance("brightness")?
+            .parameters
+            .as_ref()?
+        {
+            DeviceParameters::Integer { range, .. } => Some(range),
+            _ => None,
+        }
+    }
+
+    /// If supported, returns the range of the musicMode sensitivity
+    pub fn music_sensitivity_range(&self) -> Option<&IntegerRange> {
+        match &self
+            .capability_by_instance("musicMode")?
+            .struct_field_by_name("sensitivity")?
+            .field_type
+        {
+            DeviceParameters::Integer { range, .. } => Some(range),
+            _ => None,
+        }
+    }
+
     /// If supported, returns the number of segments
     pub fn supports_segmented_rgb(&self) -> Option<std::ops::Range<u32>> {
         let cap = self.capability_by_instance("segmentedColorRgb")?;
@@ -848,6 +1178,7 @@ pub enum DeviceCapabilityKind {
     Online = "devices.capabilities.online",
     Property = "devices.capabilities.property",
     Event = "devices.capabilities.event",
+    Timer = "devices.capabilities.timer",
 }
 }
 
@@ -956,6 +1287,44 @@ pub struct IntegerRange {
     pub precision: u32,
 }
 
+impl IntegerRange {
+    /// Brightness is a percentage for most devices, but some
+    /// use a scale that goes up to 254 or 255 instead
+    fn is_percent(&self) -> bool {
+        self.max <= 100
+    }
+
+    /// Convert a brightness percentage to the scale used by the device
+    pub fn percent_to_native(&self, percent: u8) -> u32 {
+        let percent = (percent as u32).min(100);
+        let value = if self.is_percent() {
+            percent
+        } else {
+            (percent * self.max + 50) / 100
+        };
+        value.max(self.min).min(self.max)
+    }
+
+    /// Convert a brightness reported by the device to a percentage
+    pub fn native_to_percent(&self, value: u32) -> u8 {
+        if self.max <= self.min {
+            // There is nothing to scale within a range of one value
+            return if value > 0 { 100 } else { 0 };
+        }
+        let value = value.min(self.max);
+        if self.is_percent() {
+            return value as u8;
+        }
+        let percent = (value * 100 + self.max / 2) / self.max;
+        // Don't report a light that is dimly lit as being at 0%
+        if value > 0 {
+            percent.max(1) as u8
+        } else {
+            0
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct EnumOption {
     pub name: String,
@@ -971,6 +1340,43 @@ pub struct ArrayOption {
     pub value: u32,
 }
 
+/// Scene options have values like `{"id": 1606, "paramId": 1681}`,
+/// or, for some devices, just the numeric id.
+/// Returns the scene option whose id matches scene_id.
+pub fn find_scene_by_id(
+    caps: &[DeviceCapability],
+    scene_id: u64,
+) -> Option<(&DeviceCapability, &EnumOption)> {
+    caps.iter().find_map(|cap| match &cap.parameters {
+        Some(DeviceParameters::Enum { options }) => options
+            .iter()
+            .find(|opt| opt.value.get("id").unwrap_or(&opt.value).as_u64() == Some(scene_id))
+            .map(|opt| (cap, opt)),
+        _ => None,
+    })
+}
+
+/// Find the scene whose value matches the value that a device
+/// reported as its active scene. Devices may report the whole
+/// value, or just the id portion of it.
+pub fn find_scene_by_value<'a>(
+    caps: &'a [DeviceCapability],
+    value: &JsonValue,
+) -> Option<&'a EnumOption> {
+    let id = value.get("id").unwrap_or(value).as_u64();
+    caps.iter().find_map(|cap| match &cap.parameters {
+        Some(DeviceParameters::Enum { options }) => options.iter().find(|opt| {
+            opt.value == *value
+                || (id.is_some() && opt.value.get("id").unwrap_or(&opt.value).as_u64() == id)
+        }),
+        _ => None,
+    })
+}
+
+/// The capability instance that lists the snapshots that
+/// were saved for a device in the Govee app
+pub const SNAPSHOT_INSTANCE: &str = "snapshot";
+
 pub fn from_json<T: serde::de::DeserializeOwned, S: AsRef<[u8]>>(text: S) -> anyhow::Result<T> {
     let text = text.as_ref();
     serde_json_path_to_error::from_slice(text).map_err(|err| {
@@ -1070,17 +1476,96 @@ pub async fn http_response_body<R: serde::de::DeserializeOwned>(
     })
 }
 
+/// The maximum number of times that we'll retry a platform API
+/// request that was rejected due to rate limiting or a server error
+const MAX_RETRIES: u32 = 4;
+/// The longest that we'll wait before a single retry
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// The initial delay used for exponential backoff
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Returns true if the request should be retried after receiving
+/// this status: we retry when rate limited, or when Govee has
+/// some kind of server side issue
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Compute how long to wait before making retry number `attempt`
+/// (counting from 0). A `Retry-After` header specifying a number of
+/// seconds takes precedence over the exponential backoff schedule.
+/// The result does not include jitter.
+fn retry_delay(attempt: u32, retry_after: Option<&str>) -> Duration {
+    let delay = match retry_after.and_then(|v| v.trim().parse::<u64>().ok()) {
+        Some(secs) => Duration::from_secs(secs),
+        None => BASE_RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempt)),
+    };
+    delay.min(MAX_RETRY_DELAY)
+}
+
+/// Add up to 25% of random jitter to the delay, so that multiple
+/// requests that were throttled at the same time don't all
+/// retry at the same instant
+fn with_jitter(delay: Duration) -> Duration {
+    let random = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+    delay + delay / 4 * random / 1000
+}
+
 impl GoveeApiClient {
+    /// Send the request produced by `make_request`, retrying with
+    /// exponential backoff if Govee rate limits us or returns a
+    /// server error
+    async fn send_with_retry<F: Fn() -> anyhow::Result<reqwest::RequestBuilder>>(
+        &self,
+        priority: RequestPriority,
+        key: Option<&str>,
+        make_request: F,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            self.queue.acquire(priority, key).await?;
+            let response = match make_request()?.send().await {
+                Ok(response) => response,
+                Err(err) => {
+                    METRICS.platform_api_request(None);
+                    return Err(err.into());
+                }
+            };
+            let status = response.status();
+            METRICS.platform_api_request(Some(status));
+            if !is_retryable_status(status) || attempt >= MAX_RETRIES {
+                return Ok(response);
+            }
+
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok());
+            let delay = with_jitter(retry_delay(attempt, retry_after));
+            attempt += 1;
+
+            log::warn!(
+                "Platform API request to {url} failed with status {status}. \
+                 Retrying in {delay:?} (retry {attempt} of {MAX_RETRIES})",
+                url = response.url()
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
     async fn get_request_with_json_response<T: reqwest::IntoUrl, R: serde::de::DeserializeOwned>(
         &self,
         url: T,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
-            .request(Method::GET, url)
-            .header("Govee-API-Key", &self.key)
-            .send()
+        let url = url.into_url()?;
+        let response = self
+            .send_with_retry(RequestPriority::Background, None, || {
+                Ok(reqwest::Client::builder()
+                    .timeout(Duration::from_secs(60))
+                    .build()?
+                    .request(Method::GET, url.clone())
+                    .header("Govee-API-Key", &self.key))
+            })
             .await?;
 
         http_response_body(response).await
@@ -1092,17 +1577,22 @@ impl GoveeApiClient {
         R: serde::de::DeserializeOwned,
     >(
         &self,
+        priority: RequestPriority,
+        key: Option<&str>,
         method: Method,
         url: T,
         body: &B,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
-            .request(method, url)
-            .header("Govee-API-Key", &self.key)
-            .json(body)
-            .send()
+        let url = url.into_url()?;
+        let response = self
+            .send_with_retry(priority, key, || {
+                Ok(reqwest::Client::builder()
+                    .timeout(Duration::from_secs(60))
+                    .build()?
+                    .request(method.clone(), url.clone())
+                    .header("Govee-API-Key", &self.key)
+                    .json(body))
+            })
             .await?;
 
         http_response_body(response).await
@@ -1113,6 +1603,29 @@ impl GoveeApiClient {
 mod test {
     use super::*;
 
+    #[test]
+    fn retry_delays() {
+        assert_eq!(retry_delay(0, None), Duration::from_secs(1));
+        assert_eq!(retry_delay(1, None), Duration::from_secs(2));
+        assert_eq!(retry_delay(3, None), Duration::from_secs(8));
+        assert_eq!(retry_delay(10, None), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(0, Some("5")), Duration::from_secs(5));
+        assert_eq!(retry_delay(0, Some("3600")), MAX_RETRY_DELAY);
+        // HTTP dates are not supported; fall back to backoff
+        assert_eq!(
+            retry_delay(1, Some("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Duration::from_secs(2)
+        );
+
+        let delay = Duration::from_secs(4);
+        let jittered = with_jitter(delay);
+        assert!(jittered >= delay && jittered <= delay + Duration::from_secs(1));
+
+        assert!(is_retryable_status(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable_status(reqwest::StatusCode::BAD_REQUEST));
+    }
+
     const SCENE_LIST: &str = include_str!("../test-data/scenes.json");
 
     #[test]
@@ -1121,6 +1634,104 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn brightness_scale() {
+        let percent = IntegerRange {
+            min: 1,
+            max: 100,
+            precision: 1,
+        };
+        assert_eq!(percent.percent_to_native(0), 1);
+        assert_eq!(percent.percent_to_native(42), 42);
+        assert_eq!(percent.native_to_percent(42), 42);
+
+        let wide = IntegerRange {
+            min: 1,
+            max: 254,
+            precision: 1,
+        };
+        assert_eq!(wide.percent_to_native(100), 254);
+        assert_eq!(wide.percent_to_native(50), 127);
+        assert_eq!(wide.percent_to_native(0), 1);
+        assert_eq!(wide.native_to_percent(254), 100);
+        assert_eq!(wide.native_to_percent(127), 50);
+        assert_eq!(wide.native_to_percent(1), 1);
+        assert_eq!(wide.native_to_percent(0), 0);
+        for percent in 1..=100 {
+            assert_eq!(
+                wide.native_to_percent(wide.percent_to_native(percent)),
+                percent
+            );
+        }
+
+        let fixed = IntegerRange {
+            min: 254,
+            max: 254,
+            precision: 1,
+        };
+        assert_eq!(fixed.native_to_percent(254), 100);
+        assert_eq!(fixed.native_to_percent(0), 0);
+    }
+
+    #[test]
+    fn snapshots() {
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
+        let with_snapshots = resp
+            .data
+            .iter()
+            .find(|info| info.capability_by_instance(SNAPSHOT_INSTANCE).is_some())
+            .unwrap();
+        assert_eq!(with_snapshots.snapshot_names(), vec!["Sunrise", "Sunset"]);
+
+        let without = HttpDeviceInfo {
+            capabilities: vec![],
+            ..with_snapshots.clone()
+        };
+        assert!(without.snapshot_names().is_empty());
+    }
+
+    #[test]
+    fn scene_by_id() {
+        let resp: GetDeviceScenesResponse = from_json(SCENE_LIST).unwrap();
+        let caps = &resp.payload.capabilities;
+        let (cap, opt) = find_scene_by_id(caps, 1606).unwrap();
+        assert_eq!(cap.instance, "lightScene");
+        assert_eq!(opt.name, "Sunrise");
+        // paramId is not the scene id
+        assert!(find_scene_by_id(caps, 1681).is_none());
+        assert!(find_scene_by_id(caps, 1).is_none());
+    }
+
+    #[test]
+    fn scene_by_value() {
+        let resp: GetDeviceScenesResponse = from_json(SCENE_LIST).unwrap();
+        let caps = &resp.payload.capabilities;
+        let name =
+            |value: JsonValue| find_scene_by_value(caps, &value).map(|opt| opt.name.as_str());
+        assert_eq!(
+            name(serde_json::json!({"paramId": 1681, "id": 1606})),
+            Some("Sunrise")
+        );
+        assert_eq!(name(serde_json::json!({"id": 1606})), Some("Sunrise"));
+        assert_eq!(name(serde_json::json!(1606)), Some("Sunrise"));
+        assert_eq!(name(serde_json::json!(42)), None);
+
+        let state = |value: JsonValue| HttpDeviceState {
+            sku: "H6072".to_string(),
+            device: "AA:BB".to_string(),
+            capabilities: vec![DeviceCapabilityState {
+                kind: DeviceCapabilityKind::DynamicScene,
+                instance: "lightScene".to_string(),
+                state: serde_json::json!({"value": value}),
+            }],
+        };
+        assert!(state(serde_json::json!("")).light_scene_value().is_none());
+        assert_eq!(
+            state(serde_json::json!(1606)).light_scene_value(),
+            Some(&serde_json::json!(1606))
+        );
+    }
+
     const GET_DEVICE_STATE_EXAMPLE: &str = include_str!("../test-data/get_device_state.json");
 
     #[test]
@@ -0,0 +1,101 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Credentials for an additional Govee account. The devices from
+/// all accounts are merged into a single MQTT namespace.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraAccount {
+    /// A short name for the account, used to tag its devices
+    /// and to keep its cached data separate from other accounts
+    pub label: String,
+    pub api_key: Option<String>,
+    pub email: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Parse the JSON list of additional accounts
+pub fn parse_extra_accounts(json: &str) -> anyhow::Result<Vec<ExtraAccount>> {
+    let accounts: Vec<ExtraAccount> = serde_json::from_str(json)?;
+    let mut labels = std::collections::HashSet::new();
+    for acct in &accounts {
+        if acct.label.is_empty()
+            || !acct
+                .label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!(
+                "account label {:?} must be non-empty and consist only \
+                 of letters, digits, '-' and '_'",
+                acct.label
+            );
+        }
+        if !labels.insert(acct.label.as_str()) {
+            anyhow::bail!("account label {:?} is used more than once", acct.label);
+        }
+        if acct.api_key.is_none() && (acct.email.is_none() || acct.password.is_none()) {
+            anyhow::bail!(
+                "account {:?} needs an api_key and/or an email and password",
+                acct.label
+            );
+        }
+    }
+    Ok(accounts)
+}
+
+/// Scope a cache key to an account. The primary account keeps the
+/// unscoped key, so that existing caches remain valid.
+pub fn account_cache_key(account: Option<&str>, key: &str) -> String {
+    match account {
+        Some(label) => format!("{key}@{label}"),
+        None => key.to_string(),
+    }
+}
+
+/// Scope a file path to an account, by appending the label to
+/// the file name for accounts other than the primary
+pub fn account_path(path: &Path, account: Option<&str>) -> PathBuf {
+    match account {
+        Some(label) => {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".{label}"));
+            path.with_file_name(name)
+        }
+        None => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extra_accounts() {
+        let accounts = parse_extra_accounts(
+            r#"[{"label": "shop", "api_key": "KEY"},
+               {"label": "cabin", "email": "a@example.com", "password": "pw"}]"#,
+        )
+        .unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].api_key.as_deref(), Some("KEY"));
+        assert_eq!(accounts[1].email.as_deref(), Some("a@example.com"));
+
+        assert!(parse_extra_accounts(r#"[{"label": "shop"}]"#).is_err());
+        assert!(parse_extra_accounts(r#"[{"label": "my shop", "api_key": "K"}]"#).is_err());
+        assert!(parse_extra_accounts(
+            r#"[{"label": "a", "api_key": "K"}, {"label": "a", "api_key": "L"}]"#
+        )
+        .is_err());
+
+        assert_eq!(account_cache_key(None, "device-list"), "device-list");
+        assert_eq!(
+            account_cache_key(Some("shop"), "device-list"),
+            "device-list@shop"
+        );
+        assert_eq!(
+            account_path(Path::new("/dev/shm/govee.iot.key"), Some("shop")),
+            PathBuf::from("/dev/shm/govee.iot.key.shop")
+        );
+    }
+}
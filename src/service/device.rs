@@ -1,12 +1,20 @@
 use crate::ble::NotifyHumidifierNightlightParams;
-use crate::commands::serve::POLL_INTERVAL;
+use crate::commands::serve::{
+    CONTROL_POLL_INTERVAL, CONTROL_POLL_WINDOW, LAN_POLL_INTERVAL, POLL_INTERVAL,
+};
 use crate::lan_api::{DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice};
 use crate::platform_api::{
-    DeviceCapability, DeviceCapabilityState, DeviceType, HttpDeviceInfo, HttpDeviceState,
+    DeviceCapability, DeviceCapabilityState, DeviceParameters, DeviceType, HttpDeviceInfo,
+    HttpDeviceState,
+};
+use crate::service::device_filter::entry_matches;
+use crate::service::persist::{PersistedDeviceState, PERSISTED_SOURCE};
+use crate::service::quirks::{
+    resolve_quirk, sensor_calibrations, Quirk, SensorCalibration, SensorCalibrationEntry, BULB,
 };
-use crate::service::quirks::{resolve_quirk, Quirk, BULB};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::net::IpAddr;
@@ -16,6 +24,10 @@ pub struct Device {
     pub sku: String,
     pub id: String,
 
+    /// The label of the additional Govee account through which
+    /// the device was discovered; None for the primary account
+    pub account: Option<String>,
+
     /// Probed LAN device information, found either via discovery
     /// or explicit probing by IP address
     pub lan_device: Option<LanDevice>,
@@ -42,8 +54,71 @@ pub struct Device {
     pub humidifier_param_by_mode: HashMap<u8, u8>,
 
     pub last_polled: Option<DateTime<Utc>>,
+    pub last_controlled: Option<DateTime<Utc>>,
+
+    /// The most recently commanded state of toggle capabilities,
+    /// and when it was commanded, keyed by instance name. Used to
+    /// report state for toggles that the platform API doesn't report,
+    /// or hasn't reported since the command was sent.
+    pub last_toggle_state: HashMap<String, (bool, DateTime<Utc>)>,
+
+    /// The most recently requested music mode sensitivity.
+    /// Govee doesn't report it, so we have to remember it
+    /// in order to preserve it when changing music modes.
+    pub music_sensitivity: Option<u8>,
+
+    /// The most recently requested power-on behavior, for
+    /// devices that don't report it in their state
+    pub power_on_behavior: Option<String>,
+
+    /// The most recently applied do not disturb schedule, for
+    /// devices that don't report it in their state
+    pub do_not_disturb: Option<serde_json::Value>,
+
+    /// The number of consecutive Platform API device list refreshes
+    /// that haven't included this device. A device can briefly drop
+    /// out of the list, so it is only forgotten after a few of them.
+    pub missing_from_device_list: u32,
+
+    /// The name under which the device is registered with hass,
+    /// as assigned by State::assign_hass_names
+    pub hass_name: Option<String>,
+
+    /// The most recently commanded brightness of each segment,
+    /// keyed by segment index. Govee doesn't report it.
+    pub segment_brightness: HashMap<u32, u8>,
+
+    /// The most recently commanded color of each segment, keyed by
+    /// segment index, along with when it was commanded
+    pub segment_colors: HashMap<u32, (DeviceColor, DateTime<Utc>)>,
+
+    /// The most recently known speed of a fan, as a percentage.
+    /// Used to report and carry over the speed while a preset
+    /// that doesn't report a speed is active.
+    pub fan_speed_percent: Option<u8>,
+
+    /// The brightness of the light before it was faded out,
+    /// so that it can be restored when it is next turned on
+    pub brightness_before_fade: Option<u8>,
+
+    binary_state_debounce: HashMap<String, BinaryStateDebounce>,
+
+    availability: BinaryStateDebounce,
+    published_availability: Option<bool>,
+
+    /// When the user last asked us to refresh the state of
+    /// the device, used to rate limit those requests
+    last_refresh_request: Option<DateTime<Utc>>,
+
+    /// State loaded from disk at startup; only used until
+    /// we have learned the live state of the device
+    persisted_state: Option<PersistedDeviceState>,
 
     active_scene: Option<ActiveSceneInfo>,
+
+    /// The snapshot that was most recently recalled, until
+    /// something else changes the scene or color
+    last_snapshot: Option<String>,
 }
 
 impl std::fmt::Display for Device {
@@ -52,7 +127,46 @@ impl std::fmt::Display for Device {
     }
 }
 
-/// Govee doesn't report the active scene or music mode,
+/// Compute the names under which the devices are registered with
+/// hass, keyed by device id. The name defined in the Govee App is
+/// used unless prefer_sku_names is set. Devices that would otherwise
+/// share a name have their short id appended, so that they can be
+/// told apart in hass.
+pub fn hass_device_names(devices: &[Device], prefer_sku_names: bool) -> HashMap<String, String> {
+    let base_name = |d: &Device| {
+        if prefer_sku_names {
+            d.computed_name()
+        } else {
+            d.name()
+        }
+    };
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for d in devices {
+        *counts.entry(base_name(d).to_lowercase()).or_default() += 1;
+    }
+
+    devices
+        .iter()
+        .map(|d| {
+            let name = base_name(d);
+            let name = if counts[&name.to_lowercase()] > 1 {
+                format!("{name} ({})", d.short_id())
+            } else {
+                name
+            };
+            (d.id.to_string(), name)
+        })
+        .collect()
+}
+
+/// The name reported for an active scene that isn't in our list
+pub const UNKNOWN_SCENE: &str = "Unknown";
+
+/// The toggle that has the device blend between its segment colors
+pub const GRADIENT_INSTANCE: &str = "gradientToggle";
+
+/// Most devices don't report the active scene or music mode,
 /// so we maintain our own idea of it, clearing it when
 /// the color of the light is changed
 #[derive(Clone, Debug)]
@@ -62,6 +176,147 @@ struct ActiveSceneInfo {
     pub kelvin: u32,
 }
 
+/// Holds a binary state in its "on" position until its
+/// "off" state has been observed for some time
+#[derive(Clone, Debug, Default)]
+pub struct BinaryStateDebounce {
+    reported: Option<bool>,
+    off_since: Option<DateTime<Utc>>,
+}
+
+impl BinaryStateDebounce {
+    /// Returns the state that should be reported and, if we are
+    /// holding the "on" state, how long until it should be checked again
+    pub fn update(
+        &mut self,
+        on: bool,
+        now: DateTime<Utc>,
+        hold: chrono::Duration,
+    ) -> (bool, Option<chrono::Duration>) {
+        let mut recheck = None;
+        let report = if on {
+            self.off_since.take();
+            true
+        } else {
+            let since = *self.off_since.get_or_insert(now);
+            let elapsed = now - since;
+            if self.reported == Some(true) && elapsed < hold {
+                recheck.replace(hold - elapsed);
+                true
+            } else {
+                false
+            }
+        };
+        self.reported.replace(report);
+        (report, recheck)
+    }
+}
+
+/// The firmware versions reported for a device. Govee reports
+/// these via the undocumented API and LAN discovery; each may be
+/// absent depending on how we learned about the device.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub software: Option<String>,
+    pub hardware: Option<String>,
+    pub wifi_software: Option<String>,
+    pub wifi_hardware: Option<String>,
+    pub ble_software: Option<String>,
+    pub ble_hardware: Option<String>,
+}
+
+impl FirmwareVersion {
+    /// The version to display for the device as a whole
+    pub fn summary(&self) -> Option<&str> {
+        self.software
+            .as_deref()
+            .or(self.wifi_software.as_deref())
+            .or(self.ble_software.as_deref())
+    }
+}
+
+/// Govee sometimes reports absent versions as empty strings
+fn non_empty_version(version: &str) -> Option<String> {
+    let version = version.trim();
+    if version.is_empty() {
+        None
+    } else {
+        Some(version.to_string())
+    }
+}
+
+/// A device that hasn't responded to this many polls in a
+/// row is considered to be unreachable
+const UNREACHABLE_POLL_COUNT: i32 = 3;
+
+/// Platform API state is only preferred over LAN state if it
+/// is at least this much newer
+const LAN_STATE_PREFERENCE_WINDOW: chrono::Duration = chrono::Duration::seconds(30);
+
+/// Capability instances used by purifiers to report the
+/// remaining filter life as a percentage
+pub const FILTER_LIFE_INSTANCES: &[&str] = &["filterLifeTime", "filterLife"];
+
+/// Capability instances used by purifiers to report that
+/// the filter has expired and should be replaced
+pub const FILTER_EXPIRED_INSTANCES: &[&str] = &["filterExpiredEvent", "filterExpired"];
+
+/// A cumulative usage counter that some appliances report, such as
+/// how long they have been running, which is exposed as a sensor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsageStatistic {
+    /// The capability instances that report it
+    pub instances: &'static [&'static str],
+    pub name: &'static str,
+    /// Used in the unique id of the sensor
+    pub id: &'static str,
+    pub icon: &'static str,
+    /// Runtimes are normalized to hours, whatever
+    /// unit the device reports them in
+    pub is_runtime: bool,
+}
+
+pub const USAGE_STATISTICS: &[UsageStatistic] = &[
+    UsageStatistic {
+        instances: &["totalRunTime", "runTime", "workingTime", "totalWorkTime"],
+        name: "Total Runtime",
+        id: "total-runtime",
+        icon: "mdi:timer-outline",
+        is_runtime: true,
+    },
+    UsageStatistic {
+        instances: &["waterTankCycles", "tankCycles", "waterCycles"],
+        name: "Water Tank Cycles",
+        id: "water-tank-cycles",
+        icon: "mdi:water-sync",
+        is_runtime: false,
+    },
+];
+
+/// Returns true if instance reports one of the USAGE_STATISTICS
+pub fn is_usage_statistic_instance(instance: &str) -> bool {
+    USAGE_STATISTICS
+        .iter()
+        .any(|stat| stat.instances.contains(&instance))
+}
+
+/// Convert a runtime reported in unit to hours. Runtimes
+/// without a recognized unit are assumed to be in hours.
+fn runtime_hours(unit: Option<&str>, value: f64) -> f64 {
+    let unit = unit.unwrap_or_default().to_ascii_lowercase();
+    let unit = unit.strip_prefix("unit.").unwrap_or(&unit);
+    match unit {
+        "s" | "sec" | "second" | "seconds" => value / 3600.,
+        "m" | "min" | "minute" | "minutes" => value / 60.,
+        _ => value,
+    }
+}
+
+/// For devices that report filter life but not expiry, the
+/// filter is considered to need replacing at or below this
+/// percentage
+const FILTER_REPLACEMENT_THRESHOLD: u8 = 5;
+
 /// Represents the device state; synthesized from the various
 /// sources of facts that we have in the Device
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -125,16 +380,36 @@ impl Device {
     /// Returns the name defined for the device in the Govee App
     pub fn govee_name(&self) -> Option<&str> {
         if let Some(info) = &self.http_device_info {
-            return Some(&info.device_name);
+            if !info.device_name.is_empty() {
+                return Some(&info.device_name);
+            }
+        }
+        if let Some(info) = &self.undoc_device_info {
+            if !info.entry.device_name.is_empty() {
+                return Some(&info.entry.device_name);
+            }
         }
         None
     }
 
+    /// Returns the name to use for the device in hass
+    pub fn hass_name(&self) -> String {
+        self.hass_name.clone().unwrap_or_else(|| self.name())
+    }
+
+    /// Returns the room that the device is assigned to in the Govee
+    /// App, preferring the room reported by the undocumented API
     pub fn room_name(&self) -> Option<&str> {
-        if let Some(info) = &self.undoc_device_info {
-            return info.room_name.as_deref();
-        }
-        None
+        self.undoc_device_info
+            .as_ref()
+            .and_then(|info| info.room_name.as_deref())
+            .or_else(|| {
+                self.http_device_info
+                    .as_ref()
+                    .and_then(|info| info.room_name.as_deref())
+            })
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
     }
 
     /// compute a name from the SKU and the last couple of bytes from the
@@ -142,6 +417,11 @@ impl Device {
     /// scan, or the default name for the device if not otherwise configured
     /// in the Govee App.
     pub fn computed_name(&self) -> String {
+        format!("{}_{}", self.sku, self.short_id())
+    }
+
+    /// Returns the last couple of bytes of the device id
+    pub fn short_id(&self) -> String {
         // The id is usually "XX:XX:XX:XX:XX:XX:XX:XX" but some devices
         // report it without colons, and in lowercase.  Normalize it.
         let mut id = String::new();
@@ -152,10 +432,20 @@ impl Device {
             id.push(c.to_ascii_uppercase());
         }
 
-        format!("{}_{}", self.sku, &id[id.len().saturating_sub(4)..])
+        id[id.len().saturating_sub(4)..].to_string()
     }
 
     pub fn preferred_poll_interval(&self) -> chrono::Duration {
+        if let Some(last) = &self.last_controlled {
+            if Utc::now() - last < *CONTROL_POLL_WINDOW {
+                return *CONTROL_POLL_INTERVAL;
+            }
+        }
+
+        if let Some(interval) = self.resolve_quirk().and_then(|q| q.poll_interval) {
+            return interval;
+        }
+
         match self.device_type() {
             // If the kettle is on, read its temperature more frequently
             DeviceType::Kettle => {
@@ -165,6 +455,7 @@ impl Device {
                     *POLL_INTERVAL
                 }
             }
+            _ if self.lan_device.is_some() => *LAN_POLL_INTERVAL,
             _ => *POLL_INTERVAL,
         }
     }
@@ -177,6 +468,93 @@ impl Device {
         self.last_polled.replace(Utc::now());
     }
 
+    /// Records a user initiated refresh request, returning false
+    /// if another was made less than min_interval ago.
+    pub fn try_begin_refresh(
+        &mut self,
+        now: DateTime<Utc>,
+        min_interval: chrono::Duration,
+    ) -> bool {
+        if let Some(last) = self.last_refresh_request {
+            if now - last < min_interval {
+                return false;
+            }
+        }
+        self.last_refresh_request.replace(now);
+        true
+    }
+
+    pub fn set_last_controlled(&mut self) {
+        self.last_controlled.replace(Utc::now());
+    }
+
+    pub fn set_last_toggle_state(&mut self, instance: &str, on: bool) {
+        if instance == GRADIENT_INSTANCE && on {
+            // Blending the segments replaces whatever scene was running
+            self.active_scene.take();
+        }
+        self.last_toggle_state
+            .insert(instance.to_string(), (on, Utc::now()));
+    }
+
+    pub fn set_fan_speed_percent(&mut self, percent: u8) {
+        self.fan_speed_percent.replace(percent);
+    }
+
+    pub fn set_segment_brightness(&mut self, segments: &[u32], percent: u8) {
+        for segment in segments {
+            self.segment_brightness.insert(*segment, percent);
+        }
+    }
+
+    pub fn set_segment_color(&mut self, segments: &[u32], color: DeviceColor) {
+        let now = Utc::now();
+        for segment in segments {
+            self.segment_colors.insert(*segment, (color, now));
+        }
+    }
+
+    /// Returns the current color of a segment; either the one that
+    /// was most recently commanded, or the one reported by the
+    /// Platform API if that is more recent
+    pub fn segment_color(&self, segment: u32) -> Option<DeviceColor> {
+        let reported = self
+            .reported_segment_colors()
+            .and_then(|colors| colors.get(&segment).copied());
+        match (self.segment_colors.get(&segment), reported) {
+            (Some((commanded, when)), Some(reported)) => match self.last_http_device_state_update {
+                Some(updated) if updated > *when => Some(reported),
+                _ => Some(*commanded),
+            },
+            (Some((commanded, _)), None) => Some(*commanded),
+            (None, reported) => reported,
+        }
+    }
+
+    pub fn debounce_binary_state(
+        &mut self,
+        instance: &str,
+        on: bool,
+        hold: chrono::Duration,
+    ) -> (bool, Option<chrono::Duration>) {
+        self.binary_state_debounce
+            .entry(instance.to_string())
+            .or_default()
+            .update(on, Utc::now(), hold)
+    }
+
+    pub fn set_music_sensitivity(&mut self, sensitivity: u8) {
+        self.music_sensitivity.replace(sensitivity);
+    }
+
+    /// Returns the name of the active music mode, if any
+    pub fn active_music_mode(&self) -> Option<String> {
+        self.active_scene
+            .as_ref()
+            .and_then(|info| info.name.strip_prefix("Music: "))
+            .map(|mode| mode.to_string())
+    }
+
     pub fn set_nightlight_state(&mut self, params: NotifyHumidifierNightlightParams) {
         self.nightlight_state.replace(params);
     }
@@ -215,6 +593,25 @@ impl Device {
         self.clear_scene_if_color_changed();
     }
 
+    /// Records that the device was heard from via IoT, without
+    /// any change to the state that it last reported
+    pub fn touch_iot_device_status(&mut self) {
+        if self.iot_device_status.is_some() {
+            self.last_iot_device_status_update.replace(Utc::now());
+        }
+    }
+
+    /// Associates the device with an account, unless it has already
+    /// been discovered through another account; a device shared
+    /// between accounts is controlled through the first one.
+    /// Returns true if the device belongs to the account.
+    pub fn claim_account(&mut self, account: Option<&str>) -> bool {
+        if self.http_device_info.is_none() && self.undoc_device_info.is_none() {
+            self.account = account.map(|s| s.to_string());
+        }
+        self.account.as_deref() == account
+    }
+
     pub fn set_http_device_info(&mut self, info: HttpDeviceInfo) {
         self.http_device_info.replace(info);
         self.last_http_device_update.replace(Utc::now());
@@ -288,10 +685,6 @@ impl Device {
         let mut color = DeviceColor::default();
         let mut kelvin = 0;
 
-        #[derive(serde::Deserialize)]
-        struct IntegerValueState {
-            value: u32,
-        }
         #[derive(serde::Deserialize)]
         struct BoolValueState {
             value: bool,
@@ -300,31 +693,38 @@ impl Device {
         let light_instance = self.get_light_power_toggle_instance_name();
 
         for cap in &state.capabilities {
-            if let Ok(value) = serde_json::from_value::<IntegerValueState>(cap.state.clone()) {
+            if let Some(value) = integer_state_value(&cap.state) {
                 if light_instance
                     .as_deref()
                     .map(|inst| inst == cap.instance.as_str())
                     .unwrap_or(false)
                 {
-                    light_on.replace(value.value != 0);
+                    light_on.replace(value != 0);
                 }
 
                 match cap.instance.as_str() {
                     "powerSwitch" => {
-                        on = value.value != 0;
+                        on = value != 0;
                     }
                     "colorRgb" => {
                         color = DeviceColor {
-                            r: ((value.value >> 16) & 0xff) as u8,
-                            g: ((value.value >> 8) & 0xff) as u8,
-                            b: (value.value & 0xff) as u8,
+                            r: ((value >> 16) & 0xff) as u8,
+                            g: ((value >> 8) & 0xff) as u8,
+                            b: (value & 0xff) as u8,
                         };
                     }
                     "brightness" => {
-                        brightness = value.value as u8;
+                        brightness = match self
+                            .http_device_info
+                            .as_ref()
+                            .and_then(|info| info.brightness_range())
+                        {
+                            Some(range) => range.native_to_percent(value),
+                            None => value.min(100) as u8,
+                        };
                     }
                     "colorTemperatureK" => {
-                        kelvin = value.value;
+                        kelvin = value;
                     }
                     _ => {}
                 }
@@ -348,15 +748,37 @@ impl Device {
         })
     }
 
+    /// Returns the color of each segment, keyed by segment index, as
+    /// most recently reported via the Platform API. Returns None if the
+    /// device doesn't report the colors of its segments.
+    pub fn reported_segment_colors(&self) -> Option<HashMap<u32, DeviceColor>> {
+        let state = self.http_device_state.as_ref()?;
+        let cap = state
+            .capabilities
+            .iter()
+            .find(|cap| cap.instance == "segmentedColorRgb")?;
+        decode_segment_colors(cap.state.get("value")?)
+    }
+
     /// Returns the most recently received state information
     pub fn device_state(&self) -> Option<DeviceState> {
         let mut candidates = vec![];
 
-        if let Some(state) = self.compute_lan_device_state() {
-            candidates.push(state);
-        }
-        if let Some(state) = self.compute_http_device_state() {
-            candidates.push(state);
+        let lan_state = self.compute_lan_device_state();
+        let http_state = self.compute_http_device_state();
+
+        // The platform API can continue to report the old state for
+        // a little while after a change, so we prefer LAN data
+        // unless the platform data is substantially newer
+        if let (Some(lan), Some(http)) = (&lan_state, &http_state) {
+            if http.updated - lan.updated < LAN_STATE_PREFERENCE_WINDOW {
+                candidates.push(lan.clone());
+            } else {
+                candidates.push(http.clone());
+            }
+        } else {
+            candidates.extend(lan_state);
+            candidates.extend(http_state);
         }
         if let Some(state) = self.compute_iot_device_state() {
             candidates.push(state);
@@ -364,11 +786,44 @@ impl Device {
 
         candidates.sort_by(|a, b| a.updated.cmp(&b.updated));
 
-        candidates.pop()
+        candidates.pop().or_else(|| {
+            self.persisted_state
+                .as_ref()
+                .map(|state| state.to_device_state())
+        })
+    }
+
+    /// Apply state that was saved prior to a restart. It will be
+    /// reported only until fresher data is available.
+    pub fn set_persisted_state(&mut self, state: PersistedDeviceState) {
+        if self.target_humidity_percent.is_none() {
+            self.target_humidity_percent = state.target_humidity_percent;
+        }
+        if self.humidifier_work_mode.is_none() {
+            self.humidifier_work_mode = state.humidifier_work_mode;
+        }
+        self.persisted_state.replace(state);
+    }
+
+    pub fn set_last_snapshot(&mut self, snapshot: &str) {
+        self.active_scene.take();
+        self.last_snapshot.replace(snapshot.to_string());
+    }
+
+    pub fn last_snapshot(&self) -> Option<&str> {
+        self.last_snapshot.as_deref()
+    }
+
+    /// Records the scene that the device reported as being active
+    /// via the Platform API, which may have been set via the Govee
+    /// App. name is None if the scene isn't one that we know of.
+    pub fn set_reported_scene(&mut self, name: Option<&str>) {
+        self.set_active_scene(Some(name.unwrap_or(UNKNOWN_SCENE)));
     }
 
     /// Records the active scene name
     pub fn set_active_scene(&mut self, scene: Option<&str>) {
+        self.last_snapshot.take();
         match scene {
             None => {
                 self.active_scene.take();
@@ -383,6 +838,12 @@ impl Device {
                     color,
                     kelvin,
                 });
+                // The device stops blending its segments while a scene
+                // is running
+                if self.get_capability_by_instance(GRADIENT_INSTANCE).is_some() {
+                    self.last_toggle_state
+                        .insert(GRADIENT_INSTANCE.to_string(), (false, Utc::now()));
+                }
             }
         }
     }
@@ -404,10 +865,13 @@ impl Device {
     }
 
     pub fn device_type(&self) -> DeviceType {
+        if let Some(device_type) = resolve_quirk(&self.sku).and_then(|q| q.device_type_override) {
+            return device_type;
+        }
         if let Some(info) = &self.http_device_info {
             info.device_type.clone()
         } else if let Some(q) = resolve_quirk(&self.sku) {
-            q.device_type.clone()
+            q.device_type
         } else {
             DeviceType::Light
         }
@@ -451,7 +915,8 @@ impl Device {
             if quirk.avoid_platform_api {
                 return true;
             }
-            if self.lan_device.is_some()
+            if quirk.supports_rgb
+                && self.lan_device.is_some()
                 && !self
                     .http_device_info
                     .as_ref()
@@ -469,19 +934,37 @@ impl Device {
         false
     }
 
+    /// Returns the calibration to apply to the sensor readings of
+    /// this device, preferring any that the user configured for it
+    pub fn sensor_calibration(&self) -> SensorCalibration {
+        self.configured_sensor_calibration(&sensor_calibrations())
+            .or_else(|| self.resolve_quirk().and_then(|q| q.sensor_calibration))
+            .unwrap_or_default()
+    }
+
+    /// Returns the calibration from entries that applies to this device.
+    /// An entry for the device id takes precedence over one for its SKU.
+    fn configured_sensor_calibration(
+        &self,
+        entries: &[SensorCalibrationEntry],
+    ) -> Option<SensorCalibration> {
+        entries
+            .iter()
+            .filter(|entry| entry_matches(&entry.device, self))
+            .min_by_key(|entry| entry.device.trim().eq_ignore_ascii_case(&self.sku))
+            .map(|entry| entry.calibration)
+    }
+
     pub fn resolve_quirk(&self) -> Option<Quirk> {
-        match resolve_quirk(&self.sku) {
-            Some(q) => Some(q.clone()),
-            None => {
-                // It's an unknown device, but since it showed up via LAN disco,
-                // we can assume that it is a light
-                if self.lan_device.is_some() {
-                    Some(Quirk::light(Cow::Owned(self.sku.to_string()), BULB).with_lan_api())
-                } else {
-                    None
-                }
+        resolve_quirk(&self.sku).or_else(|| {
+            // It's an unknown device, but since it showed up via LAN disco,
+            // we can assume that it is a light
+            if self.lan_device.is_some() {
+                Some(Quirk::light(Cow::Owned(self.sku.to_string()), BULB).with_lan_api())
+            } else {
+                None
             }
-        }
+        })
     }
 
     pub fn get_capability_by_instance(&self, instance: &str) -> Option<&DeviceCapability> {
@@ -499,6 +982,228 @@ impl Device {
             .and_then(|info| info.capability_by_instance(instance))
     }
 
+    /// Returns the battery level as a percentage, if the device
+    /// reports one. The platform API state is preferred, falling
+    /// back to the settings returned by the undocumented API.
+    pub fn battery_percent(&self) -> Option<u8> {
+        let value = self
+            .get_state_capability_by_instance("battery")
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|v| v.as_i64())
+            .or_else(|| {
+                self.undoc_device_info
+                    .as_ref()
+                    .and_then(|info| info.entry.device_ext.device_settings.battery)
+            })?;
+        Some(value.clamp(0, 100) as u8)
+    }
+
+    /// Returns the remaining filter life as a percentage, for
+    /// purifiers that report it via one of `FILTER_LIFE_INSTANCES`.
+    pub fn filter_life_percent(&self) -> Option<u8> {
+        let value = FILTER_LIFE_INSTANCES.iter().find_map(|instance| {
+            self.get_state_capability_by_instance(instance)
+                .and_then(|cap| cap.state.pointer("/value"))
+                .and_then(|v| v.as_f64())
+        })?;
+        Some(value.round().clamp(0., 100.) as u8)
+    }
+
+    /// Returns true if the device advertises a filter life capability
+    pub fn has_filter_life(&self) -> bool {
+        FILTER_LIFE_INSTANCES
+            .iter()
+            .any(|instance| self.get_capability_by_instance(instance).is_some())
+    }
+
+    /// Returns the instance via which the device reports stat, if any
+    pub fn usage_statistic_instance(&self, stat: &UsageStatistic) -> Option<&'static str> {
+        stat.instances
+            .iter()
+            .copied()
+            .find(|instance| self.get_capability_by_instance(instance).is_some())
+    }
+
+    /// Returns the current value of a cumulative usage counter
+    pub fn usage_statistic(&self, stat: &UsageStatistic) -> Option<f64> {
+        let instance = self.usage_statistic_instance(stat)?;
+        let value = self
+            .get_state_capability_by_instance(instance)?
+            .state
+            .pointer("/value")?
+            .as_f64()?;
+        if !stat.is_runtime {
+            return Some(value);
+        }
+        let unit =
+            self.get_capability_by_instance(instance)
+                .and_then(|cap| match &cap.parameters {
+                    Some(DeviceParameters::Integer { unit, .. }) => unit.as_deref(),
+                    _ => None,
+                });
+        Some(runtime_hours(unit, value))
+    }
+
+    /// Returns true if the device advertises a filter-expired capability
+    pub fn has_filter_expired(&self) -> bool {
+        FILTER_EXPIRED_INSTANCES
+            .iter()
+            .any(|instance| self.get_capability_by_instance(instance).is_some())
+    }
+
+    /// Returns whether the filter needs replacing.
+    /// An explicit filter-expired report from the device takes
+    /// precedence; otherwise it is inferred from the remaining
+    /// filter life, for devices that only report that.
+    pub fn filter_needs_replacement(&self) -> Option<bool> {
+        let expired = FILTER_EXPIRED_INSTANCES.iter().find_map(|instance| {
+            let value = self
+                .get_state_capability_by_instance(instance)?
+                .state
+                .pointer("/value")?;
+            value.as_bool().or_else(|| value.as_i64().map(|v| v != 0))
+        });
+        expired.or_else(|| {
+            self.filter_life_percent()
+                .map(|percent| percent <= FILTER_REPLACEMENT_THRESHOLD)
+        })
+    }
+
+    /// Returns whether the device is connected to the Govee cloud,
+    /// preferring the platform API state over the possibly stale
+    /// data returned by the undocumented API at startup.
+    pub fn is_online(&self) -> Option<bool> {
+        self.get_state_capability_by_instance("online")
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|v| v.as_bool())
+            .or_else(|| {
+                self.undoc_device_info
+                    .as_ref()
+                    .and_then(|info| info.entry.device_ext.last_device_data.online)
+            })
+    }
+
+    /// Returns the time at which we last received state for the
+    /// device from any of the APIs. After a restart, this is the
+    /// time recorded in the state file until the device is polled.
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        [
+            self.last_lan_device_status_update,
+            self.last_http_device_state_update,
+            self.last_iot_device_status_update,
+            self.persisted_state.as_ref().map(|state| state.updated),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+    }
+
+    /// Returns whether the device currently appears to be reachable:
+    /// it isn't reported as offline, and it has responded to our
+    /// recent polls. We assume that devices for which we have no
+    /// live state are reachable.
+    pub fn appears_reachable(&self, now: DateTime<Utc>) -> bool {
+        if self.is_online() == Some(false) {
+            return false;
+        }
+        match self.device_state() {
+            Some(state) if state.source != PERSISTED_SOURCE => {
+                now - state.updated <= self.preferred_poll_interval() * UNREACHABLE_POLL_COUNT
+            }
+            _ => true,
+        }
+    }
+
+    /// Updates the availability of the device, only reporting it as
+    /// unavailable once it has been unreachable for the grace period.
+    /// Returns the availability and whether it needs to be published.
+    pub fn update_availability(
+        &mut self,
+        now: DateTime<Utc>,
+        grace: chrono::Duration,
+    ) -> (bool, bool) {
+        let reachable = self.appears_reachable(now);
+        let (available, _) = self.availability.update(reachable, now, grace);
+        // Govee telling us that the device is no longer in the
+        // account takes effect without waiting for the grace period
+        let available = available && self.missing_from_device_list == 0;
+        let changed = self.published_availability != Some(available);
+        self.published_availability.replace(available);
+        (available, changed)
+    }
+
+    /// Returns the availability that we last published for the
+    /// device, or None if we haven't evaluated it yet
+    pub fn published_availability(&self) -> Option<bool> {
+        self.published_availability
+    }
+
+    /// Returns the WiFi signal strength, if known
+    pub fn wifi_rssi(&self) -> Option<i64> {
+        self.undoc_device_info
+            .as_ref()
+            .and_then(|info| info.entry.device_ext.device_settings.wifi_level)
+    }
+
+    /// Returns the firmware versions reported for the device,
+    /// or None if we don't know any of them
+    pub fn firmware_version(&self) -> Option<FirmwareVersion> {
+        let mut version = FirmwareVersion::default();
+
+        if let Some(info) = &self.undoc_device_info {
+            version.software = non_empty_version(&info.entry.version_soft);
+            version.hardware = non_empty_version(&info.entry.version_hard);
+            let settings = &info.entry.device_ext.device_settings;
+            version.wifi_software = settings
+                .wifi_soft_version
+                .as_deref()
+                .and_then(non_empty_version);
+            version.wifi_hardware = settings
+                .wifi_hard_version
+                .as_deref()
+                .and_then(non_empty_version);
+        }
+
+        if let Some(lan) = &self.lan_device {
+            version.wifi_software = version
+                .wifi_software
+                .or_else(|| non_empty_version(&lan.wifi_version_soft));
+            version.wifi_hardware = version
+                .wifi_hardware
+                .or_else(|| non_empty_version(&lan.wifi_version_hard));
+            version.ble_software = non_empty_version(&lan.ble_version_soft);
+            version.ble_hardware = non_empty_version(&lan.ble_version_hard);
+        }
+
+        if version == FirmwareVersion::default() {
+            None
+        } else {
+            Some(version)
+        }
+    }
+
+    /// Returns the state of a toggle capability. The state that we
+    /// most recently commanded takes precedence over the state that
+    /// was last polled from the platform API, until the next poll.
+    pub fn toggle_state(&self, instance: &str) -> Option<bool> {
+        let reported = self
+            .get_state_capability_by_instance(instance)
+            .and_then(|cap| {
+                integer_state_value(&cap.state)
+                    .map(|n| n != 0)
+                    .or_else(|| cap.state.pointer("/value").and_then(|v| v.as_bool()))
+            });
+        let commanded = self.last_toggle_state.get(instance).copied();
+
+        match (reported, commanded, self.last_http_device_state_update) {
+            (Some(_), Some((on, commanded_at)), Some(polled_at)) if commanded_at > polled_at => {
+                Some(on)
+            }
+            (Some(reported), _, _) => Some(reported),
+            (None, commanded, _) => commanded.map(|(on, _)| on),
+        }
+    }
+
     pub fn get_light_power_toggle_instance_name(&self) -> Option<&'static str> {
         match self.device_type() {
             DeviceType::Light => Some("powerSwitch"),
@@ -521,7 +1226,56 @@ impl Device {
         }
     }
 
+    /// Returns true if the device should have a primary light entity
+    pub fn has_light(&self) -> bool {
+        self.supports_rgb()
+            || self.get_color_temperature_range().is_some()
+            || self.supports_brightness()
+            || self.is_forced_light()
+    }
+
+    /// Returns whether the entities of the device should be
+    /// optimistic, which by default is the case when we don't
+    /// receive state updates via the IoT API. A quirk may override this.
+    pub fn optimistic(&self, use_iot: bool) -> bool {
+        self.resolve_quirk()
+            .and_then(|q| q.optimistic)
+            .unwrap_or(!use_iot)
+    }
+
+    /// Returns true if a quirk forces the device to be treated as a
+    /// light, in which case it gets a light entity even if all that
+    /// it can do is to turn on and off
+    fn is_forced_light(&self) -> bool {
+        self.resolve_quirk().and_then(|q| q.device_type_override) == Some(DeviceType::Light)
+    }
+
+    /// Returns the instance name of the toggle that controls a
+    /// nightlight or indicator light on the device, if any
+    pub fn nightlight_toggle_instance(&self) -> Option<&'static str> {
+        ["nightlightToggle", "nightlight"]
+            .into_iter()
+            .find(|instance| self.get_capability_by_instance(instance).is_some())
+    }
+
+    /// Returns true if the nightlight should be represented as a
+    /// light entity of its own. That is the case for lights, and for
+    /// other appliances when there is no primary light entity; when
+    /// there is, its power toggle already controls the nightlight.
+    pub fn has_secondary_nightlight(&self) -> bool {
+        self.nightlight_toggle_instance().is_some()
+            && (self.device_type() == DeviceType::Light || !self.has_light())
+    }
+
     pub fn get_color_temperature_range(&self) -> Option<(u32, u32)> {
+        // The platform API knows the actual range supported by
+        // the device, so prefer that unless we know it is bogus
+        if !self.avoid_platform_api() {
+            if let Some(info) = &self.http_device_info {
+                return info.get_color_temperature_range();
+            }
+        }
+
         if let Some(quirk) = self.resolve_quirk() {
             return quirk.color_temp_range;
         }
@@ -536,6 +1290,12 @@ impl Device {
             .and_then(|info| info.get_color_temperature_range())
     }
 
+    /// Returns true if the device has a dedicated means of setting
+    /// the color temperature, rather than approximating it using RGB
+    pub fn supports_native_color_temperature(&self) -> bool {
+        self.get_color_temperature_range().is_some()
+    }
+
     pub fn supports_brightness(&self) -> bool {
         if let Some(quirk) = self.resolve_quirk() {
             return quirk.supports_brightness;
@@ -603,9 +1363,408 @@ impl Device {
     }
 }
 
+/// Returns the value of an integer state capability. Some devices
+/// report integers, such as their color temperature, as floating
+/// point numbers or strings, so those are accepted too.
+fn integer_state_value(state: &JsonValue) -> Option<u32> {
+    let value = state.get("value")?;
+    if let Some(n) = value.as_u64() {
+        return u32::try_from(n).ok();
+    }
+    let n = match value {
+        JsonValue::String(s) => s.trim().parse::<f64>().ok()?,
+        _ => value.as_f64()?,
+    };
+    (n.is_finite() && n >= 0. && n <= u32::MAX as f64).then(|| n.round() as u32)
+}
+
+/// Decode the state of a segmentedColorRgb capability, which is a
+/// list of entries like `{"segment": [0, 1], "rgb": 16711680}`, or a
+/// single such entry. Most devices report an empty string instead.
+fn decode_segment_colors(value: &JsonValue) -> Option<HashMap<u32, DeviceColor>> {
+    #[derive(serde::Deserialize)]
+    struct SegmentColor {
+        segment: Vec<u32>,
+        rgb: u32,
+    }
+
+    let entries: Vec<SegmentColor> = match value {
+        JsonValue::Array(_) => serde_json::from_value(value.clone()).ok()?,
+        JsonValue::Object(_) => vec![serde_json::from_value(value.clone()).ok()?],
+        _ => return None,
+    };
+
+    let mut colors = HashMap::new();
+    for entry in entries {
+        let color = DeviceColor {
+            r: ((entry.rgb >> 16) & 0xff) as u8,
+            g: ((entry.rgb >> 8) & 0xff) as u8,
+            b: (entry.rgb & 0xff) as u8,
+        };
+        for segment in entry.segment {
+            colors.insert(segment, color);
+        }
+    }
+    (!colors.is_empty()).then_some(colors)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::platform_api::DeviceCapabilityKind;
+
+    #[test]
+    fn segment_colors() {
+        let colors = decode_segment_colors(&serde_json::json!([
+            {"segment": [0, 2], "rgb": 0xff8000},
+            {"segment": [1], "rgb": 0x0000ff},
+        ]))
+        .unwrap();
+        assert_eq!(colors.len(), 3);
+        assert_eq!(
+            colors[&2],
+            DeviceColor {
+                r: 255,
+                g: 128,
+                b: 0
+            }
+        );
+        assert_eq!(colors[&1], DeviceColor { r: 0, g: 0, b: 255 });
+
+        let colors =
+            decode_segment_colors(&serde_json::json!({"segment": [3], "rgb": 0x00ff00})).unwrap();
+        assert_eq!(colors[&3], DeviceColor { r: 0, g: 255, b: 0 });
+
+        assert_eq!(decode_segment_colors(&serde_json::json!("")), None);
+        assert_eq!(decode_segment_colors(&serde_json::json!([])), None);
+
+        let mut device = Device::new("H6199", "AA:BB:CC:DD:EE:FF:42:2A");
+        let report = |device: &mut Device, rgb: u32| {
+            device.set_http_device_state(HttpDeviceState {
+                sku: "H6199".to_string(),
+                device: device.id.to_string(),
+                capabilities: vec![DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::SegmentColorSetting,
+                    instance: "segmentedColorRgb".to_string(),
+                    state: serde_json::json!({"value": [{"segment": [0], "rgb": rgb}]}),
+                }],
+            });
+        };
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        let blue = DeviceColor { r: 0, g: 0, b: 255 };
+
+        report(&mut device, 0xff0000);
+        assert_eq!(device.segment_color(0), Some(red));
+        assert_eq!(device.segment_color(1), None);
+
+        // A commanded color is reported until the next poll
+        device.set_segment_color(&[0], blue);
+        assert_eq!(device.segment_color(0), Some(blue));
+        report(&mut device, 0xff0000);
+        assert_eq!(device.segment_color(0), Some(red));
+    }
+
+    #[test]
+    fn http_color_temperature() {
+        let mut device = Device::new("H6008", "AA:BB:CC:DD:EE:FF:42:2A");
+        let cap = |instance: &str, value: JsonValue| DeviceCapabilityState {
+            kind: DeviceCapabilityKind::ColorSetting,
+            instance: instance.to_string(),
+            state: serde_json::json!({ "value": value }),
+        };
+        device.set_http_device_state(HttpDeviceState {
+            sku: "H6008".to_string(),
+            device: device.id.to_string(),
+            capabilities: vec![
+                cap("colorRgb", serde_json::json!(0)),
+                cap("colorTemperatureK", serde_json::json!(4000.0)),
+            ],
+        });
+        assert_eq!(device.compute_http_device_state().unwrap().kelvin, 4000);
+
+        assert_eq!(
+            integer_state_value(&serde_json::json!({"value": "2700"})),
+            Some(2700)
+        );
+        assert_eq!(integer_state_value(&serde_json::json!({"value": ""})), None);
+        assert_eq!(integer_state_value(&serde_json::json!({"value": -1})), None);
+    }
+
+    #[test]
+    fn commanded_toggle_state() {
+        let mut device = Device::new("H7111", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_last_toggle_state("oscillationToggle", true);
+        assert_eq!(device.toggle_state("oscillationToggle"), Some(true));
+
+        device.set_http_device_state(HttpDeviceState {
+            sku: "H7111".to_string(),
+            device: device.id.to_string(),
+            capabilities: vec![DeviceCapabilityState {
+                kind: DeviceCapabilityKind::Toggle,
+                instance: "oscillationToggle".to_string(),
+                state: serde_json::json!({"value": 0}),
+            }],
+        });
+        // The poll is more recent than the command
+        assert_eq!(device.toggle_state("oscillationToggle"), Some(false));
+
+        // A command is reported until the next poll
+        device
+            .last_http_device_state_update
+            .replace(Utc::now() - chrono::Duration::seconds(1));
+        device.set_last_toggle_state("oscillationToggle", true);
+        assert_eq!(device.toggle_state("oscillationToggle"), Some(true));
+        device.set_last_toggle_state("oscillationToggle", false);
+        assert_eq!(device.toggle_state("oscillationToggle"), Some(false));
+    }
+
+    #[test]
+    fn sensor_calibration_precedence() {
+        let device = Device::new("H5075", "AA:BB:CC:DD:EE:FF:42:2A");
+        let entries: Vec<SensorCalibrationEntry> = ["H5075=-1.5", "AABBCCDDEEFF422A=0.5,2"]
+            .iter()
+            .map(|entry| entry.parse().unwrap())
+            .collect();
+        assert_eq!(
+            device.configured_sensor_calibration(&entries),
+            Some(SensorCalibration {
+                temperature_offset: 0.5,
+                humidity_offset: 2.,
+            })
+        );
+
+        let other = Device::new("H5075", "AA:BB:CC:DD:EE:FF:00:11");
+        assert_eq!(
+            other.configured_sensor_calibration(&entries),
+            Some(SensorCalibration {
+                temperature_offset: -1.5,
+                humidity_offset: 0.,
+            })
+        );
+        assert_eq!(
+            Device::new("H5179", "AA:BB:CC:DD:EE:FF:00:12").configured_sensor_calibration(&entries),
+            None
+        );
+    }
+
+    #[test]
+    fn gradient_and_scenes() {
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("../../test-data/replay/H6601.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(fixture["device"].clone()).unwrap();
+        let mut device = Device::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.to_string(),
+            device: device.id.to_string(),
+            capabilities: vec![DeviceCapabilityState {
+                kind: DeviceCapabilityKind::Toggle,
+                instance: GRADIENT_INSTANCE.to_string(),
+                state: serde_json::json!({"value": "1"}),
+            }],
+        });
+        assert_eq!(device.toggle_state(GRADIENT_INSTANCE), Some(true));
+        device
+            .last_http_device_state_update
+            .replace(Utc::now() - chrono::Duration::seconds(1));
+
+        // Activating a scene turns off the gradient
+        device.set_active_scene(Some("Sunrise"));
+        assert_eq!(device.toggle_state(GRADIENT_INSTANCE), Some(false));
+
+        // and turning the gradient back on replaces the scene
+        device.set_last_toggle_state(GRADIENT_INSTANCE, true);
+        assert_eq!(device.toggle_state(GRADIENT_INSTANCE), Some(true));
+        assert!(device.active_scene.is_none());
+    }
+
+    #[test]
+    fn lan_state_preferred_over_platform() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        let now = Utc::now();
+        device.lan_device_status.replace(LanDeviceStatus::default());
+        device.last_lan_device_status_update.replace(now);
+        device.http_device_state.replace(HttpDeviceState {
+            sku: "H6000".to_string(),
+            device: device.id.to_string(),
+            capabilities: vec![],
+        });
+
+        device
+            .last_http_device_state_update
+            .replace(now + chrono::Duration::seconds(10));
+        assert_eq!(device.device_state().unwrap().source, "LAN API");
+
+        device
+            .last_http_device_state_update
+            .replace(now + chrono::Duration::seconds(60));
+        assert_eq!(device.device_state().unwrap().source, "PLATFORM API");
+    }
+
+    #[test]
+    fn usage_runtime_units() {
+        assert_eq!(runtime_hours(None, 12.), 12.);
+        assert_eq!(runtime_hours(Some("unit.hour"), 12.), 12.);
+        assert_eq!(runtime_hours(Some("unit.minute"), 90.), 1.5);
+        assert_eq!(runtime_hours(Some("seconds"), 7200.), 2.);
+        assert!(is_usage_statistic_instance("totalRunTime"));
+        assert!(!is_usage_statistic_instance("filterLifeTime"));
+    }
+
+    #[test]
+    fn filter_life() {
+        use crate::platform_api::DeviceCapabilityState;
+        let mut device = Device::new("H7121", "AA:BB:CC:DD:EE:FF:42:2A");
+        let set_state = |device: &mut Device, caps: Vec<(&str, serde_json::Value)>| {
+            device.http_device_state.replace(HttpDeviceState {
+                sku: "H7121".to_string(),
+                device: device.id.to_string(),
+                capabilities: caps
+                    .into_iter()
+                    .map(|(instance, value)| DeviceCapabilityState {
+                        kind: crate::platform_api::DeviceCapabilityKind::Property,
+                        instance: instance.to_string(),
+                        state: serde_json::json!({"value": value}),
+                    })
+                    .collect(),
+            });
+        };
+
+        assert_eq!(device.filter_needs_replacement(), None);
+
+        set_state(&mut device, vec![("filterLifeTime", serde_json::json!(42))]);
+        assert_eq!(device.filter_life_percent(), Some(42));
+        assert_eq!(device.filter_needs_replacement(), Some(false));
+
+        set_state(&mut device, vec![("filterLifeTime", serde_json::json!(3))]);
+        assert_eq!(device.filter_needs_replacement(), Some(true));
+
+        set_state(
+            &mut device,
+            vec![
+                ("filterLifeTime", serde_json::json!(50)),
+                ("filterExpired", serde_json::json!(1)),
+            ],
+        );
+        assert_eq!(device.filter_needs_replacement(), Some(true));
+
+        set_state(
+            &mut device,
+            vec![("filterExpired", serde_json::json!(false))],
+        );
+        assert_eq!(device.filter_life_percent(), None);
+        assert_eq!(device.filter_needs_replacement(), Some(false));
+    }
+
+    #[test]
+    fn last_seen() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.last_seen(), None);
+
+        let now = Utc::now();
+        device.last_http_device_state_update.replace(now);
+        device
+            .last_lan_device_status_update
+            .replace(now - chrono::Duration::seconds(30));
+        assert_eq!(device.last_seen(), Some(now));
+
+        // Hearing from the device via IoT counts, even when
+        // the message doesn't change its state
+        device.touch_iot_device_status();
+        assert_eq!(device.last_iot_device_status_update, None);
+        device.set_iot_device_status(LanDeviceStatus::default());
+        device
+            .last_iot_device_status_update
+            .replace(now - chrono::Duration::seconds(60));
+        device.touch_iot_device_status();
+        assert!(device.last_iot_device_status_update.unwrap() >= now);
+    }
+
+    #[test]
+    fn availability() {
+        let grace = chrono::Duration::seconds(300);
+        let now = Utc::now();
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+
+        // We know nothing about it, so assume that it is fine
+        assert_eq!(device.update_availability(now, grace), (true, true));
+        assert_eq!(device.update_availability(now, grace), (true, false));
+
+        device.lan_device_status.replace(LanDeviceStatus::default());
+        device
+            .last_lan_device_status_update
+            .replace(now - chrono::Duration::minutes(10));
+        device.lan_device.replace(LanDevice {
+            ip: "127.0.0.1".parse().unwrap(),
+            device: device.id.to_string(),
+            sku: device.sku.to_string(),
+            ble_version_hard: String::new(),
+            ble_version_soft: String::new(),
+            wifi_version_hard: String::new(),
+            wifi_version_soft: String::new(),
+        });
+        assert!(!device.appears_reachable(now));
+
+        // Held as available until the grace period has elapsed
+        assert_eq!(device.update_availability(now, grace), (true, false));
+        assert_eq!(
+            device.update_availability(now + grace, grace),
+            (false, true)
+        );
+    }
+
+    #[test]
+    fn firmware_version() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.firmware_version(), None);
+
+        device.lan_device.replace(LanDevice {
+            ip: "127.0.0.1".parse().unwrap(),
+            device: device.id.to_string(),
+            sku: device.sku.to_string(),
+            ble_version_hard: "3.01.01".to_string(),
+            ble_version_soft: "1.04.06".to_string(),
+            wifi_version_hard: "1.00.10".to_string(),
+            wifi_version_soft: "".to_string(),
+        });
+        let version = device.firmware_version().unwrap();
+        assert_eq!(version.wifi_software, None);
+        assert_eq!(version.wifi_hardware.as_deref(), Some("1.00.10"));
+        assert_eq!(version.summary(), Some("1.04.06"));
+    }
+
+    #[test]
+    fn refresh_rate_limit() {
+        let interval = chrono::Duration::seconds(10);
+        let start = Utc::now();
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(device.try_begin_refresh(start, interval));
+        assert!(!device.try_begin_refresh(start + chrono::Duration::seconds(3), interval));
+        assert!(device.try_begin_refresh(start + chrono::Duration::seconds(11), interval));
+    }
+
+    #[test]
+    fn binary_state_debounce() {
+        let hold = chrono::Duration::seconds(60);
+        let start = Utc::now();
+        let mut debounce = BinaryStateDebounce::default();
+
+        // Initial off is reported immediately
+        assert_eq!(debounce.update(false, start, hold), (false, None));
+        assert_eq!(debounce.update(true, start, hold), (true, None));
+
+        // A brief dropout is held
+        let t = start + chrono::Duration::seconds(10);
+        assert_eq!(debounce.update(false, t, hold), (true, Some(hold)));
+        let t2 = t + chrono::Duration::seconds(30);
+        assert_eq!(
+            debounce.update(false, t2, hold),
+            (true, Some(chrono::Duration::seconds(30)))
+        );
+        // and released once it has persisted
+        let t3 = t + hold;
+        assert_eq!(debounce.update(false, t3, hold), (false, None));
+    }
 
     #[test]
     fn name_compute() {
@@ -618,4 +1777,101 @@ mod test {
         let device = Device::new("H6127", "ce");
         assert_eq!(device.name(), "H6127_CE");
     }
+
+    #[test]
+    fn hass_names() {
+        let named = |sku: &str, id: &str, name: &str| {
+            let mut device = Device::new(sku, id);
+            device.set_http_device_info(HttpDeviceInfo {
+                sku: sku.to_string(),
+                device: id.to_string(),
+                device_name: name.to_string(),
+                device_type: Default::default(),
+                room_name: None,
+                capabilities: vec![],
+            });
+            device
+        };
+        let devices = vec![
+            named("H6000", "AA:BB:CC:DD:EE:FF:42:2A", "Lamp"),
+            named("H6000", "AA:BB:CC:DD:EE:FF:42:2B", "lamp"),
+            named("H7121", "AA:BB:CC:DD:EE:FF:00:11", "Purifier"),
+            named("H7121", "AA:BB:CC:DD:EE:FF:00:12", ""),
+        ];
+
+        let names = hass_device_names(&devices, false);
+        assert_eq!(names["AA:BB:CC:DD:EE:FF:42:2A"], "Lamp (422A)");
+        assert_eq!(names["AA:BB:CC:DD:EE:FF:42:2B"], "lamp (422B)");
+        assert_eq!(names["AA:BB:CC:DD:EE:FF:00:11"], "Purifier");
+        assert_eq!(names["AA:BB:CC:DD:EE:FF:00:12"], "H7121_0012");
+
+        let names = hass_device_names(&devices, true);
+        assert_eq!(names["AA:BB:CC:DD:EE:FF:42:2A"], "H6000_422A");
+        assert_eq!(names["AA:BB:CC:DD:EE:FF:00:11"], "H7121_0011");
+    }
+
+    #[test]
+    fn room_names() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.room_name(), None);
+
+        let mut info: HttpDeviceInfo = crate::platform_api::from_json(
+            serde_json::json!({
+                "sku": "H6000",
+                "device": device.id,
+                "roomName": "Kitchen",
+                "capabilities": [],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        device.set_http_device_info(info.clone());
+        assert_eq!(device.room_name(), Some("Kitchen"));
+
+        info.room_name.replace(" ".to_string());
+        device.set_http_device_info(info);
+        assert_eq!(device.room_name(), None);
+    }
+
+    fn http_info_with_caps(sku: &str, caps: serde_json::Value) -> HttpDeviceInfo {
+        crate::platform_api::from_json(
+            serde_json::json!({
+                "sku": sku,
+                "device": "AA:BB:CC:DD:EE:FF:42:2A",
+                "capabilities": caps,
+            })
+            .to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn color_temperature_range() {
+        let mut device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        // Nothing but the quirk to go on
+        assert_eq!(device.get_color_temperature_range(), Some((2000, 9000)));
+
+        device.set_http_device_info(http_info_with_caps(
+            "H6072",
+            serde_json::json!([{
+                "type": "devices.capabilities.color_setting",
+                "instance": "colorTemperatureK",
+                "parameters": {
+                    "dataType": "INTEGER",
+                    "range": {"min": 2700, "max": 6500, "precision": 1}
+                }
+            }]),
+        ));
+        assert_eq!(device.get_color_temperature_range(), Some((2700, 6500)));
+
+        // No colorTemperatureK capability means no color temperature
+        device.set_http_device_info(http_info_with_caps("H6072", serde_json::json!([])));
+        assert_eq!(device.get_color_temperature_range(), None);
+
+        // The platform metadata for this one is known to be bogus,
+        // so we use the quirk instead
+        let mut device = Device::new("H6141", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_http_device_info(http_info_with_caps("H6141", serde_json::json!([])));
+        assert_eq!(device.get_color_temperature_range(), Some((2000, 9000)));
+    }
 }
@@ -0,0 +1,73 @@
+use crate::service::device::Device;
+
+/// Controls which devices are exposed to Home Assistant.
+/// Entries may be either a SKU or a device id.
+#[derive(Default, Clone, Debug)]
+pub struct DeviceFilter {
+    /// If non-empty, only devices matching an entry are included
+    pub include: Vec<String>,
+    /// Devices matching an entry are excluded, even if they
+    /// are also included
+    pub exclude: Vec<String>,
+}
+
+fn normalize_id(id: &str) -> String {
+    id.chars()
+        .filter(|&c| c != ':' && c != ' ')
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+/// Returns true if the entry is the SKU or the id of the device
+pub fn entry_matches(entry: &str, device: &Device) -> bool {
+    entry_matches_sku_or_id(entry, &device.sku, &device.id)
+}
+
+/// Returns true if the entry is either sku or id
+pub fn entry_matches_sku_or_id(entry: &str, sku: &str, id: &str) -> bool {
+    let entry = entry.trim();
+    entry.eq_ignore_ascii_case(sku) || normalize_id(entry) == normalize_id(id)
+}
+
+impl DeviceFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn allows(&self, device: &Device) -> bool {
+        if self.exclude.iter().any(|e| entry_matches(e, device)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|e| entry_matches(e, device))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filtering() {
+        let light = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        let other = Device::new("H7160", "AA:BB:CC:DD:EE:FF:00:01");
+
+        let filter = DeviceFilter::default();
+        assert!(filter.allows(&light));
+        assert!(filter.allows(&other));
+
+        let filter = DeviceFilter {
+            include: vec![],
+            exclude: vec!["h7160".to_string()],
+        };
+        assert!(filter.allows(&light));
+        assert!(!filter.allows(&other));
+
+        // ids can be given in the same form as in our topics
+        let filter = DeviceFilter {
+            include: vec!["AABBCCDDEEFF422A".to_string(), "H7160".to_string()],
+            exclude: vec!["aa:bb:cc:dd:ee:ff:00:01".to_string()],
+        };
+        assert!(filter.allows(&light));
+        assert!(!filter.allows(&other));
+    }
+}
@@ -0,0 +1,181 @@
+//! Remembers the discovery configs that we have published to hass,
+//! so that the entities of devices that have been removed from the
+//! Govee account, or that no longer apply to a device, can be removed
+//! from hass rather than lingering as unavailable ghosts.
+//! The record is kept in the cache so that this also works for
+//! devices that were removed while govee2mqtt wasn't running.
+use crate::cache::CACHE;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+const CACHE_TOPIC: &str = "hass-discovery";
+const CACHE_KEY: &str = "published-configs";
+/// Long enough that a device that is offline for an extended
+/// period won't be forgotten
+const CACHE_TTL: Duration = Duration::from_secs(86400 * 365);
+
+/// The identifier of the hass device that represents govee2mqtt itself,
+/// which is used for global entities such as one-click scenes
+pub const SERVICE_IDENTIFIER: &str = "gv2mqtt";
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct DiscoveryRecord {
+    /// The discovery config topic of each published entity,
+    /// mapped to the identifier of the hass device that it belongs to
+    pub configs: BTreeMap<String, String>,
+    /// The identifiers of the devices that were listed by the
+    /// Platform API when the configs were published
+    pub platform_devices: BTreeSet<String>,
+}
+
+/// What we currently know about the devices
+pub struct KnownDevices {
+    /// The identifiers of all of the devices that we know about,
+    /// including those that are excluded
+    pub known: BTreeSet<String>,
+    /// True if the Platform API device list is available, so that
+    /// a device that is missing from it has been removed from the account
+    pub have_platform_list: bool,
+}
+
+impl DiscoveryRecord {
+    pub fn load() -> Self {
+        let result = CACHE
+            .load()
+            .topic(CACHE_TOPIC)
+            .and_then(|topic| topic.get(CACHE_KEY));
+        match result {
+            Ok(Some(value)) => serde_json::from_slice(&value.data).unwrap_or_else(|err| {
+                log::warn!("Ignoring damaged record of published entities: {err:#}");
+                Self::default()
+            }),
+            Ok(None) => Self::default(),
+            Err(err) => {
+                log::warn!("Unable to load the record of published entities: {err:#}");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        CACHE
+            .load()
+            .topic(CACHE_TOPIC)?
+            .set(CACHE_KEY, &data, CACHE_TTL)?;
+        Ok(())
+    }
+
+    /// Compare the previously published configs with those that were
+    /// just published. Returns the config topics that should be removed
+    /// from hass, along with the record that should be saved.
+    ///
+    /// A config that we didn't publish this time is removed if its device
+    /// is still registered, as the entity no longer applies to it, or if
+    /// its device has been removed from the Govee account. A device that
+    /// we simply haven't heard from yet, such as one that is only
+    /// reachable via the LAN API, is kept in the record in case it
+    /// turns up later.
+    pub fn reconcile(&self, current: &Self, devices: &KnownDevices) -> (Vec<String>, Self) {
+        let registered: BTreeSet<&String> = current.configs.values().collect();
+        let mut next = current.clone();
+        let mut stale = vec![];
+
+        for (topic, identifier) in &self.configs {
+            if current.configs.contains_key(topic) {
+                continue;
+            }
+            let removed_from_account = devices.have_platform_list
+                && self.platform_devices.contains(identifier)
+                && !devices.known.contains(identifier);
+
+            if registered.contains(identifier)
+                || identifier == SERVICE_IDENTIFIER
+                || removed_from_account
+            {
+                stale.push(topic.to_string());
+            } else if !devices.known.contains(identifier) {
+                next.configs
+                    .insert(topic.to_string(), identifier.to_string());
+                if self.platform_devices.contains(identifier) {
+                    next.platform_devices.insert(identifier.to_string());
+                }
+            }
+            // Otherwise, the device is excluded, and its entities
+            // were removed along with the other excluded devices
+        }
+
+        (stale, next)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(configs: &[(&str, &str)], platform_devices: &[&str]) -> DiscoveryRecord {
+        DiscoveryRecord {
+            configs: configs
+                .iter()
+                .map(|(topic, id)| (topic.to_string(), id.to_string()))
+                .collect(),
+            platform_devices: platform_devices.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn reconcile() {
+        let previous = record(
+            &[
+                ("light/a/config", "gv2mqtt-A"),
+                ("sensor/a-old/config", "gv2mqtt-A"),
+                ("light/gone/config", "gv2mqtt-GONE"),
+                ("light/lan/config", "gv2mqtt-LAN"),
+                ("light/excluded/config", "gv2mqtt-EXCLUDED"),
+                ("scene/old/config", "gv2mqtt"),
+            ],
+            &["gv2mqtt-A", "gv2mqtt-GONE", "gv2mqtt-EXCLUDED"],
+        );
+        let current = record(&[("light/a/config", "gv2mqtt-A")], &["gv2mqtt-A"]);
+        let devices = KnownDevices {
+            known: ["gv2mqtt-A", "gv2mqtt-EXCLUDED"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            have_platform_list: true,
+        };
+
+        let (stale, next) = previous.reconcile(&current, &devices);
+        k9::assert_equal!(
+            stale,
+            vec![
+                "light/gone/config",
+                "scene/old/config",
+                "sensor/a-old/config"
+            ]
+        );
+        // The LAN device might still turn up, so we remember it
+        k9::assert_equal!(
+            next,
+            record(
+                &[
+                    ("light/a/config", "gv2mqtt-A"),
+                    ("light/lan/config", "gv2mqtt-LAN")
+                ],
+                &["gv2mqtt-A"]
+            )
+        );
+
+        // Without the Platform API device list, we can't
+        // tell that a device was removed from the account
+        let devices = KnownDevices {
+            have_platform_list: false,
+            ..devices
+        };
+        let (stale, next) = previous.reconcile(&current, &devices);
+        k9::assert_equal!(stale, vec!["scene/old/config", "sensor/a-old/config"]);
+        assert!(next.configs.contains_key("light/gone/config"));
+        assert!(next.platform_devices.contains("gv2mqtt-GONE"));
+    }
+}
@@ -0,0 +1,27 @@
+//! In dry-run mode we connect to everything and report device state
+//! to hass as usual, but control commands are logged rather than being
+//! sent to the devices, so that automations and dashboards can be
+//! tested without affecting real devices or using API quota.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(enabled: bool) {
+    DRY_RUN.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Returns true, having logged the command, if we are in dry-run
+/// mode and the command should not be sent to device via the
+/// named API
+pub fn intercept(device: &str, api: &str, command: impl std::fmt::Display) -> bool {
+    if is_dry_run() {
+        log::info!("DRY RUN: would send to {device} via {api}: {command}");
+        true
+    } else {
+        false
+    }
+}
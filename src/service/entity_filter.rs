@@ -0,0 +1,153 @@
+//! A single device can have a lot of entities, many of which may be
+//! of no interest. This allows the user to choose which entities of
+//! a device are registered with hass.
+use crate::hass_mqtt::base::EntityConfig;
+use crate::service::device_filter::entry_matches_sku_or_id;
+use crate::service::entity_overrides::entity_matches;
+use once_cell::sync::Lazy;
+use std::sync::RwLock;
+
+/// A user supplied selection in the form `DEVICE/ENTITY`, where
+/// DEVICE is a device id or SKU, and ENTITY is either the kind of
+/// entity, such as `select` or `sensor`, or identifies one of the
+/// entities of that device
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityFilterEntry {
+    pub device: String,
+    pub entity: String,
+}
+
+impl std::str::FromStr for EntityFilterEntry {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (device, entity) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("expected DEVICE/ENTITY but got {s}"))?;
+        let (device, entity) = (device.trim(), entity.trim());
+        if device.is_empty() || entity.is_empty() {
+            anyhow::bail!("expected DEVICE/ENTITY but got {s}");
+        }
+
+        Ok(Self {
+            device: device.to_string(),
+            entity: entity.to_string(),
+        })
+    }
+}
+
+impl EntityFilterEntry {
+    fn matches(&self, sku: &str, id: &str, integration: &str, unique_id: &str) -> bool {
+        entry_matches_sku_or_id(&self.device, sku, id)
+            && (self.entity.eq_ignore_ascii_case(integration)
+                || entity_matches(&self.entity, unique_id))
+    }
+}
+
+/// Controls which entities of a device are exposed to hass
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntityFilter {
+    /// If any entries apply to a device, only its entities
+    /// that match one of them are included
+    pub include: Vec<EntityFilterEntry>,
+    /// Entities matching an entry are excluded, even if they
+    /// are also included
+    pub exclude: Vec<EntityFilterEntry>,
+}
+
+static ENTITY_FILTER: Lazy<RwLock<EntityFilter>> = Lazy::new(Default::default);
+
+/// Sets the user supplied entity filter, replacing any
+/// that was previously set
+pub fn set_entity_filter(filter: EntityFilter) {
+    *ENTITY_FILTER.write().unwrap() = filter;
+}
+
+impl EntityFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns true if the entity described by base, which is
+    /// registered via the hass integration, should be exposed.
+    /// The entities of the bridge itself are always exposed.
+    pub fn allows(&self, integration: &str, base: &EntityConfig) -> bool {
+        let Some(id) = base
+            .device
+            .identifiers
+            .first()
+            .and_then(|ident| ident.strip_prefix("gv2mqtt-"))
+        else {
+            return true;
+        };
+        let sku = base.device.model.as_str();
+        let unique_id = base.unique_id.as_str();
+
+        if self
+            .exclude
+            .iter()
+            .any(|e| e.matches(sku, id, integration, unique_id))
+        {
+            return false;
+        }
+
+        let mut includes = self
+            .include
+            .iter()
+            .filter(|e| entry_matches_sku_or_id(&e.device, sku, id))
+            .peekable();
+        includes.peek().is_none() || includes.any(|e| e.matches(sku, id, integration, unique_id))
+    }
+}
+
+/// Returns true if the user wants the entity described by base,
+/// which is registered via the hass integration, to be exposed
+pub fn is_entity_included(integration: &str, base: &EntityConfig) -> bool {
+    ENTITY_FILTER.read().unwrap().allows(integration, base)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hass_mqtt::base::Device;
+
+    fn base(sku: &str, id: &str, unique_id: &str) -> EntityConfig {
+        EntityConfig {
+            unique_id: format!("gv2mqtt-{id}-{unique_id}"),
+            device: Device {
+                model: sku.to_string(),
+                identifiers: vec![format!("gv2mqtt-{id}")],
+                ..Device::default()
+            },
+            ..EntityConfig::default()
+        }
+    }
+
+    #[test]
+    fn filtering() {
+        assert!("H6008".parse::<EntityFilterEntry>().is_err());
+        assert!("H6008/".parse::<EntityFilterEntry>().is_err());
+
+        let filter = EntityFilter {
+            include: vec!["H6008/light".parse().unwrap()],
+            exclude: vec!["AA:BB:CC:DD:EE:FF:00:11/gradientToggle".parse().unwrap()],
+        };
+
+        let light = base("H6008", "AABBCCDDEEFF4242", "light");
+        let scenes = base("H6008", "AABBCCDDEEFF4242", "scenes");
+        assert!(filter.allows("light", &light));
+        assert!(!filter.allows("select", &scenes));
+
+        // Devices without an include entry keep all of their entities,
+        // other than those that are excluded
+        let gradient = base("H6199", "AABBCCDDEEFF0011", "gradientToggle");
+        let power = base("H6199", "AABBCCDDEEFF0011", "powerSwitch");
+        assert!(!filter.allows("switch", &gradient));
+        assert!(filter.allows("switch", &power));
+
+        let bridge = EntityConfig {
+            device: Device::this_service(),
+            ..EntityConfig::default()
+        };
+        assert!(filter.allows("button", &bridge));
+    }
+}
@@ -0,0 +1,209 @@
+//! User supplied names and icons for the entities that we register
+//! with hass, so that they don't need to be customized in the hass
+//! UI each time that the entities are rediscovered.
+use crate::hass_mqtt::base::EntityConfig;
+use crate::service::device_filter::entry_matches_sku_or_id;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::sync::RwLock;
+
+/// A user supplied override in the form `DEVICE[/ENTITY]=VALUE`,
+/// where DEVICE is a device id or SKU, and ENTITY identifies one
+/// of the entities of that device
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityOverrideEntry {
+    pub device: String,
+    pub entity: Option<String>,
+    pub value: String,
+}
+
+impl std::str::FromStr for EntityOverrideEntry {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (target, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected DEVICE[/ENTITY]=VALUE but got {s}"))?;
+        let (device, entity) = match target.split_once('/') {
+            Some((device, entity)) => (device.trim(), Some(entity.trim())),
+            None => (target.trim(), None),
+        };
+        let value = value.trim();
+        if device.is_empty() || entity.map(|e| e.is_empty()).unwrap_or(false) || value.is_empty() {
+            anyhow::bail!("expected DEVICE[/ENTITY]=VALUE but got {s}");
+        }
+
+        Ok(Self {
+            device: device.to_string(),
+            entity: entity.map(|e| e.to_string()),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// The overrides that apply to entities, keyed by what they replace
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntityOverrides {
+    pub names: Vec<EntityOverrideEntry>,
+    pub icons: Vec<EntityOverrideEntry>,
+}
+
+static ENTITY_OVERRIDES: Lazy<RwLock<EntityOverrides>> = Lazy::new(Default::default);
+
+/// Sets the user supplied entity overrides, replacing any
+/// that were previously set
+pub fn set_entity_overrides(overrides: EntityOverrides) {
+    *ENTITY_OVERRIDES.write().unwrap() = overrides;
+}
+
+/// Returns true if unique_id is, or ends with, the entity
+/// portion of an override
+pub fn entity_matches(entity: &str, unique_id: &str) -> bool {
+    let unique_id = unique_id.to_ascii_lowercase();
+    let entity = entity.to_ascii_lowercase();
+    unique_id == entity || unique_id.ends_with(&format!("-{entity}"))
+}
+
+/// Find the most specific entry that applies to the entity;
+/// an entry for the entity wins over one for the whole device,
+/// and a device id wins over a SKU
+fn find_entry<'a>(
+    entries: &'a [EntityOverrideEntry],
+    sku: &str,
+    id: &str,
+    unique_id: Option<&str>,
+) -> Option<&'a EntityOverrideEntry> {
+    entries
+        .iter()
+        .filter(|e| entry_matches_sku_or_id(&e.device, sku, id))
+        .filter(|e| match (&e.entity, unique_id) {
+            (None, _) => true,
+            (Some(entity), Some(unique_id)) => entity_matches(entity, unique_id),
+            (Some(_), None) => false,
+        })
+        .max_by_key(|e| (e.entity.is_some(), !e.device.eq_ignore_ascii_case(sku)))
+}
+
+impl EntityOverrides {
+    /// Apply the overrides to config, the serialized discovery
+    /// config for the entity described by base.
+    /// Returns None if no overrides apply to it.
+    pub fn apply(&self, base: &EntityConfig, config: &JsonValue) -> Option<JsonValue> {
+        let id = base.device.identifiers.first()?.strip_prefix("gv2mqtt-")?;
+        let sku = base.device.model.as_str();
+        let unique_id = base.unique_id.as_str();
+
+        // A name for the device as a whole renames the hass device,
+        // while a name for an entity renames just that entity
+        let device_name = find_entry(&self.names, sku, id, None);
+        let entity_name =
+            find_entry(&self.names, sku, id, Some(unique_id)).filter(|e| e.entity.is_some());
+        let icon = find_entry(&self.icons, sku, id, Some(unique_id));
+
+        if device_name.is_none() && entity_name.is_none() && icon.is_none() {
+            return None;
+        }
+
+        let mut config = config.clone();
+        if let Some(entry) = device_name {
+            config["device"]["name"] = entry.value.clone().into();
+        }
+        if let Some(entry) = entity_name {
+            config["name"] = entry.value.clone().into();
+        }
+        if let Some(entry) = icon {
+            config["icon"] = entry.value.clone().into();
+        }
+        Some(config)
+    }
+}
+
+/// Apply the user supplied overrides to the discovery config for
+/// the entity described by base, returning None if none apply to it
+pub fn apply_entity_overrides<T: Serialize>(
+    base: &EntityConfig,
+    config: &T,
+) -> anyhow::Result<Option<JsonValue>> {
+    let overrides = ENTITY_OVERRIDES.read().unwrap();
+    if overrides.names.is_empty() && overrides.icons.is_empty() {
+        return Ok(None);
+    }
+    Ok(overrides.apply(base, &serde_json::to_value(config)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hass_mqtt::base::Device;
+
+    fn base(unique_id: &str) -> EntityConfig {
+        EntityConfig {
+            unique_id: unique_id.to_string(),
+            device: Device {
+                name: "Living Room Fan".to_string(),
+                model: "H7102".to_string(),
+                identifiers: vec!["gv2mqtt-AABBCCDDEEFF0011".to_string()],
+                ..Device::default()
+            },
+            ..EntityConfig::default()
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            "H7102/fan=mdi:fan".parse::<EntityOverrideEntry>().unwrap(),
+            EntityOverrideEntry {
+                device: "H7102".to_string(),
+                entity: Some("fan".to_string()),
+                value: "mdi:fan".to_string(),
+            }
+        );
+        assert_eq!(
+            "AA:BB:CC:DD:EE:FF:00:11 = Bedroom Fan"
+                .parse::<EntityOverrideEntry>()
+                .unwrap(),
+            EntityOverrideEntry {
+                device: "AA:BB:CC:DD:EE:FF:00:11".to_string(),
+                entity: None,
+                value: "Bedroom Fan".to_string(),
+            }
+        );
+        assert!("H7102".parse::<EntityOverrideEntry>().is_err());
+        assert!("H7102/=mdi:fan".parse::<EntityOverrideEntry>().is_err());
+        assert!("H7102=".parse::<EntityOverrideEntry>().is_err());
+    }
+
+    #[test]
+    fn applying() {
+        let overrides = EntityOverrides {
+            names: vec![
+                "AA:BB:CC:DD:EE:FF:00:11=Bedroom Fan".parse().unwrap(),
+                "H7102/oscillationToggle=Swing".parse().unwrap(),
+            ],
+            icons: vec![
+                "H7102=mdi:fan".parse().unwrap(),
+                "AABBCCDDEEFF0011/fan=mdi:ceiling-fan".parse().unwrap(),
+            ],
+        };
+        let config = serde_json::json!({"name": null, "device": {"name": "Living Room Fan"}});
+
+        let fan = overrides
+            .apply(&base("gv2mqtt-AABBCCDDEEFF0011-fan"), &config)
+            .unwrap();
+        assert_eq!(fan["device"]["name"], "Bedroom Fan");
+        assert_eq!(fan["name"], JsonValue::Null);
+        assert_eq!(fan["icon"], "mdi:ceiling-fan");
+
+        let toggle = overrides
+            .apply(&base("gv2mqtt-AABBCCDDEEFF0011-oscillationToggle"), &config)
+            .unwrap();
+        assert_eq!(toggle["name"], "Swing");
+        assert_eq!(toggle["icon"], "mdi:fan");
+
+        let mut other = base("gv2mqtt-0011223344556677-fan");
+        other.device.identifiers = vec!["gv2mqtt-0011223344556677".to_string()];
+        other.device.model = "H7131".to_string();
+        assert_eq!(overrides.apply(&other, &config), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! The `.env` file is loaded into the environment at startup, with
+//! variables that are already set taking precedence. When the
+//! configuration is reloaded the file is read again, but rather than
+//! modifying the environment of the running process, its values are
+//! kept here and consulted by `opt_env_var`.
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::env::VarError;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+#[derive(Default)]
+struct EnvFile {
+    /// The variables that were set before the .env file was loaded
+    from_environment: HashSet<String>,
+    /// Where the .env file was found at startup
+    path: Option<PathBuf>,
+    /// The contents of the .env file as of the last reload, or
+    /// None if it hasn't been reloaded since startup
+    reloaded: Option<HashMap<String, String>>,
+}
+
+impl EnvFile {
+    fn lookup(
+        &self,
+        name: &str,
+        environment: impl Fn(&str) -> Result<String, VarError>,
+    ) -> Result<String, VarError> {
+        match &self.reloaded {
+            Some(values) if !self.from_environment.contains(name) => {
+                values.get(name).cloned().ok_or(VarError::NotPresent)
+            }
+            _ => environment(name),
+        }
+    }
+}
+
+static ENV_FILE: Lazy<RwLock<EnvFile>> = Lazy::new(Default::default);
+
+/// Load the .env file into the environment, leaving any variables
+/// that are already set unchanged. This must be called at startup,
+/// before anything reads the environment.
+pub fn load_at_startup() -> Option<PathBuf> {
+    let mut env_file = ENV_FILE.write().unwrap();
+    env_file.from_environment = std::env::vars_os()
+        .filter_map(|(key, _)| key.into_string().ok())
+        .collect();
+    env_file.path = dotenvy::dotenv().ok();
+    env_file.path.clone()
+}
+
+/// Read the .env file again, so that subsequent calls to
+/// `opt_env_var` reflect its current contents. Variables that
+/// were removed from the file are treated as being unset.
+pub fn reload() -> anyhow::Result<()> {
+    let path = ENV_FILE.read().unwrap().path.clone();
+    let iter = match &path {
+        Some(path) => dotenvy::from_path_iter(path),
+        None => dotenvy::dotenv_iter(),
+    };
+    let mut values = HashMap::new();
+    match iter {
+        Ok(iter) => {
+            for item in iter {
+                let (key, value) = item?;
+                values.insert(key, value);
+            }
+            log::info!("Reloaded environment overrides from {path:?}");
+        }
+        Err(err) if err.not_found() => {}
+        Err(err) => return Err(err.into()),
+    }
+    ENV_FILE.write().unwrap().reloaded.replace(values);
+    Ok(())
+}
+
+/// Look up an environment variable, taking into account the most
+/// recently reloaded contents of the .env file
+pub fn var(name: &str) -> Result<String, VarError> {
+    ENV_FILE
+        .read()
+        .unwrap()
+        .lookup(name, |name| std::env::var(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reloaded_values() {
+        // GOVEE_API_KEY is set in the real environment, and the
+        // others were loaded from the .env file at startup
+        let environment = |name: &str| match name {
+            "GOVEE_API_KEY" => Ok("real".to_string()),
+            "GOVEE_TEMPERATURE_SCALE" | "GOVEE_HASS_DISCOVERY_STAGGER" => Ok("startup".to_string()),
+            _ => Err(VarError::NotPresent),
+        };
+        let mut env_file = EnvFile {
+            from_environment: ["GOVEE_API_KEY".to_string()].into(),
+            ..EnvFile::default()
+        };
+        assert_eq!(
+            env_file.lookup("GOVEE_TEMPERATURE_SCALE", environment),
+            Ok("startup".to_string())
+        );
+
+        env_file.reloaded.replace(
+            [
+                ("GOVEE_API_KEY".to_string(), "file".to_string()),
+                ("GOVEE_TEMPERATURE_SCALE".to_string(), "F".to_string()),
+            ]
+            .into(),
+        );
+        // The real environment takes precedence over the file
+        assert_eq!(
+            env_file.lookup("GOVEE_API_KEY", environment),
+            Ok("real".to_string())
+        );
+        assert_eq!(
+            env_file.lookup("GOVEE_TEMPERATURE_SCALE", environment),
+            Ok("F".to_string())
+        );
+        // Removed from the file since startup
+        assert_eq!(
+            env_file.lookup("GOVEE_HASS_DISCOVERY_STAGGER", environment),
+            Err(VarError::NotPresent)
+        );
+    }
+}
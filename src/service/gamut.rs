@@ -0,0 +1,197 @@
+//! Govee lights can't reproduce every color that hass can ask for;
+//! a color outside of the range that the LEDs can produce comes out
+//! as whatever the device makes of it, which can be surprising.
+//! When enabled, such colors are mapped to the closest color that
+//! the device can display, so that what is shown is predictable.
+use crate::lan_api::DeviceColor;
+
+/// Colors that are closer than this to the gamut, in the CIE xy
+/// plane, are treated as being within it; this avoids nudging colors
+/// that are only outside of it due to rounding
+const TOLERANCE: f64 = 0.002;
+
+/// The range of colors that a device can produce, described as the
+/// CIE 1931 xy chromaticity coordinates of its red, green and blue LEDs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorGamut {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+}
+
+/// A typical gamut for RGB LEDs, used for devices for which
+/// we don't have more specific data
+pub const GENERIC_LED_GAMUT: ColorGamut = ColorGamut {
+    red: (0.6915, 0.3083),
+    green: (0.17, 0.7),
+    blue: (0.1532, 0.0475),
+};
+
+fn srgb_to_linear(c: u8) -> f64 {
+    let c = c as f64 / 255.;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> u8 {
+    let c = c.clamp(0., 1.);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    };
+    (c * 255.).round() as u8
+}
+
+/// Returns the point on the segment a-b that is closest to p
+fn closest_on_segment(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len = dx * dx + dy * dy;
+    if len == 0. {
+        return a;
+    }
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len).clamp(0., 1.);
+    (a.0 + t * dx, a.1 + t * dy)
+}
+
+fn cross(a: (f64, f64), b: (f64, f64), p: (f64, f64)) -> f64 {
+    (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+}
+
+impl ColorGamut {
+    /// Returns whether the primaries describe a triangle
+    /// within the range of valid chromaticities
+    pub fn is_valid(&self) -> bool {
+        let in_range = [self.red, self.green, self.blue]
+            .iter()
+            .all(|&(x, y)| (0. ..=1.).contains(&x) && y > 0. && y <= 1. && x + y <= 1.);
+        in_range && cross(self.red, self.green, self.blue).abs() > 1e-6
+    }
+
+    fn contains(&self, p: (f64, f64)) -> bool {
+        let d1 = cross(self.red, self.green, p);
+        let d2 = cross(self.green, self.blue, p);
+        let d3 = cross(self.blue, self.red, p);
+        let has_neg = d1 < 0. || d2 < 0. || d3 < 0.;
+        let has_pos = d1 > 0. || d2 > 0. || d3 > 0.;
+        !(has_neg && has_pos)
+    }
+
+    /// Returns the point within the gamut that is closest to p
+    fn closest(&self, p: (f64, f64)) -> (f64, f64) {
+        if self.contains(p) {
+            return p;
+        }
+        [
+            closest_on_segment(p, self.red, self.green),
+            closest_on_segment(p, self.green, self.blue),
+            closest_on_segment(p, self.blue, self.red),
+        ]
+        .into_iter()
+        .min_by(|a, b| {
+            let da = (a.0 - p.0).powi(2) + (a.1 - p.1).powi(2);
+            let db = (b.0 - p.0).powi(2) + (b.1 - p.1).powi(2);
+            da.total_cmp(&db)
+        })
+        .unwrap_or(p)
+    }
+
+    /// Map an sRGB color to the closest color within the gamut,
+    /// keeping its hue as far as possible and its brightness.
+    /// Returns None if the color is already within the gamut.
+    pub fn clamp(&self, color: DeviceColor) -> Option<DeviceColor> {
+        let lin = [
+            srgb_to_linear(color.r),
+            srgb_to_linear(color.g),
+            srgb_to_linear(color.b),
+        ];
+        let x = 0.4124 * lin[0] + 0.3576 * lin[1] + 0.1805 * lin[2];
+        let y = 0.2126 * lin[0] + 0.7152 * lin[1] + 0.0722 * lin[2];
+        let z = 0.0193 * lin[0] + 0.1192 * lin[1] + 0.9505 * lin[2];
+        let sum = x + y + z;
+        if sum <= 0. {
+            // Black is black on any device
+            return None;
+        }
+
+        let p = (x / sum, y / sum);
+        let q = self.closest(p);
+        if (q.0 - p.0).hypot(q.1 - p.1) <= TOLERANCE {
+            return None;
+        }
+
+        // Convert back at the same luminance
+        let x = q.0 * y / q.1;
+        let z = (1. - q.0 - q.1) * y / q.1;
+        let mut out = [
+            3.2406 * x - 1.5372 * y - 0.4986 * z,
+            -0.9689 * x + 1.8758 * y + 0.0415 * z,
+            0.0557 * x - 0.2040 * y + 1.0570 * z,
+        ]
+        .map(|c| c.max(0.));
+
+        // Keep the brightest channel where it was, as the brightness
+        // of the light is controlled separately
+        let max_in = lin.iter().copied().fold(0., f64::max);
+        let max_out = out.iter().copied().fold(0., f64::max);
+        if max_out > 0. {
+            for c in &mut out {
+                *c *= max_in / max_out;
+            }
+        }
+
+        let clamped = DeviceColor {
+            r: linear_to_srgb(out[0]),
+            g: linear_to_srgb(out[1]),
+            b: linear_to_srgb(out[2]),
+        };
+        (clamped != color).then_some(clamped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clamping() {
+        let white = DeviceColor {
+            r: 255,
+            g: 255,
+            b: 255,
+        };
+        assert_eq!(GENERIC_LED_GAMUT.clamp(white), None);
+        assert_eq!(
+            GENERIC_LED_GAMUT.clamp(DeviceColor { r: 0, g: 0, b: 0 }),
+            None
+        );
+
+        // A gamut that is narrower than sRGB
+        let narrow = ColorGamut {
+            red: (0.6, 0.35),
+            green: (0.32, 0.55),
+            blue: (0.17, 0.1),
+        };
+        assert!(narrow.is_valid());
+        assert_eq!(narrow.clamp(white), None);
+
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        let clamped = narrow.clamp(red).unwrap();
+        // The hue is kept, and the brightest channel is unchanged
+        assert_eq!(clamped.r, 255);
+        assert!(clamped.g > 0 || clamped.b > 0);
+        assert!(clamped.g < 128 && clamped.b < 128);
+        // A clamped color is within the gamut
+        assert_eq!(narrow.clamp(clamped), None);
+
+        assert!(!ColorGamut {
+            red: (0.3, 0.3),
+            green: (0.3, 0.3),
+            blue: (0.1, 0.1),
+        }
+        .is_valid());
+    }
+}
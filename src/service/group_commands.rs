@@ -0,0 +1,112 @@
+//! When hass controls a light group, it sends an individual command
+//! to each member of the group, one after the other. None of the
+//! Govee APIs that we use allow controlling the groups that are
+//! defined in the Govee App, so we can't replace those with a single
+//! group command. What we can do is to notice identical commands
+//! that arrive together, and release them at the same moment, so that
+//! a scene applied to a room changes all of its lights at once rather
+//! than rippling across them as each command trickles in.
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+
+static WINDOW: Lazy<RwLock<Option<Duration>>> = Lazy::new(|| RwLock::new(None));
+
+/// The batches that are currently collecting commands, keyed
+/// by the command payload
+static BATCHES: Lazy<Mutex<HashMap<String, Batch>>> = Lazy::new(Default::default);
+
+struct Batch {
+    release: watch::Receiver<bool>,
+    count: usize,
+}
+
+/// Sets how long to wait for identical commands to other devices
+/// before acting on a command. None, or a zero duration, disables
+/// grouping, so that commands are acted upon as soon as they arrive.
+pub fn set_group_command_window(window: Option<Duration>) {
+    *WINDOW.write().unwrap() = window.filter(|w| !w.is_zero());
+}
+
+fn group_command_window() -> Option<Duration> {
+    *WINDOW.read().unwrap()
+}
+
+/// Wait until the command described by key may be acted upon.
+/// The first command for a key waits for the grouping window to
+/// elapse; identical commands that arrive in the meantime are
+/// released along with it. Returns the number of commands that
+/// were released together.
+pub async fn synchronize(key: &str) -> usize {
+    let Some(window) = group_command_window() else {
+        return 1;
+    };
+
+    // Either join the batch that is collecting this command,
+    // or start a new one
+    let joined = {
+        let mut batches = BATCHES.lock().unwrap();
+        match batches.get_mut(key) {
+            Some(batch) => {
+                batch.count += 1;
+                Err(batch.release.clone())
+            }
+            None => {
+                let (tx, rx) = watch::channel(false);
+                batches.insert(
+                    key.to_string(),
+                    Batch {
+                        release: rx,
+                        count: 1,
+                    },
+                );
+                Ok(tx)
+            }
+        }
+    };
+    let sender = match joined {
+        Ok(sender) => sender,
+        Err(mut release) => {
+            let _ = release.wait_for(|released| *released).await;
+            return 0;
+        }
+    };
+
+    tokio::time::sleep(window).await;
+
+    let count = BATCHES
+        .lock()
+        .unwrap()
+        .remove(key)
+        .map(|batch| batch.count)
+        .unwrap_or(1);
+    sender.send_replace(true);
+    if count > 1 {
+        log::debug!("Releasing {count} identical commands together: {key}");
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn identical_commands_are_released_together() {
+        set_group_command_window(Some(Duration::from_millis(50)));
+
+        let first = tokio::spawn(async { synchronize("test-on").await });
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let second = tokio::spawn(async { synchronize("test-on").await });
+        let other = tokio::spawn(async { synchronize("test-off").await });
+
+        assert_eq!(first.await.unwrap(), 2);
+        assert_eq!(second.await.unwrap(), 0);
+        assert_eq!(other.await.unwrap(), 1);
+
+        set_group_command_window(None);
+        assert_eq!(synchronize("test-on").await, 1);
+    }
+}
@@ -1,26 +1,70 @@
-use crate::hass_mqtt::climate::mqtt_set_temperature;
-use crate::hass_mqtt::enumerator::{enumerate_all_entites, enumerate_entities_for_device};
+use crate::hass_mqtt::base::device_identifier;
+use crate::hass_mqtt::climate::{
+    mqtt_climate_set_mode, mqtt_climate_set_preset, mqtt_set_regulated_temperature,
+    mqtt_set_temperature,
+};
+use crate::hass_mqtt::diy_scene::mqtt_set_diy_scene;
+use crate::hass_mqtt::do_not_disturb::{mqtt_set_do_not_disturb, mqtt_set_do_not_disturb_time};
+use crate::hass_mqtt::enumerator::{
+    enumerate_entities_for_device, enumerate_entity_groups, enumerate_excluded_entities,
+};
+use crate::hass_mqtt::fan::{
+    mqtt_fan_set_combined, mqtt_fan_set_oscillation, mqtt_fan_set_preset, mqtt_fan_set_speed,
+    mqtt_fan_set_speed_step,
+};
 use crate::hass_mqtt::humidifier::{mqtt_device_set_work_mode, mqtt_humidifier_set_target};
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::number::mqtt_number_command;
+use crate::hass_mqtt::light::mqtt_nightlight_command;
+use crate::hass_mqtt::light_scene::{mqtt_set_light_scene, mqtt_set_scene_id};
+use crate::hass_mqtt::light_segments::{
+    mqtt_light_gradient, mqtt_light_segment_colors, mqtt_light_segment_command,
+    mqtt_light_segment_settings,
+};
+use crate::hass_mqtt::music_mode::{mqtt_set_music_mode, mqtt_set_music_sensitivity};
+use crate::hass_mqtt::number::{mqtt_number_command, mqtt_set_sleep_timer};
+use crate::hass_mqtt::power_on_behavior::mqtt_set_power_on_behavior;
 use crate::hass_mqtt::select::mqtt_set_mode_scene;
-use crate::lan_api::DeviceColor;
+use crate::hass_mqtt::snapshot::mqtt_set_snapshot;
+use crate::lan_api::{truthy, DeviceColor};
 use crate::opt_env_var;
-use crate::platform_api::{from_json, DeviceType};
+use crate::platform_api::DeviceType;
 use crate::service::device::Device as ServiceDevice;
-use crate::service::state::StateHandle;
+use crate::service::device_filter::DeviceFilter;
+use crate::service::discovery_gc::{DiscoveryRecord, KnownDevices};
+use crate::service::entity_filter::{set_entity_filter, EntityFilter, EntityFilterEntry};
+use crate::service::entity_overrides::{
+    set_entity_overrides, EntityOverrideEntry, EntityOverrides,
+};
+use crate::service::group_commands::{self, set_group_command_window};
+use crate::service::log_context;
+use crate::service::metrics::METRICS;
+use crate::service::persist::PERSISTED_SOURCE;
+use crate::service::quirks::{set_sensor_calibrations, SensorCalibrationEntry};
+use crate::service::state::{
+    StateHandle, DEFAULT_AVAILABILITY_GRACE_PERIOD, DEFAULT_DEVICE_REMOVAL_CYCLES,
+    DEFAULT_DEVICE_TIMEOUT,
+};
+use crate::service::transition::{brightness_steps, color_steps, run_transition};
 use crate::temperature::TemperatureScale;
 use anyhow::Context;
 use async_channel::Receiver;
-use mosquitto_rs::router::{MqttRouter, Params, Payload, State};
+use mosquitto_rs::router::{MakeDispatcher, MqttRouter, Params, Payload, State};
 use mosquitto_rs::{Client, Event, QoS};
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 const HASS_REGISTER_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(15);
+/// The delay before the first attempt to reconnect to the broker;
+/// it doubles with each failed attempt, up to MQTT_MAX_RECONNECT_DELAY
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MQTT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Debug, Clone)]
 pub struct HassArguments {
     /// The mqtt broker hostname or address.
     /// You may also set this via the GOVEE_MQTT_HOST environment variable.
@@ -46,9 +90,211 @@ pub struct HassArguments {
     #[arg(long, global = true)]
     mqtt_bind_address: Option<String>,
 
+    /// The QoS level (0, 1 or 2) for the discovery configs that
+    /// register entities with hass. If unspecified, uses 1.
+    /// You may also set this via the GOVEE_MQTT_DISCOVERY_QOS
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_discovery_qos: Option<u8>,
+
+    /// Whether the broker should retain discovery configs, so that
+    /// the entities survive a restart of the broker or of hass.
+    /// If unspecified, uses true.
+    /// You may also set this via the GOVEE_MQTT_DISCOVERY_RETAIN
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_discovery_retain: Option<String>,
+
+    /// The QoS level (0, 1 or 2) for state and availability updates.
+    /// If unspecified, uses 0.
+    /// You may also set this via the GOVEE_MQTT_STATE_QOS
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_state_qos: Option<u8>,
+
+    /// Whether the broker should retain state and availability
+    /// updates. If unspecified, uses false.
+    /// You may also set this via the GOVEE_MQTT_STATE_RETAIN
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_state_retain: Option<String>,
+
+    /// The QoS level (0, 1 or 2) at which to subscribe to the
+    /// command topics. If unspecified, uses 0.
+    /// You may also set this via the GOVEE_MQTT_COMMAND_QOS
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_command_qos: Option<u8>,
+
     #[arg(long, global = true, default_value = "homeassistant")]
     hass_discovery_prefix: String,
 
+    /// The prefix for the topics that we publish and subscribe to,
+    /// other than the hass discovery topics. Use this to avoid
+    /// collisions when running multiple instances against the same
+    /// broker.
+    /// You may also set this via the GOVEE_MQTT_TOPIC_PREFIX environment
+    /// variable. If unspecified, uses "gv2mqtt".
+    #[arg(long, global = true)]
+    mqtt_topic_prefix: Option<String>,
+
+    /// Only expose the devices with these ids or SKUs to hass.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_INCLUDE_DEVICES=H6000,AA:BB:CC:DD:EE:FF:00:01
+    /// via the environment.
+    #[arg(long, global = true)]
+    include_device: Vec<String>,
+
+    /// Don't expose the devices with these ids or SKUs to hass,
+    /// and remove any entities previously registered for them.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_EXCLUDE_DEVICES=H6000,AA:BB:CC:DD:EE:FF:00:01
+    /// via the environment.
+    #[arg(long, global = true)]
+    exclude_device: Vec<String>,
+
+    /// Correct the readings of a temperature/humidity sensor, in the
+    /// form DEVICE=TEMPERATURE_OFFSET[,HUMIDITY_OFFSET], where DEVICE
+    /// is a device id or SKU. The temperature offset is in the units
+    /// of the configured temperature scale.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_SENSOR_CALIBRATION=H5075=-1.5;H5179=0,3
+    /// via the environment.
+    #[arg(long, global = true)]
+    sensor_calibration: Vec<SensorCalibrationEntry>,
+
+    /// Override the name of an entity, in the form
+    /// DEVICE[/ENTITY]=NAME, where DEVICE is a device id or SKU.
+    /// Without an ENTITY, the hass device itself is renamed.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_ENTITY_NAMES=H7102/oscillationToggle=Swing
+    /// via the environment.
+    #[arg(long, global = true)]
+    entity_name: Vec<EntityOverrideEntry>,
+
+    /// Override the icon of an entity, in the form
+    /// DEVICE[/ENTITY]=ICON, where DEVICE is a device id or SKU.
+    /// Without an ENTITY, the icon applies to all of the entities
+    /// of the device.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_ENTITY_ICONS=H7102/fan=mdi:ceiling-fan
+    /// via the environment.
+    #[arg(long, global = true)]
+    entity_icon: Vec<EntityOverrideEntry>,
+
+    /// Only expose these entities of a device, in the form
+    /// DEVICE/ENTITY, where DEVICE is a device id or SKU and ENTITY
+    /// is either a kind of entity, such as light or select, or
+    /// identifies a specific entity. Devices without an entry
+    /// expose all of their entities.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_INCLUDE_ENTITIES=H6008/light
+    /// via the environment.
+    #[arg(long, global = true)]
+    include_entity: Vec<EntityFilterEntry>,
+
+    /// Don't expose these entities of a device, in the form
+    /// DEVICE/ENTITY, and remove them if they were previously
+    /// registered.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_EXCLUDE_ENTITIES=H6008/select
+    /// via the environment.
+    #[arg(long, global = true)]
+    exclude_entity: Vec<EntityFilterEntry>,
+
+    /// How long, in seconds, a device must be unreachable before its
+    /// entities are marked as unavailable in hass. If unspecified,
+    /// uses 300 seconds.
+    /// You may also set this via the GOVEE_AVAILABILITY_GRACE_PERIOD
+    /// environment variable.
+    #[arg(long, global = true)]
+    availability_grace_period: Option<u64>,
+
+    /// How many consecutive refreshes of the Platform API device list
+    /// a device may be missing from before its entities are removed.
+    /// The device is marked as unavailable while it is missing.
+    /// If unspecified, uses 3.
+    /// You may also set this via the GOVEE_DEVICE_REMOVAL_CYCLES
+    /// environment variable.
+    #[arg(long, global = true)]
+    device_removal_cycles: Option<u32>,
+
+    /// Expose additional diagnostic entities that are useful when
+    /// troubleshooting, such as the raw work mode reported by devices.
+    /// You may also set GOVEE_DEBUG_SENSORS=true via the environment.
+    #[arg(long, global = true)]
+    debug_sensors: bool,
+
+    /// Name devices in hass after their SKU and id, such as
+    /// "H6000_422A", rather than the names assigned in the Govee App.
+    /// You may also set GOVEE_USE_SKU_NAMES=true via the environment.
+    #[arg(long, global = true)]
+    use_sku_names: bool,
+
+    /// Don't simulate light transitions by sending a series of
+    /// brightness or color changes; apply the final value directly.
+    /// You may also set GOVEE_DISABLE_TRANSITIONS=true via the environment.
+    #[arg(long, global = true)]
+    disable_transitions: bool,
+
+    /// Map colors that a light can't reproduce to the closest color
+    /// that it can, rather than leaving it to the device.
+    /// You may also set GOVEE_GAMUT_CLAMPING=true via the environment.
+    #[arg(long, global = true)]
+    gamut_clamping: bool,
+
+    /// Prefix scene names with their category from the Govee App,
+    /// such as "Festival / Christmas", so that the scenes of each
+    /// category are listed together.
+    /// You may also set GOVEE_SCENE_CATEGORIES=true via the environment.
+    #[arg(long, global = true)]
+    scene_categories: bool,
+
+    /// How to resolve a command that sets both a color and a color
+    /// temperature, such as one from a scene. "color" applies the
+    /// color, "color_temp" applies the color temperature, and "hass",
+    /// the default, follows the color mode that hass specifies in the
+    /// command, falling back to the color temperature.
+    /// You may also set this via the GOVEE_COLOR_MODE_PRIORITY
+    /// environment variable.
+    #[arg(long, global = true)]
+    color_mode_priority: Option<String>,
+
+    /// How long, in seconds, to wait for a device to accept a command
+    /// sent via the LAN API, or as a BLE packet, before trying the next
+    /// way of controlling it. If unspecified, uses 5 seconds.
+    /// You may also set this via the GOVEE_DEVICE_TIMEOUT
+    /// environment variable.
+    #[arg(long, global = true)]
+    device_timeout: Option<u64>,
+
+    /// How long, in milliseconds, to wait for identical commands to
+    /// other lights, such as those that hass sends to the members of
+    /// a light group, so that they can all be applied together.
+    /// 0, the default, applies each command as soon as it arrives.
+    /// You may also set this via the GOVEE_GROUP_COMMAND_WINDOW
+    /// environment variable.
+    #[arg(long, global = true)]
+    group_command_window: Option<u64>,
+
+    /// State that hasn't changed since it was last published is not
+    /// published again, other than when hass restarts. Set this to
+    /// a number of seconds to also republish all state periodically,
+    /// for the benefit of other subscribers; 0 publishes every update.
+    /// You may also set this via the GOVEE_HASS_REPUBLISH_INTERVAL
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_republish_interval: Option<u64>,
+
+    /// How long, in milliseconds, to pause between devices when
+    /// registering their entities and reporting their initial state
+    /// to hass, so that a large number of devices doesn't flood the
+    /// broker and hass on startup. The default is 0, no pause.
+    /// You may also set this via the GOVEE_HASS_DISCOVERY_STAGGER
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_discovery_stagger: Option<u64>,
+
     /// The temperature scale to use when showing temperature values as
     /// entities in home assistant. Can be either "C" or "F" for Celsius
     /// or Farenheit respectively.
@@ -96,6 +342,238 @@ impl HassArguments {
         }
     }
 
+    pub fn mqtt_message_options(&self) -> anyhow::Result<MqttMessageOptions> {
+        fn qos(arg: Option<u8>, env: &str, default: QoS) -> anyhow::Result<QoS> {
+            match arg.map(Ok).or_else(|| opt_env_var(env).transpose()) {
+                Some(level) => parse_qos(level?),
+                None => Ok(default),
+            }
+        }
+        fn retain(arg: &Option<String>, env: &str, default: bool) -> anyhow::Result<bool> {
+            match arg.clone().map(Ok).or_else(|| opt_env_var(env).transpose()) {
+                Some(v) => truthy(&v?),
+                None => Ok(default),
+            }
+        }
+
+        let defaults = MqttMessageOptions::default();
+        Ok(MqttMessageOptions {
+            discovery_qos: qos(
+                self.mqtt_discovery_qos,
+                "GOVEE_MQTT_DISCOVERY_QOS",
+                defaults.discovery_qos,
+            )?,
+            discovery_retain: retain(
+                &self.mqtt_discovery_retain,
+                "GOVEE_MQTT_DISCOVERY_RETAIN",
+                defaults.discovery_retain,
+            )?,
+            state_qos: qos(
+                self.mqtt_state_qos,
+                "GOVEE_MQTT_STATE_QOS",
+                defaults.state_qos,
+            )?,
+            state_retain: retain(
+                &self.mqtt_state_retain,
+                "GOVEE_MQTT_STATE_RETAIN",
+                defaults.state_retain,
+            )?,
+            command_qos: qos(
+                self.mqtt_command_qos,
+                "GOVEE_MQTT_COMMAND_QOS",
+                defaults.command_qos,
+            )?,
+        })
+    }
+
+    pub fn mqtt_topic_prefix(&self) -> anyhow::Result<String> {
+        let prefix = match self.mqtt_topic_prefix.clone() {
+            Some(p) => p,
+            None => opt_env_var("GOVEE_MQTT_TOPIC_PREFIX")?
+                .unwrap_or_else(|| DEFAULT_TOPIC_PREFIX.to_string()),
+        };
+        validate_topic_prefix(&prefix)
+    }
+
+    pub fn device_filter(&self) -> anyhow::Result<DeviceFilter> {
+        fn from_env(name: &str, target: &mut Vec<String>) -> anyhow::Result<()> {
+            if let Some(v) = opt_env_var::<String>(name)? {
+                target.extend(
+                    v.split(',')
+                        .map(|s| s.trim())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.to_string()),
+                );
+            }
+            Ok(())
+        }
+
+        let mut filter = DeviceFilter {
+            include: self.include_device.clone(),
+            exclude: self.exclude_device.clone(),
+        };
+        from_env("GOVEE_INCLUDE_DEVICES", &mut filter.include)?;
+        from_env("GOVEE_EXCLUDE_DEVICES", &mut filter.exclude)?;
+        Ok(filter)
+    }
+
+    pub fn sensor_calibrations(&self) -> anyhow::Result<Vec<SensorCalibrationEntry>> {
+        let mut entries = self.sensor_calibration.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_SENSOR_CALIBRATION")? {
+            for entry in v.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                entries.push(entry.parse()?);
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn entity_overrides(&self) -> anyhow::Result<EntityOverrides> {
+        let mut overrides = EntityOverrides {
+            names: self.entity_name.clone(),
+            icons: self.entity_icon.clone(),
+        };
+        for (key, entries) in [
+            ("GOVEE_ENTITY_NAMES", &mut overrides.names),
+            ("GOVEE_ENTITY_ICONS", &mut overrides.icons),
+        ] {
+            if let Some(v) = opt_env_var::<String>(key)? {
+                for entry in v.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                    entries.push(entry.parse()?);
+                }
+            }
+        }
+        Ok(overrides)
+    }
+
+    pub fn entity_filter(&self) -> anyhow::Result<EntityFilter> {
+        let mut filter = EntityFilter {
+            include: self.include_entity.clone(),
+            exclude: self.exclude_entity.clone(),
+        };
+        for (key, entries) in [
+            ("GOVEE_INCLUDE_ENTITIES", &mut filter.include),
+            ("GOVEE_EXCLUDE_ENTITIES", &mut filter.exclude),
+        ] {
+            if let Some(v) = opt_env_var::<String>(key)? {
+                for entry in v.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                    entries.push(entry.parse()?);
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn availability_grace_period(&self) -> anyhow::Result<chrono::Duration> {
+        let secs = match self.availability_grace_period {
+            Some(secs) => secs,
+            None => match opt_env_var::<u64>("GOVEE_AVAILABILITY_GRACE_PERIOD")? {
+                Some(secs) => secs,
+                None => return Ok(DEFAULT_AVAILABILITY_GRACE_PERIOD),
+            },
+        };
+        chrono::Duration::from_std(std::time::Duration::from_secs(secs))
+            .with_context(|| format!("availability grace period of {secs} seconds is too large"))
+    }
+
+    pub fn device_removal_cycles(&self) -> anyhow::Result<u32> {
+        let cycles = match self.device_removal_cycles {
+            Some(cycles) => cycles,
+            None => {
+                opt_env_var("GOVEE_DEVICE_REMOVAL_CYCLES")?.unwrap_or(DEFAULT_DEVICE_REMOVAL_CYCLES)
+            }
+        };
+        Ok(cycles.max(1))
+    }
+
+    pub fn debug_sensors(&self) -> anyhow::Result<bool> {
+        if self.debug_sensors {
+            return Ok(true);
+        }
+        match opt_env_var::<String>("GOVEE_DEBUG_SENSORS")? {
+            Some(v) => truthy(&v),
+            None => Ok(false),
+        }
+    }
+
+    pub fn use_sku_names(&self) -> anyhow::Result<bool> {
+        if self.use_sku_names {
+            return Ok(true);
+        }
+        match opt_env_var::<String>("GOVEE_USE_SKU_NAMES")? {
+            Some(v) => truthy(&v),
+            None => Ok(false),
+        }
+    }
+
+    pub fn disable_transitions(&self) -> anyhow::Result<bool> {
+        if self.disable_transitions {
+            return Ok(true);
+        }
+        match opt_env_var::<String>("GOVEE_DISABLE_TRANSITIONS")? {
+            Some(v) => truthy(&v),
+            None => Ok(false),
+        }
+    }
+
+    pub fn scene_categories(&self) -> anyhow::Result<bool> {
+        if self.scene_categories {
+            return Ok(true);
+        }
+        match opt_env_var::<String>("GOVEE_SCENE_CATEGORIES")? {
+            Some(v) => truthy(&v),
+            None => Ok(false),
+        }
+    }
+
+    pub fn gamut_clamping(&self) -> anyhow::Result<bool> {
+        if self.gamut_clamping {
+            return Ok(true);
+        }
+        match opt_env_var::<String>("GOVEE_GAMUT_CLAMPING")? {
+            Some(v) => truthy(&v),
+            None => Ok(false),
+        }
+    }
+
+    pub fn color_mode_priority(&self) -> anyhow::Result<ColorModePriority> {
+        match &self.color_mode_priority {
+            Some(s) => s.parse(),
+            None => Ok(opt_env_var("GOVEE_COLOR_MODE_PRIORITY")?.unwrap_or_default()),
+        }
+    }
+
+    pub fn device_timeout(&self) -> anyhow::Result<Duration> {
+        match self.device_timeout {
+            Some(secs) => Ok(Duration::from_secs(secs)),
+            None => Ok(opt_env_var("GOVEE_DEVICE_TIMEOUT")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_DEVICE_TIMEOUT)),
+        }
+    }
+
+    pub fn group_command_window(&self) -> anyhow::Result<Option<Duration>> {
+        match self.group_command_window {
+            Some(ms) => Ok(Some(Duration::from_millis(ms))),
+            None => Ok(opt_env_var("GOVEE_GROUP_COMMAND_WINDOW")?.map(Duration::from_millis)),
+        }
+    }
+
+    pub fn hass_discovery_stagger(&self) -> anyhow::Result<Duration> {
+        match self.hass_discovery_stagger {
+            Some(ms) => Ok(Duration::from_millis(ms)),
+            None => Ok(opt_env_var("GOVEE_HASS_DISCOVERY_STAGGER")?
+                .map(Duration::from_millis)
+                .unwrap_or_default()),
+        }
+    }
+
+    pub fn hass_republish_interval(&self) -> anyhow::Result<Option<Duration>> {
+        match self.hass_republish_interval {
+            Some(secs) => Ok(Some(Duration::from_secs(secs))),
+            None => Ok(opt_env_var("GOVEE_HASS_REPUBLISH_INTERVAL")?.map(Duration::from_secs)),
+        }
+    }
+
     pub fn temperature_scale(&self) -> anyhow::Result<TemperatureScale> {
         match &self.temperature_scale {
             Some(s) => Ok(s.parse()?),
@@ -106,37 +584,417 @@ impl HassArguments {
     }
 }
 
+fn parse_qos(level: u8) -> anyhow::Result<QoS> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => anyhow::bail!("Invalid MQTT QoS level {level}; expected 0, 1 or 2"),
+    }
+}
+
+/// The QoS and retain flags for each kind of message that we
+/// exchange with the broker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MqttMessageOptions {
+    pub discovery_qos: QoS,
+    pub discovery_retain: bool,
+    pub state_qos: QoS,
+    pub state_retain: bool,
+    pub command_qos: QoS,
+}
+
+impl Default for MqttMessageOptions {
+    fn default() -> Self {
+        Self {
+            discovery_qos: QoS::AtLeastOnce,
+            discovery_retain: true,
+            state_qos: QoS::AtMostOnce,
+            state_retain: false,
+            command_qos: QoS::AtMostOnce,
+        }
+    }
+}
+
+impl MqttMessageOptions {
+    /// Returns the QoS and retain flag with which to publish to topic
+    fn for_topic(&self, topic: &str) -> (QoS, bool) {
+        if is_discovery_topic(topic) {
+            (self.discovery_qos, self.discovery_retain)
+        } else {
+            (self.state_qos, self.state_retain)
+        }
+    }
+}
+
+static MESSAGE_OPTIONS: Lazy<RwLock<MqttMessageOptions>> = Lazy::new(Default::default);
+
+fn set_message_options(options: MqttMessageOptions) {
+    *MESSAGE_OPTIONS.write().unwrap() = options;
+}
+
+fn message_options() -> MqttMessageOptions {
+    *MESSAGE_OPTIONS.read().unwrap()
+}
+
+/// The discovery configs are the only topics that we publish
+/// outside of our topic prefix
+fn is_discovery_topic(topic: &str) -> bool {
+    topic.ends_with("/config") && !topic.starts_with(&format!("{}/", topic_prefix()))
+}
+
+/// Convert a router path to the topic filter that it subscribes to,
+/// replacing the `:name` parameters with `+`
+fn route_topic_filter(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if segment.starts_with(':') {
+                "+"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Wraps MqttRouter so that the command topics are subscribed
+/// to with the configured QoS; the router always uses QoS 0
+struct CommandRouter {
+    router: MqttRouter<StateHandle>,
+    client: Client,
+    qos: QoS,
+}
+
+impl CommandRouter {
+    fn new(client: Client, qos: QoS) -> Self {
+        Self {
+            router: MqttRouter::new(client.clone()),
+            client,
+            qos,
+        }
+    }
+
+    async fn route<P, T, F>(&mut self, path: P, handler: F) -> anyhow::Result<()>
+    where
+        P: Into<String>,
+        F: MakeDispatcher<T, StateHandle>,
+    {
+        let path = path.into();
+        self.router.route(path.clone(), handler).await?;
+        if self.qos != QoS::AtMostOnce {
+            // Subscribing again to the same filter replaces
+            // the QoS of the existing subscription
+            self.client
+                .subscribe(&route_topic_filter(&path), self.qos)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn into_inner(self) -> MqttRouter<StateHandle> {
+        self.router
+    }
+}
+
+/// The messages published via a capturing HassClient,
+/// as (topic, payload) pairs in the order that they were sent
+#[cfg(test)]
+pub type CapturedPublishes = std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+#[derive(Clone)]
+enum HassTransport {
+    Mqtt(Client),
+    /// Records the messages rather than sending them, so that
+    /// tests can assert what would have been published
+    #[cfg(test)]
+    Capture(CapturedPublishes),
+}
+
+impl HassTransport {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Mqtt(client) => {
+                let (qos, retain) = message_options().for_topic(topic);
+                client.publish(topic, payload, qos, retain).await?;
+            }
+            #[cfg(test)]
+            Self::Capture(captured) => {
+                captured.lock().unwrap().push((
+                    topic.to_string(),
+                    String::from_utf8_lossy(payload).to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+static REPUBLISH_INTERVAL: Lazy<RwLock<Option<Duration>>> = Lazy::new(|| RwLock::new(None));
+
+/// Sets how often we publish all state, even if it hasn't changed.
+/// None republishes only when the cache is cleared by registering
+/// with hass.
+pub fn set_republish_interval(interval: Option<Duration>) {
+    *REPUBLISH_INTERVAL.write().unwrap() = interval;
+}
+
+static DISCOVERY_STAGGER: Lazy<RwLock<Duration>> = Lazy::new(Default::default);
+
+/// Sets how long to pause between the devices whose entities we
+/// register, and whose initial state we report, with hass
+pub fn set_discovery_stagger(stagger: Duration) {
+    *DISCOVERY_STAGGER.write().unwrap() = stagger;
+}
+
+fn discovery_stagger() -> Duration {
+    *DISCOVERY_STAGGER.read().unwrap()
+}
+
+/// Remembers the payload most recently published to each topic,
+/// so that we can skip publishing state that hasn't changed
+#[derive(Default)]
+struct PublishCache {
+    payloads: HashMap<String, Vec<u8>>,
+    cleared: Option<Instant>,
+}
+
+impl PublishCache {
+    /// Returns true if payload should be published to topic,
+    /// recording it as the most recently published payload
+    fn should_publish(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let now = Instant::now();
+        let cleared = *self.cleared.get_or_insert(now);
+        if let Some(interval) = *REPUBLISH_INTERVAL.read().unwrap() {
+            if now.duration_since(cleared) >= interval {
+                self.clear();
+            }
+        }
+
+        if self.payloads.get(topic).map(|p| p.as_slice()) == Some(payload) {
+            return false;
+        }
+        self.payloads.insert(topic.to_string(), payload.to_vec());
+        true
+    }
+
+    fn clear(&mut self) {
+        self.payloads.clear();
+        self.cleared.replace(Instant::now());
+    }
+}
+
+/// Holds the messages that were published while we were
+/// disconnected from the broker, keeping only the most recent
+/// payload for each topic, so that they can be sent once we
+/// have reconnected
+#[derive(Default)]
+struct OfflineBuffer {
+    disconnected: bool,
+    pending: BTreeMap<String, Vec<u8>>,
+}
+
 #[derive(Clone)]
 pub struct HassClient {
-    client: Client,
+    client: HassTransport,
+    /// When set, publishing an entity config will instead
+    /// remove that entity from hass
+    removing_entities: bool,
+    published: Arc<Mutex<PublishCache>>,
+    offline: Arc<Mutex<OfflineBuffer>>,
+    /// The discovery config topics published since we last registered
+    /// with hass, mapped to the identifier of the device they belong to
+    config_topics: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Held while registering with hass, so that concurrent
+    /// registrations don't see each other's partial config_topics
+    /// and remove entities that are still live
+    registering: Arc<tokio::sync::Mutex<()>>,
+    /// Set while a registration is waiting for the one in progress
+    /// to finish. Staggered registrations can take a while, so any
+    /// further requests in the meantime are left to the waiting one.
+    registration_queued: Arc<AtomicBool>,
 }
 
 impl HassClient {
-    async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
-        let entities = enumerate_all_entites(state).await?;
+    /// Returns a client that removes entities rather than
+    /// registering them
+    fn for_entity_removal(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            removing_entities: true,
+            published: self.published.clone(),
+            offline: self.offline.clone(),
+            config_topics: self.config_topics.clone(),
+            registering: self.registering.clone(),
+            registration_queued: self.registration_queued.clone(),
+        }
+    }
+
+    /// Returns a client that records the messages that would
+    /// be published, rather than sending them to the broker
+    #[cfg(test)]
+    pub fn capture() -> (Self, CapturedPublishes) {
+        let captured = CapturedPublishes::default();
+        (
+            Self {
+                client: HassTransport::Capture(captured.clone()),
+                removing_entities: false,
+                published: Default::default(),
+                offline: Default::default(),
+                config_topics: Default::default(),
+                registering: Default::default(),
+                registration_queued: Default::default(),
+            },
+            captured,
+        )
+    }
+
+    pub fn is_removing_entities(&self) -> bool {
+        self.removing_entities
+    }
+
+    /// Record that we have lost our connection to the broker;
+    /// messages are buffered until we reconnect
+    fn set_disconnected(&self) {
+        self.offline.lock().unwrap().disconnected = true;
+    }
+
+    /// Record that we have reconnected to the broker, so that we
+    /// can re-register with hass. The messages that were buffered
+    /// while we were disconnected are held until flush_offline_buffer
+    /// is called, unless they are superseded in the meantime.
+    fn set_connected(&self) {
+        self.offline.lock().unwrap().disconnected = false;
+    }
+
+    /// Send the messages that were buffered while we were disconnected
+    async fn flush_offline_buffer(&self) -> anyhow::Result<()> {
+        let pending = {
+            let mut offline = self.offline.lock().unwrap();
+            offline.disconnected = false;
+            std::mem::take(&mut offline.pending)
+        };
+        if !pending.is_empty() {
+            log::info!(
+                "Publishing {} messages that were buffered while disconnected",
+                pending.len()
+            );
+        }
+        for (topic, payload) in pending {
+            self.client.publish(&topic, &payload).await?;
+            METRICS.mqtt_publish();
+        }
+        Ok(())
+    }
 
-        // Register the configs
+    /// Record that we have published the discovery config for
+    /// an entity of the device with the specified identifier
+    pub fn note_config_topic(&self, topic: &str, device_identifier: &str) {
+        self.config_topics
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), device_identifier.to_string());
+    }
+
+    /// Remove the entities that we previously registered with hass
+    /// but which no longer apply, such as those of devices that were
+    /// removed from the Govee account
+    async fn remove_stale_configs(&self, state: &StateHandle) -> anyhow::Result<()> {
+        let devices = state.devices().await;
+        let current = DiscoveryRecord {
+            configs: self.config_topics.lock().unwrap().clone(),
+            platform_devices: devices
+                .iter()
+                .filter(|d| d.http_device_info.is_some())
+                .map(device_identifier)
+                .collect(),
+        };
+        let known = KnownDevices {
+            known: devices.iter().map(device_identifier).collect(),
+            have_platform_list: state.has_platform_client().await,
+        };
+
+        let (stale, next) = DiscoveryRecord::load().reconcile(&current, &known);
+        if !stale.is_empty() {
+            log::info!("Removing {} entities that no longer apply", stale.len());
+        }
+        for topic in stale {
+            log::debug!("Removing entity {topic}");
+            // An empty config causes hass to remove the entity
+            self.publish(&topic, "").await?;
+        }
+        next.save()
+    }
+
+    pub async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
+        if self.registration_queued.swap(true, Ordering::SeqCst) {
+            log::debug!("register_with_hass: already queued behind the current registration");
+            return Ok(());
+        }
+        let _registering = self.registering.lock().await;
+        // Anything that changes from here on needs another registration
+        self.registration_queued.store(false, Ordering::SeqCst);
+
+        // hass has either just started or we have just (re)connected
+        // to the broker, so everything needs to be published afresh
+        self.published.lock().unwrap().clear();
+        self.config_topics.lock().unwrap().clear();
+        state.assign_hass_names().await;
+        let groups = enumerate_entity_groups(state).await?;
+        let num_entities: usize = groups.iter().map(|g| g.len()).sum();
+        let stagger = discovery_stagger();
+
+        // Register the configs, pausing between devices so that
+        // we don't flood the broker and hass when there are many
         log::trace!("register_with_hass: register entities");
-        entities.publish_config(state, self).await?;
+        for (idx, entities) in groups.iter().enumerate() {
+            if idx > 0 {
+                tokio::time::sleep(stagger).await;
+            }
+            entities.publish_config(state, self).await?;
+        }
+
+        // Clean up any entities that were registered for devices
+        // before they were excluded
+        let excluded = enumerate_excluded_entities(state).await?;
+        if excluded.len() > 0 {
+            log::info!("Removing {} entities for excluded devices", excluded.len());
+            excluded
+                .publish_config(state, &self.for_entity_removal())
+                .await?;
+        }
+        if let Err(err) = self.remove_stale_configs(state).await {
+            log::warn!("Unable to remove stale entities: {err:#}");
+        }
 
         // Allow hass extra time to register the entities before
         // we mark them as available
-        let delay = tokio::time::Duration::from_millis((10 * entities.len()) as u64);
-        log::info!(
-            "Wait {delay:?} for hass to settle on {} entity configs",
-            entities.len()
-        );
+        let delay = tokio::time::Duration::from_millis((10 * num_entities) as u64);
+        log::info!("Wait {delay:?} for hass to settle on {num_entities} entity configs");
         tokio::time::sleep(delay).await;
 
         // Mark as available
         log::trace!("register_with_hass: mark as online");
+        for d in state.devices().await {
+            if state.is_device_included(&d).await {
+                state
+                    .publish_device_availability(&d.id, true)
+                    .await
+                    .context("publish_device_availability")?;
+            }
+        }
         self.publish(availability_topic(), "online")
             .await
             .context("online -> availability_topic")?;
 
         // report initial state
         log::trace!("register_with_hass: reporting state");
-        entities.notify_state(self).await.context("notify_state")?;
+        for (idx, entities) in groups.iter().enumerate() {
+            if idx > 0 {
+                tokio::time::sleep(stagger).await;
+            }
+            entities.notify_state(self).await.context("notify_state")?;
+        }
 
         log::trace!("register_with_hass: done");
 
@@ -149,10 +1007,8 @@ impl HassClient {
         payload: P,
     ) -> anyhow::Result<()> {
         log::trace!("{topic} -> {payload}");
-        self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
-            .await?;
-        Ok(())
+        self.publish_if_changed(topic.as_ref(), payload.as_ref())
+            .await
     }
 
     pub async fn publish_obj<T: AsRef<str> + std::fmt::Display, P: Serialize>(
@@ -162,9 +1018,40 @@ impl HassClient {
     ) -> anyhow::Result<()> {
         let payload = serde_json::to_string(&payload)?;
         log::trace!("{topic} -> {payload}");
-        self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
-            .await?;
+        self.publish_if_changed(topic.as_ref(), payload.as_ref())
+            .await
+    }
+
+    async fn publish_if_changed(&self, topic: &str, payload: &[u8]) -> anyhow::Result<()> {
+        let interval = *REPUBLISH_INTERVAL.read().unwrap();
+        if interval != Some(Duration::ZERO)
+            && !self
+                .published
+                .lock()
+                .unwrap()
+                .should_publish(topic, payload)
+        {
+            log::trace!("{topic} is unchanged, not publishing");
+            return Ok(());
+        }
+
+        {
+            let mut offline = self.offline.lock().unwrap();
+            if offline.disconnected {
+                log::trace!("{topic} buffered until we reconnect to the broker");
+                offline.pending.insert(topic.to_string(), payload.to_vec());
+                return Ok(());
+            }
+            // This is more recent than anything that was buffered
+            offline.pending.remove(topic);
+        }
+
+        if let Err(err) = self.client.publish(topic, payload).await {
+            // Make sure that we try again next time
+            self.published.lock().unwrap().payloads.remove(topic);
+            return Err(err);
+        }
+        METRICS.mqtt_publish();
         Ok(())
     }
 
@@ -202,34 +1089,100 @@ pub fn topic_safe_id(device: &ServiceDevice) -> String {
 
 pub fn switch_instance_state_topic(device: &ServiceDevice, instance: &str) -> String {
     format!(
-        "gv2mqtt/switch/{id}/{instance}/state",
+        "{}/switch/{id}/{instance}/state",
+        topic_prefix(),
         id = topic_safe_id(device)
     )
 }
 
 pub fn light_state_topic(device: &ServiceDevice) -> String {
-    format!("gv2mqtt/light/{id}/state", id = topic_safe_id(device))
+    format!(
+        "{}/light/{id}/state",
+        topic_prefix(),
+        id = topic_safe_id(device)
+    )
+}
+
+pub fn nightlight_state_topic(device: &ServiceDevice) -> String {
+    format!(
+        "{}/light/{id}/nightlight/state",
+        topic_prefix(),
+        id = topic_safe_id(device)
+    )
 }
 
 pub fn light_segment_state_topic(device: &ServiceDevice, segment: u32) -> String {
     format!(
-        "gv2mqtt/light/{id}/state/{segment}",
+        "{}/light/{id}/state/{segment}",
+        topic_prefix(),
         id = topic_safe_id(device)
     )
 }
 
+pub const DEFAULT_TOPIC_PREFIX: &str = "gv2mqtt";
+static TOPIC_PREFIX: OnceCell<String> = OnceCell::new();
+
+fn validate_topic_prefix(prefix: &str) -> anyhow::Result<String> {
+    let prefix = prefix.trim_end_matches('/');
+    anyhow::ensure!(!prefix.is_empty(), "The MQTT topic prefix cannot be empty");
+    anyhow::ensure!(
+        !prefix.contains(['+', '#']),
+        "The MQTT topic prefix {prefix} cannot contain wildcard characters"
+    );
+    Ok(prefix.to_string())
+}
+
+/// Sets the prefix used by all of our non-discovery topics.
+/// Must be called before any topics are computed.
+pub fn set_topic_prefix(prefix: String) {
+    if TOPIC_PREFIX.set(prefix).is_err() {
+        log::warn!("set_topic_prefix: prefix was already set");
+    }
+}
+
+/// Returns the prefix for our non-discovery topics
+pub fn topic_prefix() -> &'static str {
+    TOPIC_PREFIX
+        .get()
+        .map(|p| p.as_str())
+        .unwrap_or(DEFAULT_TOPIC_PREFIX)
+}
+
 /// All entities use the same topic so that we can mark unavailable
 /// via last-will
 pub fn availability_topic() -> String {
-    "gv2mqtt/availability".to_string()
+    format!("{}/availability", topic_prefix())
+}
+
+/// Reflects whether an individual device is online
+pub fn device_availability_topic(device: &ServiceDevice) -> String {
+    format!(
+        "{}/{id}/availability",
+        topic_prefix(),
+        id = topic_safe_id(device)
+    )
 }
 
 pub fn oneclick_topic() -> String {
-    "gv2mqtt/oneclick".to_string()
+    format!("{}/oneclick", topic_prefix())
 }
 
 pub fn purge_cache_topic() -> String {
-    "gv2mqtt/purge-caches".to_string()
+    format!("{}/purge-caches", topic_prefix())
+}
+
+pub fn reload_topic() -> String {
+    format!("{}/reload", topic_prefix())
+}
+
+/// Accepts a list of light commands for several devices
+pub fn batch_topic() -> String {
+    format!("{}/batch", topic_prefix())
+}
+
+/// Reports the outcome of each entry of a batch command
+pub fn batch_result_topic() -> String {
+    format!("{}/batch/result", topic_prefix())
 }
 
 #[derive(Deserialize)]
@@ -250,13 +1203,196 @@ async fn mqtt_request_platform_data(
     Ok(())
 }
 
+async fn mqtt_refresh_device(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_read_only(&id).await?;
+    log::info!("Refresh State for {device}");
+    if !state.refresh_device_now(&device).await? {
+        log::warn!("Ignoring refresh request for {device}: it was refreshed too recently");
+    }
+    Ok(())
+}
+
 #[derive(Deserialize, Debug, Clone)]
-struct HassLightCommand {
-    state: String,
-    color_temp: Option<u32>,
-    color: Option<DeviceColor>,
-    effect: Option<String>,
-    brightness: Option<u8>,
+pub struct HassLightCommand {
+    pub state: String,
+    pub color_temp: Option<u32>,
+    pub color: Option<DeviceColor>,
+    pub effect: Option<String>,
+    pub brightness: Option<u8>,
+    /// In seconds
+    pub transition: Option<f64>,
+    /// Hass doesn't normally include this, but it may be present
+    /// in commands that are composed by hand, such as from a script
+    pub color_mode: Option<String>,
+}
+
+/// Which of the color and the color temperature to apply when
+/// a light command specifies both
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorModePriority {
+    Color,
+    ColorTemp,
+    /// Use the color_mode from the command, if any, otherwise
+    /// the color temperature
+    #[default]
+    FollowHass,
+}
+
+impl FromStr for ColorModePriority {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "color" | "rgb" => Ok(Self::Color),
+            "color_temp" | "temp" => Ok(Self::ColorTemp),
+            "hass" | "follow_hass" => Ok(Self::FollowHass),
+            _ => anyhow::bail!(
+                "Unknown color mode priority {s}; expected one of color, color_temp or hass"
+            ),
+        }
+    }
+}
+
+/// Commands to the same light that arrive within this window of
+/// each other are coalesced, and only the last is sent to the device
+pub const LIGHT_COMMAND_DEBOUNCE: Duration = Duration::from_millis(300);
+
+impl HassLightCommand {
+    /// A light can only be in one color mode, and the Govee APIs
+    /// apply whichever of the color and the color temperature was
+    /// sent last, so when both are present, drop the one that
+    /// priority says to ignore
+    pub fn resolve_color_mode(&mut self, priority: ColorModePriority) {
+        if self.color.is_none() || self.color_temp.is_none() {
+            return;
+        }
+        let prefer_color = match priority {
+            ColorModePriority::Color => true,
+            ColorModePriority::ColorTemp => false,
+            ColorModePriority::FollowHass => matches!(
+                self.color_mode.as_deref(),
+                Some("rgb" | "rgbw" | "rgbww" | "hs" | "xy")
+            ),
+        };
+        if prefer_color {
+            self.color_temp.take();
+        } else {
+            self.color.take();
+        }
+    }
+
+    pub fn transition_duration(&self) -> Option<Duration> {
+        self.transition
+            .filter(|&secs| secs > 0. && secs.is_finite())
+            .map(Duration::from_secs_f64)
+    }
+
+    /// Commands that change the same set of properties supersede
+    /// each other; this returns a key that identifies that set
+    pub fn debounce_key(&self, target: &str) -> String {
+        format!(
+            "{target}/{state}{brightness}{color}{color_temp}{effect}",
+            state = self.state,
+            brightness = if self.brightness.is_some() {
+                "/brightness"
+            } else {
+                ""
+            },
+            color = if self.color.is_some() { "/color" } else { "" },
+            color_temp = if self.color_temp.is_some() {
+                "/color_temp"
+            } else {
+                ""
+            },
+            effect = if self.effect.is_some() { "/effect" } else { "" },
+        )
+    }
+
+    /// Returns a state update reflecting this command. Hass only
+    /// updates the fields that are present, so this can be used to
+    /// have the UI respond while the command is being debounced.
+    pub fn optimistic_state(&self) -> serde_json::Value {
+        let mut state = serde_json::json!({"state": self.state});
+        if let Some(brightness) = self.brightness {
+            state["brightness"] = brightness.into();
+        }
+        if let Some(color) = &self.color {
+            state["color_mode"] = "rgb".into();
+            state["color"] = serde_json::json!({"r": color.r, "g": color.g, "b": color.b});
+        }
+        if let Some(color_temp) = self.color_temp {
+            state["color_mode"] = "color_temp".into();
+            state["color_temp"] = color_temp.into();
+        }
+        if let Some(effect) = &self.effect {
+            state["effect"] = effect.as_str().into();
+        }
+        state
+    }
+}
+
+/// One of the control requests that make up a HassLightCommand
+#[derive(Debug, Clone, PartialEq)]
+pub enum LightCommandStep<'a> {
+    Effect(&'a str),
+    Color(DeviceColor),
+    /// In mireds, as sent by hass
+    ColorTemperature(u32),
+    Brightness(u8),
+    PowerOn,
+}
+
+impl HassLightCommand {
+    /// Returns the sequence of control requests needed to apply
+    /// an "ON" command. The Govee APIs only accept a single
+    /// capability per request, so combined changes cannot be applied
+    /// atomically. Instead, we order them to minimize visible flashes:
+    /// the color or effect is set before the brightness, so that the
+    /// light doesn't briefly show the old color at the new brightness.
+    /// Setting any of these will turn the light on, so an explicit
+    /// power on is only needed when nothing else was requested.
+    ///
+    /// That isn't true of all devices when the light is off, so if
+    /// light_is_off, the brightness is set first and the other
+    /// changes are followed by an explicit power on. Setting the
+    /// color first would show it at the previous brightness on
+    /// the devices that turn on when their color is changed.
+    pub fn steps(&self, light_is_off: bool) -> Vec<LightCommandStep<'_>> {
+        let mut steps = vec![];
+
+        let powering_on = light_is_off && self.brightness.is_some_and(|b| b > 0);
+        if powering_on {
+            steps.extend(self.brightness.map(LightCommandStep::Brightness));
+        }
+
+        if let Some(effect) = &self.effect {
+            // It doesn't make sense to vary color properties
+            // at the same time as the scene properties, so
+            // ignore those.
+            steps.push(LightCommandStep::Effect(effect));
+        } else {
+            if let Some(color) = self.color {
+                steps.push(LightCommandStep::Color(color));
+            }
+            if let Some(color_temp) = self.color_temp {
+                steps.push(LightCommandStep::ColorTemperature(color_temp));
+            }
+        }
+
+        if powering_on {
+            steps.push(LightCommandStep::PowerOn);
+        } else if let Some(brightness) = self.brightness {
+            steps.push(LightCommandStep::Brightness(brightness));
+        }
+
+        if steps.is_empty() {
+            steps.push(LightCommandStep::PowerOn);
+        }
+
+        steps
+    }
 }
 
 /// HASS is sending a command to a light
@@ -265,15 +1401,78 @@ async fn mqtt_light_command(
     Params(IdParameter { id }): Params<IdParameter>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
+    let mut command: HassLightCommand = serde_json::from_str(&payload)?;
+    command.resolve_color_mode(state.get_color_mode_priority().await);
+
+    let device = state.resolve_device_read_only(&id).await?;
+
+    // Any command for the light cancels a transition that is in progress
+    let transition_key = "light/transition";
+    let transition_generation = state
+        .next_command_generation(&device.id, transition_key)
+        .await;
+
+    // Reflect the command in the UI right away, as we may
+    // delay or skip sending it to the device
+    if command.state == "ON" {
+        if let Some(hass) = state.get_hass_client().await {
+            hass.publish_obj(light_state_topic(&device), command.optimistic_state())
+                .await?;
+        }
+    }
+
+    if !state
+        .debounce_command(
+            &device.id,
+            &command.debounce_key("light"),
+            LIGHT_COMMAND_DEBOUNCE,
+        )
+        .await
+    {
+        return Ok(());
+    }
 
-    let command: HassLightCommand = serde_json::from_str(&payload)?;
+    // Let the other members of a light group catch up, so
+    // that they all change together
+    group_commands::synchronize(&payload).await;
+
+    let device = state.resolve_device_for_control(&id).await?;
     log::info!("Command for {device}: {payload}");
 
     let is_light = device.device_type() == DeviceType::Light;
 
+    let transition = match command.transition_duration() {
+        Some(duration) if state.supports_stepped_transitions(&device).await => Some(duration),
+        _ => None,
+    };
+    // What the light is showing right now, if it is on
+    let current = device
+        .device_state()
+        .filter(|s| s.light_on == Some(true) && s.source != PERSISTED_SOURCE);
+
     if command.state == "OFF" {
         if is_light {
+            if let (Some(duration), Some(current)) = (transition, &current) {
+                if !run_transition(
+                    &state,
+                    &device,
+                    brightness_steps(current.brightness, 1, duration),
+                    duration,
+                    transition_key,
+                    transition_generation,
+                )
+                .await?
+                {
+                    // Superseded by another command
+                    return Ok(());
+                }
+                // Restore the brightness when the light is next turned on
+                state
+                    .device_mut(&device.sku, &device.id)
+                    .await
+                    .brightness_before_fade
+                    .replace(current.brightness);
+            }
             state
                 .device_light_power_on(&device, false)
                 .await
@@ -285,126 +1484,314 @@ async fn mqtt_light_command(
                 .context("mqtt_light_command: state.device_set_brightness")?;
         }
     } else {
-        let mut power_on = true;
+        let restore_brightness = state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .brightness_before_fade
+            .take();
 
-        if let Some(brightness) = command.brightness {
+        // When fading in, start from the lowest brightness
+        let fade_in = is_light && transition.is_some() && current.is_none();
+        if fade_in {
+            state.device_send_brightness_step(&device, 1).await?;
             state
-                .device_set_brightness(&device, brightness)
+                .device_light_power_on(&device, true)
                 .await
-                .context("mqtt_light_command: state.device_set_brightness")?;
-            power_on = false;
+                .context("mqtt_light_command: state.device_power_on")?;
         }
-
-        if let Some(effect) = &command.effect {
-            state
-                .device_set_scene(&device, effect)
-                .await
-                .context("mqtt_light_command: state.device_set_scene")?;
-            // It doesn't make sense to vary color properties
-            // at the same time as the scene properties, so
-            // ignore those.
-            // Brightness, set above, is ok.
-            return Ok(());
+        let from_brightness = if fade_in {
+            Some(1)
+        } else {
+            current.as_ref().map(|s| s.brightness)
+        };
+
+        // A transition fades in from the lowest brightness instead
+        let light_is_off = is_light
+            && transition.is_none()
+            && device.device_state().and_then(|s| s.light_on) == Some(false);
+
+        for step in command.steps(light_is_off) {
+            match step {
+                LightCommandStep::Effect(effect) => {
+                    state
+                        .device_set_effect(&device, effect)
+                        .await
+                        .context("mqtt_light_command: state.device_set_effect")?;
+                }
+                LightCommandStep::Color(color) => {
+                    let color = state.clamp_color_to_gamut(&device, color).await;
+                    // We can only fade from a known color
+                    let from_color = current.as_ref().filter(|s| s.kelvin == 0).map(|s| s.color);
+                    if let (Some(duration), Some(from)) = (transition, from_color) {
+                        if !run_transition(
+                            &state,
+                            &device,
+                            color_steps(from, color, duration),
+                            duration,
+                            transition_key,
+                            transition_generation,
+                        )
+                        .await?
+                        {
+                            // Superseded by another command
+                            return Ok(());
+                        }
+                    }
+                    state
+                        .device_set_color_rgb(&device, color.r, color.g, color.b)
+                        .await
+                        .context("mqtt_light_command: state.device_set_color_rgb")?;
+                }
+                LightCommandStep::ColorTemperature(mired) => {
+                    state
+                        .device_set_color_temperature(&device, mired_to_kelvin(mired))
+                        .await
+                        .context("mqtt_light_command: state.device_set_color_temperature")?;
+                }
+                LightCommandStep::Brightness(brightness) => {
+                    if let (Some(duration), Some(from)) = (transition, from_brightness) {
+                        if !run_transition(
+                            &state,
+                            &device,
+                            brightness_steps(from, brightness, duration),
+                            duration,
+                            transition_key,
+                            transition_generation,
+                        )
+                        .await?
+                        {
+                            // Superseded by another command
+                            return Ok(());
+                        }
+                    }
+                    state
+                        .device_set_brightness(&device, brightness)
+                        .await
+                        .context("mqtt_light_command: state.device_set_brightness")?;
+                }
+                LightCommandStep::PowerOn => {
+                    if fade_in {
+                        let target = restore_brightness
+                            .or(device.device_state().map(|s| s.brightness))
+                            .filter(|&b| b > 0)
+                            .unwrap_or(100);
+                        if let Some(duration) = transition {
+                            if !run_transition(
+                                &state,
+                                &device,
+                                brightness_steps(1, target, duration),
+                                duration,
+                                transition_key,
+                                transition_generation,
+                            )
+                            .await?
+                            {
+                                // Superseded by another command
+                                return Ok(());
+                            }
+                        }
+                        state
+                            .device_set_brightness(&device, target)
+                            .await
+                            .context("mqtt_light_command: state.device_set_brightness")?;
+                    } else if is_light {
+                        state
+                            .device_light_power_on(&device, true)
+                            .await
+                            .context("mqtt_light_command: state.device_power_on")?;
+                        // Unless the command has just set the brightness
+                        let restore_brightness =
+                            restore_brightness.filter(|_| command.brightness.is_none());
+                        if let Some(brightness) = restore_brightness {
+                            state
+                                .device_set_brightness(&device, brightness)
+                                .await
+                                .context("mqtt_light_command: state.device_set_brightness")?;
+                        }
+                    } else {
+                        // The device is not primarily a light and we don't have
+                        // a guaranteed way to power it on without setting the
+                        // brightness to something, and we know we didn't set
+                        // the brightness just now, so let's turn it on 100%
+                        state
+                            .device_set_brightness(&device, 100)
+                            .await
+                            .context("mqtt_light_command: state.device_set_brightness")?;
+                    }
+                }
+            }
         }
+    }
 
-        if let Some(color) = &command.color {
-            state
-                .device_set_color_rgb(&device, color.r, color.g, color.b)
-                .await
-                .context("mqtt_light_command: state.device_set_color_rgb")?;
-            power_on = false;
-        }
-        if let Some(color_temp) = command.color_temp {
-            state
-                .device_set_color_temperature(&device, mired_to_kelvin(color_temp))
-                .await
-                .context("mqtt_light_command: state.device_set_color_temperature")?;
-            power_on = false;
+    Ok(())
+}
+
+async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<()> {
+    log::info!("mqtt_purge_caches");
+    crate::cache::purge_cache()?;
+    state
+        .get_hass_client()
+        .await
+        .expect("have hass client")
+        .register_with_hass(&state)
+        .await
+        .context("register_with_hass")
+}
+
+async fn mqtt_reload(State(state): State<StateHandle>) -> anyhow::Result<()> {
+    log::info!("mqtt_reload");
+    state.reload_config().await.context("reload_config")
+}
+
+/// One entry of a batch command: a light command
+/// together with the device that it applies to
+#[derive(Debug)]
+struct BatchEntry {
+    device: String,
+    payload: String,
+}
+
+/// The outcome of one entry of a batch command
+#[derive(Serialize, Debug, PartialEq)]
+struct BatchResult {
+    device: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BatchResult {
+    fn new(device: &str, result: anyhow::Result<()>) -> Self {
+        Self {
+            device: device.to_string(),
+            ok: result.is_ok(),
+            error: result.err().map(|err| format!("{err:#}")),
         }
+    }
+}
 
-        if power_on {
-            if is_light {
-                state
-                    .device_light_power_on(&device, true)
-                    .await
-                    .context("mqtt_light_command: state.device_power_on")?;
-            } else if command.brightness.is_none() {
-                // The device is not primarily a light and we don't have
-                // a guaranteed way to power it on without setting the
-                // brightness to something, and we know we didn't set
-                // the brightness just now, so let's turn it on 100%
-                state
-                    .device_set_brightness(&device, 100)
-                    .await
-                    .context("mqtt_light_command: state.device_set_brightness")?;
+/// Split a batch payload into the light command for each device,
+/// such as `[{"device": "Lounge", "state": "ON", "brightness": 40}]`.
+/// An entry that is invalid produces an error, rather than failing
+/// the entire batch.
+fn parse_batch(payload: &str) -> anyhow::Result<Vec<Result<BatchEntry, BatchResult>>> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_str(payload).context("expected a JSON list of commands")?;
+
+    Ok(entries
+        .into_iter()
+        .enumerate()
+        .map(|(idx, mut entry)| {
+            let device = entry
+                .as_object_mut()
+                .and_then(|obj| obj.remove("device"))
+                .and_then(|device| device.as_str().map(|s| s.to_string()))
+                .ok_or_else(|| {
+                    BatchResult::new(
+                        &format!("#{idx}"),
+                        Err(anyhow::anyhow!("entry #{idx} has no `device` field")),
+                    )
+                })?;
+            serde_json::from_value::<HassLightCommand>(entry.clone())
+                .map_err(|err| BatchResult::new(&device, Err(err.into())))?;
+            Ok(BatchEntry {
+                device,
+                payload: entry.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Someone sent a list of commands for several devices, such as for
+/// a scene in an automation. The commands are applied concurrently,
+/// via the same path as the commands for individual lights, and the
+/// outcome for each device is published to the batch result topic.
+async fn mqtt_batch(
+    Payload(payload): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_batch: {payload}");
+
+    let mut tasks = vec![];
+    for entry in parse_batch(&payload)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(result) => {
+                tasks.push(tokio::spawn(async move { result }));
+                continue;
             }
+        };
+        let state = state.clone();
+        tasks.push(tokio::spawn(async move {
+            let result = match state.resolve_device_read_only(&entry.device).await {
+                Ok(device) => {
+                    mqtt_light_command(
+                        Payload(entry.payload),
+                        Params(IdParameter { id: device.id }),
+                        State(state),
+                    )
+                    .await
+                }
+                Err(err) => Err(err),
+            };
+            BatchResult::new(&entry.device, result)
+        }));
+    }
+
+    let mut results = vec![];
+    for task in tasks {
+        results.push(task.await?);
+    }
+    for result in &results {
+        if let Some(error) = &result.error {
+            log::error!("mqtt_batch: {}: {error}", result.device);
         }
     }
 
+    if let Some(hass) = state.get_hass_client().await {
+        hass.publish_obj(batch_result_topic(), &results).await?;
+    }
     Ok(())
 }
 
-#[derive(Deserialize)]
-struct IdAndSeg {
-    id: String,
-    segment: String,
+/// Parse a color in the form `#RRGGBB`; the `#` is optional
+fn parse_hex_color(s: &str) -> anyhow::Result<DeviceColor> {
+    let s = s.trim();
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("expected a color in the form #RRGGBB but got {s:?}");
+    }
+    let value = u32::from_str_radix(hex, 16)?;
+    Ok(DeviceColor {
+        r: (value >> 16) as u8,
+        g: (value >> 8) as u8,
+        b: value as u8,
+    })
 }
 
-async fn mqtt_light_segment_command(
-    Payload(payload): Payload<String>,
-    Params(IdAndSeg { id, segment }): Params<IdAndSeg>,
+/// A convenience for scripts: sets the color of a light from a
+/// `#RRGGBB` string, turning it on, in the same way as a hass
+/// light command that specifies that color
+async fn mqtt_light_set_hex(
+    Payload(hex): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
-    let segment: u32 = segment.parse()?;
-
-    let command: HassLightCommand = from_json(&payload)?;
-    log::info!("Command for {device} segment {segment}: {payload}");
-
-    if let Some(client) = state.get_platform_client().await {
-        let info = device
-            .http_device_info
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("HTTP device info is missing"))?;
-
-        log::info!("Using Platform API to control {device} segment");
-
-        if let Some(brightness) = command.brightness {
-            client
-                .set_segment_brightness(&info, segment, brightness)
-                .await?;
-        } else if command.state == "OFF" {
-            // Do nothing here. We used to set brightness to zero,
-            // but it is problematic:
-            // * Some devices don't have a 0
-            // * Setting it to 0 will power up the rest of the device,
-            //   so if HASS is turning off all lights in an area, the
-            //   effect is that they will turn off and then immediate
-            //   on again when there are segments involved
-            // client.set_segment_brightness(&info, segment, 0).await?;
-        }
-        if let Some(color) = &command.color {
-            client
-                .set_segment_rgb(&info, segment, color.r, color.g, color.b)
-                .await?;
+    let color = match parse_hex_color(&hex) {
+        Ok(color) => color,
+        Err(err) => {
+            log::error!("mqtt_light_set_hex: {id}: {err:#}");
+            return Ok(());
         }
-    } else {
-        anyhow::bail!("set segments for {device}: Platform API is not available");
-    }
-
-    Ok(())
-}
-
-async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<()> {
-    log::info!("mqtt_purge_caches");
-    crate::cache::purge_cache()?;
-    state
-        .get_hass_client()
-        .await
-        .expect("have hass client")
-        .register_with_hass(&state)
-        .await
-        .context("register_with_hass")
+    };
+    log::info!("mqtt_light_set_hex: {id}: {hex}");
+
+    let payload = serde_json::json!({
+        "state": "ON",
+        "color": {"r": color.r, "g": color.g, "b": color.b},
+    })
+    .to_string();
+    mqtt_light_command(Payload(payload), Params(IdParameter { id }), State(state)).await
 }
 
 async fn mqtt_oneclick(
@@ -432,9 +1819,9 @@ async fn mqtt_oneclick(
 }
 
 #[derive(Deserialize)]
-struct IdAndInst {
-    id: String,
-    instance: String,
+pub struct IdAndInst {
+    pub id: String,
+    pub instance: String,
 }
 
 async fn mqtt_switch_command(
@@ -453,9 +1840,14 @@ async fn mqtt_switch_command(
 
     if instance == "powerSwitch" {
         state.device_power_on(&device, on).await?;
-    } else if let Some(client) = state.get_platform_client().await {
+    } else if let Some(client) = state.get_platform_client_for(&device).await {
         if let Some(http_dev) = &device.http_device_info {
             client.set_toggle_state(http_dev, &instance, on).await?;
+            state
+                .device_mut(&device.sku, &device.id)
+                .await
+                .set_last_toggle_state(&instance, on);
+            state.notify_of_state_change(&device.id).await?;
         } else {
             anyhow::bail!("No platform state available to set {id} {instance} to {on}");
         }
@@ -466,6 +1858,43 @@ async fn mqtt_switch_command(
     Ok(())
 }
 
+/// The color temperature range that we offer for devices that
+/// can only approximate color temperature using RGB
+pub const APPROXIMATE_COLOR_TEMP_RANGE: (u32, u32) = (2000, 9000);
+
+/// Approximate the color of a black body radiator at the specified
+/// temperature, for devices that have no native color temperature
+/// support. This uses Tanner Helland's curve fit of the CIE 1964
+/// 10 degree color matching functions:
+/// <https://tannerhelland.com/2012/09/18/convert-temperature-rgb-algorithm-code.html>
+pub fn kelvin_to_rgb(kelvin: u32) -> DeviceColor {
+    let temp = kelvin.clamp(1000, 40000) as f64 / 100.;
+
+    let r = if temp <= 66. {
+        255.
+    } else {
+        329.698727446 * (temp - 60.).powf(-0.1332047592)
+    };
+    let g = if temp <= 66. {
+        99.4708025861 * temp.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (temp - 60.).powf(-0.0755148492)
+    };
+    let b = if temp >= 66. {
+        255.
+    } else if temp <= 19. {
+        0.
+    } else {
+        138.5177312231 * (temp - 10.).ln() - 305.0447927307
+    };
+
+    DeviceColor {
+        r: r.round().clamp(0., 255.) as u8,
+        g: g.round().clamp(0., 255.) as u8,
+        b: b.round().clamp(0., 255.) as u8,
+    }
+}
+
 pub fn mired_to_kelvin(mired: u32) -> u32 {
     if mired == 0 {
         0
@@ -514,59 +1943,223 @@ async fn run_mqtt_loop(
         state: &StateHandle,
     ) -> anyhow::Result<Arc<MqttRouter<StateHandle>>> {
         let disco_prefix = state.get_hass_disco_prefix().await;
-        let mut router: MqttRouter<StateHandle> = MqttRouter::new(client.clone());
+        let mut router = CommandRouter::new(client.clone(), message_options().command_qos);
 
         router
             .route(format!("{disco_prefix}/status"), mqtt_homeassitant_status)
             .await?;
 
         router
-            .route("gv2mqtt/light/:id/command", mqtt_light_command)
+            .route(
+                format!("{}/light/:id/command", topic_prefix()),
+                mqtt_light_command,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/light/:id/set-hex", topic_prefix()),
+                mqtt_light_set_hex,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/light/:id/nightlight/command", topic_prefix()),
+                mqtt_nightlight_command,
+            )
             .await?;
         router
             .route(
-                "gv2mqtt/light/:id/command/:segment",
+                format!("{}/light/:id/command/:segment", topic_prefix()),
                 mqtt_light_segment_command,
             )
             .await?;
         router
-            .route("gv2mqtt/switch/:id/command/:instance", mqtt_switch_command)
+            .route(
+                format!("{}/light/:id/segment-color", topic_prefix()),
+                mqtt_light_segment_colors,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/light/:id/segment-command", topic_prefix()),
+                mqtt_light_segment_settings,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/light/:id/gradient", topic_prefix()),
+                mqtt_light_gradient,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/switch/:id/command/:instance", topic_prefix()),
+                mqtt_switch_command,
+            )
             .await?;
 
         router.route(oneclick_topic(), mqtt_oneclick).await?;
         router.route(purge_cache_topic(), mqtt_purge_caches).await?;
+        router.route(reload_topic(), mqtt_reload).await?;
+        router.route(batch_topic(), mqtt_batch).await?;
+        router
+            .route(
+                format!("{}/:id/refresh", topic_prefix()),
+                mqtt_refresh_device,
+            )
+            .await?;
         router
             .route(
-                "gv2mqtt/:id/request-platform-data",
+                format!("{}/:id/request-platform-data", topic_prefix()),
                 mqtt_request_platform_data,
             )
             .await?;
         router
             .route(
-                "gv2mqtt/number/:id/command/:mode_name/:work_mode",
+                format!(
+                    "{}/number/:id/command/:mode_name/:work_mode",
+                    topic_prefix()
+                ),
                 mqtt_number_command,
             )
             .await?;
         router
-            .route("gv2mqtt/humidifier/:id/set-mode", mqtt_device_set_work_mode)
+            .route(
+                format!("{}/humidifier/:id/set-mode", topic_prefix()),
+                mqtt_device_set_work_mode,
+            )
             .await?;
         router
-            .route("gv2mqtt/:id/set-work-mode", mqtt_device_set_work_mode)
+            .route(
+                format!("{}/:id/set-work-mode", topic_prefix()),
+                mqtt_device_set_work_mode,
+            )
             .await?;
         router
             .route(
-                "gv2mqtt/humidifier/:id/set-target",
+                format!("{}/humidifier/:id/set-target", topic_prefix()),
                 mqtt_humidifier_set_target,
             )
             .await?;
         router
             .route(
-                "gv2mqtt/:id/set-temperature/:instance/:units",
+                format!("{}/fan/:id/set-speed", topic_prefix()),
+                mqtt_fan_set_speed,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/fan/:id/set-preset", topic_prefix()),
+                mqtt_fan_set_preset,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/fan/:id/set-speed-step", topic_prefix()),
+                mqtt_fan_set_speed_step,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/fan/:id/set-oscillation", topic_prefix()),
+                mqtt_fan_set_oscillation,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/fan/:id/set-combined", topic_prefix()),
+                mqtt_fan_set_combined,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-temperature/:instance/:units", topic_prefix()),
                 mqtt_set_temperature,
             )
             .await?;
         router
-            .route("gv2mqtt/:id/set-mode-scene", mqtt_set_mode_scene)
+            .route(
+                format!("{}/:id/set-regulated-temperature/:units", topic_prefix()),
+                mqtt_set_regulated_temperature,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/climate/:id/set-mode", topic_prefix()),
+                mqtt_climate_set_mode,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/climate/:id/set-preset", topic_prefix()),
+                mqtt_climate_set_preset,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-mode-scene", topic_prefix()),
+                mqtt_set_mode_scene,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-diy-scene", topic_prefix()),
+                mqtt_set_diy_scene,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-snapshot", topic_prefix()),
+                mqtt_set_snapshot,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-light-scene", topic_prefix()),
+                mqtt_set_light_scene,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/light/:id/set-scene-id", topic_prefix()),
+                mqtt_set_scene_id,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-music-mode", topic_prefix()),
+                mqtt_set_music_mode,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-music-sensitivity", topic_prefix()),
+                mqtt_set_music_sensitivity,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-power-on-behavior", topic_prefix()),
+                mqtt_set_power_on_behavior,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-do-not-disturb", topic_prefix()),
+                mqtt_set_do_not_disturb,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-do-not-disturb/:field", topic_prefix()),
+                mqtt_set_do_not_disturb_time,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/number/:id/sleep-timer/:instance", topic_prefix()),
+                mqtt_set_sleep_timer,
+            )
             .await?;
 
         tokio::time::sleep(HASS_REGISTER_DELAY).await;
@@ -578,7 +2171,7 @@ async fn run_mqtt_loop(
             .await
             .context("register_with_hass")?;
 
-        Ok(Arc::new(router))
+        Ok(Arc::new(router.into_inner()))
     }
 
     let mut router = rebuild_router(&client, &state).await?;
@@ -589,20 +2182,47 @@ async fn run_mqtt_loop(
             Event::Message(msg) => {
                 let router = router.clone();
                 let state = state.clone();
-                tokio::spawn(async move {
+                tokio::spawn(log_context::scope(async move {
                     if let Err(err) = router.dispatch(msg.clone(), state.clone()).await {
                         log::error!("While dispatching {msg:?}: {err:#}");
                     }
-                });
+                }));
             }
             Event::Disconnected(reason) => {
-                log::warn!("MQTT disconnected with reason={reason}");
+                log::warn!(
+                    "MQTT disconnected with reason={reason}, \
+                     will reconnect and re-register with hass"
+                );
+                if let Some(hass) = state.get_hass_client().await {
+                    hass.set_disconnected();
+                }
                 need_rebuild = true;
             }
             Event::Connected(status) => {
                 log::info!("MQTT connected with status={status}");
                 if need_rebuild {
-                    router = rebuild_router(&client, &state).await?;
+                    if let Some(hass) = state.get_hass_client().await {
+                        hass.set_connected();
+                    }
+                    // If this fails, we have most likely been
+                    // disconnected again, and will retry when
+                    // the connection is re-established
+                    match rebuild_router(&client, &state).await {
+                        Ok(r) => {
+                            router = r;
+                            need_rebuild = false;
+                            // Now that hass has the entity configs again,
+                            // deliver the state changes that it missed
+                            if let Some(hass) = state.get_hass_client().await {
+                                if let Err(err) = hass.flush_offline_buffer().await {
+                                    log::warn!("Unable to publish buffered messages: {err:#}");
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            log::error!("Failed to re-register with hass: {err:#}");
+                        }
+                    }
                 }
             }
         }
@@ -613,6 +2233,49 @@ async fn run_mqtt_loop(
     Ok(())
 }
 
+/// Apply the settings that can be changed by reloading the
+/// configuration, without reconnecting to the MQTT broker
+pub async fn apply_reloadable_settings(
+    state: &StateHandle,
+    args: &HassArguments,
+) -> anyhow::Result<()> {
+    state.set_temperature_scale(args.temperature_scale()?).await;
+    set_sensor_calibrations(args.sensor_calibrations()?);
+    set_entity_overrides(args.entity_overrides()?);
+    state
+        .set_availability_grace_period(args.availability_grace_period()?)
+        .await;
+    state
+        .set_device_removal_cycles(args.device_removal_cycles()?)
+        .await;
+
+    let filter = args.device_filter()?;
+    if !filter.is_empty() {
+        log::info!("Filtering devices: {filter:?}");
+    }
+    state.set_device_filter(filter).await;
+    let entity_filter = args.entity_filter()?;
+    if !entity_filter.is_empty() {
+        log::info!("Filtering entities: {entity_filter:?}");
+    }
+    set_entity_filter(entity_filter);
+    state.set_debug_sensors(args.debug_sensors()?).await;
+    state.set_prefer_sku_names(args.use_sku_names()?).await;
+    state
+        .set_transition_simulation_disabled(args.disable_transitions()?)
+        .await;
+    state.set_gamut_clamping(args.gamut_clamping()?).await;
+    state.set_scene_categories(args.scene_categories()?).await;
+    state
+        .set_color_mode_priority(args.color_mode_priority()?)
+        .await;
+    set_republish_interval(args.hass_republish_interval()?);
+    set_discovery_stagger(args.hass_discovery_stagger()?);
+    set_group_command_window(args.group_command_window()?);
+    state.set_device_timeout(args.device_timeout()?).await;
+    Ok(())
+}
+
 pub async fn spawn_hass_integration(
     state: StateHandle,
     args: &HassArguments,
@@ -622,14 +2285,22 @@ pub async fn spawn_hass_integration(
         true,
     )?;
 
-    state.set_temperature_scale(args.temperature_scale()?).await;
+    set_topic_prefix(args.mqtt_topic_prefix()?);
+    apply_reloadable_settings(&state, args).await?;
 
     let mqtt_host = args.mqtt_host()?;
     let mqtt_username = args.mqtt_username()?;
     let mqtt_password = args.mqtt_password()?;
     let mqtt_port = args.mqtt_port()?;
 
-    client.set_last_will(availability_topic(), "offline", QoS::AtMostOnce, false)?;
+    let options = args.mqtt_message_options()?;
+    set_message_options(options);
+    client.set_last_will(
+        availability_topic(),
+        "offline",
+        options.state_qos,
+        options.state_retain,
+    )?;
 
     if mqtt_username.is_some() != mqtt_password.is_some() {
         log::error!(
@@ -637,20 +2308,47 @@ pub async fn spawn_hass_integration(
         );
     }
     client.set_username_and_password(mqtt_username.as_deref(), mqtt_password.as_deref())?;
-    client
-        .connect(
-            &mqtt_host,
-            mqtt_port.into(),
-            Duration::from_secs(120),
-            args.mqtt_bind_address.as_deref(),
-        )
-        .await
-        .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))?;
+    client.set_reconnect_delay(MQTT_RECONNECT_DELAY, MQTT_MAX_RECONNECT_DELAY, true)?;
+
+    // The broker may still be starting up, so keep trying
+    // with the same backoff that is used to reconnect
+    let mut delay = MQTT_RECONNECT_DELAY;
+    loop {
+        match client
+            .connect(
+                &mqtt_host,
+                mqtt_port.into(),
+                Duration::from_secs(120),
+                args.mqtt_bind_address.as_deref(),
+            )
+            .await
+        {
+            Ok(_) => break,
+            Err(err @ mosquitto_rs::Error::RejectedConnection(_)) => {
+                return Err(err)
+                    .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"));
+            }
+            Err(err) => {
+                log::warn!(
+                    "Unable to connect to mqtt broker {mqtt_host}:{mqtt_port}: {err:#}. \
+                     Retrying in {delay:?}"
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MQTT_MAX_RECONNECT_DELAY);
+            }
+        }
+    }
     let subscriber = client.subscriber().expect("to own the subscriber");
 
     state
         .set_hass_client(HassClient {
-            client: client.clone(),
+            client: HassTransport::Mqtt(client.clone()),
+            removing_entities: false,
+            published: Default::default(),
+            offline: Default::default(),
+            config_topics: Default::default(),
+            registering: Default::default(),
+            registration_queued: Default::default(),
         })
         .await;
 
@@ -694,3 +2392,273 @@ fn test_camel_case_to_space_separated() {
         "Oscillation Toggle"
     );
 }
+
+#[cfg(test)]
+#[test]
+fn test_light_command_debounce_key() {
+    let brightness: HassLightCommand =
+        serde_json::from_str(r#"{"state":"ON","brightness":42}"#).unwrap();
+    let brightness2: HassLightCommand =
+        serde_json::from_str(r#"{"state":"ON","brightness":64}"#).unwrap();
+    let color: HassLightCommand =
+        serde_json::from_str(r#"{"state":"ON","color":{"r":255,"g":0,"b":0}}"#).unwrap();
+
+    assert_eq!(
+        brightness.debounce_key("light"),
+        brightness2.debounce_key("light")
+    );
+    assert_ne!(
+        brightness.debounce_key("light"),
+        color.debounce_key("light")
+    );
+    assert_ne!(
+        brightness.debounce_key("light"),
+        brightness.debounce_key("other")
+    );
+
+    k9::assert_equal!(
+        color.optimistic_state(),
+        serde_json::json!({"state":"ON","color_mode":"rgb","color":{"r":255,"g":0,"b":0}})
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_kelvin_to_rgb() {
+    k9::assert_equal!(
+        kelvin_to_rgb(2700),
+        DeviceColor {
+            r: 255,
+            g: 167,
+            b: 87
+        }
+    );
+    k9::assert_equal!(
+        kelvin_to_rgb(6600),
+        DeviceColor {
+            r: 255,
+            g: 255,
+            b: 255
+        }
+    );
+    let cool = kelvin_to_rgb(9000);
+    assert!(cool.b == 255 && cool.r < 255);
+}
+
+#[cfg(test)]
+#[test]
+fn test_validate_topic_prefix() {
+    k9::assert_equal!(validate_topic_prefix("gv2mqtt").unwrap(), "gv2mqtt");
+    k9::assert_equal!(
+        validate_topic_prefix("test/gv2mqtt/").unwrap(),
+        "test/gv2mqtt"
+    );
+    assert!(validate_topic_prefix("/").is_err());
+    assert!(validate_topic_prefix("gv2mqtt/#").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_light_command_steps() {
+    let command: HassLightCommand =
+        serde_json::from_str(r#"{"state":"ON","brightness":42,"color":{"r":255,"g":0,"b":0}}"#)
+            .unwrap();
+    k9::assert_equal!(
+        command.steps(false),
+        vec![
+            LightCommandStep::Color(DeviceColor { r: 255, g: 0, b: 0 }),
+            LightCommandStep::Brightness(42)
+        ]
+    );
+
+    let command: HassLightCommand =
+        serde_json::from_str(r#"{"state":"ON","effect":"Sunrise","brightness":42}"#).unwrap();
+    k9::assert_equal!(
+        command.steps(false),
+        vec![
+            LightCommandStep::Effect("Sunrise"),
+            LightCommandStep::Brightness(42)
+        ]
+    );
+
+    let command: HassLightCommand = serde_json::from_str(r#"{"state":"ON"}"#).unwrap();
+    k9::assert_equal!(command.steps(false), vec![LightCommandStep::PowerOn]);
+
+    // Turning on a light that is off to a given brightness sets
+    // the brightness before powering it on
+    let command: HassLightCommand =
+        serde_json::from_str(r#"{"state":"ON","brightness":10}"#).unwrap();
+    k9::assert_equal!(command.steps(false), vec![LightCommandStep::Brightness(10)]);
+    k9::assert_equal!(
+        command.steps(true),
+        vec![LightCommandStep::Brightness(10), LightCommandStep::PowerOn]
+    );
+    let command: HassLightCommand = serde_json::from_str(r#"{"state":"ON"}"#).unwrap();
+    k9::assert_equal!(command.steps(true), vec![LightCommandStep::PowerOn]);
+
+    // Including when the color changes too
+    let command: HassLightCommand =
+        serde_json::from_str(r#"{"state":"ON","brightness":42,"color":{"r":255,"g":0,"b":0}}"#)
+            .unwrap();
+    k9::assert_equal!(
+        command.steps(true),
+        vec![
+            LightCommandStep::Brightness(42),
+            LightCommandStep::Color(DeviceColor { r: 255, g: 0, b: 0 }),
+            LightCommandStep::PowerOn
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_light_command_color_mode() {
+    let both = |color_mode: &str, priority: &str| {
+        let mut command: HassLightCommand = serde_json::from_str(&format!(
+            r#"{{"state":"ON","color":{{"r":255,"g":0,"b":0}},"color_temp":300{color_mode}}}"#
+        ))
+        .unwrap();
+        command.resolve_color_mode(priority.parse().unwrap());
+        (command.color, command.color_temp)
+    };
+    let color = (Some(DeviceColor { r: 255, g: 0, b: 0 }), None);
+    let temp = (None, Some(300));
+
+    k9::assert_equal!(both("", "color"), color);
+    k9::assert_equal!(both("", "color_temp"), temp);
+    k9::assert_equal!(both("", "hass"), temp);
+    k9::assert_equal!(both(r#","color_mode":"rgb""#, "hass"), color);
+    k9::assert_equal!(both(r#","color_mode":"rgb""#, "color_temp"), temp);
+    assert!("sometimes".parse::<ColorModePriority>().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_mqtt_message_options() {
+    let options = MqttMessageOptions::default();
+    k9::assert_equal!(
+        options.for_topic("homeassistant/light/gv2mqtt-AABB/config"),
+        (QoS::AtLeastOnce, true)
+    );
+    k9::assert_equal!(
+        options.for_topic(&format!("{}/light/AABB/state", topic_prefix())),
+        (QoS::AtMostOnce, false)
+    );
+    k9::assert_equal!(
+        route_topic_filter("gv2mqtt/light/:id/command"),
+        "gv2mqtt/light/+/command"
+    );
+    k9::assert_equal!(parse_qos(2).unwrap(), QoS::ExactlyOnce);
+    assert!(parse_qos(3).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_hex_color() {
+    k9::assert_equal!(
+        parse_hex_color("#FF8000").unwrap(),
+        DeviceColor {
+            r: 255,
+            g: 128,
+            b: 0
+        }
+    );
+    k9::assert_equal!(
+        parse_hex_color(" 00ff7f\n").unwrap(),
+        DeviceColor {
+            r: 0,
+            g: 255,
+            b: 127
+        }
+    );
+    assert!(parse_hex_color("#FF80").is_err());
+    assert!(parse_hex_color("#GG8000").is_err());
+    assert!(parse_hex_color("#+F8000").is_err());
+    assert!(parse_hex_color("").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_publish_cache() {
+    let mut cache = PublishCache::default();
+    assert!(cache.should_publish("light/state", b"ON"));
+    assert!(!cache.should_publish("light/state", b"ON"));
+    assert!(cache.should_publish("switch/state", b"ON"));
+    assert!(cache.should_publish("light/state", b"OFF"));
+    assert!(!cache.should_publish("light/state", b"OFF"));
+
+    // Registering with hass publishes everything again
+    cache.clear();
+    assert!(cache.should_publish("light/state", b"OFF"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_offline_buffer() {
+    let (client, captured) = HassClient::capture();
+    client.set_disconnected();
+    client.publish("light/state", "ON").await.unwrap();
+    client.publish("light/state", "OFF").await.unwrap();
+    client.publish("switch/state", "ON").await.unwrap();
+    assert!(captured.lock().unwrap().is_empty());
+
+    // Only the latest payload for each topic is sent
+    client.flush_offline_buffer().await.unwrap();
+    client.publish("light/state", "ON").await.unwrap();
+    k9::assert_equal!(
+        std::mem::take(&mut *captured.lock().unwrap()),
+        vec![
+            ("light/state".to_string(), "OFF".to_string()),
+            ("switch/state".to_string(), "ON".to_string()),
+            ("light/state".to_string(), "ON".to_string()),
+        ]
+    );
+
+    // What is published while re-registering supersedes the
+    // buffered payload for the same topic
+    client.set_disconnected();
+    client.publish("light/state", "OFF").await.unwrap();
+    client.publish("switch/state", "OFF").await.unwrap();
+    client.set_connected();
+    client.publish("light/state", "ON").await.unwrap();
+    client.flush_offline_buffer().await.unwrap();
+    k9::assert_equal!(
+        std::mem::take(&mut *captured.lock().unwrap()),
+        vec![
+            ("light/state".to_string(), "ON".to_string()),
+            ("switch/state".to_string(), "OFF".to_string()),
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_batch() {
+    let entries = parse_batch(
+        r#"[
+            {"device": "Lounge", "state": "ON", "brightness": 40},
+            {"state": "OFF"},
+            {"device": "Hall", "brightness": 40}
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 3);
+
+    let lounge = entries[0].as_ref().unwrap();
+    assert_eq!(lounge.device, "Lounge");
+    let command: HassLightCommand = serde_json::from_str(&lounge.payload).unwrap();
+    assert_eq!(command.brightness, Some(40));
+
+    let missing_device = entries[1].as_ref().unwrap_err();
+    assert_eq!(missing_device.device, "#1");
+    assert!(!missing_device.ok);
+
+    let missing_state = entries[2].as_ref().unwrap_err();
+    assert_eq!(missing_state.device, "Hall");
+    assert_eq!(
+        missing_state.error.as_deref(),
+        Some("missing field `state`")
+    );
+
+    assert!(parse_batch(r#"{"device": "Lounge"}"#).is_err());
+}
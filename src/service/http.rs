@@ -1,14 +1,20 @@
+use crate::hass_mqtt::fan::fan_set_speed;
+use crate::hass_mqtt::humidifier::set_work_mode;
 use crate::service::coordinator::Coordinator;
 use crate::service::device::{Device, DeviceState};
+use crate::service::metrics::METRICS;
 use crate::service::state::StateHandle;
 use anyhow::Context;
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::{Json, Router};
 use serde::Serialize;
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::Arc;
 use tower_http::services::ServeDir;
 
 fn response_with_code<T: ToString + std::fmt::Display>(code: StatusCode, err: T) -> Response {
@@ -160,6 +166,34 @@ async fn device_set_color(
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+/// Sets the speed of a given fan, as a percentage
+async fn device_set_fan_speed(
+    State(state): State<StateHandle>,
+    Path((id, percent)): Path<(String, u8)>,
+) -> Result<Response, Response> {
+    let device = resolve_device_for_control(&state, &id).await?;
+
+    fan_set_speed(&state, &device, percent)
+        .await
+        .map_err(generic)?;
+
+    Ok(response_with_code(StatusCode::OK, "ok"))
+}
+
+/// Switches a given device into the named work mode
+async fn device_set_work_mode(
+    State(state): State<StateHandle>,
+    Path((id, mode)): Path<(String, String)>,
+) -> Result<Response, Response> {
+    let device = resolve_device_for_control(&state, &id).await?;
+
+    set_work_mode(&state, &device, &mode)
+        .await
+        .map_err(generic)?;
+
+    Ok(response_with_code(StatusCode::OK, "ok"))
+}
+
 /// Activates the named scene for a given device
 async fn device_set_scene(
     State(state): State<StateHandle>,
@@ -229,8 +263,61 @@ async fn redirect_to_index() -> Response {
     axum::response::Redirect::to("/assets/index.html").into_response()
 }
 
-pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()> {
-    let app = Router::new()
+/// Returns operational metrics in the Prometheus text format
+async fn metrics(State(state): State<StateHandle>) -> Response {
+    let num_devices = state.devices().await.len();
+    let iot_connected = state.get_iot_client().await.is_some();
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        METRICS.render(num_devices, iot_connected),
+    )
+        .into_response()
+}
+
+/// Returns whether the request carries the token, either as a
+/// bearer token in the Authorization header, or as the token
+/// query parameter for clients that can't set headers
+fn request_has_token(headers: &HeaderMap, query: &HashMap<String, String>, token: &str) -> bool {
+    let bearer = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.trim());
+
+    let matches = |candidate: Option<&str>| candidate.is_some_and(|c| constant_time_eq(c, token));
+    matches(bearer) | matches(query.get("token").map(String::as_str))
+}
+
+/// Compare two strings in a time that depends only on their lengths,
+/// so that the time taken to reject a token doesn't reveal how much
+/// of it was correct
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+async fn require_token(State(token): State<Arc<String>>, request: Request, next: Next) -> Response {
+    let query = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .map(|Query(query)| query)
+        .unwrap_or_default();
+    if !request_has_token(request.headers(), &query, &token) {
+        return response_with_code(StatusCode::UNAUTHORIZED, "a valid token is required");
+    }
+    next.run(request).await
+}
+
+/// Build the routes of the HTTP service. When a token is set, it
+/// is required for everything other than the web UI assets
+fn build_router(state: StateHandle, enable_metrics: bool, token: Option<String>) -> Router {
+    let mut api = Router::new()
         .route("/api/devices", get(list_devices))
         .route("/api/device/:id/power/on", get(device_power_on))
         .route("/api/device/:id/power/off", get(device_power_off))
@@ -243,13 +330,37 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
             get(device_set_color_temperature),
         )
         .route("/api/device/:id/color/:color", get(device_set_color))
+        .route(
+            "/api/device/:id/fan/speed/:percent",
+            get(device_set_fan_speed),
+        )
+        .route("/api/device/:id/workmode/:mode", get(device_set_work_mode))
         .route("/api/device/:id/scene/:scene", get(device_set_scene))
         .route("/api/device/:id/scenes", get(device_list_scenes))
         .route("/api/oneclicks", get(list_one_clicks))
-        .route("/api/oneclick/activate/:scene", get(activate_one_click))
-        .route("/", get(redirect_to_index))
+        .route("/api/oneclick/activate/:scene", get(activate_one_click));
+    if enable_metrics {
+        api = api.route("/metrics", get(metrics));
+    }
+    if let Some(token) = token {
+        api = api.route_layer(axum::middleware::from_fn_with_state(
+            Arc::new(token),
+            require_token,
+        ));
+    }
+
+    api.route("/", get(redirect_to_index))
         .nest_service("/assets", ServeDir::new("assets"))
-        .with_state(state);
+        .with_state(state)
+}
+
+pub async fn run_http_server(
+    state: StateHandle,
+    port: u16,
+    enable_metrics: bool,
+    token: Option<String>,
+) -> anyhow::Result<()> {
+    let app = build_router(state, enable_metrics, token);
 
     let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
         .await
@@ -262,3 +373,47 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token() {
+        let mut headers = HeaderMap::new();
+        let mut query = HashMap::new();
+        assert!(!request_has_token(&headers, &query, "secret"));
+
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            "Bearer secret".parse().unwrap(),
+        );
+        assert!(request_has_token(&headers, &query, "secret"));
+        assert!(!request_has_token(&headers, &query, "other"));
+
+        headers.clear();
+        query.insert("token".to_string(), "secret".to_string());
+        assert!(request_has_token(&headers, &query, "secret"));
+        assert!(!request_has_token(&headers, &query, "secreT"));
+        assert!(!request_has_token(&headers, &query, "secret2"));
+    }
+
+    #[tokio::test]
+    async fn metrics_require_token() {
+        let state = Arc::new(crate::service::state::State::new());
+        let app = build_router(state, true, Some("secret".to_string()));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let url = format!("http://{addr}/metrics");
+        let status = |response: reqwest::Response| response.status().as_u16();
+        assert_eq!(status(reqwest::get(&url).await.unwrap()), 401);
+        assert_eq!(
+            status(reqwest::get(format!("{url}?token=secret")).await.unwrap()),
+            200
+        );
+    }
+}
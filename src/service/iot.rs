@@ -1,8 +1,12 @@
 use crate::ble::{Base64HexBytes, GoveeBlePacket, HumidifierAutoMode, NotifyHumidifierMode};
 use crate::lan_api::{DeviceColor, DeviceStatus};
 use crate::platform_api::from_json;
+use crate::service::account::account_path;
+use crate::service::dry_run;
 use crate::service::state::StateHandle;
-use crate::undoc_api::{ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick};
+use crate::undoc_api::{
+    ms_timestamp, DeviceEntry, GoveeUndocumentedApi, LoginAccountResponse, ParsedOneClick,
+};
 use crate::Args;
 use anyhow::Context;
 use async_channel::Receiver;
@@ -11,6 +15,9 @@ use serde::Deserialize;
 use std::time::Duration;
 use tokio::time::timeout;
 
+const IOT_RECONNECT_MIN_DELAY: Duration = Duration::from_secs(5);
+const IOT_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
+
 #[derive(Clone)]
 pub struct IotClient {
     client: mosquitto_rs::Client,
@@ -60,50 +67,46 @@ impl IotClient {
             _ => pwr(on, 1, 0),
         };
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "turn",
-                        "data": {
-                            "val": power_state,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_power_state")?;
+        self.send_command(
+            device,
+            device_topic,
+            serde_json::json!({
+                "msg": {
+                    "cmd": "turn",
+                    "data": {
+                        "val": power_state,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
+        .context("IotClient::set_power_state")?;
         Ok(())
     }
 
     pub async fn set_brightness(&self, device: &DeviceEntry, percent: u8) -> anyhow::Result<()> {
         log::trace!("set_brightness for {} to {percent}", device.device);
         let device_topic = device.device_topic()?;
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "brightness",
-                        "data": {
-                            "val": percent,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_brightness")?;
+        self.send_command(
+            device,
+            device_topic,
+            serde_json::json!({
+                "msg": {
+                    "cmd": "brightness",
+                    "data": {
+                        "val": percent,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
+        .context("IotClient::set_brightness")?;
         Ok(())
     }
 
@@ -115,30 +118,28 @@ impl IotClient {
         log::trace!("set_color_temperature for {} to {kelvin}", device.device);
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color": {
-                                "r": 0,
-                                "g": 0,
-                                "b": 0,
-                            },
-                            "colorTemInKelvin": kelvin,
+        self.send_command(
+            device,
+            device_topic,
+            serde_json::json!({
+                "msg": {
+                    "cmd": "colorwc",
+                    "data": {
+                        "color": {
+                            "r": 0,
+                            "g": 0,
+                            "b": 0,
                         },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_temperature")?;
+                        "colorTemInKelvin": kelvin,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
+        .context("IotClient::set_color_temperature")?;
         Ok(())
     }
 
@@ -152,30 +153,28 @@ impl IotClient {
         log::trace!("set_color_rgb for {} to {r},{g},{b}", device.device);
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color":{
-                                "r": r,
-                                "g": g,
-                                "b": b,
-                            },
-                            "colorTemInKelvin": 0,
+        self.send_command(
+            device,
+            device_topic,
+            serde_json::json!({
+                "msg": {
+                    "cmd": "colorwc",
+                    "data": {
+                        "color":{
+                            "r": r,
+                            "g": g,
+                            "b": b,
                         },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_rgb")?;
+                        "colorTemInKelvin": 0,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
+        .context("IotClient::set_color_rgb")?;
         Ok(())
     }
 
@@ -187,31 +186,49 @@ impl IotClient {
         log::trace!("send_real for {} to {commands:?}", device.device);
         let device_topic = device.device_topic()?;
 
+        self.send_command(
+            device,
+            device_topic,
+            serde_json::json!({
+                "msg": {
+                    "cmd": "ptReal",
+                    "data": {
+                        "command": commands,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
+        .context("IotClient::send_real")?;
+        Ok(())
+    }
+
+    /// Publish a control command to the topic of a device
+    async fn send_command(
+        &self,
+        device: &DeviceEntry,
+        device_topic: &str,
+        command: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let command = serde_json::to_string(&command)?;
+        if dry_run::intercept(&device.device, "IoT", &command) {
+            return Ok(());
+        }
         self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "ptReal",
-                        "data": {
-                            "command": commands,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::send_real")?;
+            .publish(device_topic, command, QoS::AtMostOnce, false)
+            .await?;
         Ok(())
     }
 
     pub async fn activate_one_click(&self, item: &ParsedOneClick) -> anyhow::Result<()> {
         for entry in &item.entries {
             for command in &entry.msgs {
+                if dry_run::intercept(&entry.device, "IoT", command) {
+                    continue;
+                }
                 self.client
                     .publish(
                         entry.topic.as_str(),
@@ -227,12 +244,15 @@ impl IotClient {
     }
 }
 
+/// Connect to the IoT service on behalf of the account;
+/// account is None for the primary account
 pub async fn start_iot_client(
     args: &Args,
     state: StateHandle,
+    client: &GoveeUndocumentedApi,
+    account: Option<&str>,
     acct: Option<LoginAccountResponse>,
 ) -> anyhow::Result<()> {
-    let client = args.undoc_args.api_client()?;
     let acct = match acct {
         Some(a) => a,
         None => client.login_account_cached().await?,
@@ -242,6 +262,8 @@ pub async fn start_iot_client(
     log::trace!("{res:#?}");
 
     let key_bytes = data_encoding::BASE64.decode(res.p12.as_bytes())?;
+    let key_path = account_path(&args.undoc_args.govee_iot_key, account);
+    let cert_path = account_path(&args.undoc_args.govee_iot_cert, account);
 
     log::trace!("parsing IoT PFX key");
     let container = p12::PFX::parse(&key_bytes).context("PFX::parse")?;
@@ -250,12 +272,12 @@ pub async fn start_iot_client(
         let pem = priv_key
             .private_key_to_pem_pkcs8()
             .context("to_pem_pkcs8")?;
-        std::fs::write(&args.undoc_args.govee_iot_key, &pem)?;
+        std::fs::write(&key_path, &pem)?;
     }
     for cert in container.cert_bags(&res.p12_pass).context("cert_bags")? {
         let cert = openssl::x509::X509::from_der(&cert).context("x509 from der")?;
         let pem = cert.to_pem().context("cert.to_pem")?;
-        std::fs::write(&args.undoc_args.govee_iot_cert, &pem)?;
+        std::fs::write(&cert_path, &pem)?;
     }
 
     let client = mosquitto_rs::Client::with_id(
@@ -271,11 +293,16 @@ pub async fn start_iot_client(
         .configure_tls(
             Some(&args.undoc_args.amazon_root_ca),
             None::<&std::path::Path>,
-            Some(&args.undoc_args.govee_iot_cert),
-            Some(&args.undoc_args.govee_iot_key),
+            Some(&cert_path),
+            Some(&key_path),
             None,
         )
         .context("configure_tls")?;
+    // libmosquitto takes care of reconnecting for us; back off
+    // so that we don't hammer AWS during an outage
+    client
+        .set_reconnect_delay(IOT_RECONNECT_MIN_DELAY, IOT_RECONNECT_MAX_DELAY, true)
+        .context("set_reconnect_delay")?;
     log::trace!("Connecting to IoT {} port 8883", res.endpoint);
     let status = timeout(
         Duration::from_secs(60),
@@ -289,13 +316,17 @@ pub async fn start_iot_client(
     let subscriptions = client.subscriber().expect("first and only");
 
     state
-        .set_iot_client(IotClient {
-            client: client.clone(),
-        })
+        .set_iot_client(
+            account,
+            IotClient {
+                client: client.clone(),
+            },
+        )
         .await;
 
+    let account = account.map(|s| s.to_string());
     tokio::spawn(async move {
-        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct).await {
+        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct, account).await {
             log::error!("IoT loop failed: {err:#}");
         }
         log::info!("IoT loop terminated");
@@ -314,22 +345,89 @@ struct Packet {
     cmd: Option<String>,
     /// This is an embedded json string
     msg: Option<String>,
+    #[serde(default)]
     state: StateUpdate,
     op: Option<OpData>,
 }
 
-#[derive(Deserialize, Debug)]
+/// The state fields of an IoT message. Devices push messages that
+/// only include the fields that changed, along with others that we
+/// don't model; those, and fields that don't have the type that we
+/// expect, are ignored rather than causing the message to be dropped.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(from = "serde_json::Value")]
 struct StateUpdate {
-    #[serde(rename = "onOff")]
     pub on_off: Option<u8>,
     pub brightness: Option<u8>,
     pub color: Option<DeviceColor>,
-    #[serde(rename = "colorTemInKelvin")]
     pub color_temperature_kelvin: Option<u32>,
     pub sku: Option<String>,
     pub device: Option<String>,
 }
 
+impl From<serde_json::Value> for StateUpdate {
+    fn from(value: serde_json::Value) -> Self {
+        fn field<T: serde::de::DeserializeOwned>(
+            value: &serde_json::Value,
+            name: &str,
+        ) -> Option<T> {
+            let v = value.get(name)?;
+            match serde_json::from_value(v.clone()) {
+                Ok(v) => Some(v),
+                Err(err) => {
+                    log::debug!("Ignoring IoT state field {name}={v}: {err}");
+                    None
+                }
+            }
+        }
+
+        Self {
+            on_off: field(&value, "onOff"),
+            brightness: field(&value, "brightness"),
+            color: field(&value, "color"),
+            color_temperature_kelvin: field(&value, "colorTemInKelvin"),
+            sku: field(&value, "sku"),
+            device: field(&value, "device"),
+        }
+    }
+}
+
+impl StateUpdate {
+    /// Returns true if the update includes any of the fields
+    /// that make up the light state of a device
+    fn has_light_state(&self) -> bool {
+        self.on_off.is_some()
+            || self.brightness.is_some()
+            || self.color.is_some()
+            || self.color_temperature_kelvin.is_some()
+    }
+
+    /// Merge the fields that are present into status, leaving
+    /// the others as they were. The on/off state is applied
+    /// separately, by apply_on_off, as it takes precedence over
+    /// the on state that is implied by the other fields.
+    fn apply_to(&self, status: &mut DeviceStatus) {
+        if let Some(v) = self.brightness {
+            status.brightness = v;
+            status.on = v != 0;
+        }
+        if let Some(v) = self.color {
+            status.color = v;
+            status.on = true;
+        }
+        if let Some(v) = self.color_temperature_kelvin {
+            status.color_temperature_kelvin = v;
+            status.on = true;
+        }
+    }
+
+    fn apply_on_off(&self, status: &mut DeviceStatus) {
+        if let Some(on_off) = self.on_off {
+            status.on = on_off != 0;
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(unused)]
 struct OpData {
@@ -375,7 +473,10 @@ async fn run_iot_subscriber(
     state: StateHandle,
     client: mosquitto_rs::Client,
     acct: LoginAccountResponse,
+    account: Option<String>,
 ) -> anyhow::Result<()> {
+    let mut was_disconnected = false;
+
     while let Ok(event) = subscriptions.recv().await {
         match event {
             Event::Message(msg) => {
@@ -386,6 +487,24 @@ async fn run_iot_subscriber(
                     Ok(packet) => {
                         log::debug!("{packet:?}");
                         if let Some((sku, device_id)) = packet.sku_and_device() {
+                            let has_commands =
+                                packet.op.as_ref().is_some_and(|op| !op.command.is_empty());
+                            if !packet.state.has_light_state() && !has_commands {
+                                // Nothing that we model has changed, so
+                                // there is nothing to merge or report,
+                                // but the device is evidently reachable
+                                log::trace!("Ignoring IoT message for {device_id} with no state");
+                                state
+                                    .device_mut(sku, device_id)
+                                    .await
+                                    .touch_iot_device_status();
+                                if let Err(err) =
+                                    state.publish_device_availability(device_id, false).await
+                                {
+                                    log::warn!("publish_device_availability: {err:#}");
+                                }
+                                continue;
+                            }
                             {
                                 let mut device = state.device_mut(sku, device_id).await;
                                 let mut state = match device.iot_device_status.clone() {
@@ -401,18 +520,7 @@ async fn run_iot_subscriber(
                                     },
                                 };
 
-                                if let Some(v) = packet.state.brightness {
-                                    state.brightness = v;
-                                    state.on = v != 0;
-                                }
-                                if let Some(v) = packet.state.color {
-                                    state.color = v;
-                                    state.on = true;
-                                }
-                                if let Some(v) = packet.state.color_temperature_kelvin {
-                                    state.color_temperature_kelvin = v;
-                                    state.on = true;
-                                }
+                                packet.state.apply_to(&mut state);
 
                                 if let Some(op) = &packet.op {
                                     for cmd in &op.command {
@@ -463,9 +571,7 @@ async fn run_iot_subscriber(
 
                                 // Check on/off last, as we can synthesize "on"
                                 // if the other fields are present
-                                if let Some(on_off) = packet.state.on_off {
-                                    state.on = on_off != 0;
-                                }
+                                packet.state.apply_on_off(&mut state);
                                 device.set_iot_device_status(state);
                             }
                             state.notify_of_state_change(device_id).await?;
@@ -478,6 +584,13 @@ async fn run_iot_subscriber(
             }
             Event::Disconnected(reason) => {
                 log::warn!("IoT disconnected with reason {reason}");
+                if !was_disconnected {
+                    was_disconnected = true;
+                    state.clear_iot_client(account.as_deref()).await;
+                    // Entities that rely on IoT for feedback need to
+                    // switch to optimistic mode until we reconnect
+                    republish_hass_entities(&state);
+                }
             }
             Event::Connected(status) => {
                 log::info!("IoT (re)connected with status {status}");
@@ -486,6 +599,17 @@ async fn run_iot_subscriber(
                     .subscribe(&acct.topic, mosquitto_rs::QoS::AtMostOnce)
                     .await
                     .context("subscribe to account topic")?;
+
+                if was_disconnected {
+                    was_disconnected = false;
+                    let iot = IotClient {
+                        client: client.clone(),
+                    };
+                    state.set_iot_client(account.as_deref(), iot.clone()).await;
+                    resync_after_reconnect(&state, &iot, account.as_deref()).await;
+                    republish_hass_entities(&state);
+                }
+
                 // This logic tries to subscribe to the same data that is
                 // being sent to the individual devices, but the server
                 // will close the connection on us when we try this.
@@ -509,3 +633,76 @@ async fn run_iot_subscriber(
     }
     Ok(())
 }
+
+/// We may have missed state changes while we were disconnected,
+/// so ask each of the IoT capable devices for their current state
+async fn resync_after_reconnect(state: &StateHandle, iot: &IotClient, account: Option<&str>) {
+    for d in state.devices().await {
+        if d.account.as_deref() != account {
+            continue;
+        }
+        if let Some(info) = &d.undoc_device_info {
+            if iot.is_device_compatible(&info.entry) {
+                if let Err(err) = iot.request_status_update(&info.entry).await {
+                    log::warn!("Failed to request status update for {d} after reconnect: {err:#}");
+                }
+            }
+        }
+    }
+}
+
+/// Re-register the hass entities in the background, as their
+/// configuration depends on whether IoT is available
+fn republish_hass_entities(state: &StateHandle) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Some(hass) = state.get_hass_client().await {
+            if let Err(err) = hass.register_with_hass(&state).await {
+                log::error!("Failed to re-register with hass after IoT state change: {err:#}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partial_state_update() {
+        let mut status = DeviceStatus {
+            on: true,
+            brightness: 80,
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            color_temperature_kelvin: 0,
+        };
+
+        // Only the brightness changed; the other fields are kept,
+        // and fields that we don't model are ignored
+        let update: StateUpdate = serde_json::from_str(
+            r#"{"brightness": 20, "mode": {"id": 3}, "result": 1, "sku": "H6199"}"#,
+        )
+        .unwrap();
+        assert!(update.has_light_state());
+        update.apply_to(&mut status);
+        update.apply_on_off(&mut status);
+        assert_eq!(status.brightness, 20);
+        assert_eq!(status.color, DeviceColor { r: 255, g: 0, b: 0 });
+        assert!(status.on);
+
+        // A field with an unexpected type doesn't spoil the others
+        let update: StateUpdate =
+            serde_json::from_str(r#"{"onOff": 0, "color": "purple"}"#).unwrap();
+        assert_eq!(update.color, None);
+        update.apply_to(&mut status);
+        update.apply_on_off(&mut status);
+        assert!(!status.on);
+        assert_eq!(status.brightness, 20);
+
+        let packet: Packet =
+            serde_json::from_str(r#"{"sku": "H7160", "device": "AA:BB", "op": {"command": []}}"#)
+                .unwrap();
+        assert!(!packet.state.has_light_state());
+        assert_eq!(packet.sku_and_device(), Some(("H7160", "AA:BB")));
+    }
+}
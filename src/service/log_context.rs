@@ -0,0 +1,117 @@
+//! Associates log records with the device that they concern, so
+//! that the activity for a single device can be picked out of the
+//! polling and command paths, either by filtering with
+//! GOVEE_LOG_DEVICES or by searching the logs for its id.
+use crate::service::device::Device;
+use crate::service::device_filter::entry_matches_sku_or_id;
+use once_cell::sync::Lazy;
+use std::cell::RefCell;
+use std::future::Future;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceContext {
+    pub sku: String,
+    pub id: String,
+}
+
+tokio::task_local! {
+    static DEVICE_CONTEXT: RefCell<Option<DeviceContext>>;
+}
+
+/// Run fut such that it can call set_device to associate
+/// its subsequent log records with a device
+pub async fn scope<F: Future>(fut: F) -> F::Output {
+    DEVICE_CONTEXT.scope(RefCell::new(None), fut).await
+}
+
+/// Run fut with its log records associated with device
+pub async fn with_device<F: Future>(device: &Device, fut: F) -> F::Output {
+    DEVICE_CONTEXT
+        .scope(RefCell::new(Some(context_for(device))), fut)
+        .await
+}
+
+/// Associate the subsequent log records of the current task with
+/// device. Has no effect outside of scope or with_device.
+pub fn set_device(device: &Device) {
+    let _ = DEVICE_CONTEXT.try_with(|ctx| ctx.replace(Some(context_for(device))));
+}
+
+pub fn current_device() -> Option<DeviceContext> {
+    DEVICE_CONTEXT
+        .try_with(|ctx| ctx.borrow().clone())
+        .ok()
+        .flatten()
+}
+
+fn context_for(device: &Device) -> DeviceContext {
+    DeviceContext {
+        sku: device.sku.to_string(),
+        id: device.id.to_string(),
+    }
+}
+
+/// The devices listed in GOVEE_LOG_DEVICES, by SKU or id
+static LOG_DEVICES: Lazy<Vec<String>> = Lazy::new(|| {
+    std::env::var("GOVEE_LOG_DEVICES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+});
+
+/// Returns true if a record for the device should be logged.
+/// Records that aren't associated with a device are always logged.
+pub fn device_is_logged(device: Option<&DeviceContext>) -> bool {
+    is_logged(&LOG_DEVICES, device)
+}
+
+fn is_logged(filter: &[String], device: Option<&DeviceContext>) -> bool {
+    match device {
+        Some(ctx) if !filter.is_empty() => filter
+            .iter()
+            .any(|entry| entry_matches_sku_or_id(entry, &ctx.sku, &ctx.id)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn device_context() {
+        let light = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(current_device(), None);
+
+        let ctx = with_device(&light, async { current_device() }).await;
+        assert_eq!(ctx.as_ref().map(|c| c.sku.as_str()), Some("H6000"));
+
+        let ctx = scope(async {
+            assert_eq!(current_device(), None);
+            set_device(&light);
+            current_device()
+        })
+        .await;
+        assert_eq!(ctx.map(|c| c.id), Some(light.id.to_string()));
+
+        // set_device is ignored outside of a scope
+        set_device(&light);
+        assert_eq!(current_device(), None);
+    }
+
+    #[test]
+    fn filtering() {
+        let ctx = DeviceContext {
+            sku: "H6000".to_string(),
+            id: "AA:BB:CC:DD:EE:FF:42:2A".to_string(),
+        };
+        assert!(is_logged(&[], Some(&ctx)));
+        assert!(is_logged(&["h6000".to_string()], Some(&ctx)));
+        assert!(is_logged(&["AABBCCDDEEFF422A".to_string()], Some(&ctx)));
+        assert!(!is_logged(&["H7160".to_string()], Some(&ctx)));
+        assert!(is_logged(&["H7160".to_string()], None));
+    }
+}
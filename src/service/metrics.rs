@@ -0,0 +1,243 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counters that describe the operation of the bridge, which
+/// can be exported in the Prometheus text format via /metrics
+#[derive(Default)]
+pub struct Metrics {
+    platform_api_requests: AtomicU64,
+    platform_api_errors: AtomicU64,
+    platform_api_rate_limited: AtomicU64,
+    mqtt_publishes: AtomicU64,
+    polls: Mutex<BTreeMap<PollKey, PollStats>>,
+    last_cloud_success: Mutex<Option<DateTime<Utc>>>,
+    last_cloud_failure: Mutex<Option<DateTime<Utc>>>,
+}
+
+/// Identifies the device and the API used to poll it
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct PollKey {
+    device: String,
+    api: &'static str,
+}
+
+/// Extracts a value from PollStats when rendering
+type PollValue = fn(&PollStats) -> String;
+
+#[derive(Default, Clone, Debug)]
+struct PollStats {
+    count: u64,
+    errors: u64,
+    last_duration: Duration,
+}
+
+pub static METRICS: once_cell::sync::Lazy<Metrics> = once_cell::sync::Lazy::new(Metrics::default);
+
+impl Metrics {
+    /// Record the outcome of sending a request to the platform API.
+    /// status is None if the request couldn't be sent at all.
+    pub fn platform_api_request(&self, status: Option<reqwest::StatusCode>) {
+        self.platform_api_requests.fetch_add(1, Ordering::Relaxed);
+        match status {
+            Some(reqwest::StatusCode::TOO_MANY_REQUESTS) => {
+                self.platform_api_rate_limited
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Some(status) if status.is_success() => {
+                self.cloud_success();
+            }
+            Some(status) if !status.is_server_error() => {
+                // The cloud is reachable, even though it didn't
+                // like this particular request
+                self.platform_api_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {
+                self.platform_api_errors.fetch_add(1, Ordering::Relaxed);
+                self.cloud_failure();
+            }
+        }
+    }
+
+    fn cloud_success(&self) {
+        self.last_cloud_success.lock().unwrap().replace(Utc::now());
+    }
+
+    fn cloud_failure(&self) {
+        self.last_cloud_failure.lock().unwrap().replace(Utc::now());
+    }
+
+    /// Returns the time of the most recent successful request
+    /// to one of the Govee cloud APIs
+    pub fn last_cloud_success(&self) -> Option<DateTime<Utc>> {
+        *self.last_cloud_success.lock().unwrap()
+    }
+
+    /// Returns the time of the most recent request to one of the
+    /// Govee cloud APIs that failed to reach it
+    pub fn last_cloud_failure(&self) -> Option<DateTime<Utc>> {
+        *self.last_cloud_failure.lock().unwrap()
+    }
+
+    pub fn mqtt_publish(&self) {
+        self.mqtt_publishes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a poll of a device via the specified API
+    pub fn device_poll(&self, device: &str, api: &'static str, duration: Duration, ok: bool) {
+        if api != "lan" {
+            if ok {
+                self.cloud_success();
+            } else if api == "iot" {
+                // IoT polls only fail when no reply arrives
+                self.cloud_failure();
+            }
+        }
+        let mut polls = self.polls.lock().unwrap();
+        let stats = polls
+            .entry(PollKey {
+                device: device.to_string(),
+                api,
+            })
+            .or_default();
+        stats.count += 1;
+        if !ok {
+            stats.errors += 1;
+        }
+        stats.last_duration = duration;
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    /// Values that are only known to the caller are passed in.
+    pub fn render(&self, num_devices: usize, iot_connected: bool) -> String {
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} counter").ok();
+            writeln!(out, "{name} {value}").ok();
+        };
+        counter(
+            "govee_platform_api_requests_total",
+            "Requests sent to the Govee Platform API, including retries",
+            self.platform_api_requests.load(Ordering::Relaxed),
+        );
+        counter(
+            "govee_platform_api_errors_total",
+            "Platform API requests that failed, other than due to rate limiting",
+            self.platform_api_errors.load(Ordering::Relaxed),
+        );
+        counter(
+            "govee_platform_api_rate_limited_total",
+            "Platform API requests that were rejected with status 429",
+            self.platform_api_rate_limited.load(Ordering::Relaxed),
+        );
+        counter(
+            "govee_mqtt_publish_total",
+            "Messages published to the MQTT broker",
+            self.mqtt_publishes.load(Ordering::Relaxed),
+        );
+
+        let mut gauge = |name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} gauge").ok();
+            writeln!(out, "{name} {value}").ok();
+        };
+        gauge(
+            "govee_devices",
+            "The number of known devices",
+            num_devices as u64,
+        );
+        gauge(
+            "govee_iot_connected",
+            "Whether we are connected to the Govee IoT service",
+            iot_connected as u64,
+        );
+
+        let polls = self.polls.lock().unwrap();
+        let per_device: [(&str, &str, &str, PollValue); 3] = [
+            (
+                "govee_device_polls_total",
+                "counter",
+                "Polls of each device via each API",
+                |stats| stats.count.to_string(),
+            ),
+            (
+                "govee_device_poll_errors_total",
+                "counter",
+                "Failed polls of each device via each API",
+                |stats| stats.errors.to_string(),
+            ),
+            (
+                "govee_device_poll_duration_seconds",
+                "gauge",
+                "How long the most recent poll of each device took",
+                |stats| format!("{:.3}", stats.last_duration.as_secs_f64()),
+            ),
+        ];
+        for (name, kind, help, value) in per_device {
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} {kind}").ok();
+            for (key, stats) in polls.iter() {
+                writeln!(
+                    out,
+                    "{name}{{device=\"{}\",api=\"{}\"}} {}",
+                    escape_label(&key.device),
+                    key.api,
+                    value(stats)
+                )
+                .ok();
+            }
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.device_poll("AA:BB:CC:DD:EE:FF:42:2B", "lan", Duration::ZERO, true);
+        assert!(metrics.last_cloud_success().is_none());
+        metrics.platform_api_request(Some(reqwest::StatusCode::OK));
+        metrics.platform_api_request(Some(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        metrics.platform_api_request(None);
+        metrics.mqtt_publish();
+        metrics.device_poll(
+            "AA:BB:CC:DD:EE:FF:42:2A",
+            "platform",
+            Duration::from_millis(250),
+            true,
+        );
+
+        assert!(metrics.last_cloud_success().is_some());
+
+        let text = metrics.render(3, true);
+        assert!(text.contains("govee_platform_api_requests_total 3\n"));
+        assert!(text.contains("govee_platform_api_rate_limited_total 1\n"));
+        assert!(text.contains("govee_platform_api_errors_total 1\n"));
+        assert!(text.contains("govee_mqtt_publish_total 1\n"));
+        assert!(text.contains("govee_devices 3\n"));
+        assert!(text.contains("govee_iot_connected 1\n"));
+        assert!(text.contains(
+            "govee_device_polls_total{device=\"AA:BB:CC:DD:EE:FF:42:2A\",api=\"platform\"} 1\n"
+        ));
+        assert!(text.contains(
+            "govee_device_poll_duration_seconds{device=\"AA:BB:CC:DD:EE:FF:42:2A\",api=\"platform\"} 0.250\n"
+        ));
+    }
+}
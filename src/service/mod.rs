@@ -1,7 +1,22 @@
+pub mod account;
 pub mod coordinator;
 pub mod device;
+pub mod device_filter;
+pub mod discovery_gc;
+pub mod dry_run;
+pub mod entity_filter;
+pub mod entity_overrides;
+pub mod env_file;
+pub mod gamut;
+pub mod group_commands;
 pub mod hass;
 pub mod http;
 pub mod iot;
+pub mod log_context;
+pub mod metrics;
+pub mod persist;
 pub mod quirks;
+pub mod request_queue;
+pub mod scene_categories;
 pub mod state;
+pub mod transition;
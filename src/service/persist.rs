@@ -0,0 +1,137 @@
+use crate::lan_api::DeviceColor;
+use crate::service::device::{Device, DeviceState};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The subset of the device state that we save to disk, so that
+/// we have something to report to hass immediately after a restart,
+/// rather than publishing guesses until the first poll completes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PersistedDeviceState {
+    pub sku: String,
+    pub on: bool,
+    pub light_on: Option<bool>,
+    pub brightness: u8,
+    pub color: DeviceColor,
+    pub kelvin: u32,
+    #[serde(default)]
+    pub target_humidity_percent: Option<u8>,
+    #[serde(default)]
+    pub humidifier_work_mode: Option<u8>,
+    /// When the state was observed. This is preserved across
+    /// restarts so that the persisted state is correctly seen
+    /// as being stale.
+    pub updated: DateTime<Utc>,
+}
+
+impl PersistedDeviceState {
+    /// Capture the persistable portion of the live device state.
+    /// Returns None if we have no live state for the device.
+    pub fn from_device(device: &Device) -> Option<Self> {
+        let state = device.device_state()?;
+        if state.source == PERSISTED_SOURCE {
+            return None;
+        }
+
+        Some(Self {
+            sku: device.sku.to_string(),
+            on: state.on,
+            light_on: state.light_on,
+            brightness: state.brightness,
+            color: state.color,
+            kelvin: state.kelvin,
+            target_humidity_percent: device.target_humidity_percent,
+            humidifier_work_mode: device.humidifier_work_mode,
+            updated: state.updated,
+        })
+    }
+
+    pub fn to_device_state(&self) -> DeviceState {
+        DeviceState {
+            on: self.on,
+            light_on: self.light_on,
+            online: None,
+            brightness: self.brightness,
+            color: self.color,
+            kelvin: self.kelvin,
+            scene: None,
+            source: PERSISTED_SOURCE,
+            updated: self.updated,
+        }
+    }
+
+    /// Returns true if the state differs in a way that is worth
+    /// writing out to disk; the updated timestamp alone is not.
+    pub fn differs_from(&self, other: &Self) -> bool {
+        let mut other = other.clone();
+        other.updated = self.updated;
+        *self != other
+    }
+}
+
+pub const PERSISTED_SOURCE: &str = "Persisted";
+
+pub type PersistedStateMap = HashMap<String, PersistedDeviceState>;
+
+pub async fn load_state_file(path: &Path) -> anyhow::Result<PersistedStateMap> {
+    match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data).with_context(|| format!("parsing {path:?}")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(PersistedStateMap::new()),
+        Err(err) => Err(err).with_context(|| format!("reading {path:?}")),
+    }
+}
+
+/// Write the state to a temporary file and then rename it into place,
+/// so that we never leave a partially written file behind
+pub async fn save_state_file(path: &Path, map: &PersistedStateMap) -> anyhow::Result<()> {
+    let data = serde_json::to_string_pretty(map)?;
+    let mut temp_path = PathBuf::from(path);
+    temp_path.set_extension("tmp");
+    tokio::fs::write(&temp_path, data)
+        .await
+        .with_context(|| format!("writing {temp_path:?}"))?;
+    tokio::fs::rename(&temp_path, path)
+        .await
+        .with_context(|| format!("renaming {temp_path:?} -> {path:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_fresh_state_is_persisted() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(PersistedDeviceState::from_device(&device).is_none());
+
+        let persisted = PersistedDeviceState {
+            sku: "H6000".to_string(),
+            on: true,
+            light_on: Some(true),
+            brightness: 42,
+            color: DeviceColor { r: 1, g: 2, b: 3 },
+            kelvin: 0,
+            target_humidity_percent: None,
+            humidifier_work_mode: None,
+            updated: Utc::now() - chrono::Duration::hours(1),
+        };
+        device.set_persisted_state(persisted.clone());
+
+        // The persisted state is reported in the absence of anything better,
+        let state = device.device_state().unwrap();
+        assert_eq!(state.source, PERSISTED_SOURCE);
+        assert_eq!(state.brightness, 42);
+        // but we don't want to write it back out again
+        assert!(PersistedDeviceState::from_device(&device).is_none());
+
+        let mut later = persisted.clone();
+        later.updated = Utc::now();
+        assert!(!persisted.differs_from(&later));
+        later.brightness = 10;
+        assert!(persisted.differs_from(&later));
+    }
+}
@@ -1,8 +1,15 @@
 use crate::platform_api::DeviceType;
+use crate::service::gamut::ColorGamut;
 use crate::temperature::TemperatureUnits;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
 
 #[allow(unused)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -20,6 +27,135 @@ impl HumidityUnits {
     }
 }
 
+/// Offsets that are added to the readings of temperature
+/// and humidity sensors to correct for inaccurate sensors
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SensorCalibration {
+    /// Added to the temperature after it has been converted
+    /// to the configured temperature scale
+    pub temperature_offset: f64,
+    /// Added to the relative humidity percentage
+    pub humidity_offset: f64,
+}
+
+impl SensorCalibration {
+    pub fn apply_to_temperature(&self, value: f64) -> f64 {
+        value + self.temperature_offset
+    }
+
+    pub fn apply_to_humidity(&self, value: f64) -> f64 {
+        (value + self.humidity_offset).clamp(0., 100.)
+    }
+}
+
+/// Reduces the number of updates published for a noisy sensor.
+/// Readings are first smoothed, and are then only published when
+/// they differ enough from the value that was last published.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorFilter {
+    /// The smallest change from the last published value
+    /// that will be published
+    pub deadband: f64,
+    /// The weight, between 0 and 1, given to each new reading
+    /// by exponential smoothing. 1 disables smoothing.
+    pub smoothing: f64,
+}
+
+impl Default for SensorFilter {
+    fn default() -> Self {
+        Self {
+            deadband: 0.,
+            smoothing: 1.,
+        }
+    }
+}
+
+/// The history that a SensorFilter needs for a single sensor
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SensorFilterState {
+    smoothed: Option<f64>,
+    published: Option<f64>,
+    /// When the most recent reading was taken
+    read_at: Option<DateTime<Utc>>,
+}
+
+impl SensorFilter {
+    /// Feed a reading, taken at read_at, through the filter, returning
+    /// the value that should be published. A reading that the deadband
+    /// suppresses yields the previously published value, so that the
+    /// sensor still has a state after hass or the broker restarts.
+    /// Passing the same reading again doesn't advance the smoothing.
+    pub fn apply(&self, state: &mut SensorFilterState, value: f64, read_at: DateTime<Utc>) -> f64 {
+        if let (Some(prior), Some(published)) = (state.read_at, state.published) {
+            if prior == read_at {
+                return published;
+            }
+        }
+        state.read_at.replace(read_at);
+
+        let smoothed = match state.smoothed {
+            Some(prior) => prior + self.smoothing * (value - prior),
+            None => value,
+        };
+        state.smoothed.replace(smoothed);
+
+        match state.published {
+            Some(published) if (smoothed - published).abs() < self.deadband => published,
+            _ => {
+                state.published.replace(smoothed);
+                smoothed
+            }
+        }
+    }
+}
+
+/// A user supplied calibration for a specific device id or SKU,
+/// in the form `DEVICE=TEMPERATURE_OFFSET[,HUMIDITY_OFFSET]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorCalibrationEntry {
+    pub device: String,
+    pub calibration: SensorCalibration,
+}
+
+impl std::str::FromStr for SensorCalibrationEntry {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (device, offsets) = s.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("expected DEVICE=TEMPERATURE_OFFSET[,HUMIDITY_OFFSET] but got {s}")
+        })?;
+
+        let mut calibration = SensorCalibration::default();
+        let mut offsets = offsets.split(',').map(|o| o.trim());
+        if let Some(temp) = offsets.next().filter(|o| !o.is_empty()) {
+            calibration.temperature_offset = temp
+                .parse()
+                .with_context(|| format!("parsing temperature offset {temp} in {s}"))?;
+        }
+        if let Some(humidity) = offsets.next().filter(|o| !o.is_empty()) {
+            calibration.humidity_offset = humidity
+                .parse()
+                .with_context(|| format!("parsing humidity offset {humidity} in {s}"))?;
+        }
+
+        Ok(Self {
+            device: device.trim().to_string(),
+            calibration,
+        })
+    }
+}
+
+static SENSOR_CALIBRATIONS: Lazy<RwLock<Vec<SensorCalibrationEntry>>> = Lazy::new(Default::default);
+
+/// Sets the user supplied sensor calibrations, replacing any
+/// that were previously set
+pub fn set_sensor_calibrations(entries: Vec<SensorCalibrationEntry>) {
+    *SENSOR_CALIBRATIONS.write().unwrap() = entries;
+}
+
+pub fn sensor_calibrations() -> Vec<SensorCalibrationEntry> {
+    SENSOR_CALIBRATIONS.read().unwrap().clone()
+}
+
 #[derive(Clone, Debug)]
 pub struct Quirk {
     pub sku: Cow<'static, str>,
@@ -31,6 +167,10 @@ pub struct Quirk {
     pub ble_only: bool,
     pub lan_api_capable: bool,
     pub device_type: DeviceType,
+    /// Takes precedence over the device type reported by the
+    /// platform API, for devices that it misclassifies.
+    /// device_type is only used when the API doesn't report one.
+    pub device_type_override: Option<DeviceType>,
     pub platform_temperature_sensor_units: Option<TemperatureUnits>,
     pub platform_humidity_sensor_units: Option<HumidityUnits>,
     /// If true, we can correctly parse all appropriate
@@ -38,6 +178,34 @@ pub struct Quirk {
     /// their state.
     pub iot_api_supported: bool,
     pub show_as_preset_buttons: Option<&'static [&'static str]>,
+    /// Overrides the number of segments reported by the platform
+    /// API, for devices that misreport it
+    pub segment_count: Option<u32>,
+    /// Overrides the default interval between state polls.
+    /// Keep in mind that polling via the platform API counts
+    /// against the daily request quota.
+    pub poll_interval: Option<chrono::Duration>,
+    /// If true, expose a select entity for choosing an exact
+    /// speed step, in addition to the percentage based control
+    pub fan_speed_select: bool,
+    /// The speed, as a percentage, to assume for a fan until it
+    /// reports one or one is set, rather than the lowest speed
+    pub fan_initial_speed: Option<u8>,
+    /// Overrides whether hass treats the fan and humidifier entities
+    /// as optimistic, which otherwise depends on whether we receive
+    /// state updates via the IoT API
+    pub optimistic: Option<bool>,
+    /// Corrects for sensors of this model that are known to be
+    /// inaccurate. Users can override this for individual devices.
+    pub sensor_calibration: Option<SensorCalibration>,
+    /// Damps the readings of jittery temperature sensors.
+    /// Readings are passed through unchanged when not set.
+    pub temperature_filter: Option<SensorFilter>,
+    /// Damps the readings of jittery humidity sensors
+    pub humidity_filter: Option<SensorFilter>,
+    /// The colors that the LEDs of the device can produce,
+    /// used when gamut clamping is enabled
+    pub color_gamut: Option<ColorGamut>,
 }
 
 impl Quirk {
@@ -56,10 +224,20 @@ impl Quirk {
             icon: icon.into(),
             lan_api_capable: false,
             device_type,
+            device_type_override: None,
             platform_temperature_sensor_units: None,
             platform_humidity_sensor_units: None,
             iot_api_supported: false,
             show_as_preset_buttons: None,
+            segment_count: None,
+            poll_interval: None,
+            fan_speed_select: false,
+            fan_initial_speed: None,
+            optimistic: None,
+            sensor_calibration: None,
+            temperature_filter: None,
+            humidity_filter: None,
+            color_gamut: None,
         }
     }
 
@@ -133,6 +311,16 @@ impl Quirk {
         self
     }
 
+    pub fn with_poll_interval(mut self, seconds: i64) -> Self {
+        self.poll_interval = Some(chrono::Duration::seconds(seconds));
+        self
+    }
+
+    pub fn with_fan_speed_select(mut self) -> Self {
+        self.fan_speed_select = true;
+        self
+    }
+
     pub fn lan_api_capable_light(sku: &'static str, icon: &'static str) -> Self {
         Self::light(sku, icon).with_lan_api()
     }
@@ -210,6 +398,7 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::humidifier("H7160")
             .with_broken_platform()
             .with_iot_api_support(true)
+            .with_fan_speed_select()
             .with_rgb()
             .with_brightness(),
         Quirk::space_heater("H7130")
@@ -227,6 +416,12 @@ fn load_quirks() -> HashMap<String, Quirk> {
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit),
         Quirk::space_heater("H7135")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit),
+        // Air quality monitor, reporting PM2.5 as well as
+        // temperature and humidity
+        Quirk::device("H5106", DeviceType::Sensor, "mdi:air-filter")
+            .with_poll_interval(300)
+            .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit)
+            .with_platform_humidity_sensor_units(HumidityUnits::RelativePercent),
         Quirk::thermometer("H5051")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit)
             .with_platform_humidity_sensor_units(HumidityUnits::RelativePercent),
@@ -244,6 +439,10 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::device("H7171", DeviceType::Kettle, "mdi:kettle")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit)
             .with_show_as_preset_modes(&["M1", "M2", "M3", "M4"]),
+        Quirk::device("H7172", DeviceType::IceMaker, "mdi:cube-outline"),
+        // Leaks are urgent, so poll more often than the default
+        Quirk::device("H5054", DeviceType::Sensor, "mdi:water-alert").with_poll_interval(120),
+        Quirk::device("H5058", DeviceType::Sensor, "mdi:water-alert").with_poll_interval(120),
         Quirk::device("H7173", DeviceType::Kettle, "mdi:kettle")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit)
             .with_show_as_preset_modes(&["Tea", "Coffee", "DIY"]),
@@ -313,6 +512,499 @@ fn load_quirks() -> HashMap<String, Quirk> {
     map
 }
 
-pub fn resolve_quirk(sku: &str) -> Option<&'static Quirk> {
-    QUIRKS.get(sku)
+/// Quirks loaded from the user's quirks file; these take
+/// precedence over the built-in quirks.
+/// They are leaked so that they can be resolved in the same way
+/// as the built-in quirks; reloads are rare and quirks are small.
+static CUSTOM_QUIRKS: Lazy<RwLock<HashMap<String, Quirk>>> = Lazy::new(Default::default);
+
+/// The fields that may be specified for a custom quirk
+const CUSTOM_QUIRK_FIELDS: &[&str] = &[
+    "sku",
+    "device_type",
+    "device_type_override",
+    "icon",
+    "supports_rgb",
+    "supports_brightness",
+    "color_temp_range",
+    "avoid_platform_api",
+    "ble_only",
+    "lan_api_capable",
+    "iot_api_supported",
+    "segment_count",
+    "poll_interval",
+    "fan_speed_select",
+    "fan_initial_speed",
+    "optimistic",
+    "temperature_offset",
+    "humidity_offset",
+    "temperature_deadband",
+    "temperature_smoothing",
+    "humidity_deadband",
+    "humidity_smoothing",
+    "color_gamut",
+];
+
+/// The device types that may be used in a custom quirk, without
+/// their "devices.types." prefix
+const CUSTOM_QUIRK_DEVICE_TYPES: &[&str] = &[
+    "light",
+    "air_purifier",
+    "thermometer",
+    "socket",
+    "sensor",
+    "heater",
+    "humidifier",
+    "dehumidifier",
+    "ice_maker",
+    "aroma_diffuser",
+    "fan",
+    "kettle",
+];
+
+/// Extract an optional field from a custom quirk, producing an
+/// error that names the field if it has the wrong type
+fn quirk_field<T: DeserializeOwned>(
+    obj: &serde_json::Map<String, JsonValue>,
+    name: &str,
+) -> anyhow::Result<Option<T>> {
+    match obj.get(name) {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(value) => T::deserialize(value)
+            .map(Some)
+            .map_err(|err| anyhow!("field `{name}`: {err}")),
+    }
+}
+
+fn parse_device_type(field: &str, name: &str) -> anyhow::Result<DeviceType> {
+    let short = name.strip_prefix("devices.types.").unwrap_or(name);
+    if !CUSTOM_QUIRK_DEVICE_TYPES.contains(&short) {
+        anyhow::bail!(
+            "field `{field}`: unknown device type {name:?}; expected one of {}",
+            CUSTOM_QUIRK_DEVICE_TYPES.join(", ")
+        );
+    }
+    format!("devices.types.{short}")
+        .parse()
+        .map_err(|err| anyhow!("field `{field}`: {err}"))
+}
+
+/// Parse and validate a single custom quirk.
+/// Fields that are omitted take their values from the built-in
+/// quirk for the same SKU, if there is one.
+fn parse_custom_quirk(entry: &JsonValue) -> anyhow::Result<Quirk> {
+    let obj = entry
+        .as_object()
+        .ok_or_else(|| anyhow!("expected an object, but got {entry}"))?;
+
+    for key in obj.keys() {
+        if !CUSTOM_QUIRK_FIELDS.contains(&key.as_str()) {
+            anyhow::bail!(
+                "unknown field `{key}`; expected one of {}",
+                CUSTOM_QUIRK_FIELDS.join(", ")
+            );
+        }
+    }
+
+    let sku: String = quirk_field(obj, "sku")?
+        .filter(|sku: &String| !sku.trim().is_empty())
+        .ok_or_else(|| anyhow!("field `sku` is required"))?;
+    let device_type = quirk_field::<String>(obj, "device_type")?
+        .map(|name| parse_device_type("device_type", &name))
+        .transpose()?;
+    let device_type_override = quirk_field::<String>(obj, "device_type_override")?
+        .map(|name| parse_device_type("device_type_override", &name))
+        .transpose()?;
+
+    // The override also serves as the type when there is no other
+    let device_type = device_type.or_else(|| device_type_override.clone());
+    let mut quirk = match (QUIRKS.get(&sku), device_type) {
+        (Some(base), device_type) => {
+            let mut quirk = base.clone();
+            if let Some(device_type) = device_type {
+                quirk.device_type = device_type;
+            }
+            quirk
+        }
+        (None, Some(device_type)) => {
+            let icon = if device_type == DeviceType::Light {
+                BULB
+            } else {
+                "mdi:devices"
+            };
+            Quirk::device(sku.clone(), device_type, icon)
+        }
+        (None, None) => anyhow::bail!(
+            "field `device_type` is required, as there is no \
+             built-in quirk for {sku} to take it from"
+        ),
+    };
+
+    if device_type_override.is_some() {
+        quirk.device_type_override = device_type_override;
+    }
+
+    if let Some(icon) = quirk_field::<String>(obj, "icon")? {
+        if !icon.starts_with("mdi:") {
+            anyhow::bail!(
+                "field `icon`: expected an icon name like mdi:lightbulb, but got {icon:?}"
+            );
+        }
+        quirk.icon = icon.into();
+    }
+
+    for (name, flag) in [
+        ("supports_rgb", &mut quirk.supports_rgb),
+        ("supports_brightness", &mut quirk.supports_brightness),
+        ("avoid_platform_api", &mut quirk.avoid_platform_api),
+        ("ble_only", &mut quirk.ble_only),
+        ("lan_api_capable", &mut quirk.lan_api_capable),
+        ("iot_api_supported", &mut quirk.iot_api_supported),
+        ("fan_speed_select", &mut quirk.fan_speed_select),
+    ] {
+        if let Some(value) = quirk_field(obj, name)? {
+            *flag = value;
+        }
+    }
+
+    if let Some((min, max)) = quirk_field::<(u32, u32)>(obj, "color_temp_range")? {
+        if min == 0 || min >= max {
+            anyhow::bail!(
+                "field `color_temp_range`: expected [MIN_KELVIN, MAX_KELVIN] \
+                 with MIN_KELVIN < MAX_KELVIN, but got [{min}, {max}]"
+            );
+        }
+        quirk.color_temp_range = Some((min, max));
+    }
+    if let Some(count) = quirk_field::<u32>(obj, "segment_count")? {
+        if count == 0 {
+            anyhow::bail!("field `segment_count`: must be greater than 0");
+        }
+        quirk.segment_count = Some(count);
+    }
+    if let Some(seconds) = quirk_field::<i64>(obj, "poll_interval")? {
+        if seconds <= 0 {
+            anyhow::bail!("field `poll_interval`: must be a positive number of seconds");
+        }
+        quirk.poll_interval = Some(chrono::Duration::seconds(seconds));
+    }
+    if let Some(percent) = quirk_field::<u8>(obj, "fan_initial_speed")? {
+        if percent == 0 || percent > 100 {
+            anyhow::bail!("field `fan_initial_speed`: expected a percentage between 1 and 100");
+        }
+        quirk.fan_initial_speed = Some(percent);
+    }
+    if let Some(optimistic) = quirk_field::<bool>(obj, "optimistic")? {
+        quirk.optimistic = Some(optimistic);
+    }
+
+    let temperature_offset = quirk_field::<f64>(obj, "temperature_offset")?;
+    let humidity_offset = quirk_field::<f64>(obj, "humidity_offset")?;
+    if temperature_offset.is_some() || humidity_offset.is_some() {
+        let mut calibration = quirk.sensor_calibration.unwrap_or_default();
+        if let Some(offset) = temperature_offset {
+            calibration.temperature_offset = offset;
+        }
+        if let Some(offset) = humidity_offset {
+            calibration.humidity_offset = offset;
+        }
+        quirk.sensor_calibration = Some(calibration);
+    }
+
+    for (sensor, filter) in [
+        ("temperature", &mut quirk.temperature_filter),
+        ("humidity", &mut quirk.humidity_filter),
+    ] {
+        let deadband = quirk_field::<f64>(obj, &format!("{sensor}_deadband"))?;
+        let smoothing = quirk_field::<f64>(obj, &format!("{sensor}_smoothing"))?;
+        if deadband.is_none() && smoothing.is_none() {
+            continue;
+        }
+        let mut updated = filter.unwrap_or_default();
+        if let Some(deadband) = deadband {
+            if deadband < 0. {
+                anyhow::bail!("field `{sensor}_deadband`: must not be negative");
+            }
+            updated.deadband = deadband;
+        }
+        if let Some(smoothing) = smoothing {
+            if smoothing <= 0. || smoothing > 1. {
+                anyhow::bail!(
+                    "field `{sensor}_smoothing`: expected a weight greater \
+                     than 0 and at most 1, but got {smoothing}"
+                );
+            }
+            updated.smoothing = smoothing;
+        }
+        *filter = Some(updated);
+    }
+
+    if let Some([red, green, blue]) = quirk_field::<[(f64, f64); 3]>(obj, "color_gamut")? {
+        let gamut = ColorGamut { red, green, blue };
+        if !gamut.is_valid() {
+            anyhow::bail!(
+                "field `color_gamut`: expected the CIE xy coordinates of the \
+                 red, green and blue LEDs, such as [[0.69, 0.31], [0.17, 0.7], \
+                 [0.15, 0.05]], but got {:?}",
+                [red, green, blue]
+            );
+        }
+        quirk.color_gamut = Some(gamut);
+    }
+
+    Ok(quirk)
+}
+
+/// Parse a JSON list of custom quirks.
+/// Invalid entries are skipped rather than failing the whole
+/// list; a description of each problem is returned alongside
+/// the valid quirks.
+pub fn parse_custom_quirks(json: &str) -> (Vec<Quirk>, Vec<String>) {
+    let entries: Vec<JsonValue> = match serde_json::from_str(json) {
+        Ok(entries) => entries,
+        Err(err) => {
+            return (
+                vec![],
+                vec![format!("expected a JSON list of quirks: {err}")],
+            )
+        }
+    };
+
+    let mut quirks = vec![];
+    let mut errors = vec![];
+    for (idx, entry) in entries.iter().enumerate() {
+        match parse_custom_quirk(entry) {
+            Ok(quirk) => quirks.push(quirk),
+            Err(err) => {
+                let sku = entry
+                    .get("sku")
+                    .and_then(|sku| sku.as_str())
+                    .unwrap_or("unknown SKU");
+                errors.push(format!("quirk #{idx} ({sku}): {err:#}"));
+            }
+        }
+    }
+    (quirks, errors)
+}
+
+/// Load custom quirks from the specified file, replacing any
+/// that were previously loaded.
+/// Problems are logged, and the affected quirks are ignored,
+/// so that the built-in defaults continue to apply.
+pub fn load_custom_quirks(path: &Path) {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(err) => {
+            log::error!("Unable to read quirks file {}: {err:#}", path.display());
+            return;
+        }
+    };
+
+    let (quirks, errors) = parse_custom_quirks(&json);
+    for err in &errors {
+        log::error!("Ignoring invalid quirk in {}: {err}", path.display());
+    }
+    log::info!(
+        "Loaded {} custom quirk(s) from {}",
+        quirks.len(),
+        path.display()
+    );
+
+    set_custom_quirks(quirks);
+}
+
+/// Replace the custom quirks with quirks
+pub fn set_custom_quirks(quirks: Vec<Quirk>) {
+    let map = quirks
+        .into_iter()
+        .map(|quirk| (quirk.sku.to_string(), quirk))
+        .collect();
+    *CUSTOM_QUIRKS.write().unwrap() = map;
+}
+
+pub fn resolve_quirk(sku: &str) -> Option<Quirk> {
+    CUSTOM_QUIRKS
+        .read()
+        .unwrap()
+        .get(sku)
+        .or_else(|| QUIRKS.get(sku))
+        .cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sensor_calibration() {
+        k9::assert_equal!(
+            "H5075=-1.5".parse::<SensorCalibrationEntry>().unwrap(),
+            SensorCalibrationEntry {
+                device: "H5075".to_string(),
+                calibration: SensorCalibration {
+                    temperature_offset: -1.5,
+                    humidity_offset: 0.
+                }
+            }
+        );
+        k9::assert_equal!(
+            "AA:BB:CC:DD:EE:FF:42:2A = 0.5, 3"
+                .parse::<SensorCalibrationEntry>()
+                .unwrap(),
+            SensorCalibrationEntry {
+                device: "AA:BB:CC:DD:EE:FF:42:2A".to_string(),
+                calibration: SensorCalibration {
+                    temperature_offset: 0.5,
+                    humidity_offset: 3.
+                }
+            }
+        );
+        assert!("H5075".parse::<SensorCalibrationEntry>().is_err());
+        assert!("H5075=warm".parse::<SensorCalibrationEntry>().is_err());
+
+        let calibration = SensorCalibration {
+            temperature_offset: -1.5,
+            humidity_offset: 5.,
+        };
+        assert_eq!(calibration.apply_to_temperature(22.), 20.5);
+        assert_eq!(calibration.apply_to_humidity(97.), 100.);
+    }
+
+    #[test]
+    fn sensor_filter() {
+        let start = Utc::now();
+        let at = |n: i64| start + chrono::Duration::seconds(n);
+
+        let passthrough = SensorFilter::default();
+        let mut state = SensorFilterState::default();
+        assert_eq!(passthrough.apply(&mut state, 21., at(0)), 21.);
+        assert_eq!(passthrough.apply(&mut state, 21.1, at(1)), 21.1);
+
+        let deadband = SensorFilter {
+            deadband: 0.5,
+            smoothing: 1.,
+        };
+        let mut state = SensorFilterState::default();
+        assert_eq!(deadband.apply(&mut state, 21., at(0)), 21.);
+        // Suppressed changes hold the previously published value
+        assert_eq!(deadband.apply(&mut state, 21.3, at(1)), 21.);
+        assert_eq!(deadband.apply(&mut state, 20.7, at(2)), 21.);
+        assert_eq!(deadband.apply(&mut state, 21.5, at(3)), 21.5);
+
+        let smoothed = SensorFilter {
+            deadband: 0.,
+            smoothing: 0.5,
+        };
+        let mut state = SensorFilterState::default();
+        assert_eq!(smoothed.apply(&mut state, 20., at(0)), 20.);
+        assert_eq!(smoothed.apply(&mut state, 22., at(1)), 21.);
+        // Republishing the same reading doesn't advance the smoothing
+        assert_eq!(smoothed.apply(&mut state, 22., at(1)), 21.);
+        assert_eq!(smoothed.apply(&mut state, 22., at(2)), 21.5);
+    }
+
+    #[test]
+    fn custom_quirks() {
+        let (quirks, errors) = parse_custom_quirks(
+            r#"[
+                {"sku": "H9999", "device_type": "light", "supports_rgb": true,
+                 "color_temp_range": [2700, 6500],
+                 "color_gamut": [[0.68, 0.31], [0.2, 0.7], [0.15, 0.06]]},
+                {"sku": "H6141", "avoid_platform_api": false, "segment_count": 5,
+                 "device_type_override": "devices.types.light", "optimistic": false},
+                {"sku": "H9998", "device_type": "thermometer", "temperature_offset": -1.5,
+                 "temperature_deadband": 0.2, "humidity_smoothing": 0.25},
+                {"sku": "H9997", "device_type": "fan", "fan_initial_speed": 50}
+            ]"#,
+        );
+        k9::assert_equal!(errors, Vec::<String>::new());
+        k9::assert_equal!(quirks.len(), 4);
+        k9::assert_equal!(quirks[3].fan_initial_speed, Some(50));
+        k9::assert_equal!(quirks[3].device_type, DeviceType::Fan);
+        k9::assert_equal!(quirks[0].device_type, DeviceType::Light);
+        k9::assert_equal!(quirks[0].color_temp_range, Some((2700, 6500)));
+        k9::assert_equal!(quirks[0].icon, BULB);
+        k9::assert_equal!(
+            quirks[0].color_gamut,
+            Some(ColorGamut {
+                red: (0.68, 0.31),
+                green: (0.2, 0.7),
+                blue: (0.15, 0.06)
+            })
+        );
+        // Fields that aren't specified are taken from the built-in quirk
+        assert!(!quirks[1].avoid_platform_api);
+        assert!(quirks[1].supports_rgb);
+        k9::assert_equal!(quirks[1].segment_count, Some(5));
+        k9::assert_equal!(quirks[1].device_type_override, Some(DeviceType::Light));
+        k9::assert_equal!(quirks[0].device_type_override, None);
+        k9::assert_equal!(quirks[1].optimistic, Some(false));
+        k9::assert_equal!(quirks[0].optimistic, None);
+        k9::assert_equal!(
+            quirks[2].sensor_calibration.map(|c| c.temperature_offset),
+            Some(-1.5)
+        );
+        k9::assert_equal!(
+            quirks[2].temperature_filter,
+            Some(SensorFilter {
+                deadband: 0.2,
+                smoothing: 1.
+            })
+        );
+        k9::assert_equal!(
+            quirks[2].humidity_filter,
+            Some(SensorFilter {
+                deadband: 0.,
+                smoothing: 0.25
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_custom_quirks() {
+        let (quirks, errors) = parse_custom_quirks(r#"{"sku": "H9999"}"#);
+        assert!(quirks.is_empty());
+        assert!(errors[0].starts_with("expected a JSON list of quirks"));
+
+        let (quirks, errors) = parse_custom_quirks(
+            r#"[
+                {"sku": "H9999", "device_type": "light", "suports_rgb": true},
+                {"sku": "H9999", "device_type": "lamp"},
+                {"sku": "H9999"},
+                {"device_type": "light"},
+                {"sku": "H9999", "device_type": "light", "segment_count": "5"},
+                {"sku": "H9999", "device_type": "light", "color_temp_range": [6500, 2700]},
+                {"sku": "H9999", "device_type": "light", "icon": "lightbulb"},
+                {"sku": "H9999", "device_type": "light", "poll_interval": 0},
+                "H9999",
+                {"sku": "H9999", "device_type": "thermometer", "humidity_smoothing": 2},
+                {"sku": "H9999", "device_type": "light", "color_gamut": [[0.5, 0.5], [0.5, 0.5], [0.1, 0.1]]},
+                {"sku": "H6199", "device_type_override": "bulb"},
+                {"sku": "H9997", "device_type": "fan", "fan_initial_speed": 0},
+                {"sku": "H9997", "device_type": "fan"}
+            ]"#,
+        );
+        k9::assert_equal!(quirks.len(), 1);
+        k9::assert_equal!(quirks[0].sku, "H9997");
+        k9::snapshot!(
+            errors,
+            r#"
+[
+    "quirk #0 (H9999): unknown field `suports_rgb`; expected one of sku, device_type, device_type_override, icon, supports_rgb, supports_brightness, color_temp_range, avoid_platform_api, ble_only, lan_api_capable, iot_api_supported, segment_count, poll_interval, fan_speed_select, fan_initial_speed, optimistic, temperature_offset, humidity_offset, temperature_deadband, temperature_smoothing, humidity_deadband, humidity_smoothing, color_gamut",
+    "quirk #1 (H9999): field `device_type`: unknown device type "lamp"; expected one of light, air_purifier, thermometer, socket, sensor, heater, humidifier, dehumidifier, ice_maker, aroma_diffuser, fan, kettle",
+    "quirk #2 (H9999): field `device_type` is required, as there is no built-in quirk for H9999 to take it from",
+    "quirk #3 (unknown SKU): field `sku` is required",
+    "quirk #4 (H9999): field `segment_count`: invalid type: string "5", expected u32",
+    "quirk #5 (H9999): field `color_temp_range`: expected [MIN_KELVIN, MAX_KELVIN] with MIN_KELVIN < MAX_KELVIN, but got [6500, 2700]",
+    "quirk #6 (H9999): field `icon`: expected an icon name like mdi:lightbulb, but got "lightbulb"",
+    "quirk #7 (H9999): field `poll_interval`: must be a positive number of seconds",
+    "quirk #8 (unknown SKU): expected an object, but got "H9999"",
+    "quirk #9 (H9999): field `humidity_smoothing`: expected a weight greater than 0 and at most 1, but got 2",
+    "quirk #10 (H9999): field `color_gamut`: expected the CIE xy coordinates of the red, green and blue LEDs, such as [[0.69, 0.31], [0.17, 0.7], [0.15, 0.05]], but got [(0.5, 0.5), (0.5, 0.5), (0.1, 0.1)]",
+    "quirk #11 (H6199): field `device_type_override`: unknown device type "bulb"; expected one of light, air_purifier, thermometer, socket, sensor, heater, humidifier, dehumidifier, ice_maker, aroma_diffuser, fan, kettle",
+    "quirk #12 (H9997): field `fan_initial_speed`: expected a percentage between 1 and 100",
+]
+"#
+        );
+    }
 }
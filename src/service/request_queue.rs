@@ -0,0 +1,325 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Govee allows each account this many Platform API requests per day
+const DAILY_REQUEST_LIMIT: f64 = 10_000.;
+/// How many requests may be sent in a burst before we start to
+/// pace them to fit within the daily limit
+const BURST_CAPACITY: f64 = 100.;
+/// The most requests that may be waiting at once
+const MAX_QUEUE_DEPTH: usize = 64;
+/// A background request that has waited this long is treated as
+/// though it were user-initiated, so that a flood of commands can't
+/// starve state polling indefinitely
+const MAX_BACKGROUND_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestPriority {
+    /// State polls and metadata requests
+    Background,
+    /// Commands that were initiated by the user
+    Command,
+}
+
+struct Waiter {
+    seq: u64,
+    priority: RequestPriority,
+    key: Option<String>,
+    queued: Instant,
+    /// Set when the request was dropped from the queue; the
+    /// reason is reported to the task that was waiting for it
+    dropped: Option<&'static str>,
+}
+
+impl Waiter {
+    fn effective_priority(&self, now: Instant) -> RequestPriority {
+        if now.duration_since(self.queued) >= MAX_BACKGROUND_WAIT {
+            RequestPriority::Command
+        } else {
+            self.priority
+        }
+    }
+}
+
+struct QueueState {
+    waiting: Vec<Waiter>,
+    tokens: f64,
+    refilled: Instant,
+    next_seq: u64,
+}
+
+/// Paces the requests that we make to the Platform API of an account
+/// so that we stay within its rate limit. When requests have to wait,
+/// user-initiated commands go ahead of background polls, and a command
+/// that is still waiting is dropped if a newer one arrives for the same
+/// device and capability.
+pub struct RequestQueue {
+    inner: Mutex<QueueState>,
+    notify: Notify,
+    capacity: f64,
+    refill_per_second: f64,
+    max_depth: usize,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self::with_limits(
+            BURST_CAPACITY,
+            DAILY_REQUEST_LIMIT / 86400.,
+            MAX_QUEUE_DEPTH,
+        )
+    }
+}
+
+enum Outcome {
+    Proceed,
+    Dropped(&'static str),
+    Wait(Duration),
+}
+
+/// Removes the waiter from the queue when its task either
+/// proceeds, gives up, or is cancelled
+struct WaiterGuard<'a> {
+    queue: &'a RequestQueue,
+    seq: u64,
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.queue
+            .inner
+            .lock()
+            .unwrap()
+            .waiting
+            .retain(|w| w.seq != self.seq);
+        self.queue.notify.notify_waiters();
+    }
+}
+
+impl RequestQueue {
+    pub fn with_limits(capacity: f64, refill_per_second: f64, max_depth: usize) -> Self {
+        Self {
+            inner: Mutex::new(QueueState {
+                waiting: vec![],
+                tokens: capacity,
+                refilled: Instant::now(),
+                next_seq: 0,
+            }),
+            notify: Notify::new(),
+            capacity,
+            refill_per_second,
+            max_depth,
+        }
+    }
+
+    /// Returns the number of requests that are waiting to be sent
+    pub fn depth(&self) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .filter(|w| w.dropped.is_none())
+            .count()
+    }
+
+    /// Wait until a request may be sent. key identifies the device
+    /// and capability that a command affects; a queued command is
+    /// dropped, returning an error, when another arrives for the same key.
+    pub async fn acquire(
+        &self,
+        priority: RequestPriority,
+        key: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let seq = {
+            let mut q = self.inner.lock().unwrap();
+            if let Some(key) = key {
+                for w in q.waiting.iter_mut() {
+                    if w.key.as_deref() == Some(key) && w.dropped.is_none() {
+                        w.dropped.replace("superseded by a more recent command");
+                    }
+                }
+            }
+
+            let depth = q.waiting.iter().filter(|w| w.dropped.is_none()).count();
+            if depth >= self.max_depth {
+                // Make room for a command by dropping the
+                // most recently queued background request
+                let victim = if priority == RequestPriority::Command {
+                    q.waiting
+                        .iter_mut()
+                        .rev()
+                        .find(|w| w.dropped.is_none() && w.priority == RequestPriority::Background)
+                } else {
+                    None
+                };
+                match victim {
+                    Some(w) => {
+                        w.dropped.replace("dropped to make room for a command");
+                    }
+                    None => anyhow::bail!("the Platform API request queue is full"),
+                }
+            }
+
+            let seq = q.next_seq;
+            q.next_seq += 1;
+            q.waiting.push(Waiter {
+                seq,
+                priority,
+                key: key.map(|k| k.to_string()),
+                queued: Instant::now(),
+                dropped: None,
+            });
+            seq
+        };
+        self.notify.notify_waiters();
+        let _guard = WaiterGuard { queue: self, seq };
+
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match self.poll(seq) {
+                Outcome::Proceed => return Ok(()),
+                Outcome::Dropped(reason) => anyhow::bail!("{reason}"),
+                Outcome::Wait(delay) => {
+                    tokio::select! {
+                        _ = notified => {}
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll(&self, seq: u64) -> Outcome {
+        let mut q = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        let elapsed = now.duration_since(q.refilled).as_secs_f64();
+        q.tokens = (q.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        q.refilled = now;
+
+        let Some(me) = q.waiting.iter().find(|w| w.seq == seq) else {
+            return Outcome::Dropped("removed from the queue");
+        };
+        if let Some(reason) = me.dropped {
+            return Outcome::Dropped(reason);
+        }
+
+        let head = q
+            .waiting
+            .iter()
+            .filter(|w| w.dropped.is_none())
+            .max_by_key(|w| (w.effective_priority(now), std::cmp::Reverse(w.seq)))
+            .map(|w| w.seq);
+
+        if head != Some(seq) {
+            // Priorities change as requests age, so check back
+            // even if we aren't notified
+            return Outcome::Wait(Duration::from_secs(1));
+        }
+        if q.tokens >= 1. {
+            q.tokens -= 1.;
+            return Outcome::Proceed;
+        }
+        Outcome::Wait(Duration::from_secs_f64(
+            (1. - q.tokens) / self.refill_per_second,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Let the spawned tasks run until the expected number of
+    /// requests are waiting in the queue
+    async fn wait_for_depth(queue: &RequestQueue, depth: usize) {
+        while queue.depth() != depth {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_go_first() {
+        let queue = Arc::new(RequestQueue::with_limits(1., 20., 8));
+        queue.acquire(RequestPriority::Command, None).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let poll = {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                queue
+                    .acquire(RequestPriority::Background, None)
+                    .await
+                    .unwrap();
+                tx.send("poll").unwrap();
+            })
+        };
+        wait_for_depth(&queue, 1).await;
+        let command = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                queue.acquire(RequestPriority::Command, None).await.unwrap();
+                tx.send("command").unwrap();
+            })
+        };
+        poll.await.unwrap();
+        command.await.unwrap();
+
+        assert_eq!(rx.recv().await, Some("command"));
+        assert_eq!(rx.recv().await, Some("poll"));
+    }
+
+    #[tokio::test]
+    async fn superseded_commands() {
+        let queue = Arc::new(RequestQueue::with_limits(1., 20., 8));
+        queue.acquire(RequestPriority::Command, None).await.unwrap();
+
+        let first = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                queue
+                    .acquire(RequestPriority::Command, Some("light/brightness"))
+                    .await
+            })
+        };
+        wait_for_depth(&queue, 1).await;
+
+        let second = queue.acquire(RequestPriority::Command, Some("light/brightness"));
+        assert!(second.await.is_ok());
+        assert!(first.await.unwrap().is_err());
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn bounded() {
+        let queue = Arc::new(RequestQueue::with_limits(0., 0.001, 1));
+
+        let poll = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.acquire(RequestPriority::Background, None).await })
+        };
+        wait_for_depth(&queue, 1).await;
+
+        // The queue is full of background requests
+        assert!(queue
+            .acquire(RequestPriority::Background, None)
+            .await
+            .is_err());
+
+        // but a command displaces one of them
+        let command = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.acquire(RequestPriority::Command, None).await })
+        };
+        assert!(poll.await.unwrap().is_err());
+        assert_eq!(queue.depth(), 1);
+        command.abort();
+    }
+}
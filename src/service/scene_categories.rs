@@ -0,0 +1,108 @@
+//! Some devices have hundreds of scenes, which are hard to find in
+//! a flat list. The Govee App groups them into categories, such as
+//! Holiday or Nature, so when enabled we prefix each scene name with
+//! its category, which keeps the scenes of a category together when
+//! the list is sorted.
+use crate::undoc_api::LightEffectCategory;
+use std::collections::HashMap;
+
+/// Separates the category from the scene name, as in
+/// "Holiday / Christmas"
+pub const CATEGORY_SEPARATOR: &str = " / ";
+
+/// Maps the scenes of a device to their categories
+#[derive(Clone, Debug, Default)]
+pub struct SceneCategories {
+    /// Keyed by lowercased scene name
+    by_scene: HashMap<String, String>,
+}
+
+impl SceneCategories {
+    pub fn new(categories: &[LightEffectCategory]) -> Self {
+        let mut by_scene = HashMap::new();
+        for category in categories {
+            let name = category.category_name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            for scene in &category.scenes {
+                // A scene that appears in several categories
+                // is listed under the first of them
+                by_scene
+                    .entry(scene.scene_name.to_ascii_lowercase())
+                    .or_insert_with(|| name.to_string());
+            }
+        }
+        Self { by_scene }
+    }
+
+    /// Returns the scene name prefixed by its category, or the
+    /// name as-is if its category is not known
+    pub fn categorize(&self, scene: &str) -> String {
+        match self.by_scene.get(&scene.to_ascii_lowercase()) {
+            Some(category) => format!("{category}{CATEGORY_SEPARATOR}{scene}"),
+            None => scene.to_string(),
+        }
+    }
+
+    /// Returns the scene name without its category prefix.
+    /// The prefix is only removed if it is the category of the
+    /// scene, so that scenes whose names happen to contain the
+    /// separator are left intact.
+    pub fn strip<'a>(&self, name: &'a str) -> &'a str {
+        if let Some((category, scene)) = name.split_once(CATEGORY_SEPARATOR) {
+            if self
+                .by_scene
+                .get(&scene.to_ascii_lowercase())
+                .map(|c| c.eq_ignore_ascii_case(category))
+                .unwrap_or(false)
+            {
+                return scene;
+            }
+        }
+        name
+    }
+}
+
+/// Find the entry in a list of scene options that corresponds to the
+/// scene reported by the device, which doesn't include its category
+pub fn find_scene_option<'a>(options: &'a [String], scene: &str) -> Option<&'a String> {
+    options.iter().find(|option| {
+        option.eq_ignore_ascii_case(scene)
+            || option
+                .split_once(CATEGORY_SEPARATOR)
+                .map(|(_, name)| name.eq_ignore_ascii_case(scene))
+                .unwrap_or(false)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn categories() {
+        let resp: serde_json::Value = serde_json::from_str(include_str!(
+            "../../test-data/light-effect-library-h6072.json"
+        ))
+        .unwrap();
+        let categories: Vec<LightEffectCategory> =
+            serde_json::from_value(resp["data"]["categories"].clone()).unwrap();
+        let first = &categories[0];
+        let scene = &first.scenes[0].scene_name;
+
+        let cats = SceneCategories::new(&categories);
+        let named = cats.categorize(scene);
+        assert_eq!(
+            named,
+            format!("{}{CATEGORY_SEPARATOR}{scene}", first.category_name)
+        );
+        assert_eq!(cats.strip(&named), scene);
+        assert_eq!(cats.categorize("Not A Scene"), "Not A Scene");
+        assert_eq!(cats.strip("Up / Down"), "Up / Down");
+
+        let options = vec!["DIY: Mine".to_string(), named.clone()];
+        assert_eq!(find_scene_option(&options, scene), Some(&named));
+        assert_eq!(find_scene_option(&options, "Mine"), None);
+    }
+}
@@ -1,15 +1,30 @@
 use crate::ble::{Base64HexBytes, SetHumidifierMode, SetHumidifierNightlightParams};
-use crate::lan_api::{Client as LanClient, DeviceStatus as LanDeviceStatus, LanDevice};
+use crate::lan_api::{
+    Client as LanClient, DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice,
+};
 use crate::platform_api::{DeviceCapability, GoveeApiClient};
 use crate::service::coordinator::Coordinator;
-use crate::service::device::Device;
-use crate::service::hass::{topic_safe_id, HassClient};
+use crate::service::device::{hass_device_names, Device};
+use crate::service::device_filter::DeviceFilter;
+use crate::service::gamut::GENERIC_LED_GAMUT;
+use crate::service::hass::{
+    apply_reloadable_settings, device_availability_topic, kelvin_to_rgb, topic_safe_id,
+    ColorModePriority, HassArguments, HassClient,
+};
 use crate::service::iot::IotClient;
+use crate::service::log_context;
+use crate::service::metrics::METRICS;
+use crate::service::persist::{
+    load_state_file, save_state_file, PersistedDeviceState, PersistedStateMap,
+};
+use crate::service::quirks::load_custom_quirks;
+use crate::service::scene_categories::SceneCategories;
 use crate::temperature::{TemperatureScale, TemperatureValue};
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, Semaphore};
@@ -20,12 +35,38 @@ pub struct State {
     devices_by_id: Mutex<HashMap<String, Device>>,
     semaphore_by_id: Mutex<HashMap<String, Arc<Semaphore>>>,
     lan_client: Mutex<Option<LanClient>>,
-    platform_client: Mutex<Option<GoveeApiClient>>,
-    undoc_client: Mutex<Option<GoveeUndocumentedApi>>,
-    iot_client: Mutex<Option<IotClient>>,
+    /// The cloud API clients, keyed by account label;
+    /// None is the primary account
+    platform_clients: Mutex<HashMap<Option<String>, GoveeApiClient>>,
+    undoc_clients: Mutex<HashMap<Option<String>, GoveeUndocumentedApi>>,
+    iot_clients: Mutex<HashMap<Option<String>, IotClient>>,
     hass_client: Mutex<Option<HassClient>>,
     hass_discovery_prefix: Mutex<String>,
     temperature_scale: Mutex<TemperatureScale>,
+    state_file: Mutex<Option<PathBuf>>,
+    persisted_state: Mutex<PersistedStateMap>,
+    /// The generation of the most recent command, keyed by
+    /// device id and then by the kind of command
+    command_generation: Mutex<HashMap<String, HashMap<String, u64>>>,
+    device_filter: Mutex<DeviceFilter>,
+    availability_grace_period: Mutex<Option<chrono::Duration>>,
+    device_removal_cycles: Mutex<Option<u32>>,
+    last_device_list_refresh: Mutex<Option<Instant>>,
+    debug_sensors: Mutex<bool>,
+    prefer_sku_names: Mutex<bool>,
+    transition_simulation_disabled: Mutex<bool>,
+    gamut_clamping: Mutex<bool>,
+    scene_categories: Mutex<bool>,
+    color_mode_priority: Mutex<ColorModePriority>,
+    device_timeout: Mutex<Option<Duration>>,
+    reload_sources: Mutex<Option<ReloadSources>>,
+}
+
+/// Where the configuration that can be reloaded at runtime comes from
+#[derive(Clone)]
+pub struct ReloadSources {
+    pub hass_args: HassArguments,
+    pub quirks_file: Option<PathBuf>,
 }
 
 pub type StateHandle = Arc<State>;
@@ -43,6 +84,251 @@ impl State {
         *self.temperature_scale.lock().await
     }
 
+    pub async fn set_availability_grace_period(&self, grace: chrono::Duration) {
+        self.availability_grace_period.lock().await.replace(grace);
+    }
+
+    /// Sets how many consecutive device list refreshes a device
+    /// may be missing from before it is forgotten
+    pub async fn set_device_removal_cycles(&self, cycles: u32) {
+        self.device_removal_cycles.lock().await.replace(cycles);
+    }
+
+    /// Publish the availability of the device to hass, if it
+    /// has changed, or unconditionally if force is true
+    pub async fn publish_device_availability(
+        &self,
+        device_id: &str,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let Some(hass) = self.get_hass_client().await else {
+            return Ok(());
+        };
+        let grace = self
+            .availability_grace_period
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_AVAILABILITY_GRACE_PERIOD);
+
+        let Some(device) = self.device_by_id(device_id).await else {
+            anyhow::bail!("cannot find device {device_id}!?");
+        };
+        let (available, changed) = self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .update_availability(chrono::Utc::now(), grace);
+
+        if changed || force {
+            if changed {
+                log::info!(
+                    "{device} is now {}",
+                    if available {
+                        "available"
+                    } else {
+                        "unavailable"
+                    }
+                );
+            }
+            hass.publish(
+                device_availability_topic(&device),
+                if available { "online" } else { "offline" },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_reload_sources(&self, sources: ReloadSources) {
+        self.reload_sources.lock().await.replace(sources);
+    }
+
+    /// Re-read the .env file and the quirks file, and re-apply the
+    /// settings derived from them, such as the device filter and
+    /// sensor calibrations, then re-register the entities with hass.
+    /// The connections to the MQTT broker and to Govee are left
+    /// intact, so settings that affect them require a restart.
+    pub async fn reload_config(self: &Arc<Self>) -> anyhow::Result<()> {
+        let Some(sources) = self.reload_sources.lock().await.clone() else {
+            anyhow::bail!("configuration reloading is not available");
+        };
+
+        if let Err(err) = crate::service::env_file::reload() {
+            log::warn!("Unable to reload environment overrides: {err:#}");
+        }
+        if let Some(path) = &sources.quirks_file {
+            load_custom_quirks(path);
+        }
+        apply_reloadable_settings(self, &sources.hass_args).await?;
+        if let Err(err) = self.refresh_platform_device_list().await {
+            log::warn!("refresh_platform_device_list: {err:#}");
+        }
+
+        if let Some(hass) = self.get_hass_client().await {
+            hass.register_with_hass(self).await?;
+        }
+        log::info!("Configuration reloaded");
+        Ok(())
+    }
+
+    pub async fn set_device_filter(&self, filter: DeviceFilter) {
+        *self.device_filter.lock().await = filter;
+    }
+
+    /// Returns true if the device should be exposed to hass
+    pub async fn is_device_included(&self, device: &Device) -> bool {
+        self.device_filter.lock().await.allows(device)
+    }
+
+    pub async fn set_debug_sensors(&self, enable: bool) {
+        *self.debug_sensors.lock().await = enable;
+    }
+
+    /// Returns true if troubleshooting entities should be exposed to hass
+    pub async fn debug_sensors(&self) -> bool {
+        *self.debug_sensors.lock().await
+    }
+
+    pub async fn set_prefer_sku_names(&self, enable: bool) {
+        *self.prefer_sku_names.lock().await = enable;
+    }
+
+    /// Assign the names under which devices are registered with hass.
+    /// Returns true if any device name has changed.
+    pub async fn assign_hass_names(&self) -> bool {
+        let mut included = vec![];
+        for d in self.devices().await {
+            if self.is_device_included(&d).await {
+                included.push(d);
+            }
+        }
+        let names = hass_device_names(&included, *self.prefer_sku_names.lock().await);
+
+        let mut changed = false;
+        for d in self.devices_by_id.lock().await.values_mut() {
+            let name = names.get(&d.id).cloned();
+            if name.is_some() && d.hass_name != name {
+                if let Some(prior) = &d.hass_name {
+                    log::info!("{prior} is now known as {}", name.as_deref().unwrap_or(""));
+                }
+                d.hass_name = name;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Sets how long to wait for a device to accept a command sent
+    /// via the LAN API, or as a BLE packet, before giving up and
+    /// trying the next way of controlling it
+    pub async fn set_device_timeout(&self, timeout: Duration) {
+        self.device_timeout.lock().await.replace(timeout);
+    }
+
+    pub async fn get_device_timeout(&self) -> Duration {
+        self.device_timeout
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_DEVICE_TIMEOUT)
+    }
+
+    /// Run an operation that talks to the device directly, via the LAN
+    /// API or a BLE packet, giving up on it if the device doesn't respond
+    /// within the device timeout, so that a misbehaving device can't
+    /// stall the caller. Returns Ok(false) if it timed out, so that
+    /// the caller can fall back to the next way of controlling the device.
+    async fn try_direct_control<F>(
+        &self,
+        device: &Device,
+        api: &str,
+        fut: F,
+    ) -> anyhow::Result<bool>
+    where
+        F: std::future::Future<Output = anyhow::Result<()>>,
+    {
+        let timeout = self.get_device_timeout().await;
+        match tokio::time::timeout(timeout, fut).await {
+            Ok(result) => result.map(|()| true),
+            Err(_) => {
+                log::warn!(
+                    "{device} didn't respond via the {api} within {timeout:?}, \
+                     trying the next way of controlling it"
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    pub async fn set_transition_simulation_disabled(&self, disabled: bool) {
+        *self.transition_simulation_disabled.lock().await = disabled;
+    }
+
+    pub async fn set_gamut_clamping(&self, enabled: bool) {
+        *self.gamut_clamping.lock().await = enabled;
+    }
+
+    pub async fn set_scene_categories(&self, enabled: bool) {
+        *self.scene_categories.lock().await = enabled;
+    }
+
+    /// Returns the scene categories for the device, if prefixing
+    /// scene names with their category is enabled
+    async fn scene_categories_for(&self, device: &Device) -> Option<SceneCategories> {
+        if !*self.scene_categories.lock().await {
+            return None;
+        }
+        match GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await {
+            Ok(categories) => Some(SceneCategories::new(&categories)),
+            Err(err) => {
+                log::warn!("Unable to get scene categories for {device}: {err:#}");
+                None
+            }
+        }
+    }
+
+    pub async fn set_color_mode_priority(&self, priority: ColorModePriority) {
+        *self.color_mode_priority.lock().await = priority;
+    }
+
+    pub async fn get_color_mode_priority(&self) -> ColorModePriority {
+        *self.color_mode_priority.lock().await
+    }
+
+    /// If gamut clamping is enabled, map color to the closest color
+    /// that the device can display, using the gamut from its quirk,
+    /// or a typical gamut for RGB LEDs
+    pub async fn clamp_color_to_gamut(&self, device: &Device, color: DeviceColor) -> DeviceColor {
+        if !*self.gamut_clamping.lock().await {
+            return color;
+        }
+        let gamut = device
+            .resolve_quirk()
+            .and_then(|quirk| quirk.color_gamut)
+            .unwrap_or(GENERIC_LED_GAMUT);
+        match gamut.clamp(color) {
+            Some(clamped) => {
+                log::info!("{device}: {color:?} is outside of its gamut, using {clamped:?}");
+                clamped
+            }
+            None => color,
+        }
+    }
+
+    /// Returns true if transitions can be simulated for the device by
+    /// sending a series of commands. We only do that for devices that
+    /// we can control via the LAN or IoT APIs, as the Platform API is
+    /// too slow and too heavily rate limited.
+    pub async fn supports_stepped_transitions(&self, device: &Device) -> bool {
+        if *self.transition_simulation_disabled.lock().await {
+            return false;
+        }
+        if device.lan_device.is_some() {
+            return true;
+        }
+        device.iot_api_supported()
+            && device.undoc_device_info.is_some()
+            && self.get_iot_client_for(device).await.is_some()
+    }
+
     pub async fn set_hass_disco_prefix(&self, prefix: String) {
         *self.hass_discovery_prefix.lock().await = prefix;
     }
@@ -72,6 +358,50 @@ impl State {
         devices.get(id).cloned()
     }
 
+    /// Coalesce a burst of commands, such as those produced by
+    /// dragging a slider in hass, so that only the final command
+    /// of a given kind for the device is acted upon.
+    /// Waits for the debounce window and then returns true if
+    /// this is still the most recent command of its kind.
+    pub async fn debounce_command(&self, device_id: &str, kind: &str, window: Duration) -> bool {
+        let generation = self.next_command_generation(device_id, kind).await;
+
+        sleep(window).await;
+
+        if !self.is_current_command(device_id, kind, generation).await {
+            log::trace!("debounce_command: {kind} for {device_id} was superseded");
+            return false;
+        }
+        true
+    }
+
+    /// Records that a new command of the specified kind has arrived
+    /// for the device, superseding any earlier ones, and returns its
+    /// generation number
+    pub async fn next_command_generation(&self, device_id: &str, kind: &str) -> u64 {
+        let mut generations = self.command_generation.lock().await;
+        let generation = generations
+            .entry(device_id.to_string())
+            .or_default()
+            .entry(kind.to_string())
+            .or_default();
+        *generation += 1;
+        *generation
+    }
+
+    /// Returns true if no command of the specified kind has arrived
+    /// for the device since the one with the specified generation
+    pub async fn is_current_command(&self, device_id: &str, kind: &str, generation: u64) -> bool {
+        self.command_generation
+            .lock()
+            .await
+            .get(device_id)
+            .and_then(|kinds| kinds.get(kind))
+            .copied()
+            .unwrap_or_default()
+            == generation
+    }
+
     async fn semaphore_for_device(&self, device: &Device) -> Arc<Semaphore> {
         self.semaphore_by_id
             .lock()
@@ -101,8 +431,12 @@ impl State {
             .resolve_device(label)
             .await
             .ok_or_else(|| anyhow::anyhow!("device '{label}' not found"))?;
+        log_context::set_device(&device);
         let semaphore = self.semaphore_for_device(&device).await;
         let permit = semaphore.acquire_owned().await?;
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_last_controlled();
         let (tx, rx) = tokio::sync::oneshot::channel();
 
         // Schedule a task that will poll the device a short
@@ -152,12 +486,30 @@ impl State {
         self.hass_client.lock().await.clone()
     }
 
-    pub async fn set_iot_client(&self, client: IotClient) {
-        self.iot_client.lock().await.replace(client);
+    pub async fn set_iot_client(&self, account: Option<&str>, client: IotClient) {
+        self.iot_clients
+            .lock()
+            .await
+            .insert(account.map(|s| s.to_string()), client);
     }
 
+    /// Forget the IoT client while it is disconnected, so that
+    /// control and polling fall back to the other APIs
+    pub async fn clear_iot_client(&self, account: Option<&str>) -> Option<IotClient> {
+        self.iot_clients
+            .lock()
+            .await
+            .remove(&account.map(|s| s.to_string()))
+    }
+
+    /// Returns the IoT client of the primary account
     pub async fn get_iot_client(&self) -> Option<IotClient> {
-        self.iot_client.lock().await.clone()
+        self.iot_clients.lock().await.get(&None).cloned()
+    }
+
+    /// Returns the IoT client of the account that owns the device
+    pub async fn get_iot_client_for(&self, device: &Device) -> Option<IotClient> {
+        self.iot_clients.lock().await.get(&device.account).cloned()
     }
 
     pub async fn set_lan_client(&self, client: LanClient) {
@@ -168,34 +520,186 @@ impl State {
         self.lan_client.lock().await.clone()
     }
 
-    pub async fn set_platform_client(&self, client: GoveeApiClient) {
-        self.platform_client.lock().await.replace(client);
+    pub async fn set_platform_client(&self, account: Option<&str>, client: GoveeApiClient) {
+        self.platform_clients
+            .lock()
+            .await
+            .insert(account.map(|s| s.to_string()), client);
+    }
+
+    /// Returns the platform client of the account that owns the device
+    pub async fn get_platform_client_for(&self, device: &Device) -> Option<GoveeApiClient> {
+        self.platform_clients
+            .lock()
+            .await
+            .get(&device.account)
+            .cloned()
+    }
+
+    /// Returns true if any account has a platform client
+    pub async fn has_platform_client(&self) -> bool {
+        !self.platform_clients.lock().await.is_empty()
+    }
+
+    /// Discard the cached Platform API device list and fetch it again.
+    /// This is used when we learn of a device that wasn't in the list,
+    /// which may mean that the cached list is stale.
+    /// Returns false if the list was refreshed too recently.
+    pub async fn refresh_platform_device_list(self: &Arc<Self>) -> anyhow::Result<bool> {
+        let clients: Vec<_> = self
+            .platform_clients
+            .lock()
+            .await
+            .iter()
+            .map(|(account, client)| (account.clone(), client.clone()))
+            .collect();
+        if clients.is_empty() {
+            return Ok(false);
+        }
+
+        {
+            let mut last = self.last_device_list_refresh.lock().await;
+            if last.is_some_and(|when| when.elapsed() < MIN_DEVICE_LIST_REFRESH_INTERVAL) {
+                return Ok(false);
+            }
+            last.replace(Instant::now());
+        }
+
+        log::info!("Refreshing the Platform API device list");
+        let mut removed = false;
+        let mut changed_availability = vec![];
+        for (account, client) in clients {
+            client.invalidate_device_list()?;
+            let mut listed = HashSet::new();
+            for info in client.get_devices().await? {
+                listed.insert(info.device.to_string());
+                let mut device = self.device_mut(&info.sku, &info.device).await;
+                if device.claim_account(account.as_deref()) {
+                    device.set_http_device_info(info);
+                }
+            }
+            let (forgotten, changed) = self
+                .forget_unlisted_devices(account.as_deref(), &listed)
+                .await;
+            removed |= forgotten;
+            changed_availability.extend(changed);
+        }
+
+        for id in changed_availability {
+            if let Err(err) = self.publish_device_availability(&id, false).await {
+                log::warn!("publish_device_availability: {err:#}");
+            }
+        }
+
+        // Propagate devices that were renamed in the Govee App,
+        // and remove the entities of those that were removed
+        if self.assign_hass_names().await || removed {
+            if let Some(hass) = self.get_hass_client().await {
+                hass.register_with_hass(self).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Forget the devices that the Platform API previously listed for
+    /// account, but which are no longer in its device list, as they
+    /// have been removed from the account. Devices occasionally drop
+    /// out of the list for a while, so a device is only forgotten once
+    /// it has been missing from several consecutive refreshes; in the
+    /// meantime it is reported as unavailable.
+    /// Returns true if any device was forgotten, along with the ids
+    /// of the devices whose availability has changed as a result.
+    async fn forget_unlisted_devices(
+        &self,
+        account: Option<&str>,
+        listed: &HashSet<String>,
+    ) -> (bool, Vec<String>) {
+        let cycles = self
+            .device_removal_cycles
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_DEVICE_REMOVAL_CYCLES);
+        let mut changed = vec![];
+        let mut forgotten = vec![];
+        let mut devices = self.devices_by_id.lock().await;
+        devices.retain(|id, device| {
+            if device.http_device_info.is_none() || device.account.as_deref() != account {
+                return true;
+            }
+            if listed.contains(id) {
+                if device.missing_from_device_list > 0 {
+                    log::info!("{device} is back in the Govee account device list");
+                    device.missing_from_device_list = 0;
+                    changed.push(id.to_string());
+                }
+                return true;
+            }
+
+            device.missing_from_device_list += 1;
+            if device.missing_from_device_list < cycles {
+                log::warn!(
+                    "{device} is missing from the Govee account device list \
+                     ({} of {cycles} refreshes before it is removed)",
+                    device.missing_from_device_list
+                );
+                if device.missing_from_device_list == 1 {
+                    changed.push(id.to_string());
+                }
+                return true;
+            }
+            log::info!("{device} is no longer in the Govee account");
+            forgotten.push(id.to_string());
+            false
+        });
+        drop(devices);
+
+        if !forgotten.is_empty() {
+            let mut generations = self.command_generation.lock().await;
+            let mut semaphores = self.semaphore_by_id.lock().await;
+            for id in &forgotten {
+                generations.remove(id);
+                semaphores.remove(id);
+            }
+        }
+        (!forgotten.is_empty(), changed)
     }
 
-    pub async fn get_platform_client(&self) -> Option<GoveeApiClient> {
-        self.platform_client.lock().await.clone()
+    /// Returns the number of Platform API requests waiting
+    /// to be sent, across all accounts
+    pub async fn platform_queue_depth(&self) -> usize {
+        self.platform_clients
+            .lock()
+            .await
+            .values()
+            .map(|client| client.queue_depth())
+            .sum()
     }
 
-    pub async fn set_undoc_client(&self, client: GoveeUndocumentedApi) {
-        self.undoc_client.lock().await.replace(client);
+    pub async fn set_undoc_client(&self, account: Option<&str>, client: GoveeUndocumentedApi) {
+        self.undoc_clients
+            .lock()
+            .await
+            .insert(account.map(|s| s.to_string()), client);
     }
 
-    #[allow(dead_code)]
+    /// Returns the undocumented API client of the primary account
     pub async fn get_undoc_client(&self) -> Option<GoveeUndocumentedApi> {
-        self.undoc_client.lock().await.clone()
+        self.undoc_clients.lock().await.get(&None).cloned()
     }
 
     pub async fn poll_iot_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
-        if let Some(iot) = self.get_iot_client().await {
+        if let Some(iot) = self.get_iot_client_for(device).await {
             if let Some(info) = device.undoc_device_info.clone() {
                 if iot.is_device_compatible(&info.entry) {
                     let device_state = device.device_state();
                     log::info!("requesting update via IoT MQTT {device} {device_state:?}");
-                    match iot
+                    let started = Instant::now();
+                    let result = iot
                         .request_status_update(&info.entry)
                         .await
-                        .context("iot.request_status_update")
-                    {
+                        .context("iot.request_status_update");
+                    METRICS.device_poll(&device.id, "iot", started.elapsed(), result.is_ok());
+                    match result {
                         Err(err) => {
                             log::error!("Failed: {err:#}");
                         }
@@ -218,19 +722,38 @@ impl State {
     }
 
     pub async fn poll_platform_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             let device_state = device.device_state();
             log::info!("requesting update via Platform API {device} {device_state:?}");
             if let Some(info) = &device.http_device_info {
-                let http_state = client
-                    .get_device_state(info)
-                    .await
-                    .context("get_device_state")?;
+                let started = Instant::now();
+                let result = client.get_device_state(info).await;
+                METRICS.device_poll(&device.id, "platform", started.elapsed(), result.is_ok());
+                let http_state = result.context("get_device_state")?;
                 log::trace!("updated state for {device}");
 
+                // The scene may have been changed via the Govee App.
+                // Failing to look it up mustn't lose the rest of the state.
+                let reported_scene = match http_state.light_scene_value() {
+                    Some(value) => Some(
+                        client
+                            .light_scene_name_for_value(info, value)
+                            .await
+                            .map_err(|err| {
+                                log::warn!("light_scene_name_for_value for {device}: {err:#}");
+                            }),
+                    ),
+                    None => None,
+                };
+
                 {
                     let mut device = self.device_mut(&device.sku, &device.id).await;
                     device.set_http_device_state(http_state);
+                    match reported_scene {
+                        Some(Ok(scene)) => device.set_reported_scene(scene.as_deref()),
+                        Some(Err(())) => device.set_active_scene(None),
+                        None => {}
+                    }
                     device.set_last_polled();
                 }
                 self.notify_of_state_change(&device.id)
@@ -246,6 +769,31 @@ impl State {
         Ok(false)
     }
 
+    /// Query the current status of a LAN device. This is used by the
+    /// periodic poller, so it uses a short timeout to avoid holding
+    /// up the polling of other devices when the device is unresponsive.
+    pub async fn poll_lan_status(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        let Some(lan_device) = &device.lan_device else {
+            anyhow::bail!("{device} is not a LAN device");
+        };
+        let Some(client) = self.get_lan_client().await else {
+            anyhow::bail!("no lan client");
+        };
+        log::trace!("requesting update via LAN API {device}");
+        let started = Instant::now();
+        let result = client
+            .query_status_with_timeout(lan_device, LAN_POLL_TIMEOUT)
+            .await;
+        METRICS.device_poll(&device.id, "lan", started.elapsed(), result.is_ok());
+        let status = result?;
+        {
+            let mut device = self.device_mut(&device.sku, &device.id).await;
+            device.set_lan_device_status(status);
+            device.set_last_polled();
+        }
+        self.notify_of_state_change(&device.id).await
+    }
+
     async fn poll_lan_api<F: Fn(&LanDeviceStatus) -> bool>(
         self: &Arc<Self>,
         device: &LanDevice,
@@ -253,9 +801,12 @@ impl State {
     ) -> anyhow::Result<()> {
         match self.get_lan_client().await {
             Some(client) => {
-                let deadline = Instant::now() + Duration::from_secs(5);
+                // Leave time within the device timeout to notice a device
+                // that is responding, but hasn't applied the change yet
+                let timeout = self.get_device_timeout().await;
+                let deadline = Instant::now() + timeout / 2;
                 while Instant::now() <= deadline {
-                    let status = client.query_status(device).await?;
+                    let status = client.query_status_with_timeout(device, timeout).await?;
                     let accepted = (acceptor)(&status);
                     self.device_mut(&device.sku, &device.device)
                         .await
@@ -279,7 +830,7 @@ impl State {
         value: V,
     ) -> anyhow::Result<()> {
         let value: JsonValue = value.into();
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to send {value:?} control to {device}");
                 client.control_device(info, capability, value).await?;
@@ -313,13 +864,19 @@ impl State {
 
         if let Some(lan_dev) = &device.lan_device {
             log::info!("Using LAN API to set {device} light power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
+            if self
+                .try_direct_control(device, "LAN API", async {
+                    lan_dev.send_turn(on).await?;
+                    self.poll_lan_api(lan_dev, |status| status.on == on).await
+                })
+                .await?
+            {
+                return Ok(());
+            }
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} light power state");
                     iot.set_power_state(&info.entry, on).await?;
@@ -328,7 +885,7 @@ impl State {
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} light {instance_name} state");
                 client.set_toggle_state(info, instance_name, on).await?;
@@ -346,13 +903,19 @@ impl State {
     ) -> anyhow::Result<()> {
         if let Some(lan_dev) = &device.lan_device {
             log::info!("Using LAN API to set {device} power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
+            if self
+                .try_direct_control(device, "LAN API", async {
+                    lan_dev.send_turn(on).await?;
+                    self.poll_lan_api(lan_dev, |status| status.on == on).await
+                })
+                .await?
+            {
+                return Ok(());
+            }
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} power state");
                     iot.set_power_state(&info.entry, on).await?;
@@ -361,7 +924,7 @@ impl State {
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} power state");
                 client.set_power_state(info, on).await?;
@@ -389,14 +952,20 @@ impl State {
 
         if let Some(lan_dev) = &device.lan_device {
             log::info!("Using LAN API to set {device} brightness");
-            lan_dev.send_brightness(percent).await?;
-            self.poll_lan_api(lan_dev, |status| status.brightness == percent)
-                .await?;
-            return Ok(());
+            if self
+                .try_direct_control(device, "LAN API", async {
+                    lan_dev.send_brightness(percent).await?;
+                    self.poll_lan_api(lan_dev, |status| status.brightness == percent)
+                        .await
+                })
+                .await?
+            {
+                return Ok(());
+            }
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} brightness");
                     iot.set_brightness(&info.entry, percent).await?;
@@ -405,7 +974,7 @@ impl State {
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} brightness");
                 client.set_brightness(info, percent).await?;
@@ -415,24 +984,87 @@ impl State {
         anyhow::bail!("Unable to control brightness for {device}");
     }
 
+    /// Send a brightness change as part of a simulated transition,
+    /// without waiting for the device to confirm it
+    pub async fn device_send_brightness_step(
+        self: &Arc<Self>,
+        device: &Device,
+        percent: u8,
+    ) -> anyhow::Result<()> {
+        if let Some(lan_dev) = &device.lan_device {
+            return lan_dev.send_brightness(percent).await;
+        }
+        if let (Some(iot), Some(info)) = (
+            self.get_iot_client_for(device).await,
+            &device.undoc_device_info,
+        ) {
+            return iot.set_brightness(&info.entry, percent).await;
+        }
+        anyhow::bail!("Unable to step brightness for {device}");
+    }
+
+    /// Send a color change as part of a simulated transition,
+    /// without waiting for the device to confirm it
+    pub async fn device_send_color_step(
+        self: &Arc<Self>,
+        device: &Device,
+        color: crate::lan_api::DeviceColor,
+    ) -> anyhow::Result<()> {
+        if let Some(lan_dev) = &device.lan_device {
+            return lan_dev.send_color_rgb(color).await;
+        }
+        if let (Some(iot), Some(info)) = (
+            self.get_iot_client_for(device).await,
+            &device.undoc_device_info,
+        ) {
+            return iot
+                .set_color_rgb(&info.entry, color.r, color.g, color.b)
+                .await;
+        }
+        anyhow::bail!("Unable to step color for {device}");
+    }
+
     pub async fn device_set_color_temperature(
         self: &Arc<Self>,
         device: &Device,
         kelvin: u32,
     ) -> anyhow::Result<()> {
+        if !device.supports_native_color_temperature() && device.supports_rgb() {
+            let color = kelvin_to_rgb(kelvin);
+            log::info!(
+                "{device} has no native color temperature support, \
+                 approximating {kelvin}K as {color:?}"
+            );
+            return self
+                .device_set_color_rgb(device, color.r, color.g, color.b)
+                .await;
+        }
+
+        // Don't ask for a color temperature that the device can't produce
+        let kelvin = match device.get_color_temperature_range() {
+            Some((min, max)) => kelvin.clamp(min, max),
+            None => kelvin,
+        };
+
         if let Some(lan_dev) = &device.lan_device {
             log::info!("Using LAN API to set {device} color temperature");
-            lan_dev.send_color_temperature_kelvin(kelvin).await?;
-            self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
-                .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
+            if self
+                .try_direct_control(device, "LAN API", async {
+                    lan_dev.send_color_temperature_kelvin(kelvin).await?;
+                    self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
+                        .await
+                })
+                .await?
+            {
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_active_scene(None);
+                return Ok(());
+            }
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} color temperature");
                     iot.set_color_temperature(&info.entry, kelvin).await?;
@@ -441,7 +1073,7 @@ impl State {
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} color temperature");
                 client.set_color_temperature(info, kelvin).await?;
@@ -465,11 +1097,17 @@ impl State {
         (apply)(&mut params);
 
         if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, &params) {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} color");
-                    iot.send_real(&info.entry, vec![command.base64()]).await?;
-                    return Ok(true);
+                    if self
+                        .try_direct_control(device, "IoT API", async {
+                            iot.send_real(&info.entry, vec![command.base64()]).await
+                        })
+                        .await?
+                    {
+                        return Ok(true);
+                    }
                 }
             }
         }
@@ -490,15 +1128,21 @@ impl State {
                 param: value as u8,
             },
         ) {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
-                    iot.send_real(&info.entry, vec![command.base64()]).await?;
-                    return Ok(());
+                    if self
+                        .try_direct_control(device, "IoT API", async {
+                            iot.send_real(&info.entry, vec![command.base64()]).await
+                        })
+                        .await?
+                    {
+                        return Ok(());
+                    }
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 client.set_work_mode(info, work_mode, value).await?;
                 return Ok(());
@@ -529,17 +1173,23 @@ impl State {
         if let Some(lan_dev) = &device.lan_device {
             let color = crate::lan_api::DeviceColor { r, g, b };
             log::info!("Using LAN API to set {device} color");
-            lan_dev.send_color_rgb(color).await?;
-            self.poll_lan_api(lan_dev, |status| status.color == color)
-                .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
+            if self
+                .try_direct_control(device, "LAN API", async {
+                    lan_dev.send_color_rgb(color).await?;
+                    self.poll_lan_api(lan_dev, |status| status.color == color)
+                        .await
+                })
+                .await?
+            {
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_active_scene(None);
+                return Ok(());
+            }
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} color");
                     iot.set_color_rgb(&info.entry, r, g, b).await?;
@@ -548,7 +1198,7 @@ impl State {
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} color");
                 client.set_color_rgb(info, r, g, b).await?;
@@ -561,12 +1211,41 @@ impl State {
         anyhow::bail!("Unable to control color for {device}");
     }
 
+    /// Immediately poll the device for its current state, using the
+    /// best available transport, regardless of the normal poll interval.
+    /// Returns false if the request was rate limited.
+    pub async fn refresh_device_now(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
+        if !self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .try_begin_refresh(chrono::Utc::now(), MIN_REFRESH_INTERVAL)
+        {
+            return Ok(false);
+        }
+
+        if let Some(lan_device) = &device.lan_device {
+            match self.poll_lan_api(lan_device, |_| true).await {
+                Ok(()) => return Ok(true),
+                Err(err) => log::warn!("Refreshing {device} via LAN failed: {err:#}"),
+            }
+        }
+
+        if self.poll_iot_api(device).await? {
+            return Ok(true);
+        }
+
+        if !self.poll_platform_api(device).await? {
+            log::warn!("Unable to refresh {device}: no suitable API is available");
+        }
+        Ok(true)
+    }
+
     pub async fn poll_after_control(self: &Arc<Self>, id: String) {
         let Some(device) = self.device_by_id(&id).await else {
             return;
         };
 
-        let iot_available = self.get_iot_client().await.is_some();
+        let iot_available = self.get_iot_client_for(&device).await.is_some();
 
         if device.pollable_via_iot() && iot_available {
             return;
@@ -589,9 +1268,13 @@ impl State {
 
     pub async fn device_list_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
-                return Ok(sort_and_dedup_scenes(client.list_scene_names(info).await?));
+                let mut scenes = client.list_scene_names(info).await?;
+                if let Some(categories) = self.scene_categories_for(device).await {
+                    scenes = scenes.iter().map(|s| categories.categorize(s)).collect();
+                }
+                return Ok(sort_and_dedup_scenes(scenes));
             }
         }
 
@@ -600,13 +1283,82 @@ impl State {
         Ok(vec![])
     }
 
+    /// Returns the effect list for the light entity of the device.
+    /// DIY scenes and music modes are namespaced with a prefix so
+    /// that device_set_effect can dispatch them appropriately.
+    pub async fn device_list_effects(&self, device: &Device) -> anyhow::Result<Vec<String>> {
+        let Some(client) = self.get_platform_client_for(device).await else {
+            return Ok(vec![]);
+        };
+        let Some(info) = &device.http_device_info else {
+            return Ok(vec![]);
+        };
+
+        let diy_scenes = client
+            .list_diy_scene_names(info)
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!("Unable to list DIY scenes for {device}: {err:#}");
+                vec![]
+            });
+
+        let mut scenes: Vec<String> = client
+            .list_scene_names(info)
+            .await?
+            .into_iter()
+            .filter(|s| !s.is_empty() && !s.starts_with("Music: ") && !diy_scenes.contains(s))
+            .collect();
+        if let Some(categories) = self.scene_categories_for(device).await {
+            scenes = scenes.iter().map(|s| categories.categorize(s)).collect();
+        }
+
+        let mut effects: Vec<String> = diy_scenes
+            .iter()
+            .map(|name| format!("DIY: {name}"))
+            .chain(
+                info.music_mode_names()
+                    .iter()
+                    .map(|name| format!("Music: {name}")),
+            )
+            .collect();
+
+        // Some devices have hundreds of scenes, which makes for an
+        // unwieldy effect list and discovery payload. The remaining
+        // scenes are still available via the scene select entity.
+        let limit = MAX_LIGHT_EFFECTS.saturating_sub(effects.len());
+        if scenes.len() > limit {
+            log::info!(
+                "{device} has {} scenes; only the first {limit} will be \
+                 included in the effect list",
+                scenes.len()
+            );
+            scenes.truncate(limit);
+        }
+        effects.append(&mut scenes);
+
+        Ok(sort_and_dedup_scenes(effects))
+    }
+
+    /// Activates an effect from the list returned by device_list_effects
+    pub async fn device_set_effect(
+        self: &Arc<Self>,
+        device: &Device,
+        effect: &str,
+    ) -> anyhow::Result<()> {
+        if let Some(diy) = effect.strip_prefix("DIY: ") {
+            return self.device_set_diy_scene(device, diy).await;
+        }
+        // Music modes are handled by device_set_scene
+        self.device_set_scene(device, effect).await
+    }
+
     pub async fn device_set_target_temperature(
         self: &Arc<Self>,
         device: &Device,
         instance_name: &str,
         target: TemperatureValue,
     ) -> anyhow::Result<()> {
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} target temperature to {target}");
                 client
@@ -624,11 +1376,20 @@ impl State {
         device: &Device,
         scene: &str,
     ) -> anyhow::Result<()> {
+        if let Some(music_mode) = scene.strip_prefix("Music: ") {
+            return self.device_set_music_mode(device, music_mode).await;
+        }
+        let categories = self.scene_categories_for(device).await;
+        let scene = match &categories {
+            Some(categories) => categories.strip(scene),
+            None => scene,
+        };
+
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
         let avoid_platform_api = device.avoid_platform_api();
 
         if !avoid_platform_api {
-            if let Some(client) = self.get_platform_client().await {
+            if let Some(client) = self.get_platform_client_for(device).await {
                 if let Some(info) = &device.http_device_info {
                     log::info!("Using Platform API to set {device} to scene {scene}");
                     client.set_scene_by_name(info, scene).await?;
@@ -642,17 +1403,179 @@ impl State {
 
         if let Some(lan_dev) = &device.lan_device {
             log::info!("Using LAN API to set {device} to scene {scene}");
-            lan_dev.set_scene_by_name(scene).await?;
+            if self
+                .try_direct_control(device, "LAN API", lan_dev.set_scene_by_name(scene))
+                .await?
+            {
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_active_scene(Some(scene));
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to set scene for {device}");
+    }
+
+    /// Activates a scene by its numeric id, as listed by the
+    /// Platform API, rather than by its name
+    pub async fn device_set_scene_by_id(
+        self: &Arc<Self>,
+        device: &Device,
+        scene_id: u64,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.get_platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} to scene id {scene_id}");
+                let scene = client.set_scene_by_id(info, scene_id).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_active_scene(Some(&scene));
+                return Ok(());
+            }
+        }
 
-            self.device_mut(&device.sku, &device.id)
+        anyhow::bail!("Unable to set scene by id for {device}: the Platform API is required");
+    }
+
+    /// Activates a music mode, using the most recently set sensitivity.
+    /// This replaces any scene that was active.
+    pub async fn device_set_music_mode(
+        self: &Arc<Self>,
+        device: &Device,
+        music_mode: &str,
+    ) -> anyhow::Result<()> {
+        let sensitivity = device.music_sensitivity.unwrap_or(100);
+
+        if let Some(client) = self.get_platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                log::info!(
+                    "Using Platform API to set {device} to music mode {music_mode} \
+                     with sensitivity {sensitivity}"
+                );
+                client.set_music_mode(info, music_mode, sensitivity).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_active_scene(Some(&format!("Music: {music_mode}")));
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to set music mode for {device}");
+    }
+
+    /// Records the music mode sensitivity, and applies it
+    /// immediately if a music mode is active
+    pub async fn device_set_music_sensitivity(
+        self: &Arc<Self>,
+        device: &Device,
+        sensitivity: u8,
+    ) -> anyhow::Result<()> {
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_music_sensitivity(sensitivity);
+
+        if let Some(music_mode) = device.active_music_mode() {
+            let device = self
+                .device_by_id(&device.id)
                 .await
-                .set_active_scene(Some(scene));
-            return Ok(());
+                .ok_or_else(|| anyhow::anyhow!("device {device} went away"))?;
+            self.device_set_music_mode(&device, &music_mode).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn device_list_light_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
+        if let Some(client) = self.get_platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                return Ok(sort_and_dedup_scenes(
+                    client.list_light_scene_names(info).await?,
+                ));
+            }
+        }
+
+        Ok(vec![])
+    }
+
+    pub async fn device_set_light_scene(
+        self: &Arc<Self>,
+        device: &Device,
+        scene: &str,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.get_platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} to scene {scene}");
+                client.set_light_scene_by_name(info, scene).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_active_scene(Some(scene));
+                return Ok(());
+            }
         }
 
         anyhow::bail!("Unable to set scene for {device}");
     }
 
+    pub async fn device_list_snapshots(&self, device: &Device) -> Vec<String> {
+        if self.get_platform_client_for(device).await.is_some() {
+            if let Some(info) = &device.http_device_info {
+                return info.snapshot_names();
+            }
+        }
+        vec![]
+    }
+
+    pub async fn device_set_snapshot(
+        self: &Arc<Self>,
+        device: &Device,
+        snapshot: &str,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.get_platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to recall snapshot {snapshot} on {device}");
+                client.set_snapshot_by_name(info, snapshot).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_last_snapshot(snapshot);
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to recall snapshot for {device}");
+    }
+
+    pub async fn device_list_diy_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
+        if let Some(client) = self.get_platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                return Ok(sort_and_dedup_scenes(
+                    client.list_diy_scene_names(info).await?,
+                ));
+            }
+        }
+
+        Ok(vec![])
+    }
+
+    pub async fn device_set_diy_scene(
+        self: &Arc<Self>,
+        device: &Device,
+        scene: &str,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.get_platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} to DIY scene {scene}");
+                client.set_diy_scene_by_name(info, scene).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_active_scene(Some(scene));
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to set DIY scene for {device}");
+    }
+
     // Take care not to call this while you hold a mutable device
     // reference, as that will deadlock!
     pub async fn notify_of_state_change(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
@@ -660,6 +1583,16 @@ impl State {
             anyhow::bail!("cannot find device {device_id}!?");
         };
 
+        if let Err(err) = self.persist_device_state(&canonical_device).await {
+            log::warn!("Failed to persist state for {canonical_device}: {err:#}");
+        }
+
+        if !self.is_device_included(&canonical_device).await {
+            return Ok(());
+        }
+
+        self.publish_device_availability(device_id, false).await?;
+
         if let Some(hass) = self.get_hass_client().await {
             hass.advise_hass_of_light_state(&canonical_device, self)
                 .await?;
@@ -667,10 +1600,175 @@ impl State {
 
         Ok(())
     }
+
+    /// Load previously persisted state from the specified file,
+    /// and apply it to the devices that we know about.
+    /// Subsequent state changes will be saved to that same file.
+    pub async fn load_persisted_state(&self, path: PathBuf) {
+        // If the file is damaged, start over rather than refusing
+        // to persist anything further
+        let map = match load_state_file(&path).await {
+            Ok(map) => map,
+            Err(err) => {
+                log::warn!("Ignoring persisted state: {err:#}");
+                PersistedStateMap::new()
+            }
+        };
+        log::info!(
+            "Loaded persisted state for {} devices from {path:?}",
+            map.len()
+        );
+
+        let mut devices = self.devices_by_id.lock().await;
+        for (id, persisted) in &map {
+            // Only apply to devices that we discovered; we don't
+            // want to resurrect devices that were since removed
+            if let Some(device) = devices.get_mut(id) {
+                if device.device_state().is_none() {
+                    device.set_persisted_state(persisted.clone());
+                }
+            }
+        }
+        drop(devices);
+
+        *self.persisted_state.lock().await = map;
+        self.state_file.lock().await.replace(path);
+    }
+
+    async fn persist_device_state(&self, device: &Device) -> anyhow::Result<()> {
+        let Some(path) = self.state_file.lock().await.clone() else {
+            return Ok(());
+        };
+        let Some(current) = PersistedDeviceState::from_device(device) else {
+            return Ok(());
+        };
+
+        let mut map = self.persisted_state.lock().await;
+        if let Some(prior) = map.get(&device.id) {
+            if !prior.differs_from(&current) {
+                return Ok(());
+            }
+        }
+        map.insert(device.id.to_string(), current);
+        save_state_file(&path, &map).await
+    }
 }
 
+/// How long a device must be unreachable before we report
+/// it as being unavailable
+pub const DEFAULT_AVAILABILITY_GRACE_PERIOD: chrono::Duration = chrono::Duration::seconds(300);
+
+/// How many consecutive device list refreshes a device must be
+/// missing from before we forget it
+pub const DEFAULT_DEVICE_REMOVAL_CYCLES: u32 = 3;
+
+/// How long to wait for a device to accept a command that was
+/// sent to it via the LAN API or as a BLE packet
+pub const DEFAULT_DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a LAN device to respond to a periodic
+/// status query before falling back to the cloud APIs
+const LAN_POLL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Limits how often an unknown device can cause us
+/// to fetch the Platform API device list again
+const MIN_DEVICE_LIST_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Users can't request that a device be refreshed more often than this
+const MIN_REFRESH_INTERVAL: chrono::Duration = chrono::Duration::seconds(10);
+
+/// The maximum number of effects to list for a light
+const MAX_LIGHT_EFFECTS: usize = 200;
+
 pub fn sort_and_dedup_scenes(mut scenes: Vec<String>) -> Vec<String> {
     scenes.sort_by_key(|s| s.to_ascii_lowercase());
     scenes.dedup();
     scenes
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::HttpDeviceInfo;
+
+    #[tokio::test]
+    async fn direct_control_timeout() {
+        let state = State::new();
+        state.set_device_timeout(Duration::from_millis(20)).await;
+        let device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+
+        assert!(state
+            .try_direct_control(&device, "LAN API", async { Ok(()) })
+            .await
+            .unwrap());
+        assert!(state
+            .try_direct_control(&device, "LAN API", async { anyhow::bail!("unreachable") })
+            .await
+            .is_err());
+
+        // A device that doesn't respond lets us move on to the next API
+        assert!(!state
+            .try_direct_control(&device, "LAN API", async {
+                sleep(Duration::from_secs(60)).await;
+                Ok(())
+            })
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn device_missing_from_list() {
+        let state = State::new();
+        state.set_device_removal_cycles(2).await;
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("../../test-data/replay/H7111.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(fixture["device"].clone()).unwrap();
+        let id = info.device.to_string();
+        state
+            .device_mut(&info.sku, &id)
+            .await
+            .set_http_device_info(info);
+
+        let listed = HashSet::from([id.clone()]);
+        assert_eq!(
+            state.forget_unlisted_devices(None, &listed).await,
+            (false, vec![])
+        );
+
+        // The first time that it goes missing, it is kept but is unavailable
+        let (removed, changed) = state.forget_unlisted_devices(None, &HashSet::new()).await;
+        assert!(!removed);
+        assert_eq!(changed, vec![id.clone()]);
+        let mut device = state.device_by_id(&id).await.unwrap();
+        assert!(
+            !device
+                .update_availability(chrono::Utc::now(), chrono::Duration::zero())
+                .0
+        );
+
+        // Coming back restores it
+        assert_eq!(
+            state.forget_unlisted_devices(None, &listed).await,
+            (false, vec![id.clone()])
+        );
+        assert_eq!(
+            state
+                .device_by_id(&id)
+                .await
+                .unwrap()
+                .missing_from_device_list,
+            0
+        );
+
+        // It is only forgotten once it is missing for long enough
+        assert_eq!(
+            state.next_command_generation(&id, "light/transition").await,
+            1
+        );
+        assert!(!state.forget_unlisted_devices(None, &HashSet::new()).await.0);
+        assert!(state.forget_unlisted_devices(None, &HashSet::new()).await.0);
+        assert!(state.device_by_id(&id).await.is_none());
+        // Along with the commands that were sent to it
+        assert!(state.command_generation.lock().await.is_empty());
+    }
+}
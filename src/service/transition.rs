@@ -0,0 +1,162 @@
+use crate::lan_api::DeviceColor;
+use crate::service::device::Device;
+use crate::service::state::StateHandle;
+use tokio::time::{sleep, Duration};
+
+/// The shortest time between the steps of a simulated transition
+pub const TRANSITION_STEP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Bounds the number of commands that a single transition can produce
+const MAX_TRANSITION_STEPS: usize = 40;
+
+/// An intermediate value that is sent to a device during
+/// a simulated transition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransitionStep {
+    Brightness(u8),
+    Color(DeviceColor),
+}
+
+fn step_count(duration: Duration, distance: usize) -> usize {
+    let by_time = (duration.as_millis() / TRANSITION_STEP_INTERVAL.as_millis()) as usize;
+    by_time.min(distance).clamp(1, MAX_TRANSITION_STEPS)
+}
+
+fn lerp(from: u8, to: u8, step: usize, count: usize) -> u8 {
+    let from = from as f64;
+    let to = to as f64;
+    (from + (to - from) * step as f64 / count as f64).round() as u8
+}
+
+/// Returns the intermediate brightness values to move from `from`
+/// to `to` over `duration`. Neither end point is included; the
+/// caller is expected to set the final value itself.
+pub fn brightness_steps(from: u8, to: u8, duration: Duration) -> Vec<TransitionStep> {
+    let count = step_count(duration, from.abs_diff(to) as usize);
+    (1..count)
+        .map(|i| TransitionStep::Brightness(lerp(from, to, i, count)))
+        .collect()
+}
+
+/// Returns the intermediate colors to move from `from` to `to`
+/// over `duration`, excluding both end points.
+pub fn color_steps(from: DeviceColor, to: DeviceColor, duration: Duration) -> Vec<TransitionStep> {
+    let distance = from
+        .r
+        .abs_diff(to.r)
+        .max(from.g.abs_diff(to.g))
+        .max(from.b.abs_diff(to.b));
+    let count = step_count(duration, distance as usize);
+    (1..count)
+        .map(|i| {
+            TransitionStep::Color(DeviceColor {
+                r: lerp(from.r, to.r, i, count),
+                g: lerp(from.g, to.g, i, count),
+                b: lerp(from.b, to.b, i, count),
+            })
+        })
+        .collect()
+}
+
+/// Returns how long to wait before each of `num_steps` intermediate
+/// steps, and before the final value, so that the final value is
+/// reached once `duration` has elapsed
+fn step_interval(duration: Duration, num_steps: usize) -> Duration {
+    duration / (num_steps as u32 + 1)
+}
+
+/// Sends the steps of a simulated transition to the device, spread
+/// evenly over `duration`; this returns when it is time for the caller
+/// to set the final value.
+/// The transition is abandoned if another command for the same
+/// device arrives while it is running, in which case this returns false.
+pub async fn run_transition(
+    state: &StateHandle,
+    device: &Device,
+    steps: Vec<TransitionStep>,
+    duration: Duration,
+    key: &str,
+    generation: u64,
+) -> anyhow::Result<bool> {
+    if steps.is_empty() {
+        return Ok(state.is_current_command(&device.id, key, generation).await);
+    }
+    let interval = step_interval(duration, steps.len());
+    for step in steps {
+        sleep(interval).await;
+        if !state.is_current_command(&device.id, key, generation).await {
+            log::trace!("transition for {device} was superseded");
+            return Ok(false);
+        }
+        match step {
+            TransitionStep::Brightness(percent) => {
+                state.device_send_brightness_step(device, percent).await?;
+            }
+            TransitionStep::Color(color) => {
+                state.device_send_color_step(device, color).await?;
+            }
+        }
+    }
+    sleep(interval).await;
+    Ok(state.is_current_command(&device.id, key, generation).await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn steps() {
+        k9::assert_equal!(
+            brightness_steps(0, 100, Duration::from_secs(1)),
+            vec![
+                TransitionStep::Brightness(25),
+                TransitionStep::Brightness(50),
+                TransitionStep::Brightness(75),
+            ]
+        );
+        // Too short to step at all
+        k9::assert_equal!(brightness_steps(0, 100, Duration::from_millis(100)), vec![]);
+        // Never more steps than there are distinct values
+        k9::assert_equal!(
+            brightness_steps(50, 48, Duration::from_secs(5)),
+            vec![TransitionStep::Brightness(49)]
+        );
+        // Long transitions are bounded
+        assert_eq!(
+            brightness_steps(0, 100, Duration::from_secs(60)).len(),
+            MAX_TRANSITION_STEPS - 1
+        );
+
+        k9::assert_equal!(
+            color_steps(
+                DeviceColor { r: 255, g: 0, b: 0 },
+                DeviceColor { r: 0, g: 0, b: 255 },
+                Duration::from_millis(500)
+            ),
+            vec![TransitionStep::Color(DeviceColor {
+                r: 128,
+                g: 0,
+                b: 128
+            })]
+        );
+    }
+
+    #[test]
+    fn total_duration_matches_transition() {
+        // A long transition is capped at MAX_TRANSITION_STEPS, but
+        // the steps are spread out to take the requested time
+        let duration = Duration::from_secs(30);
+        let steps = brightness_steps(0, 100, duration);
+        assert_eq!(
+            step_interval(duration, steps.len()) * (steps.len() as u32 + 1),
+            duration
+        );
+
+        // A small change over a long time has few, widely spaced steps
+        let duration = Duration::from_secs(10);
+        let steps = brightness_steps(50, 45, duration);
+        assert_eq!(steps.len(), 4);
+        assert_eq!(step_interval(duration, steps.len()), Duration::from_secs(2));
+    }
+}
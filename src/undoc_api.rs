@@ -6,6 +6,7 @@ use crate::platform_api::{
     from_json, http_response_body, DeviceCapability, DeviceCapabilityKind, DeviceParameters,
     EnumOption,
 };
+use crate::service::account::account_cache_key;
 use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
@@ -138,6 +139,8 @@ pub struct GoveeUndocumentedApi {
     email: String,
     password: String,
     client_id: String,
+    /// The label of the account, if it isn't the primary account
+    account: Option<String>,
 }
 
 impl GoveeUndocumentedApi {
@@ -150,15 +153,25 @@ impl GoveeUndocumentedApi {
             email,
             password,
             client_id,
+            account: None,
         }
     }
 
+    pub fn with_account(mut self, label: &str) -> Self {
+        self.account.replace(label.to_string());
+        self
+    }
+
+    fn cache_key(&self, key: &str) -> String {
+        account_cache_key(self.account.as_deref(), key)
+    }
+
     #[allow(unused)]
     pub async fn get_iot_key(&self, token: &str) -> anyhow::Result<IotKey> {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "iot-key",
+                key: &self.cache_key("iot-key"),
                 soft_ttl: HALF_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: Duration::from_secs(10),
@@ -196,7 +209,7 @@ impl GoveeUndocumentedApi {
     }
 
     pub fn invalidate_account_login(&self) {
-        crate::cache::invalidate_key("undoc-api", "account-info").ok();
+        crate::cache::invalidate_key("undoc-api", &self.cache_key("account-info")).ok();
     }
 
     async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
@@ -233,7 +246,7 @@ impl GoveeUndocumentedApi {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "account-info",
+                key: &self.cache_key("account-info"),
                 soft_ttl: HALF_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: FIFTEEN_MINS,
@@ -278,7 +291,7 @@ impl GoveeUndocumentedApi {
     }
 
     pub fn invalidate_community_login(&self) {
-        crate::cache::invalidate_key("undoc-api", "community-login").ok();
+        crate::cache::invalidate_key("undoc-api", &self.cache_key("community-login")).ok();
     }
 
     /// Login to community-api.govee.com and return the bearer token
@@ -286,7 +299,7 @@ impl GoveeUndocumentedApi {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "community-login",
+                key: &self.cache_key("community-login"),
                 soft_ttl: ONE_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: Duration::from_secs(10),
@@ -348,7 +361,7 @@ impl GoveeUndocumentedApi {
                 key: &key,
                 soft_ttl: ONE_DAY,
                 hard_ttl: ONE_WEEK,
-                negative_ttl: Duration::from_secs(1),
+                negative_ttl: Duration::from_secs(60),
                 allow_stale: true,
             },
             async {
@@ -413,7 +426,7 @@ impl GoveeUndocumentedApi {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "one-click-shortcuts",
+                key: &self.cache_key("one-click-shortcuts"),
                 soft_ttl: ONE_DAY,
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(1),